@@ -16,7 +16,10 @@ fn main() {
     let proto_path = std::path::PathBuf::from(PROTO_FILE).canonicalize().unwrap();
     let proto_dir = proto_path.parent().unwrap();
 
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
     tonic_build::configure()
+        // Served by gRPC reflection.
+        .file_descriptor_set_path(out_dir.join("blueking_descriptor.bin"))
         .compile_protos(&[proto_path.as_path()], &[proto_dir])
         .expect("failed to compile protobuf definitions");
 
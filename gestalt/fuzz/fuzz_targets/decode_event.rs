@@ -0,0 +1,10 @@
+//! Feeds arbitrary frames to the inbound event decoder, which must reject bad input with an
+//! error instead of panicking or blowing the stack.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = blueking::events::decode_event(data);
+});
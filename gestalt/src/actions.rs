@@ -1,75 +1,1309 @@
 //! `actions` module hosts outbound actions on computers and the service for dispatching them.
 
-use crate::events::Capability;
-use crate::websocket::{ClientRegistry, LuaCommand};
+use crate::DispatchError;
+use crate::audit::AuditLog;
+use crate::events::{Capability, CommandResultEvent};
+use crate::websocket::{
+    BroadcastOutcome, ClientQuery, ClientRegistry, ClientSender, LuaCommand, SkipReason,
+    serialize_lua_command,
+};
 use axum::extract::ws::Message as WsMessage;
-use blueking::DispatchError;
 use pin_project_lite::pin_project;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore, broadcast, oneshot};
 use tower::Service;
 
+/// Default maximum number of commands in flight to a single capability, i.e. sent without a
+/// result yet; 0 disables the cap.
+const ENV_BLUEKING_DISPATCH_LIMIT: &str = "BLUEKING_DISPATCH_LIMIT";
+/// Per-capability overrides of the in-flight cap, e.g. `chat=2,turtle=16`.
+const ENV_BLUEKING_DISPATCH_LIMITS: &str = "BLUEKING_DISPATCH_LIMITS";
+const DEFAULT_DISPATCH_LIMIT: usize = 8;
+/// Commands per second each client may receive; 0 disables rate limiting.
+const ENV_BLUEKING_CLIENT_RATE: &str = "BLUEKING_CLIENT_RATE";
+/// Per-capability overrides of the client rate, e.g. `chat=1,turtle=20`.
+const ENV_BLUEKING_CLIENT_RATES: &str = "BLUEKING_CLIENT_RATES";
+/// Commands a client may receive in a burst before the rate applies.
+const ENV_BLUEKING_CLIENT_BURST: &str = "BLUEKING_CLIENT_BURST";
+const DEFAULT_CLIENT_RATE: f64 = 10.0;
+const DEFAULT_CLIENT_BURST: f64 = 20.0;
+/// Capabilities that start out disabled, e.g. `reboot,turtle`; re-read on reload.
+const ENV_BLUEKING_DISABLED_CAPABILITIES: &str = "BLUEKING_DISABLED_CAPABILITIES";
+/// Maximum number of commands awaiting a result; 0 disables the cap.
+const ENV_BLUEKING_MAX_PENDING_COMMANDS: &str = "BLUEKING_MAX_PENDING_COMMANDS";
+const DEFAULT_MAX_PENDING_COMMANDS: usize = 1024;
+/// Seconds after which a command without a result stops counting against the cap.
+const ENV_BLUEKING_PENDING_TIMEOUT_SECS: &str = "BLUEKING_PENDING_TIMEOUT_SECS";
+const DEFAULT_PENDING_TIMEOUT_SECS: u64 = 300;
+/// Maximum number of commands awaiting a result from any one client; 0 disables the cap.
+const ENV_BLUEKING_MAX_PENDING_PER_CLIENT: &str = "BLUEKING_MAX_PENDING_PER_CLIENT";
+const DEFAULT_MAX_PENDING_PER_CLIENT: usize = 64;
+/// How `SendToCapability` picks recipients by default: `first`, `round_robin`, `broadcast` or
+/// `most_recent`.
+const ENV_BLUEKING_DISPATCH_STRATEGY: &str = "BLUEKING_DISPATCH_STRATEGY";
+/// Per-capability overrides of the strategy, e.g. `chat=first,reboot=broadcast`.
+///
+/// `chat` defaults to `most_recent` so replies stick to the conversation's computer.
+const ENV_BLUEKING_DISPATCH_STRATEGIES: &str = "BLUEKING_DISPATCH_STRATEGIES";
+/// Records buffered per `/monitor` subscriber before a lagging one is dropped.
+const MONITOR_QUEUE: usize = 256;
+/// Milliseconds a dispatched command may wait in a client's outbound queue before it is
+/// dropped as stale; 0 never expires.
+const ENV_BLUEKING_COMMAND_TTL_MS: &str = "BLUEKING_COMMAND_TTL_MS";
+const DEFAULT_COMMAND_TTL_MS: u64 = 0;
+/// `true` to log outbound commands instead of sending them, e.g. in staging.
+const ENV_BLUEKING_DRY_RUN: &str = "BLUEKING_DRY_RUN";
+
 /// Outbound actions towards computers / websocket clients.
 #[derive(Clone)]
 pub enum ComputerAction {
-    #[allow(dead_code)]
-    SendToId { id: i32, message: WsMessage },
+    SendToId {
+        id: i32,
+        message: WsMessage,
+    },
     SendToCapability {
         capability: Capability,
         command: LuaCommand,
     },
+    /// Send to one client by id, with the checks of `SendToCapability`: the capability must be
+    /// enabled and advertised by the client, and the command is validated and tracked.
+    SendCommandToId {
+        id: i32,
+        capability: Capability,
+        command: LuaCommand,
+    },
+    /// Fan out to every client with the capability; slow clients are skipped, not awaited.
+    Broadcast {
+        capability: Capability,
+        command: LuaCommand,
+    },
+    /// Send to the client an operator labelled, as an alternative to its numeric id; the lowest
+    /// id wins when several share the label.
+    SendToLabel {
+        label: String,
+        command: LuaCommand,
+    },
+    /// Fan out to every client matching `predicate`, e.g. chat clients in a given dimension.
+    SendToQuery {
+        predicate: ClientQuery,
+        command: LuaCommand,
+    },
+}
+
+/// Delivery state of a command sent to a computer that has not reported a result yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandState {
+    /// Queued for the client, no acknowledgment seen.
+    Sent,
+    /// The client acknowledged receipt but has not reported execution.
+    Received,
+}
+
+impl CommandState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CommandState::Sent => "sent",
+            CommandState::Received => "received",
+        }
+    }
+}
+
+/// Point-in-time view of one command awaiting a result.
+#[derive(Debug, Clone)]
+pub struct PendingSnapshot {
+    pub command_id: String,
+    /// Recipient of a single-client send; `None` for fan-outs.
+    pub client: Option<i32>,
+    pub age: Duration,
+    pub state: CommandState,
+    /// Whether a caller is blocked on the result, e.g. a `SendAndWait` RPC.
+    pub awaited: bool,
+}
+
+struct PendingEntry {
+    state: CommandState,
+    sent_at: Instant,
+    /// Recipient of a single-client send; `None` for fan-outs.
+    client: Option<i32>,
+    /// Clients a fan-out went to, each of which it counts against.
+    recipients: Vec<i32>,
+    /// In-flight slot of the targeted capability, released with the entry.
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl PendingEntry {
+    fn counts_against(&self, client: i32) -> bool {
+        self.client == Some(client) || self.recipients.contains(&client)
+    }
+}
+
+/// Command ids remembered after they stop being tracked, to tell late results from unknown ones.
+const RETIRED_MEMORY: usize = 1024;
+
+/// Why a command stopped being tracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Retired {
+    Completed,
+    Expired,
+}
+
+/// How a reported result relates to the commands this server issued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultMatch {
+    /// A tracked command finished within the pending timeout.
+    OnTime(CommandState),
+    /// A tracked command finished, but only after the pending timeout.
+    Late(Duration),
+    /// The command was presumed lost and evicted before its result arrived.
+    Expired,
+    /// The command already reported a result, e.g. from another broadcast recipient.
+    Duplicate,
+    /// The id matches nothing this server issued recently.
+    Unknown,
+}
+
+/// Commands awaiting a `command_result`, keyed by command id.
+///
+/// Bounded so a brain issuing commands faster than clients finish them can't grow the map
+/// without limit; entries older than the timeout are presumed lost and make room. A second,
+/// per-client cap keeps one client that never reports results from using up the whole map; a
+/// fan-out counts against every client it reached.
+#[derive(Clone)]
+pub struct PendingCommands {
+    commands: Arc<Mutex<HashMap<String, PendingEntry>>>,
+    /// Recently untracked ids, oldest first, capped at `RETIRED_MEMORY`.
+    retired: Arc<std::sync::Mutex<VecDeque<(String, Retired)>>>,
+    /// Callers blocked on a command's result, keyed by command id.
+    waiters: Arc<std::sync::Mutex<HashMap<String, oneshot::Sender<CommandResultEvent>>>>,
+    max: usize,
+    max_per_client: usize,
+    timeout: Duration,
+}
+
+impl PendingCommands {
+    pub fn new(max: usize, max_per_client: usize, timeout: Duration) -> Self {
+        Self {
+            commands: Arc::new(Mutex::new(HashMap::new())),
+            retired: Arc::new(std::sync::Mutex::new(VecDeque::new())),
+            waiters: Arc::default(),
+            max,
+            max_per_client,
+            timeout,
+        }
+    }
+
+    /// Read `BLUEKING_MAX_PENDING_COMMANDS`, `BLUEKING_MAX_PENDING_PER_CLIENT` and
+    /// `BLUEKING_PENDING_TIMEOUT_SECS`.
+    pub fn from_env() -> Self {
+        Self::new(
+            crate::env_or(
+                ENV_BLUEKING_MAX_PENDING_COMMANDS,
+                DEFAULT_MAX_PENDING_COMMANDS,
+            ),
+            crate::env_or(
+                ENV_BLUEKING_MAX_PENDING_PER_CLIENT,
+                DEFAULT_MAX_PENDING_PER_CLIENT,
+            ),
+            Duration::from_secs(crate::env_or(
+                ENV_BLUEKING_PENDING_TIMEOUT_SECS,
+                DEFAULT_PENDING_TIMEOUT_SECS,
+            )),
+        )
+    }
+
+    /// Start tracking a command about to be sent to `client` (`None` for a fan-out).
+    ///
+    /// `permit` is the command's in-flight slot from `DispatchLimits`, held until the command
+    /// finishes or expires. Fails with `Overloaded` at the overall cap and `TooManyPending` at
+    /// the per-client cap. Callers release the slot with `complete` if the send then fails.
+    pub async fn track(
+        &self,
+        command_id: String,
+        client: Option<i32>,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<(), DispatchError> {
+        let mut commands = self.commands.lock().await;
+        self.make_room(&mut commands)?;
+        if let Some(client) = client
+            && self.is_full(&commands, client)
+        {
+            self.evict_expired(&mut commands);
+            if self.is_full(&commands, client) {
+                return Err(DispatchError::TooManyPending(client));
+            }
+        }
+        commands.insert(
+            command_id,
+            PendingEntry {
+                state: CommandState::Sent,
+                sent_at: Instant::now(),
+                client,
+                recipients: Vec::new(),
+                _permit: permit,
+            },
+        );
+        Ok(())
+    }
+
+    /// Start tracking a command about to be fanned out to `recipients`.
+    ///
+    /// Recipients already at the per-client cap are left out rather than failing the whole
+    /// fan-out. Returns the recipients the command may go to and those it may not, in order;
+    /// fails with `Overloaded` at the overall cap.
+    pub async fn track_fan_out(
+        &self,
+        command_id: String,
+        recipients: Vec<i32>,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<(Vec<i32>, Vec<i32>), DispatchError> {
+        let mut commands = self.commands.lock().await;
+        self.make_room(&mut commands)?;
+        if recipients.iter().any(|id| self.is_full(&commands, *id)) {
+            self.evict_expired(&mut commands);
+        }
+        let (admitted, refused): (Vec<i32>, Vec<i32>) = recipients
+            .into_iter()
+            .partition(|id| !self.is_full(&commands, *id));
+        commands.insert(
+            command_id,
+            PendingEntry {
+                state: CommandState::Sent,
+                sent_at: Instant::now(),
+                client: None,
+                recipients: admitted.clone(),
+                _permit: permit,
+            },
+        );
+        Ok((admitted, refused))
+    }
+
+    /// Narrow a fan-out's recipients to the clients it was actually delivered to.
+    async fn delivered(&self, command_id: &str, delivered: &[i32]) {
+        if let Some(entry) = self.commands.lock().await.get_mut(command_id) {
+            entry.recipients.retain(|id| delivered.contains(id));
+        }
+    }
+
+    /// Fail with `Overloaded` when the map is at the overall cap even after evicting.
+    fn make_room(&self, commands: &mut HashMap<String, PendingEntry>) -> Result<(), DispatchError> {
+        if self.max > 0 && commands.len() >= self.max {
+            self.evict_expired(commands);
+            if commands.len() >= self.max {
+                return Err(DispatchError::Overloaded("pending commands".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `client` is at the per-client cap, counting fan-outs that reached it.
+    fn is_full(&self, commands: &HashMap<String, PendingEntry>, client: i32) -> bool {
+        self.max_per_client > 0
+            && commands
+                .values()
+                .filter(|entry| entry.counts_against(client))
+                .count()
+                >= self.max_per_client
+    }
+
+    /// Drop every entry older than the timeout, releasing its in-flight slot.
+    pub async fn expire(&self) {
+        self.evict_expired(&mut *self.commands.lock().await);
+    }
+
+    /// Drop entries older than the timeout, remembering them as expired.
+    fn evict_expired(&self, commands: &mut HashMap<String, PendingEntry>) {
+        let now = Instant::now();
+        commands.retain(|id, entry| {
+            let live = now.duration_since(entry.sent_at) < self.timeout;
+            if !live {
+                tracing::warn!("Command {} timed out without a result", id);
+                self.retire(id.clone(), Retired::Expired);
+            }
+            live
+        });
+    }
+
+    /// How long a command may go without a result before it is presumed lost.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Register interest in the result of `command_id`; call before dispatching it so a fast
+    /// result can't be missed. Dropping the waiter withdraws the interest.
+    pub fn wait_for(&self, command_id: &str) -> ResultWaiter {
+        let (tx, rx) = oneshot::channel();
+        self.waiters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(command_id.to_string(), tx);
+        ResultWaiter {
+            command_id: command_id.to_string(),
+            waiters: Arc::clone(&self.waiters),
+            rx,
+        }
+    }
+
+    /// Hand a reported result to the caller waiting on it, if any; later results for the same
+    /// id, e.g. from other broadcast recipients, go nowhere.
+    pub fn deliver(&self, result: &CommandResultEvent) {
+        let waiter = self
+            .waiters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&result.command_id);
+        if let Some(waiter) = waiter {
+            let _ = waiter.send(result.clone());
+        }
+    }
+
+    /// Number of commands awaiting a result, per client, counting each fan-out once for every
+    /// client it reached.
+    pub async fn per_client(&self) -> HashMap<i32, usize> {
+        let mut counts = HashMap::new();
+        for entry in self.commands.lock().await.values() {
+            for client in entry.client.iter().chain(&entry.recipients) {
+                *counts.entry(*client).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// Copy of every command awaiting a result, oldest first.
+    ///
+    /// Holds the map only while copying, so dispatch is not held up by the caller.
+    pub async fn snapshot(&self) -> Vec<PendingSnapshot> {
+        let now = Instant::now();
+        let mut commands: Vec<PendingSnapshot> = self
+            .commands
+            .lock()
+            .await
+            .iter()
+            .map(|(id, entry)| PendingSnapshot {
+                command_id: id.clone(),
+                client: entry.client,
+                age: now.duration_since(entry.sent_at),
+                state: entry.state,
+                awaited: false,
+            })
+            .collect();
+        let waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        for command in &mut commands {
+            command.awaited = waiters.contains_key(&command.command_id);
+        }
+        drop(waiters);
+        commands.sort_by_key(|command| std::cmp::Reverse(command.age));
+        commands
+    }
+
+    /// Mark a command as received by its client. Returns `false` for unknown ids.
+    pub async fn acknowledge(&self, command_id: &str) -> bool {
+        match self.commands.lock().await.get_mut(command_id) {
+            Some(entry) => {
+                entry.state = CommandState::Received;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop tracking a command that has completed, returning the state it was in.
+    pub async fn complete(&self, command_id: &str) -> Option<CommandState> {
+        self.commands
+            .lock()
+            .await
+            .remove(command_id)
+            .map(|entry| entry.state)
+    }
+
+    /// Stop tracking a command whose result arrived and classify how the result matched.
+    pub async fn finish(&self, command_id: &str) -> ResultMatch {
+        let entry = self.commands.lock().await.remove(command_id);
+        if let Some(entry) = entry {
+            self.retire(command_id.to_string(), Retired::Completed);
+            let elapsed = entry.sent_at.elapsed();
+            return if elapsed >= self.timeout {
+                ResultMatch::Late(elapsed)
+            } else {
+                ResultMatch::OnTime(entry.state)
+            };
+        }
+        let retired = self.retired.lock().unwrap_or_else(|e| e.into_inner());
+        match retired.iter().rev().find(|(id, _)| id == command_id) {
+            Some((_, Retired::Completed)) => ResultMatch::Duplicate,
+            Some((_, Retired::Expired)) => ResultMatch::Expired,
+            None => ResultMatch::Unknown,
+        }
+    }
+
+    fn retire(&self, command_id: String, how: Retired) {
+        let mut retired = self.retired.lock().unwrap_or_else(|e| e.into_inner());
+        if retired.len() >= RETIRED_MEMORY {
+            retired.pop_front();
+        }
+        retired.push_back((command_id, how));
+    }
+}
+
+/// Pending interest in one command's result, from `PendingCommands::wait_for`.
+pub struct ResultWaiter {
+    command_id: String,
+    waiters: Arc<std::sync::Mutex<HashMap<String, oneshot::Sender<CommandResultEvent>>>>,
+    rx: oneshot::Receiver<CommandResultEvent>,
+}
+
+impl ResultWaiter {
+    /// Wait for the result; `None` if the server dropped the waiter first.
+    pub async fn result(mut self) -> Option<CommandResultEvent> {
+        (&mut self.rx).await.ok()
+    }
+}
+
+impl Drop for ResultWaiter {
+    fn drop(&mut self) {
+        self.waiters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.command_id);
+    }
+}
+
+/// Operator kill-switch: capabilities that receive no commands until re-enabled.
+///
+/// Shared across clones and read on every capability dispatch, so it sits behind a std
+/// `RwLock` rather than an async lock.
+#[derive(Clone, Default)]
+pub struct DisabledCapabilities {
+    disabled: Arc<std::sync::RwLock<HashSet<Capability>>>,
+}
+
+impl DisabledCapabilities {
+    /// Settings `reload` re-reads.
+    pub const VARS: &[&str] = &[ENV_BLUEKING_DISABLED_CAPABILITIES];
+
+    /// Start with the capabilities listed in `BLUEKING_DISABLED_CAPABILITIES` disabled.
+    pub fn from_env() -> Self {
+        let this = Self::default();
+        this.reload(crate::config::var);
+        this
+    }
+
+    /// Replace the disabled set with the one configured in `var`, discarding runtime toggles.
+    pub fn reload(&self, var: impl Fn(&str) -> Option<String>) {
+        let configured: HashSet<Capability> = var(ENV_BLUEKING_DISABLED_CAPABILITIES)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|cap| !cap.is_empty())
+            .map(|cap| Capability::from(cap.to_string()))
+            .collect();
+        *self.disabled.write().unwrap_or_else(|e| e.into_inner()) = configured;
+    }
+
+    /// Enable or disable routing to `capability`; returns whether anything changed.
+    pub fn set_enabled(&self, capability: Capability, enabled: bool) -> bool {
+        let mut disabled = self.disabled.write().unwrap_or_else(|e| e.into_inner());
+        if enabled {
+            disabled.remove(&capability)
+        } else {
+            disabled.insert(capability)
+        }
+    }
+
+    /// Currently disabled capabilities, sorted by name.
+    pub fn list(&self) -> Vec<Capability> {
+        let mut list: Vec<Capability> = self
+            .disabled
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        list
+    }
+
+    fn check(&self, capability: &Capability) -> Result<(), DispatchError> {
+        let disabled = self.disabled.read().unwrap_or_else(|e| e.into_inner());
+        if disabled.contains(capability) {
+            return Err(DispatchError::Disabled(capability.as_str().to_string()));
+        }
+        Ok(())
+    }
+
+    /// Refuse a command whose required capability (e.g. `file_transfer` for `write_file`) is
+    /// disabled or is not what the command is routed by, so it only reaches clients that opted in.
+    fn check_required(
+        &self,
+        command: &LuaCommand,
+        routed_by: Option<&Capability>,
+    ) -> Result<(), DispatchError> {
+        let Some(required) = command.required_capability() else {
+            return Ok(());
+        };
+        self.check(&required)?;
+        if routed_by != Some(&required) {
+            return Err(DispatchError::InvalidCommand(format!(
+                "command {} must be sent to clients with the {} capability",
+                command.id(),
+                required.as_str()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How a `SendToCapability` action chooses among the clients advertising the capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DispatchStrategy {
+    /// Any one matching client.
+    #[default]
+    First,
+    /// Matching clients in turn, ordered by id.
+    RoundRobin,
+    /// Every matching client, as with `ComputerAction::Broadcast`.
+    Broadcast,
+    /// The matching client that most recently sent an event; round-robin when none has.
+    MostRecent,
+}
+
+impl DispatchStrategy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DispatchStrategy::First => "first",
+            DispatchStrategy::RoundRobin => "round_robin",
+            DispatchStrategy::Broadcast => "broadcast",
+            DispatchStrategy::MostRecent => "most_recent",
+        }
+    }
+}
+
+impl std::str::FromStr for DispatchStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(DispatchStrategy::First),
+            "round_robin" => Ok(DispatchStrategy::RoundRobin),
+            "broadcast" => Ok(DispatchStrategy::Broadcast),
+            "most_recent" => Ok(DispatchStrategy::MostRecent),
+            other => Err(format!("unknown dispatch strategy: {other}")),
+        }
+    }
+}
+
+/// Per-capability `DispatchStrategy`, adjustable at runtime, plus round-robin cursors.
+#[derive(Clone)]
+pub struct DispatchStrategies {
+    default: DispatchStrategy,
+    overrides: Arc<std::sync::RwLock<HashMap<Capability, DispatchStrategy>>>,
+    turns: Arc<std::sync::Mutex<HashMap<Capability, usize>>>,
+}
+
+impl DispatchStrategies {
+    pub fn new(
+        default: DispatchStrategy,
+        overrides: HashMap<Capability, DispatchStrategy>,
+    ) -> Self {
+        Self {
+            default,
+            overrides: Arc::new(std::sync::RwLock::new(overrides)),
+            turns: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Read `BLUEKING_DISPATCH_STRATEGY` and `BLUEKING_DISPATCH_STRATEGIES`, skipping malformed entries.
+    pub fn from_env() -> Self {
+        let default = crate::env_or(ENV_BLUEKING_DISPATCH_STRATEGY, DispatchStrategy::First);
+        let mut overrides = capability_overrides(
+            ENV_BLUEKING_DISPATCH_STRATEGIES,
+            crate::config::var(ENV_BLUEKING_DISPATCH_STRATEGIES),
+        );
+        overrides
+            .entry(Capability::Chat)
+            .or_insert(DispatchStrategy::MostRecent);
+        Self::new(default, overrides)
+    }
+
+    /// Strategy in effect for `capability`.
+    pub fn get(&self, capability: &Capability) -> DispatchStrategy {
+        self.overrides
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(capability)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Override the strategy for `capability`.
+    pub fn set(&self, capability: Capability, strategy: DispatchStrategy) {
+        self.overrides
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(capability, strategy);
+    }
+
+    /// Advance and return the round-robin cursor of `capability`.
+    fn next_turn(&self, capability: &Capability) -> usize {
+        let mut turns = self.turns.lock().unwrap_or_else(|e| e.into_inner());
+        let turn = turns.entry(capability.clone()).or_default();
+        let current = *turn;
+        *turn = turn.wrapping_add(1);
+        current
+    }
+}
+
+/// Caps how many commands may be in flight to each capability; excess commands are shed.
+///
+/// A command holds its slot until it reports a result or its pending entry expires.
+#[derive(Clone)]
+pub struct DispatchLimits {
+    default: usize,
+    overrides: Arc<HashMap<Capability, usize>>,
+    semaphores: Arc<std::sync::Mutex<HashMap<Capability, Arc<Semaphore>>>>,
+}
+
+impl DispatchLimits {
+    pub fn new(default: usize, overrides: HashMap<Capability, usize>) -> Self {
+        Self {
+            default,
+            overrides: Arc::new(overrides),
+            semaphores: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Read `BLUEKING_DISPATCH_LIMIT` and `BLUEKING_DISPATCH_LIMITS`, skipping malformed entries.
+    pub fn from_env() -> Self {
+        let default = crate::env_or(ENV_BLUEKING_DISPATCH_LIMIT, DEFAULT_DISPATCH_LIMIT);
+        let overrides = capability_overrides(
+            ENV_BLUEKING_DISPATCH_LIMITS,
+            crate::config::var(ENV_BLUEKING_DISPATCH_LIMITS),
+        );
+        Self::new(default, overrides)
+    }
+
+    /// Reserve an in-flight slot for `capability`, held until the permit is dropped.
+    ///
+    /// Returns `None` when the capability is uncapped.
+    fn try_acquire(
+        &self,
+        capability: &Capability,
+    ) -> Result<Option<OwnedSemaphorePermit>, DispatchError> {
+        let limit = self
+            .overrides
+            .get(capability)
+            .copied()
+            .unwrap_or(self.default);
+        if limit == 0 {
+            return Ok(None);
+        }
+        let semaphore = self
+            .semaphores
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(capability.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone();
+        semaphore
+            .try_acquire_owned()
+            .map(Some)
+            .map_err(|_| DispatchError::Overloaded(capability.as_str().to_string()))
+    }
+
+    /// Like `try_acquire`, but first frees the slots of expired commands when none is left.
+    async fn acquire(
+        &self,
+        capability: &Capability,
+        pending: &PendingCommands,
+    ) -> Result<Option<OwnedSemaphorePermit>, DispatchError> {
+        match self.try_acquire(capability) {
+            Err(DispatchError::Overloaded(_)) => {
+                pending.expire().await;
+                self.try_acquire(capability)
+            }
+            acquired => acquired,
+        }
+    }
+}
+
+/// Parse the `capability=value,...` list `raw` read from `name`, skipping malformed entries.
+fn capability_overrides<T: std::str::FromStr>(
+    name: &str,
+    raw: Option<String>,
+) -> HashMap<Capability, T> {
+    let mut overrides = HashMap::new();
+    let raw = raw.unwrap_or_default();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry
+            .split_once('=')
+            .and_then(|(cap, value)| Some((cap.trim(), value.trim().parse().ok()?)))
+        {
+            Some((cap, value)) if !cap.is_empty() => {
+                overrides.insert(Capability::from(cap.to_string()), value);
+            }
+            _ => tracing::warn!("Ignoring invalid {} entry: {}", name, entry),
+        }
+    }
+    overrides
+}
+
+/// Token bucket of a single client, refilled continuously at its rate.
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Client id and the capability a send targeted, if any.
+type BucketKey = (i32, Option<Capability>);
+
+/// How often `RateLimits` drops buckets that have refilled, and so hold no state a fresh
+/// bucket would not.
+const BUCKET_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token buckets by key, and when idle ones were last dropped.
+struct Buckets {
+    by_key: HashMap<BucketKey, TokenBucket>,
+    swept_at: Instant,
+}
+
+/// Per-client outbound rate limit, so a runaway brain can't flood one computer.
+///
+/// Each client gets a bucket per targeted capability; sends that don't name a capability use
+/// the global rate. Fan-outs are left to queue backpressure instead.
+#[derive(Clone)]
+pub struct RateLimits {
+    settings: Arc<std::sync::RwLock<RateSettings>>,
+    buckets: Arc<std::sync::Mutex<Buckets>>,
+}
+
+struct RateSettings {
+    rate: f64,
+    burst: f64,
+    overrides: HashMap<Capability, f64>,
+}
+
+impl RateSettings {
+    fn new(rate: f64, burst: f64, overrides: HashMap<Capability, f64>) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1.0),
+            overrides,
+        }
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        Self::new(
+            crate::parse_or(
+                ENV_BLUEKING_CLIENT_RATE,
+                var(ENV_BLUEKING_CLIENT_RATE),
+                DEFAULT_CLIENT_RATE,
+            ),
+            crate::parse_or(
+                ENV_BLUEKING_CLIENT_BURST,
+                var(ENV_BLUEKING_CLIENT_BURST),
+                DEFAULT_CLIENT_BURST,
+            ),
+            capability_overrides(ENV_BLUEKING_CLIENT_RATES, var(ENV_BLUEKING_CLIENT_RATES)),
+        )
+    }
+}
+
+impl RateLimits {
+    /// Settings `reload` re-reads.
+    pub const VARS: &[&str] = &[
+        ENV_BLUEKING_CLIENT_RATE,
+        ENV_BLUEKING_CLIENT_RATES,
+        ENV_BLUEKING_CLIENT_BURST,
+    ];
+
+    pub fn new(rate: f64, burst: f64, overrides: HashMap<Capability, f64>) -> Self {
+        Self::with_settings(RateSettings::new(rate, burst, overrides))
+    }
+
+    fn with_settings(settings: RateSettings) -> Self {
+        Self {
+            settings: Arc::new(std::sync::RwLock::new(settings)),
+            buckets: Arc::new(std::sync::Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                swept_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Read `BLUEKING_CLIENT_RATE`, `BLUEKING_CLIENT_RATES` and `BLUEKING_CLIENT_BURST`.
+    pub fn from_env() -> Self {
+        Self::with_settings(RateSettings::from_vars(crate::config::var))
+    }
+
+    /// Swap in rates read from `var`; existing buckets keep their tokens, capped to the new burst.
+    pub fn reload(&self, var: impl Fn(&str) -> Option<String>) {
+        let settings = RateSettings::from_vars(var);
+        *self.settings.write().unwrap_or_else(|e| e.into_inner()) = settings;
+    }
+
+    /// Spend one token for a send to `id`, or fail with `RateLimited` if its bucket is empty.
+    fn check(&self, id: i32, capability: Option<&Capability>) -> Result<(), DispatchError> {
+        self.check_at(id, capability, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        id: i32,
+        capability: Option<&Capability>,
+        now: Instant,
+    ) -> Result<(), DispatchError> {
+        let settings = self.settings.read().unwrap_or_else(|e| e.into_inner());
+        let rate_for = |capability: Option<&Capability>| {
+            capability
+                .and_then(|cap| settings.overrides.get(cap))
+                .copied()
+                .unwrap_or(settings.rate)
+        };
+        let (rate, burst) = (rate_for(capability), settings.burst);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if now.duration_since(buckets.swept_at) >= BUCKET_SWEEP_INTERVAL {
+            buckets.swept_at = now;
+            buckets.by_key.retain(|(_, capability), bucket| {
+                let rate = rate_for(capability.as_ref());
+                rate > 0.0
+                    && bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * rate
+                        < burst
+            });
+        }
+        drop(settings);
+        if rate <= 0.0 {
+            return Ok(());
+        }
+        let bucket = buckets
+            .by_key
+            .entry((id, capability.cloned()))
+            .or_insert(TokenBucket {
+                tokens: burst,
+                refilled_at: now,
+            });
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.refilled_at = now;
+        if bucket.tokens < 1.0 {
+            return Err(DispatchError::RateLimited(format!("client {id}")));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
 }
 
 /// Service that dispatches outbound actions to connected websocket clients via the registry.
 #[derive(Clone)]
 pub struct ComputerDispatchService {
     registry: ClientRegistry,
+    pending: PendingCommands,
+    audit: AuditLog,
+    limits: DispatchLimits,
+    rate_limits: RateLimits,
+    disabled: DisabledCapabilities,
+    strategies: DispatchStrategies,
+    /// Log outbound commands instead of sending them; lookups still run.
+    dry_run: bool,
+    /// How long a command may sit in a client's outbound queue; `None` never expires.
+    command_ttl: Option<Duration>,
+    /// JSON records of dispatched actions for `/monitor` subscribers.
+    monitor: broadcast::Sender<Arc<str>>,
 }
 
 impl ComputerDispatchService {
-    pub fn new(registry: ClientRegistry) -> Self {
-        Self { registry }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        registry: ClientRegistry,
+        audit: AuditLog,
+        pending: PendingCommands,
+        limits: DispatchLimits,
+        rate_limits: RateLimits,
+        disabled: DisabledCapabilities,
+        strategies: DispatchStrategies,
+        dry_run: bool,
+        command_ttl: Option<Duration>,
+    ) -> Self {
+        let (monitor, _) = broadcast::channel(MONITOR_QUEUE);
+        Self {
+            registry,
+            pending,
+            audit,
+            limits,
+            rate_limits,
+            disabled,
+            strategies,
+            dry_run,
+            command_ttl,
+            monitor,
+        }
+    }
+
+    /// Build the service with pending cap, limits, rates and dry-run mode from the environment.
+    pub fn from_env(registry: ClientRegistry, audit: AuditLog) -> Self {
+        let dry_run = crate::env_or(ENV_BLUEKING_DRY_RUN, false);
+        if dry_run {
+            tracing::warn!("Dry-run mode: commands are logged, not sent to computers");
+        }
+        Self::new(
+            registry,
+            audit,
+            PendingCommands::from_env(),
+            DispatchLimits::from_env(),
+            RateLimits::from_env(),
+            DisabledCapabilities::from_env(),
+            DispatchStrategies::from_env(),
+            dry_run,
+            match crate::env_or(ENV_BLUEKING_COMMAND_TTL_MS, DEFAULT_COMMAND_TTL_MS) {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+        )
+    }
+
+    /// Tracker for commands that were dispatched but have not completed yet.
+    pub fn pending(&self) -> &PendingCommands {
+        &self.pending
+    }
+
+    /// Per-capability recipient selection for `SendToCapability`.
+    pub fn strategies(&self) -> &DispatchStrategies {
+        &self.strategies
+    }
+
+    /// Capabilities an operator has switched off at runtime.
+    pub fn disabled(&self) -> &DisabledCapabilities {
+        &self.disabled
+    }
+
+    /// Per-client outbound rate limits.
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.rate_limits
+    }
+
+    /// Feed of every dispatched action as a JSON record.
+    pub fn subscribe_monitor(&self) -> broadcast::Receiver<Arc<str>> {
+        self.monitor.subscribe()
     }
 
     fn dispatch_action(&self, action: ComputerAction) -> ClientDispatchFuture {
-        let registry = self.registry.clone();
+        self.audit.record_action(&action);
+        if self.monitor.receiver_count() > 0 {
+            let mut record = crate::audit::action_record(&action);
+            record["ts"] = crate::audit::now_ms().into();
+            let _ = self.monitor.send(record.to_string().into());
+        }
+        let this = self.clone();
         ClientDispatchFuture {
-            handle: tokio::spawn(async move { Self::handle_action(registry, action).await }),
+            handle: tokio::spawn(async move { this.handle_action(action).await }),
         }
     }
 
-    async fn handle_action(
-        registry: ClientRegistry,
-        action: ComputerAction,
-    ) -> Result<(), DispatchError> {
+    /// Carry out `action`, returning the ids of the clients that received the command.
+    async fn handle_action(self, action: ComputerAction) -> Result<Vec<i32>, DispatchError> {
+        let Self {
+            registry,
+            pending,
+            limits,
+            rate_limits,
+            disabled,
+            strategies,
+            dry_run,
+            command_ttl,
+            ..
+        } = self;
+        let action = match action {
+            ComputerAction::SendToCapability {
+                capability,
+                command,
+            } if strategies.get(&capability) == DispatchStrategy::Broadcast => {
+                ComputerAction::Broadcast {
+                    capability,
+                    command,
+                }
+            }
+            action => action,
+        };
         match action {
             ComputerAction::SendToId { id, message } => {
-                registry
-                    .send_to(id, message)
-                    .await
-                    .map_err(DispatchError::SendFailed)?;
+                Self::check_paused(&registry, id).await?;
+                rate_limits.check(id, None)?;
+                if dry_run {
+                    if !registry.is_connected(id).await {
+                        return Err(DispatchError::ClientGone(id));
+                    }
+                    tracing::info!("Dry run: would send {:?} to client {}", message, id);
+                    return Ok(vec![id]);
+                }
+                registry.send_tracked(id, message, command_ttl).await?;
+                Ok(vec![id])
             }
             ComputerAction::SendToCapability {
                 capability,
                 command,
             } => {
-                if let Some(sender) = registry.find_by_capability(capability).await {
-                    sender
-                        .send_lua_command(&command)
-                        .await
-                        .map_err(|e| DispatchError::SendFailed(e.to_string()))?;
-                } else {
+                disabled.check(&capability)?;
+                disabled.check_required(&command, Some(&capability))?;
+                command.validate().map_err(DispatchError::InvalidCommand)?;
+                let permit = limits.acquire(&capability, &pending).await?;
+                let sender = match strategies.get(&capability) {
+                    DispatchStrategy::RoundRobin => {
+                        let turn = strategies.next_turn(&capability);
+                        registry.pick_by_capability(&capability, turn).await
+                    }
+                    DispatchStrategy::MostRecent => {
+                        match registry
+                            .find_most_recent_by_capability(&capability, 0)
+                            .await
+                        {
+                            Some(sender) => Some(sender),
+                            None => {
+                                let turn = strategies.next_turn(&capability);
+                                registry.pick_by_capability(&capability, turn).await
+                            }
+                        }
+                    }
+                    _ => registry.find_by_capability(capability.clone()).await,
+                };
+                let Some(sender) = sender else {
+                    return Err(DispatchError::NoClient);
+                };
+                Self::check_paused(&registry, sender.id()).await?;
+                rate_limits.check(sender.id(), Some(&capability))?;
+                if dry_run {
+                    return Self::log_dry_run(&command, vec![sender.id()]);
+                }
+                Self::send_and_track_pending(
+                    &registry,
+                    &pending,
+                    &sender,
+                    &command,
+                    permit,
+                    command_ttl,
+                )
+                .await?;
+                Ok(vec![sender.id()])
+            }
+            ComputerAction::SendCommandToId {
+                id,
+                capability,
+                command,
+            } => {
+                disabled.check(&capability)?;
+                disabled.check_required(&command, Some(&capability))?;
+                command.validate().map_err(DispatchError::InvalidCommand)?;
+                let permit = limits.acquire(&capability, &pending).await?;
+                let Some(sender) = registry.find_with_capability(id, &capability).await else {
                     return Err(DispatchError::NoClient);
+                };
+                Self::check_paused(&registry, id).await?;
+                rate_limits.check(id, Some(&capability))?;
+                if dry_run {
+                    return Self::log_dry_run(&command, vec![id]);
                 }
+                Self::send_and_track_pending(
+                    &registry,
+                    &pending,
+                    &sender,
+                    &command,
+                    permit,
+                    command_ttl,
+                )
+                .await?;
+                Ok(vec![id])
             }
+            ComputerAction::Broadcast {
+                capability,
+                command,
+            } => {
+                disabled.check(&capability)?;
+                disabled.check_required(&command, Some(&capability))?;
+                command.validate().map_err(DispatchError::InvalidCommand)?;
+                let permit = limits.acquire(&capability, &pending).await?;
+                let query = ClientQuery {
+                    capability: Some(capability),
+                    ..Default::default()
+                };
+                let recipients = registry.recipients(&query).await;
+                if dry_run {
+                    return Self::log_dry_run(&command, recipients);
+                }
+                Self::fan_out_tracked(
+                    &registry,
+                    &pending,
+                    recipients,
+                    &command,
+                    permit,
+                    command_ttl,
+                )
+                .await
+            }
+            ComputerAction::SendToLabel { label, command } => {
+                command.validate().map_err(DispatchError::InvalidCommand)?;
+                let Some(sender) = registry.find_by_label(&label).await else {
+                    return Err(DispatchError::NoClient);
+                };
+                let capabilities = registry.capabilities(sender.id()).await.unwrap_or_default();
+                let required = command
+                    .required_capability()
+                    .filter(|required| capabilities.contains(required));
+                if let Some(required) = &required {
+                    disabled.check(required)?;
+                }
+                disabled.check_required(&command, required.as_ref())?;
+                Self::check_paused(&registry, sender.id()).await?;
+                rate_limits.check(sender.id(), None)?;
+                if dry_run {
+                    return Self::log_dry_run(&command, vec![sender.id()]);
+                }
+                Self::send_and_track_pending(
+                    &registry,
+                    &pending,
+                    &sender,
+                    &command,
+                    None,
+                    command_ttl,
+                )
+                .await?;
+                Ok(vec![sender.id()])
+            }
+            ComputerAction::SendToQuery { predicate, command } => {
+                if let Some(capability) = &predicate.capability {
+                    disabled.check(capability)?;
+                }
+                disabled.check_required(&command, predicate.capability.as_ref())?;
+                command.validate().map_err(DispatchError::InvalidCommand)?;
+                let permit = match &predicate.capability {
+                    Some(capability) => limits.acquire(capability, &pending).await?,
+                    None => None,
+                };
+                let recipients = registry.recipients(&predicate).await;
+                if dry_run {
+                    return Self::log_dry_run(&command, recipients);
+                }
+                Self::fan_out_tracked(
+                    &registry,
+                    &pending,
+                    recipients,
+                    &command,
+                    permit,
+                    command_ttl,
+                )
+                .await
+            }
+        }
+    }
+
+    /// Refuse a single-recipient send to a client an operator has paused.
+    async fn check_paused(registry: &ClientRegistry, id: i32) -> Result<(), DispatchError> {
+        if registry.is_paused(id).await {
+            return Err(DispatchError::ClientPaused(id));
         }
         Ok(())
     }
+
+    /// Log the command a dry run would have sent, failing like a real send when nobody matches.
+    ///
+    /// Reports the would-be recipients as if they had received it.
+    fn log_dry_run(command: &LuaCommand, recipients: Vec<i32>) -> Result<Vec<i32>, DispatchError> {
+        if recipients.is_empty() {
+            return Err(DispatchError::NoClient);
+        }
+        let encoded =
+            serialize_lua_command(command).map_err(|e| DispatchError::SendFailed(e.to_string()))?;
+        tracing::info!(
+            "Dry run: would send {} to client(s) {:?}",
+            encoded,
+            recipients
+        );
+        Ok(recipients)
+    }
+
+    /// Send a command to one client, tracking it before the send so an early ack can't race.
+    async fn send_and_track_pending(
+        registry: &ClientRegistry,
+        pending: &PendingCommands,
+        sender: &ClientSender,
+        command: &LuaCommand,
+        permit: Option<OwnedSemaphorePermit>,
+        ttl: Option<Duration>,
+    ) -> Result<(), DispatchError> {
+        pending
+            .track(command.id().to_string(), Some(sender.id()), permit)
+            .await?;
+        if let Err(err) = registry.send_command_tracked(sender, command, ttl).await {
+            pending.complete(command.id()).await;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Send `command` to each of `recipients` that is under its pending cap, tracking it against
+    /// the clients it reaches.
+    async fn fan_out_tracked(
+        registry: &ClientRegistry,
+        pending: &PendingCommands,
+        recipients: Vec<i32>,
+        command: &LuaCommand,
+        permit: Option<OwnedSemaphorePermit>,
+        ttl: Option<Duration>,
+    ) -> Result<Vec<i32>, DispatchError> {
+        let (admitted, refused) = pending
+            .track_fan_out(command.id().to_string(), recipients, permit)
+            .await?;
+        let mut outcomes = registry.fan_out_lua_command(&admitted, command, ttl).await;
+        for id in refused {
+            tracing::warn!("Broadcast skipped client {}: too many pending commands", id);
+            outcomes.push((id, BroadcastOutcome::Skipped(SkipReason::TooManyPending)));
+        }
+        outcomes.sort_unstable_by_key(|(id, _)| *id);
+        Self::settle_fan_out(pending, command, outcomes).await
+    }
+
+    /// Log the outcome of a fan-out, releasing its pending slot if nobody received it.
+    ///
+    /// Returns the clients it was delivered to, possibly none.
+    async fn settle_fan_out(
+        pending: &PendingCommands,
+        command: &LuaCommand,
+        outcomes: Vec<(i32, BroadcastOutcome)>,
+    ) -> Result<Vec<i32>, DispatchError> {
+        if outcomes.is_empty() {
+            pending.complete(command.id()).await;
+            return Err(DispatchError::NoClient);
+        }
+        let mut delivered = Vec::new();
+        for (id, outcome) in &outcomes {
+            match outcome {
+                BroadcastOutcome::Delivered => delivered.push(*id),
+                BroadcastOutcome::Skipped(_) => {}
+                BroadcastOutcome::Failed(err) => {
+                    tracing::warn!("Broadcast to client {} failed: {}", id, err)
+                }
+            }
+        }
+        tracing::debug!(
+            "Broadcast {} delivered to {}/{} client(s)",
+            command.id(),
+            delivered.len(),
+            outcomes.len()
+        );
+        if delivered.is_empty() {
+            pending.complete(command.id()).await;
+        } else {
+            pending.delivered(command.id(), &delivered).await;
+        }
+        Ok(delivered)
+    }
 }
 
+/// Resolves to the ids of the clients that received the command; for a dry run, the clients
+/// that would have.
 impl Service<ComputerAction> for ComputerDispatchService {
-    type Response = ();
+    type Response = Vec<i32>;
     type Error = DispatchError;
     type Future = ClientDispatchFuture;
 
@@ -85,12 +1319,12 @@ impl Service<ComputerAction> for ComputerDispatchService {
 pin_project! {
     pub struct ClientDispatchFuture {
         #[pin]
-        handle: tokio::task::JoinHandle<Result<(), DispatchError>>,
+        handle: tokio::task::JoinHandle<Result<Vec<i32>, DispatchError>>,
     }
 }
 
 impl Future for ClientDispatchFuture {
-    type Output = Result<(), DispatchError>;
+    type Output = Result<Vec<i32>, DispatchError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
@@ -98,9 +1332,416 @@ impl Future for ClientDispatchFuture {
         match join {
             Ok(res) => Poll::Ready(res),
             Err(err) => {
-                tracing::warn!("Dispatch task join error: {}", err);
-                Poll::Ready(Ok(()))
+                tracing::error!("Dispatch task join error: {}", err);
+                Poll::Ready(Err(DispatchError::TaskPanicked(err.to_string())))
             }
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::events::FrameFormat;
+    use crate::store::MemoryStore;
+    use crate::websocket::{Outbound, RegistryConfig};
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    pub(crate) fn registry() -> ClientRegistry {
+        ClientRegistry::new(RegistryConfig::from_env(), Arc::new(MemoryStore::default()))
+    }
+
+    pub(crate) fn dispatch(
+        registry: &ClientRegistry,
+        pending: PendingCommands,
+    ) -> ComputerDispatchService {
+        dispatch_limited(registry, pending, DispatchLimits::new(0, HashMap::new()))
+    }
+
+    fn dispatch_limited(
+        registry: &ClientRegistry,
+        pending: PendingCommands,
+        limits: DispatchLimits,
+    ) -> ComputerDispatchService {
+        ComputerDispatchService::new(
+            registry.clone(),
+            AuditLog::default(),
+            pending,
+            limits,
+            RateLimits::new(0.0, 1.0, HashMap::new()),
+            DisabledCapabilities::default(),
+            DispatchStrategies::new(DispatchStrategy::First, HashMap::new()),
+            false,
+            None,
+        )
+    }
+
+    /// Register client `id`, returning its outbound queue.
+    pub(crate) async fn connect(
+        registry: &ClientRegistry,
+        id: i32,
+        capabilities: Vec<Capability>,
+    ) -> mpsc::Receiver<Outbound> {
+        let (tx, rx) = mpsc::channel(8);
+        registry
+            .register(id, tx, capabilities, 1, None, FrameFormat::Text)
+            .await
+            .unwrap();
+        rx
+    }
+
+    fn chat() -> LuaCommand {
+        LuaCommand::chat_message("hi".to_string())
+    }
+
+    #[tokio::test]
+    async fn targeted_send_checks_capability_validates_and_tracks() {
+        let registry = registry();
+        let mut chat_client = connect(&registry, 1, vec![Capability::Chat]).await;
+        let _silent_client = connect(&registry, 2, Vec::new()).await;
+        let service = dispatch(
+            &registry,
+            PendingCommands::new(16, 4, Duration::from_secs(60)),
+        );
+        let send = |id, command| {
+            service.clone().oneshot(ComputerAction::SendCommandToId {
+                id,
+                capability: Capability::Chat,
+                command,
+            })
+        };
+
+        assert!(matches!(
+            send(2, chat()).await,
+            Err(DispatchError::NoClient)
+        ));
+        let nested = LuaCommand::batch(vec![LuaCommand::batch(Vec::new())]);
+        assert!(matches!(
+            send(1, nested).await,
+            Err(DispatchError::InvalidCommand(_))
+        ));
+        service.disabled().set_enabled(Capability::Chat, false);
+        assert!(matches!(
+            send(1, chat()).await,
+            Err(DispatchError::Disabled(_))
+        ));
+        service.disabled().set_enabled(Capability::Chat, true);
+
+        assert_eq!(send(1, chat()).await.unwrap(), [1]);
+        assert!(chat_client.try_recv().is_ok());
+        assert_eq!(service.pending().per_client().await.get(&1), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn write_file_only_reaches_file_transfer_clients() {
+        let registry = registry();
+        let _chat_client = connect(&registry, 1, vec![Capability::Chat]).await;
+        let mut file_client = connect(&registry, 2, vec![Capability::FileTransfer]).await;
+        let service = dispatch(
+            &registry,
+            PendingCommands::new(16, 4, Duration::from_secs(60)),
+        );
+        let write = || {
+            LuaCommand::write_file(
+                "notes.txt".to_string(),
+                "hi".to_string(),
+                crate::websocket::WriteMode::Overwrite,
+            )
+        };
+
+        assert!(matches!(
+            service.clone().oneshot(to_chat(write())).await,
+            Err(DispatchError::InvalidCommand(_))
+        ));
+        let batched = LuaCommand::batch(vec![write()]);
+        assert!(matches!(
+            service.clone().oneshot(to_chat(batched)).await,
+            Err(DispatchError::InvalidCommand(_))
+        ));
+        assert!(file_client.try_recv().is_err());
+
+        let to_files = || ComputerAction::SendToCapability {
+            capability: Capability::FileTransfer,
+            command: write(),
+        };
+        assert_eq!(service.clone().oneshot(to_files()).await.unwrap(), [2]);
+        assert!(file_client.try_recv().is_ok());
+        service
+            .disabled()
+            .set_enabled(Capability::FileTransfer, false);
+        assert!(matches!(
+            service.clone().oneshot(to_files()).await,
+            Err(DispatchError::Disabled(_))
+        ));
+    }
+
+    fn to_chat(command: LuaCommand) -> ComputerAction {
+        ComputerAction::SendToCapability {
+            capability: Capability::Chat,
+            command,
+        }
+    }
+
+    #[tokio::test]
+    async fn in_flight_slot_is_held_until_the_result_arrives() {
+        let registry = registry();
+        let _client = connect(&registry, 1, vec![Capability::Chat]).await;
+        let service = dispatch_limited(
+            &registry,
+            PendingCommands::new(16, 4, Duration::from_secs(60)),
+            DispatchLimits::new(1, HashMap::new()),
+        );
+
+        let first = chat();
+        let first_id = first.id().to_string();
+        service.clone().oneshot(to_chat(first)).await.unwrap();
+        assert!(matches!(
+            service.clone().oneshot(to_chat(chat())).await,
+            Err(DispatchError::Overloaded(_))
+        ));
+
+        service.pending().finish(&first_id).await;
+        service.clone().oneshot(to_chat(chat())).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_flight_slot_is_released_when_the_command_expires() {
+        let registry = registry();
+        let _client = connect(&registry, 1, vec![Capability::Chat]).await;
+        let service = dispatch_limited(
+            &registry,
+            PendingCommands::new(16, 4, Duration::ZERO),
+            DispatchLimits::new(1, HashMap::new()),
+        );
+
+        service.clone().oneshot(to_chat(chat())).await.unwrap();
+        service.clone().oneshot(to_chat(chat())).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn single_client_sends_are_counted_and_a_missing_client_is_typed() {
+        let registry = registry();
+        let _client = connect(&registry, 1, vec![Capability::Chat]).await;
+        let service = dispatch(
+            &registry,
+            PendingCommands::new(16, 4, Duration::from_secs(60)),
+        );
+
+        service.clone().oneshot(to_chat(chat())).await.unwrap();
+        service
+            .clone()
+            .oneshot(ComputerAction::SendCommandToId {
+                id: 1,
+                capability: Capability::Chat,
+                command: chat(),
+            })
+            .await
+            .unwrap();
+        let snapshot = registry.snapshot().await;
+        assert_eq!(snapshot.clients[0].sent, 2);
+
+        let gone = service
+            .oneshot(ComputerAction::SendToId {
+                id: 9,
+                message: axum::extract::ws::Message::Text("{}".to_string()),
+            })
+            .await;
+        assert!(matches!(gone, Err(DispatchError::ClientGone(9))));
+    }
+
+    #[test]
+    fn refilled_rate_limit_buckets_are_swept() {
+        let limits = RateLimits::new(1.0, 2.0, HashMap::new());
+        let start = Instant::now();
+        for id in 1..=100 {
+            limits.check_at(id, None, start).unwrap();
+        }
+        // Client 7 keeps sending until it is limited.
+        limits.check_at(7, Some(&Capability::Chat), start).unwrap();
+        limits.check_at(7, Some(&Capability::Chat), start).unwrap();
+        assert!(limits.check_at(7, Some(&Capability::Chat), start).is_err());
+        assert_eq!(limits.buckets.lock().unwrap().by_key.len(), 101);
+
+        // Every bucket refills within two seconds, but a sweep waits for its interval.
+        let later = start + Duration::from_secs(5);
+        limits.check_at(7, Some(&Capability::Chat), later).unwrap();
+        assert_eq!(limits.buckets.lock().unwrap().by_key.len(), 101);
+
+        let swept = start + BUCKET_SWEEP_INTERVAL;
+        limits.check_at(7, Some(&Capability::Chat), swept).unwrap();
+        let buckets = limits.buckets.lock().unwrap();
+        // Only the bucket just spent from is left, still short of full.
+        assert_eq!(
+            buckets.by_key.keys().collect::<Vec<_>>(),
+            vec![&(7, Some(Capability::Chat))]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_shared_label_sends_to_the_lowest_id() {
+        let registry = registry();
+        let mut low = connect(&registry, 2, Vec::new()).await;
+        let _high = connect(&registry, 3, Vec::new()).await;
+        for id in [3, 2] {
+            registry
+                .set_label(id, Some("miner".to_string()))
+                .await
+                .unwrap();
+        }
+        let service = dispatch(
+            &registry,
+            PendingCommands::new(16, 4, Duration::from_secs(60)),
+        );
+        let send = |label: &str| {
+            service.clone().oneshot(ComputerAction::SendToLabel {
+                label: label.to_string(),
+                command: chat(),
+            })
+        };
+
+        assert_eq!(send("miner").await.unwrap(), vec![2]);
+        assert!(low.try_recv().is_ok());
+        assert!(matches!(send("digger").await, Err(DispatchError::NoClient)));
+    }
+
+    #[tokio::test]
+    async fn fan_outs_count_against_each_recipients_pending_cap() {
+        let registry = registry();
+        let mut busy = connect(&registry, 1, vec![Capability::Chat]).await;
+        let mut idle = connect(&registry, 2, vec![Capability::Chat]).await;
+        let service = dispatch(
+            &registry,
+            PendingCommands::new(16, 1, Duration::from_secs(60)),
+        );
+        let broadcast = || {
+            service.clone().oneshot(ComputerAction::Broadcast {
+                capability: Capability::Chat,
+                command: chat(),
+            })
+        };
+        let first = chat();
+        let first_id = first.id().to_string();
+        service
+            .clone()
+            .oneshot(ComputerAction::SendCommandToId {
+                id: 1,
+                capability: Capability::Chat,
+                command: first,
+            })
+            .await
+            .unwrap();
+
+        // Client 1 is at its cap, so only client 2 gets the broadcast, which now fills its cap.
+        assert_eq!(broadcast().await.unwrap(), vec![2]);
+        let counts = service.pending().per_client().await;
+        assert_eq!((counts.get(&1), counts.get(&2)), (Some(&1), Some(&1)));
+        assert_eq!(broadcast().await.unwrap(), Vec::<i32>::new());
+        assert_eq!(service.pending().snapshot().await.len(), 2);
+
+        service.pending().complete(&first_id).await;
+        assert_eq!(broadcast().await.unwrap(), vec![1]);
+        let queued =
+            |rx: &mut mpsc::Receiver<Outbound>| std::iter::from_fn(|| rx.try_recv().ok()).count();
+        assert_eq!((queued(&mut busy), queued(&mut idle)), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn pending_caps_refuse_one_more_until_a_command_resolves() {
+        let pending = PendingCommands::new(3, 2, Duration::from_secs(60));
+        let track = |id: &str, client| pending.track(id.to_string(), client, None);
+
+        track("a", Some(1)).await.unwrap();
+        track("b", Some(1)).await.unwrap();
+        assert!(matches!(
+            track("c", Some(1)).await,
+            Err(DispatchError::TooManyPending(1))
+        ));
+        assert_eq!(
+            pending.finish("a").await,
+            ResultMatch::OnTime(CommandState::Sent)
+        );
+        track("c", Some(1)).await.unwrap();
+
+        // Client 2 has room of its own, but its first command fills the overall cap.
+        track("d", Some(2)).await.unwrap();
+        assert!(matches!(
+            track("e", Some(2)).await,
+            Err(DispatchError::Overloaded(_))
+        ));
+        pending.complete("b").await;
+        track("e", Some(2)).await.unwrap();
+        assert_eq!(pending.snapshot().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn disabled_capabilities_are_refused_by_query_and_by_label() {
+        let registry = registry();
+        let mut chat_client = connect(&registry, 1, vec![Capability::Chat]).await;
+        let mut file_client = connect(&registry, 2, vec![Capability::FileTransfer]).await;
+        registry
+            .set_label(2, Some("archive".to_string()))
+            .await
+            .unwrap();
+        let service = dispatch(
+            &registry,
+            PendingCommands::new(16, 4, Duration::from_secs(60)),
+        );
+        service.disabled().set_enabled(Capability::Chat, false);
+        service
+            .disabled()
+            .set_enabled(Capability::FileTransfer, false);
+
+        let by_query = ComputerAction::SendToQuery {
+            predicate: ClientQuery {
+                capability: Some(Capability::Chat),
+                ..Default::default()
+            },
+            command: chat(),
+        };
+        assert!(matches!(
+            service.clone().oneshot(by_query).await,
+            Err(DispatchError::Disabled(_))
+        ));
+        let by_label = ComputerAction::SendToLabel {
+            label: "archive".to_string(),
+            command: LuaCommand::write_file(
+                "notes.txt".to_string(),
+                "hi".to_string(),
+                crate::websocket::WriteMode::Overwrite,
+            ),
+        };
+        assert!(matches!(
+            service.clone().oneshot(by_label).await,
+            Err(DispatchError::Disabled(_))
+        ));
+        assert!(chat_client.try_recv().is_err());
+        assert!(file_client.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn a_dry_run_send_to_a_missing_client_fails_like_a_live_one() {
+        let registry = registry();
+        let mut rx = connect(&registry, 1, vec![Capability::Chat]).await;
+        let service = ComputerDispatchService::new(
+            registry.clone(),
+            AuditLog::default(),
+            PendingCommands::new(16, 4, Duration::from_secs(60)),
+            DispatchLimits::new(0, HashMap::new()),
+            RateLimits::new(0.0, 1.0, HashMap::new()),
+            DisabledCapabilities::default(),
+            DispatchStrategies::new(DispatchStrategy::First, HashMap::new()),
+            true,
+            None,
+        );
+        let send = |id| ComputerAction::SendToId {
+            id,
+            message: axum::extract::ws::Message::Text("{}".to_string()),
+        };
+
+        assert_eq!(service.clone().oneshot(send(1)).await.unwrap(), vec![1]);
+        assert!(rx.try_recv().is_err());
+        let gone = service.oneshot(send(9)).await;
+        assert!(matches!(gone, Err(DispatchError::ClientGone(9))));
+    }
+}
@@ -0,0 +1,356 @@
+//! `audit` module keeps an optional append-only JSON-lines record of inbound events and outbound actions.
+
+use crate::actions::ComputerAction;
+use crate::brain::BrainAction;
+use crate::events::{ClientEvent, DeadLetterReason};
+use axum::extract::ws::Message as WsMessage;
+use serde_json::{Value, json};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Path of the audit log file. When unset, nothing is recorded.
+const ENV_BLUEKING_AUDIT_LOG: &str = "BLUEKING_AUDIT_LOG";
+/// Lines buffered between the hot path and the writer task before records are dropped.
+const AUDIT_QUEUE: usize = 1024;
+/// Size in bytes past which the audit log is rotated, e.g. `104857600` for 100MB; 0 never rotates.
+const ENV_BLUEKING_AUDIT_ROTATE_BYTES: &str = "BLUEKING_AUDIT_ROTATE_BYTES";
+const DEFAULT_AUDIT_ROTATE_BYTES: u64 = 0;
+/// Compression applied to rotated segments: `none` (default), `gzip` or, with the `zstd`
+/// feature, `zstd`. The active segment is always plain text.
+const ENV_BLUEKING_AUDIT_COMPRESSION: &str = "BLUEKING_AUDIT_COMPRESSION";
+
+/// How rotated audit log segments are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditCompression {
+    None,
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl std::str::FromStr for AuditCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AuditCompression::None),
+            "gzip" => Ok(AuditCompression::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(AuditCompression::Zstd),
+            #[cfg(not(feature = "zstd"))]
+            "zstd" => Err("zstd support requires the `zstd` feature".to_string()),
+            other => Err(format!("unknown audit compression {other:?}")),
+        }
+    }
+}
+
+impl AuditCompression {
+    /// Compress the segment at `path` next to it and remove the original.
+    ///
+    /// Blocking; run it off the async runtime.
+    fn compress(self, path: &Path) -> std::io::Result<PathBuf> {
+        let extension = match self {
+            AuditCompression::None => return Ok(path.to_path_buf()),
+            AuditCompression::Gzip => "gz",
+            #[cfg(feature = "zstd")]
+            AuditCompression::Zstd => "zst",
+        };
+        let mut target = path.as_os_str().to_owned();
+        target.push(".");
+        target.push(extension);
+        let target = PathBuf::from(target);
+        let mut input = std::fs::File::open(path)?;
+        let output = std::fs::File::create(&target)?;
+        match self {
+            AuditCompression::None => unreachable!("handled above"),
+            AuditCompression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(output, flate2::Compression::default());
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?.sync_all()?;
+            }
+            #[cfg(feature = "zstd")]
+            AuditCompression::Zstd => zstd::stream::copy_encode(&mut input, output, 0)?,
+        }
+        std::fs::remove_file(path)?;
+        Ok(target)
+    }
+}
+
+/// Where the audit log lives and when its segments are rotated out.
+struct Rotation {
+    path: PathBuf,
+    /// Rotate once the active segment reaches this size; 0 never rotates.
+    max_bytes: u64,
+    compression: AuditCompression,
+}
+
+impl Rotation {
+    fn from_env(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_bytes: crate::env_or(ENV_BLUEKING_AUDIT_ROTATE_BYTES, DEFAULT_AUDIT_ROTATE_BYTES),
+            compression: crate::env_or(ENV_BLUEKING_AUDIT_COMPRESSION, AuditCompression::None),
+        }
+    }
+
+    /// Move the active segment aside as `<path>.<unix ms>` and start a fresh one.
+    ///
+    /// Compression of the old segment runs on the blocking pool; the writer does not wait.
+    async fn rotate(&self, out: &mut BufWriter<tokio::fs::File>) -> std::io::Result<()> {
+        out.flush().await?;
+        let mut rotated = self.path.as_os_str().to_owned();
+        rotated.push(format!(".{}", now_ms()));
+        let rotated = PathBuf::from(rotated);
+        tokio::fs::rename(&self.path, &rotated).await?;
+        *out = BufWriter::new(open_append(&self.path).await?);
+        tracing::info!("Rotated audit log to {}", rotated.display());
+        let compression = self.compression;
+        if compression != AuditCompression::None {
+            tokio::task::spawn_blocking(move || match compression.compress(&rotated) {
+                Ok(target) => tracing::debug!("Compressed audit segment {}", target.display()),
+                Err(err) => tracing::warn!(
+                    "Failed to compress audit segment {}: {}",
+                    rotated.display(),
+                    err
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+async fn open_append(path: &Path) -> std::io::Result<tokio::fs::File> {
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// Handle for submitting audit records; cheap to clone and a no-op when disabled.
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    tx: Option<mpsc::Sender<String>>,
+}
+
+impl AuditLog {
+    /// Open the audit log named by `BLUEKING_AUDIT_LOG`, if any, and spawn its writer.
+    ///
+    /// The returned task keeps writing until every `AuditLog` clone is dropped, so records from
+    /// connections still draining after shutdown are kept; await it before the process exits.
+    pub async fn from_env() -> Result<(Self, Option<JoinHandle<()>>), std::io::Error> {
+        let Some(path) = crate::config::var(ENV_BLUEKING_AUDIT_LOG).filter(|p| !p.is_empty())
+        else {
+            return Ok((Self::default(), None));
+        };
+        let path = PathBuf::from(path);
+        let file = open_append(&path).await?;
+        tracing::info!("Writing audit log to {}", path.display());
+        let (tx, rx) = mpsc::channel(AUDIT_QUEUE);
+        let writer = tokio::spawn(run_writer(file, Rotation::from_env(path), rx));
+        Ok((Self { tx: Some(tx) }, Some(writer)))
+    }
+
+    /// Record an event received from a computer.
+    pub fn record_event(&self, event: &ClientEvent) {
+        if self.tx.is_none() {
+            return;
+        }
+        let data = serde_json::to_value(&event.event).unwrap_or(Value::Null);
+        let kind = data.get("type").cloned().unwrap_or(Value::Null);
+        self.write(json!({
+            "client_id": event.client_id,
+            "direction": "event",
+            "type": kind,
+            "data": data,
+        }));
+    }
+
+    /// Record an action dispatched towards computers.
+    pub fn record_action(&self, action: &ComputerAction) {
+        if self.tx.is_none() {
+            return;
+        }
+        self.write(action_record(action));
+    }
+
+    /// Record a brain action that could not be sent, with the reason.
+    pub fn record_dead_letter(&self, action: &BrainAction, reason: &DeadLetterReason) {
+        if self.tx.is_none() {
+            return;
+        }
+        self.write(json!({
+            "client_id": Value::Null,
+            "direction": "dead_letter",
+            "type": action.kind,
+            "capability": action.capability,
+            "data": action.command,
+            "reason": reason.to_string(),
+        }));
+    }
+
+    fn write(&self, mut record: Value) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        record["ts"] = now_ms().into();
+        if let Err(err) = tx.try_send(record.to_string()) {
+            tracing::warn!("Dropping audit record: {}", err);
+        }
+    }
+}
+
+/// JSON description of an outbound action, shared by the audit log and the `/monitor` feed.
+pub fn action_record(action: &ComputerAction) -> Value {
+    match action {
+        ComputerAction::SendToId { id, message } => json!({
+            "client_id": id,
+            "direction": "action",
+            "type": "send_to_id",
+            "data": match message {
+                WsMessage::Text(text) => Value::String(text.clone()),
+                _ => Value::Null,
+            },
+        }),
+        ComputerAction::SendToCapability {
+            capability,
+            command,
+        } => json!({
+            "client_id": Value::Null,
+            "direction": "action",
+            "type": "send_to_capability",
+            "capability": capability,
+            "data": command,
+        }),
+        ComputerAction::SendCommandToId {
+            id,
+            capability,
+            command,
+        } => json!({
+            "client_id": id,
+            "direction": "action",
+            "type": "send_command_to_id",
+            "capability": capability,
+            "data": command,
+        }),
+        ComputerAction::Broadcast {
+            capability,
+            command,
+        } => json!({
+            "client_id": Value::Null,
+            "direction": "action",
+            "type": "broadcast",
+            "capability": capability,
+            "data": command,
+        }),
+        ComputerAction::SendToLabel { label, command } => json!({
+            "client_id": Value::Null,
+            "direction": "action",
+            "type": "send_to_label",
+            "label": label,
+            "data": command,
+        }),
+        ComputerAction::SendToQuery { predicate, command } => json!({
+            "client_id": Value::Null,
+            "direction": "action",
+            "type": "send_to_query",
+            "query": predicate,
+            "data": command,
+        }),
+    }
+}
+
+/// Milliseconds since the Unix epoch, used to stamp records.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Append queued lines to the file, flushing whenever the queue runs dry.
+///
+/// Runs until every sender is gone, then flushes and syncs the file. Rotates the file once it
+/// passes the configured size.
+async fn run_writer(file: tokio::fs::File, rotation: Rotation, mut rx: mpsc::Receiver<String>) {
+    let mut written = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+    let mut out = BufWriter::new(file);
+
+    while let Some(line) = rx.recv().await {
+        if let Err(err) = write_line(&mut out, &line).await {
+            tracing::error!("Failed to write audit log: {}", err);
+        }
+        written += line.len() as u64 + 1;
+        if rotation.max_bytes > 0 && written >= rotation.max_bytes {
+            match rotation.rotate(&mut out).await {
+                Ok(()) => written = 0,
+                Err(err) => tracing::error!("Failed to rotate audit log: {}", err),
+            }
+        }
+        if rx.is_empty()
+            && let Err(err) = out.flush().await
+        {
+            tracing::error!("Failed to flush audit log: {}", err);
+        }
+    }
+
+    if let Err(err) = out.flush().await {
+        tracing::error!("Failed to flush audit log: {}", err);
+    }
+    if let Err(err) = out.get_mut().sync_all().await {
+        tracing::error!("Failed to sync audit log: {}", err);
+    }
+}
+
+async fn write_line(out: &mut BufWriter<tokio::fs::File>, line: &str) -> std::io::Result<()> {
+    out.write_all(line.as_bytes()).await?;
+    out.write_all(b"\n").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_writer_keeps_records_until_the_last_handle_is_dropped() {
+        let path = std::env::temp_dir().join(format!("blueking-audit-{}.log", now_ms()));
+        let file = open_append(&path).await.unwrap();
+        let (tx, rx) = mpsc::channel(AUDIT_QUEUE);
+        let writer = tokio::spawn(run_writer(
+            file,
+            Rotation {
+                path: path.clone(),
+                max_bytes: 0,
+                compression: AuditCompression::None,
+            },
+            rx,
+        ));
+        let audit = AuditLog { tx: Some(tx) };
+
+        audit.write(json!({"seq": 1}));
+        // Records keep arriving while connections drain after shutdown.
+        tokio::task::yield_now().await;
+        let draining = audit.clone();
+        drop(audit);
+        draining.write(json!({"seq": 2}));
+        assert!(!writer.is_finished());
+        drop(draining);
+        writer.await.unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let seqs: Vec<u64> = written
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<Value>(line).unwrap()["seq"]
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(seqs, [1, 2]);
+    }
+}
@@ -1,21 +1,106 @@
 //! `brain` module is a gRPC client for the external Python-based agentic AI "Brain" service.
 
-use blueking as pb;
-use blueking::brain_client::BrainClient;
+use crate as pb;
+use crate::brain_client::BrainClient;
 
-use crate::{ShutdownSignal, events::ComputerChatEvent};
+use crate::{
+    ShutdownSignal,
+    events::{Capability, CommandResultEvent, ComputerChatEvent},
+    metrics::{EventKind, Metrics},
+};
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use tonic::transport::Endpoint;
 use tower::ServiceExt;
 
 const BRAIN_BIND: ([u8; 4], u16) = ([192, 168, 50, 157], 50051);
+/// `host:port` of the brain; a DNS name is re-resolved periodically. Defaults to `BRAIN_BIND`.
+const ENV_BLUEKING_BRAIN_ADDR: &str = "BLUEKING_BRAIN_ADDR";
+/// Dedicated brains per event kind, as `kind=host:port,...` (e.g. `chat=chat-brain:50051`).
+/// Kinds without an entry go to `BLUEKING_BRAIN_ADDR`.
+const ENV_BLUEKING_BRAIN_ROUTES: &str = "BLUEKING_BRAIN_ROUTES";
+/// Event kinds the brain is called for, and so the only ones that can be routed.
+const ROUTABLE_KINDS: [EventKind; 2] = [EventKind::Chat, EventKind::CommandResult];
+/// Seconds between re-resolving the brain address while connected; 0 disables.
+const ENV_BLUEKING_BRAIN_RESOLVE_SECS: &str = "BLUEKING_BRAIN_RESOLVE_SECS";
+const DEFAULT_BRAIN_RESOLVE_SECS: u64 = 30;
+/// Seconds between HTTP/2 keepalive pings to the brain; 0 disables keepalive.
+const ENV_BLUEKING_BRAIN_KEEPALIVE_SECS: &str = "BLUEKING_BRAIN_KEEPALIVE_SECS";
+const DEFAULT_BRAIN_KEEPALIVE_SECS: u64 = 60;
+/// Seconds to wait for a keepalive ack before the connection is considered dead.
+const ENV_BLUEKING_BRAIN_KEEPALIVE_TIMEOUT_SECS: &str = "BLUEKING_BRAIN_KEEPALIVE_TIMEOUT_SECS";
+const DEFAULT_BRAIN_KEEPALIVE_TIMEOUT_SECS: u64 = 20;
+/// `false` to only ping while a call is in flight.
+const ENV_BLUEKING_BRAIN_KEEPALIVE_WHILE_IDLE: &str = "BLUEKING_BRAIN_KEEPALIVE_WHILE_IDLE";
+/// Seconds without a brain call after which the channel is closed; 0 keeps it open.
+const ENV_BLUEKING_BRAIN_IDLE_DISCONNECT_SECS: &str = "BLUEKING_BRAIN_IDLE_DISCONNECT_SECS";
+const DEFAULT_BRAIN_IDLE_DISCONNECT_SECS: u64 = 0;
+/// Chat calls per second allowed to each brain, across all clients; 0 disables the limit.
+const ENV_BLUEKING_BRAIN_CHAT_QPS: &str = "BLUEKING_BRAIN_CHAT_QPS";
+const DEFAULT_BRAIN_CHAT_QPS: f64 = 0.0;
+/// Chat calls that may be made back to back before pacing kicks in.
+const ENV_BLUEKING_BRAIN_CHAT_BURST: &str = "BLUEKING_BRAIN_CHAT_BURST";
+const DEFAULT_BRAIN_CHAT_BURST: f64 = 1.0;
+/// Milliseconds a chat call may wait for its turn before it is shed; 0 sheds instead of waiting.
+const ENV_BLUEKING_BRAIN_CHAT_MAX_WAIT_MS: &str = "BLUEKING_BRAIN_CHAT_MAX_WAIT_MS";
+const DEFAULT_BRAIN_CHAT_MAX_WAIT_MS: u64 = 10_000;
 
 /// Shared connection state for `BrainService`, guarded by a mutex to allow reconnect.
 struct BrainInner {
     endpoint: Endpoint,
     channel: Option<tonic::transport::Channel>,
+    /// `host:port` the endpoint points at.
+    target: String,
+    /// Addresses `target` resolved to when last checked.
+    resolved: Vec<std::net::SocketAddr>,
+    checked_at: Option<Instant>,
+    resolve_every: Option<Duration>,
+    /// Start of the most recent call, for closing the channel when idle.
+    last_used: Instant,
+}
+
+impl BrainInner {
+    /// The target to re-resolve, if a check is due; marks the check as started.
+    fn resolve_due(&mut self) -> Option<String> {
+        let every = self.resolve_every?;
+        if self.checked_at.is_some_and(|at| at.elapsed() < every) {
+            return None;
+        }
+        self.checked_at = Some(Instant::now());
+        Some(self.target.clone())
+    }
+
+    /// Record what `target` resolved to; returns `true` when its addresses changed since
+    /// last time.
+    fn address_changed(&mut self, mut resolved: Vec<std::net::SocketAddr>) -> bool {
+        resolved.sort();
+        resolved.dedup();
+        let changed = !self.resolved.is_empty() && resolved != self.resolved;
+        if changed {
+            tracing::info!(
+                "Brain address {} moved from {:?} to {:?}",
+                self.target,
+                self.resolved,
+                resolved
+            );
+        }
+        self.resolved = resolved;
+        changed
+    }
+}
+
+/// Look `target` up without holding any lock; `None` if resolution failed.
+async fn resolve(target: &str) -> Option<Vec<std::net::SocketAddr>> {
+    match tokio::net::lookup_host(target).await {
+        Ok(addrs) => Some(addrs.collect()),
+        Err(err) => {
+            tracing::warn!("Failed to resolve brain address {}: {}", target, err);
+            None
+        }
+    }
 }
 
 /// gRPC-backed brain client that forwards chat events to the Python service.
@@ -23,6 +108,73 @@ struct BrainInner {
 pub struct BrainService {
     inner: Arc<Mutex<BrainInner>>,
     shutdown: ShutdownSignal,
+    metrics: Arc<Metrics>,
+    /// Circuit state: cleared when a connection attempt fails, set again once one succeeds.
+    available: Arc<AtomicBool>,
+    /// Close the channel after this long without a call; `None` keeps it open.
+    idle_timeout: Option<Duration>,
+    /// Paces chat calls to the configured rate; `None` when unlimited.
+    chat_pacer: Option<Arc<ChatPacer>>,
+}
+
+/// Token bucket that paces brain chat calls, queueing each for at most `max_wait`.
+///
+/// Calls reserve a token up front, so waiting calls go out in order at the configured rate.
+struct ChatPacer {
+    rate: f64,
+    burst: f64,
+    max_wait: Duration,
+    /// Tokens left as of `refilled_at`; negative while calls are queued for future tokens.
+    bucket: std::sync::Mutex<(f64, tokio::time::Instant)>,
+}
+
+impl ChatPacer {
+    /// A full bucket of `burst` tokens, refilled at `rate` per second.
+    fn new(rate: f64, burst: f64, max_wait: Duration) -> Self {
+        Self {
+            rate,
+            burst,
+            max_wait,
+            bucket: std::sync::Mutex::new((burst, tokio::time::Instant::now())),
+        }
+    }
+
+    fn from_env() -> Option<Self> {
+        Self::limited(
+            crate::env_or(ENV_BLUEKING_BRAIN_CHAT_QPS, DEFAULT_BRAIN_CHAT_QPS),
+            crate::env_or(ENV_BLUEKING_BRAIN_CHAT_BURST, DEFAULT_BRAIN_CHAT_BURST),
+            Duration::from_millis(crate::env_or(
+                ENV_BLUEKING_BRAIN_CHAT_MAX_WAIT_MS,
+                DEFAULT_BRAIN_CHAT_MAX_WAIT_MS,
+            )),
+        )
+    }
+
+    /// A pacer for `rate` calls per second, or `None` (unlimited) unless the rate is a
+    /// finite positive number; `NaN` parses as an `f64` but can't pace anything.
+    fn limited(rate: f64, burst: f64, max_wait: Duration) -> Option<Self> {
+        if !rate.is_finite() || rate <= 0.0 {
+            return None;
+        }
+        Some(Self::new(rate, burst.max(1.0), max_wait))
+    }
+
+    /// Reserve a token, returning how long to wait before using it, or `None` when that
+    /// would exceed `max_wait` (or overflow a `Duration`) and the call should be shed.
+    fn reserve(&self) -> Option<Duration> {
+        let now = tokio::time::Instant::now();
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let (tokens, refilled_at) = &mut *bucket;
+        *tokens =
+            (*tokens + now.duration_since(*refilled_at).as_secs_f64() * self.rate).min(self.burst);
+        *refilled_at = now;
+        let wait = Duration::try_from_secs_f64((1.0 - *tokens).max(0.0) / self.rate).ok()?;
+        if wait > self.max_wait {
+            return None;
+        }
+        *tokens -= 1.0;
+        Some(wait)
+    }
 }
 
 #[derive(Debug)]
@@ -30,6 +182,8 @@ pub enum BrainError {
     Transport(tonic::transport::Error),
     Rpc(tonic::Status),
     Canceled,
+    /// Shed because the brain's chat rate limit would have kept the call waiting too long.
+    RateLimited,
 }
 
 impl std::fmt::Display for BrainError {
@@ -38,6 +192,7 @@ impl std::fmt::Display for BrainError {
             BrainError::Transport(e) => write!(f, "transport error: {}", e),
             BrainError::Rpc(e) => write!(f, "rpc error: {}", e),
             BrainError::Canceled => write!(f, "canceled"),
+            BrainError::RateLimited => write!(f, "brain chat rate limit exceeded"),
         }
     }
 }
@@ -56,46 +211,201 @@ impl From<tonic::Status> for BrainError {
     }
 }
 
+/// What the brain answered to a chat message.
+#[derive(Debug, Clone, Default)]
+pub struct BrainReply {
+    /// Reply text; empty means "no response".
+    pub text: String,
+    /// Computer to reply to, overriding routing by the chat capability.
+    pub target: Option<i32>,
+    /// Commands to run besides the reply.
+    pub actions: Vec<BrainAction>,
+}
+
+/// A command the brain asked for alongside its reply.
+#[derive(Debug, Clone)]
+pub struct BrainAction {
+    /// What the action does, e.g. `chat`; picks the capability when none is given.
+    pub kind: String,
+    /// Capability to route to; `None` uses the default target for `kind`.
+    pub capability: Option<Capability>,
+    /// The command as WebSocket JSON, parsed when the action is routed.
+    pub command: String,
+}
+
+impl From<pb::BrainAction> for BrainAction {
+    fn from(action: pb::BrainAction) -> Self {
+        BrainAction {
+            kind: action.kind,
+            capability: action.capability.map(Capability::from),
+            command: action.command_json,
+        }
+    }
+}
+
 /// Trait to allow mocking / swapping the brain backend.
 #[tonic::async_trait]
 pub trait Brain: Send + Sync + 'static {
-    /// Forward an in‑game chat event to the Brain and return its textual reply.
+    /// Forward an in‑game chat event to the Brain and return its reply.
+    async fn chat(&self, chat_event: ComputerChatEvent) -> Result<BrainReply, BrainError>;
+
+    /// Whether the brain is believed reachable; `false` while reconnecting after a failure.
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    /// Report how a previously issued command turned out.
     ///
-    /// An empty reply means "no response".
-    async fn chat(&self, chat_event: ComputerChatEvent) -> Result<String, BrainError>;
+    /// The default implementation ignores the result.
+    async fn command_result(&self, _result: CommandResultEvent) -> Result<(), BrainError> {
+        Ok(())
+    }
 }
 
 impl BrainService {
-    /// Create a new brain client, initially disconnected.
+    /// Create a new client for the brain at `target` (`host:port`), initially disconnected.
     ///
-    /// The first call to `chat` (or `ensure_channel`) will establish a connection.
-    pub fn new(shutdown: ShutdownSignal) -> Self {
-        let endpoint = tonic::transport::Endpoint::from_shared(
-            std::net::SocketAddr::from(BRAIN_BIND).to_string(),
-        )
-        .expect("failed to parse brain endpoint");
+    /// The first call to `chat` (or `ensure_channel`) will establish a connection. Fails if
+    /// `target` does not make a valid URI.
+    pub fn new(
+        target: String,
+        shutdown: ShutdownSignal,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, tonic::transport::Error> {
+        let mut endpoint = tonic::transport::Endpoint::from_shared(format!("http://{target}"))?;
+        // Pings keep idle connections open through NATs and load balancers and expose dead
+        // ones before the next call has to fail on them.
+        match crate::env_or(
+            ENV_BLUEKING_BRAIN_KEEPALIVE_SECS,
+            DEFAULT_BRAIN_KEEPALIVE_SECS,
+        ) {
+            0 => {}
+            secs => {
+                endpoint = endpoint
+                    .http2_keep_alive_interval(Duration::from_secs(secs))
+                    .keep_alive_timeout(Duration::from_secs(crate::env_or(
+                        ENV_BLUEKING_BRAIN_KEEPALIVE_TIMEOUT_SECS,
+                        DEFAULT_BRAIN_KEEPALIVE_TIMEOUT_SECS,
+                    )))
+                    .keep_alive_while_idle(crate::env_or(
+                        ENV_BLUEKING_BRAIN_KEEPALIVE_WHILE_IDLE,
+                        true,
+                    ));
+            }
+        }
+        let resolve_every =
+            match crate::env_or(ENV_BLUEKING_BRAIN_RESOLVE_SECS, DEFAULT_BRAIN_RESOLVE_SECS) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            };
 
-        Self {
+        Ok(Self {
             inner: Arc::new(Mutex::new(BrainInner {
                 endpoint,
                 channel: None,
+                target,
+                resolved: Vec::new(),
+                checked_at: None,
+                resolve_every,
+                last_used: Instant::now(),
             })),
             shutdown,
+            metrics,
+            available: Arc::new(AtomicBool::new(true)),
+            idle_timeout: match crate::env_or(
+                ENV_BLUEKING_BRAIN_IDLE_DISCONNECT_SECS,
+                DEFAULT_BRAIN_IDLE_DISCONNECT_SECS,
+            ) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            chat_pacer: ChatPacer::from_env().map(Arc::new),
+        })
+    }
+
+    /// Wait for this call's turn under the chat rate limit, if one is configured.
+    async fn pace_chat(&self) -> Result<(), BrainError> {
+        let Some(pacer) = &self.chat_pacer else {
+            return Ok(());
+        };
+        let wait = pacer.reserve().ok_or(BrainError::RateLimited)?;
+        if wait.is_zero() {
+            return Ok(());
+        }
+        tracing::debug!("Pacing brain chat call by {:?}", wait);
+        tokio::select! {
+            _ = self.shutdown.subscribe() => Err(BrainError::Canceled),
+            _ = tokio::time::sleep(wait) => Ok(()),
+        }
+    }
+
+    /// Close the channel once no call has been made for the idle timeout, until shutdown.
+    ///
+    /// The next call reconnects through `ensure_channel`. Finishes at once when idle
+    /// disconnect is disabled.
+    pub fn idle_sweeper(&self) -> impl Future<Output = ()> + Send + use<> {
+        let inner = Arc::clone(&self.inner);
+        let shutdown = self.shutdown.clone();
+        let idle_timeout = self.idle_timeout;
+        async move {
+            let Some(idle_timeout) = idle_timeout else {
+                return;
+            };
+            let stop = shutdown.subscribe();
+            tokio::pin!(stop);
+            loop {
+                let next_check = {
+                    let mut inner = inner.lock().await;
+                    let idle = inner.last_used.elapsed();
+                    if idle < idle_timeout {
+                        idle_timeout - idle
+                    } else {
+                        if inner.channel.take().is_some() {
+                            tracing::info!("Closing brain channel after {:?} idle", idle);
+                        }
+                        idle_timeout
+                    }
+                };
+                tokio::select! {
+                    _ = &mut stop => break,
+                    _ = tokio::time::sleep(next_check) => {}
+                }
+            }
         }
     }
 
     /// Ensure we have a ready channel, reconnecting with backoff and honoring shutdown.
     async fn ensure_channel(&self) -> Result<tonic::transport::Channel, BrainError> {
         loop {
+            // DNS can be slow; look the target up before taking the lock callers wait on.
+            let due = {
+                let mut inner = self.inner.lock().await;
+                inner.last_used = Instant::now();
+                if inner.channel.is_some() {
+                    inner.resolve_due()
+                } else {
+                    None
+                }
+            };
+            let resolved = match due {
+                Some(target) => resolve(&target).await,
+                None => None,
+            };
             // Fast path: reuse existing channel if ready.
             {
                 let mut inner = self.inner.lock().await;
+                if let Some(resolved) = resolved
+                    && inner.address_changed(resolved)
+                {
+                    inner.channel = None;
+                }
                 if let Some(channel) = inner.channel.as_mut() {
                     match channel.ready().await {
                         Ok(_) => return Ok(channel.clone()),
                         Err(err) => {
                             tracing::warn!("Brain channel not ready, reconnecting: {}", err);
                             inner.channel = None;
+                            self.available.store(false, Ordering::Relaxed);
                         }
                     }
                 }
@@ -120,9 +430,13 @@ impl BrainService {
                 Ok(channel) => {
                     let mut inner = self.inner.lock().await;
                     inner.channel = Some(channel.clone());
+                    if !self.available.swap(true, Ordering::Relaxed) {
+                        tracing::info!("Brain reachable again");
+                    }
                     return Ok(channel);
                 }
                 Err(err) => {
+                    self.available.store(false, Ordering::Relaxed);
                     tracing::warn!(
                         "Failed to connect to brain at {} ({}), retrying...",
                         endpoint.uri(),
@@ -138,14 +452,132 @@ impl BrainService {
     }
 }
 
+/// Brains keyed by the kind of event they handle; kinds without a route share the default.
+#[derive(Clone)]
+pub struct BrainRouter {
+    default: BrainService,
+    routes: HashMap<EventKind, BrainService>,
+    /// Every distinct brain, one per address.
+    brains: Vec<BrainService>,
+}
+
+impl BrainRouter {
+    /// Connect to `BLUEKING_BRAIN_ADDR`, plus any dedicated brains in `BLUEKING_BRAIN_ROUTES`.
+    ///
+    /// Fails if any of the addresses is not a valid URI authority.
+    pub fn from_env(
+        shutdown: ShutdownSignal,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, tonic::transport::Error> {
+        let default_target = crate::config::var(ENV_BLUEKING_BRAIN_ADDR)
+            .filter(|t| !t.is_empty())
+            .unwrap_or_else(|| std::net::SocketAddr::from(BRAIN_BIND).to_string());
+        let default = BrainService::new(default_target.clone(), shutdown.clone(), metrics.clone())?;
+        let mut by_target = HashMap::from([(default_target, default.clone())]);
+        let mut routes = HashMap::new();
+        for (kind, target) in parse_routes(crate::config::var(ENV_BLUEKING_BRAIN_ROUTES)) {
+            tracing::info!("Routing {} events to brain at {}", kind.label(), target);
+            let brain = match by_target.entry(target.clone()) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => entry.insert(
+                    BrainService::new(target, shutdown.clone(), metrics.clone())?,
+                ),
+            };
+            routes.insert(kind, brain.clone());
+        }
+        Ok(Self {
+            default,
+            routes,
+            brains: by_target.into_values().collect(),
+        })
+    }
+
+    fn route(&self, kind: EventKind) -> &BrainService {
+        self.routes.get(&kind).unwrap_or(&self.default)
+    }
+
+    /// Run `BrainService::idle_sweeper` for every distinct brain.
+    pub fn idle_sweeper(&self) -> impl Future<Output = ()> + Send + use<> {
+        let sweepers: Vec<_> = self.brains.iter().map(BrainService::idle_sweeper).collect();
+        async move {
+            futures::future::join_all(sweepers).await;
+        }
+    }
+}
+
+/// Parse `kind=host:port,...`, skipping malformed entries and kinds the brain is never called for.
+fn parse_routes(raw: Option<String>) -> Vec<(EventKind, String)> {
+    let mut routes = Vec::new();
+    let raw = raw.unwrap_or_default();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let route = entry.split_once('=').and_then(|(kind, target)| {
+            let kind = ROUTABLE_KINDS
+                .into_iter()
+                .find(|k| k.label() == kind.trim())?;
+            Some((kind, target.trim().to_string())).filter(|(_, target)| !target.is_empty())
+        });
+        match route {
+            Some(route) => routes.push(route),
+            None => tracing::warn!(
+                "Ignoring invalid {} entry: {}",
+                ENV_BLUEKING_BRAIN_ROUTES,
+                entry
+            ),
+        }
+    }
+    routes
+}
+
+#[tonic::async_trait]
+impl Brain for BrainRouter {
+    /// Availability of the chat brain, which is the one chat backlogging and readiness track.
+    fn is_available(&self) -> bool {
+        self.route(EventKind::Chat).is_available()
+    }
+
+    async fn chat(&self, chat_event: ComputerChatEvent) -> Result<BrainReply, BrainError> {
+        self.route(EventKind::Chat).chat(chat_event).await
+    }
+
+    async fn command_result(&self, result: CommandResultEvent) -> Result<(), BrainError> {
+        self.route(EventKind::CommandResult)
+            .command_result(result)
+            .await
+    }
+}
+
 #[tonic::async_trait]
 impl Brain for BrainService {
-    async fn chat(&self, chat_event: ComputerChatEvent) -> Result<String, BrainError> {
+    fn is_available(&self) -> bool {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    async fn chat(&self, chat_event: ComputerChatEvent) -> Result<BrainReply, BrainError> {
+        self.pace_chat().await?;
         let channel = self.ensure_channel().await?;
         let mut client = BrainClient::new(channel);
         let request = tonic::Request::new(pb::ChatEvent::from(chat_event));
-        let response = client.chat(request).await?;
-        Ok(response.into_inner().reply)
+        let started = Instant::now();
+        let response = client.chat(request).await;
+        self.metrics.brain_chat.observe(started.elapsed());
+        let response = response?.into_inner();
+        Ok(BrainReply {
+            text: response.reply,
+            target: response.target_id,
+            actions: response
+                .actions
+                .into_iter()
+                .map(BrainAction::from)
+                .collect(),
+        })
+    }
+
+    async fn command_result(&self, result: CommandResultEvent) -> Result<(), BrainError> {
+        let channel = self.ensure_channel().await?;
+        let mut client = BrainClient::new(channel);
+        let request = tonic::Request::new(pb::CommandResultReport::from(result));
+        client.command_result(request).await?;
+        Ok(())
     }
 }
 
@@ -154,6 +586,97 @@ impl From<ComputerChatEvent> for pb::ChatEvent {
         pb::ChatEvent {
             username: event.username,
             message: event.message,
+            computer_id: event.computer_id,
+        }
+    }
+}
+
+impl From<CommandResultEvent> for pb::CommandResultReport {
+    fn from(event: CommandResultEvent) -> Self {
+        pb::CommandResultReport {
+            command_id: event.command_id,
+            success: event.error.is_none(),
+            error: event.error.unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_unparseable_brain_address_fails_at_startup() {
+        let built = BrainService::new(
+            "not a host:50051".to_string(),
+            ShutdownSignal::new(),
+            Arc::new(Metrics::default()),
+        );
+        assert!(built.is_err());
+    }
+
+    #[tokio::test]
+    async fn address_changes_are_recorded_from_a_lookup_made_without_the_lock() {
+        let brain = BrainService::new(
+            "localhost:50051".to_string(),
+            ShutdownSignal::new(),
+            Arc::new(Metrics::default()),
+        )
+        .unwrap();
+        let mut inner = brain.inner.lock().await;
+        inner.resolve_every = Some(Duration::from_secs(60));
+        let target = inner.resolve_due().expect("first check is due at once");
+        assert!(inner.resolve_due().is_none());
+        drop(inner);
+
+        let first = resolve(&target).await.expect("localhost resolves");
+        let mut inner = brain.inner.lock().await;
+        assert!(!inner.address_changed(first.clone()));
+        assert!(!inner.address_changed(first.into_iter().rev().collect()));
+        let moved = vec![std::net::SocketAddr::from(([10, 0, 0, 9], 50051))];
+        assert!(inner.address_changed(moved));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chat_pacer_spends_the_burst_then_queues_until_max_wait() {
+        let pacer = ChatPacer::new(2.0, 2.0, Duration::from_secs(1));
+        let waits: Vec<_> = (0..5).map(|_| pacer.reserve()).collect();
+        assert_eq!(
+            waits,
+            vec![
+                Some(Duration::ZERO),
+                Some(Duration::ZERO),
+                Some(Duration::from_millis(500)),
+                Some(Duration::from_secs(1)),
+                // Would wait 1.5s, past max_wait: shed without taking a token.
+                None,
+            ]
+        );
+
+        // A second refills two tokens, which pay for the two calls already queued.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(pacer.reserve(), Some(Duration::from_millis(500)));
+
+        // A long quiet spell refills no more than the burst.
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(pacer.reserve(), Some(Duration::ZERO));
+        assert_eq!(pacer.reserve(), Some(Duration::ZERO));
+        assert_eq!(pacer.reserve(), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn only_a_finite_positive_chat_rate_builds_a_pacer() {
+        let max_wait = Duration::from_secs(1);
+        for rate in [f64::NAN, f64::INFINITY, 0.0, -1.0] {
+            assert!(ChatPacer::limited(rate, 1.0, max_wait).is_none(), "{rate}");
         }
+        assert!(ChatPacer::limited(2.0, 0.0, max_wait).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chat_pacer_sheds_a_wait_too_long_for_a_duration() {
+        let pacer = ChatPacer::limited(1e-300, 1.0, Duration::MAX).unwrap();
+        assert_eq!(pacer.reserve(), Some(Duration::ZERO));
+        assert_eq!(pacer.reserve(), None);
     }
 }
@@ -0,0 +1,373 @@
+//! `config` module loads an optional TOML or JSON config file whose settings become defaults for the `BLUEKING_*` environment variables.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Path of the config file; `--config <path>` takes precedence.
+const ENV_BLUEKING_CONFIG: &str = "BLUEKING_CONFIG";
+
+/// File the process started with, consulted by `var` for variables the environment leaves unset.
+static INSTALLED: OnceLock<Config> = OnceLock::new();
+
+/// Declare `Config` with one optional field per setting and the variable each one defaults.
+macro_rules! settings {
+    ($($field:ident: $ty:ty => $var:expr,)*) => {
+        /// Settings named like their environment variable without the `BLUEKING_` prefix, in
+        /// lower case; an unknown name is an error rather than silently ignored.
+        ///
+        /// ```toml
+        /// brain_addr = "brain:50051"
+        /// dispatch_limit = 4
+        /// ws_allowed_origins = ["https://dash.example"]
+        /// ```
+        #[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        pub struct Config {
+            $(pub $field: Option<$ty>,)*
+        }
+
+        impl Config {
+            /// Each setting the file gives, keyed by variable name, as that variable would hold it.
+            fn values(&self) -> BTreeMap<&'static str, String> {
+                let mut values = BTreeMap::new();
+                $(if let Some(value) = &self.$field {
+                    values.insert($var, value.to_env_string());
+                })*
+                values
+            }
+        }
+    };
+}
+
+settings! {
+    debug: Setting => crate::ENV_BLUEKING_DEBUG,
+    log: String => crate::ENV_BLUEKING_LOG,
+    worker_threads: u64 => "BLUEKING_WORKER_THREADS",
+    shutdown_timeout_secs: u64 => crate::ENV_BLUEKING_SHUTDOWN_TIMEOUT_SECS,
+    state_file: PathBuf => "BLUEKING_STATE_FILE",
+    templates_file: PathBuf => "BLUEKING_TEMPLATES_FILE",
+    audit_log: PathBuf => "BLUEKING_AUDIT_LOG",
+    audit_rotate_bytes: u64 => "BLUEKING_AUDIT_ROTATE_BYTES",
+    audit_compression: String => "BLUEKING_AUDIT_COMPRESSION",
+
+    grpc_addr: String => "BLUEKING_GRPC_ADDR",
+    grpc_token: String => "BLUEKING_GRPC_TOKEN",
+    grpc_allow: List => "BLUEKING_GRPC_ALLOW",
+    grpc_reflection: bool => "BLUEKING_GRPC_REFLECTION",
+    max_chat_payload_chars: u64 => "BLUEKING_MAX_CHAT_PAYLOAD_CHARS",
+    migration_grace_secs: u64 => "BLUEKING_MIGRATION_GRACE_SECS",
+
+    ws_addr: String => "BLUEKING_WS_ADDR",
+    ws_allowed_origins: List => "BLUEKING_WS_ALLOWED_ORIGINS",
+    slow_client_deadline_ms: u64 => "BLUEKING_SLOW_CLIENT_DEADLINE_MS",
+    slow_client_strikes: u64 => "BLUEKING_SLOW_CLIENT_STRIKES",
+    evict_slow_clients: bool => "BLUEKING_EVICT_SLOW_CLIENTS",
+    event_timeout_secs: u64 => "BLUEKING_EVENT_TIMEOUT_SECS",
+    max_events_in_flight: u64 => "BLUEKING_MAX_EVENTS_IN_FLIGHT",
+    socket_write_timeout_ms: u64 => "BLUEKING_SOCKET_WRITE_TIMEOUT_MS",
+    app_ping_idle_secs: u64 => "BLUEKING_APP_PING_IDLE_SECS",
+    app_ping_timeout_secs: u64 => "BLUEKING_APP_PING_TIMEOUT_SECS",
+    reconnect_grace_ms: u64 => "BLUEKING_RECONNECT_GRACE_MS",
+    restore_capabilities: bool => "BLUEKING_RESTORE_CAPABILITIES",
+    max_tombstones: u64 => "BLUEKING_MAX_TOMBSTONES",
+    broadcast_concurrency: u64 => "BLUEKING_BROADCAST_CONCURRENCY",
+    max_clients: u64 => "BLUEKING_MAX_CLIENTS",
+    takeover_after_secs: u64 => "BLUEKING_TAKEOVER_AFTER_SECS",
+    client_token: String => "BLUEKING_CLIENT_TOKEN",
+    strict_register: bool => "BLUEKING_STRICT_REGISTER",
+    pre_register_events: u64 => "BLUEKING_PRE_REGISTER_EVENTS",
+    snapshot_file: PathBuf => "BLUEKING_SNAPSHOT_FILE",
+    trace_frame_chars: u64 => "BLUEKING_TRACE_FRAME_CHARS",
+    coalesce_commands: List => "BLUEKING_COALESCE_COMMANDS",
+    command_ids: String => "BLUEKING_COMMAND_IDS",
+
+    dispatch_limit: u64 => "BLUEKING_DISPATCH_LIMIT",
+    dispatch_limits: List => "BLUEKING_DISPATCH_LIMITS",
+    dispatch_strategy: String => "BLUEKING_DISPATCH_STRATEGY",
+    dispatch_strategies: List => "BLUEKING_DISPATCH_STRATEGIES",
+    client_rate: f64 => "BLUEKING_CLIENT_RATE",
+    client_rates: List => "BLUEKING_CLIENT_RATES",
+    client_burst: f64 => "BLUEKING_CLIENT_BURST",
+    disabled_capabilities: List => "BLUEKING_DISABLED_CAPABILITIES",
+    max_pending_commands: u64 => "BLUEKING_MAX_PENDING_COMMANDS",
+    max_pending_per_client: u64 => "BLUEKING_MAX_PENDING_PER_CLIENT",
+    pending_timeout_secs: u64 => "BLUEKING_PENDING_TIMEOUT_SECS",
+    command_ttl_ms: u64 => "BLUEKING_COMMAND_TTL_MS",
+    dry_run: bool => "BLUEKING_DRY_RUN",
+
+    brain_addr: String => "BLUEKING_BRAIN_ADDR",
+    brain_routes: List => "BLUEKING_BRAIN_ROUTES",
+    brain_resolve_secs: u64 => "BLUEKING_BRAIN_RESOLVE_SECS",
+    brain_keepalive_secs: u64 => "BLUEKING_BRAIN_KEEPALIVE_SECS",
+    brain_keepalive_timeout_secs: u64 => "BLUEKING_BRAIN_KEEPALIVE_TIMEOUT_SECS",
+    brain_keepalive_while_idle: bool => "BLUEKING_BRAIN_KEEPALIVE_WHILE_IDLE",
+    brain_idle_disconnect_secs: u64 => "BLUEKING_BRAIN_IDLE_DISCONNECT_SECS",
+    brain_chat_qps: f64 => "BLUEKING_BRAIN_CHAT_QPS",
+    brain_chat_burst: f64 => "BLUEKING_BRAIN_CHAT_BURST",
+    brain_chat_max_wait_ms: u64 => "BLUEKING_BRAIN_CHAT_MAX_WAIT_MS",
+    brain_chat_queue: u64 => "BLUEKING_BRAIN_CHAT_QUEUE",
+
+    private_replies: bool => "BLUEKING_PRIVATE_REPLIES",
+    max_reply_chars: u64 => "BLUEKING_MAX_REPLY_CHARS",
+    max_brain_reply_bytes: u64 => "BLUEKING_MAX_BRAIN_REPLY_BYTES",
+    thinking_message: String => "BLUEKING_THINKING_MESSAGE",
+    thinking_delay_ms: u64 => "BLUEKING_THINKING_DELAY_MS",
+    default_targets: List => "BLUEKING_DEFAULT_TARGETS",
+    event_history: u64 => "BLUEKING_EVENT_HISTORY",
+    event_bus_capacity: u64 => "BLUEKING_EVENT_BUS_CAPACITY",
+}
+
+/// A list-valued setting, written as an array or as the comma-separated string its variable takes.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum List {
+    Items(Vec<String>),
+    Joined(String),
+}
+
+/// A setting that takes a flag, a number or a name, e.g. `debug = true` or `debug = "trace"`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Setting {
+    Flag(bool),
+    Number(u64),
+    Name(String),
+}
+
+/// How a typed setting is written into its environment variable.
+trait EnvValue {
+    fn to_env_string(&self) -> String;
+}
+
+impl EnvValue for bool {
+    fn to_env_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl EnvValue for u64 {
+    fn to_env_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl EnvValue for f64 {
+    fn to_env_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl EnvValue for String {
+    fn to_env_string(&self) -> String {
+        self.clone()
+    }
+}
+
+impl EnvValue for PathBuf {
+    fn to_env_string(&self) -> String {
+        self.display().to_string()
+    }
+}
+
+impl EnvValue for List {
+    fn to_env_string(&self) -> String {
+        match self {
+            List::Items(items) => items.join(","),
+            List::Joined(joined) => joined.clone(),
+        }
+    }
+}
+
+impl EnvValue for Setting {
+    fn to_env_string(&self) -> String {
+        match self {
+            Setting::Flag(flag) => flag.to_string(),
+            Setting::Number(number) => number.to_string(),
+            Setting::Name(name) => name.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the file named by `--config` or `BLUEKING_CONFIG`; `None` when neither is given.
+    ///
+    /// Files ending in `.json` are parsed as JSON, anything else as TOML.
+    pub fn load() -> Result<Option<(PathBuf, Self)>, ConfigError> {
+        let Some(path) = config_path() else {
+            return Ok(None);
+        };
+        Ok(Some((path.clone(), Self::from_path(&path)?)))
+    }
+
+    /// Parse the file at `path`.
+    pub fn from_path(path: &Path) -> Result<Self, ConfigError> {
+        read_file(path)
+    }
+
+    /// Make this the file `var` falls back to for the rest of the process; only the first
+    /// call has an effect.
+    pub fn install(&self) {
+        let _ = INSTALLED.set(self.clone());
+    }
+
+    /// Value of the variable `name` with this file's settings as defaults.
+    ///
+    /// A reload uses this to see edits to the file while the environment still takes precedence.
+    pub fn var(&self, name: &str) -> Option<String> {
+        self.resolve(name, std::env::var(name).ok())
+    }
+
+    fn resolve(&self, name: &str, from_env: Option<String>) -> Option<String> {
+        from_env.or_else(|| self.values().remove(name))
+    }
+
+    /// Variables whose setting differs between `self` and `other`, sorted by name.
+    pub fn changed(&self, other: &Config) -> Vec<String> {
+        let (before, after) = (self.values(), other.values());
+        let mut changed: Vec<String> = before
+            .keys()
+            .chain(after.keys())
+            .filter(|name| before.get(*name) != after.get(*name))
+            .map(|name| name.to_string())
+            .collect();
+        changed.sort();
+        changed.dedup();
+        changed
+    }
+
+    /// Number of settings in the file.
+    pub fn len(&self) -> usize {
+        self.values().len()
+    }
+
+    /// Whether the file sets nothing.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Value of the variable `name`, falling back to the installed config file when it is unset.
+pub fn var(name: &str) -> Option<String> {
+    match INSTALLED.get() {
+        Some(config) => config.var(name),
+        None => std::env::var(name).ok(),
+    }
+}
+
+/// Read a TOML file, or a JSON one when its name ends in `.json`.
+pub fn read_file<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let path = path.to_path_buf();
+    let text = std::fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+    if is_json(&path) {
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&text).map_err(|e| e.to_string())
+    }
+    .map_err(|e| ConfigError::Parse(path, e))
+}
+
+fn config_path() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os(ENV_BLUEKING_CONFIG)
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    #[test]
+    fn settings_are_typed_and_unknown_names_rejected() {
+        let config = parse(
+            r#"
+            debug = "trace"
+            dispatch_limit = 4
+            client_rate = 2
+            ws_allowed_origins = ["https://a.example", "https://b.example"]
+            disabled_capabilities = "chat,ping"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.dispatch_limit, Some(4));
+        assert_eq!(config.client_rate, Some(2.0));
+        assert_eq!(config.len(), 5);
+        assert_eq!(
+            config.var("BLUEKING_WS_ALLOWED_ORIGINS").as_deref(),
+            Some("https://a.example,https://b.example")
+        );
+        assert_eq!(
+            config.var("BLUEKING_DISABLED_CAPABILITIES").as_deref(),
+            Some("chat,ping")
+        );
+        assert_eq!(config.var("BLUEKING_DEBUG").as_deref(), Some("trace"));
+
+        let err = parse("dispatch_limt = 4").unwrap_err().to_string();
+        assert!(err.contains("dispatch_limt"), "{err}");
+        assert!(parse(r#"dispatch_limit = "four""#).is_err());
+        assert!(
+            serde_json::from_str::<Config>(r#"{"brain_addr": "brain:50051", "extra": 1}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn environment_takes_precedence_over_the_file() {
+        let config = Config {
+            grpc_token: Some("from-file".to_string()),
+            ..Config::default()
+        };
+        let resolve = |from_env: Option<&str>| {
+            config.resolve("BLUEKING_GRPC_TOKEN", from_env.map(str::to_string))
+        };
+        assert_eq!(resolve(None).as_deref(), Some("from-file"));
+        assert_eq!(resolve(Some("from-env")).as_deref(), Some("from-env"));
+        assert_eq!(config.resolve("BLUEKING_GRPC_ADDR", None), None);
+    }
+
+    #[test]
+    fn changed_lists_only_differing_variables() {
+        let before = parse("dispatch_limit = 4\ndry_run = true").unwrap();
+        let after = parse("dispatch_limit = 8\ndry_run = true\nclient_burst = 3").unwrap();
+        assert_eq!(
+            before.changed(&after),
+            ["BLUEKING_CLIENT_BURST", "BLUEKING_DISPATCH_LIMIT"]
+        );
+        assert!(before.changed(&before).is_empty());
+    }
+}
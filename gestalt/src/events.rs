@@ -1,34 +1,369 @@
 //! `events` module provides processing of events received from computers and their forwarding to the "Brain".
 
-use crate::actions::{ComputerAction, ComputerDispatchService};
-use crate::brain::{Brain, BrainService};
+use crate as pb;
+use crate::DispatchError;
+use crate::ShutdownSignal;
+use crate::actions::{ComputerAction, ComputerDispatchService, ResultMatch};
+use crate::audit::AuditLog;
+use crate::brain::{Brain, BrainAction, BrainError, BrainReply, BrainRouter};
+use crate::metrics::{EventKind, Metrics, UnmatchedResult};
+use crate::store::Telemetry;
 use crate::websocket::{ClientRegistry, LuaCommand};
-use blueking as pb;
-use blueking::DispatchError;
 use pin_project_lite::pin_project;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{Notify, RwLock, broadcast};
 use tower::Service;
 use tower::ServiceExt;
 
 /// Capability advertised by a client on registration.
+///
+/// Names the server does not recognize deserialize into `Unknown` so that clients running
+/// newer firmware can still register for the capabilities we do support.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-#[serde(rename_all = "snake_case")]
+#[serde(from = "String", into = "String")]
 pub enum Capability {
     Chat,
+    /// Accepts `write_file` commands.
+    FileTransfer,
+    /// Answers application-level `ping` commands, for clients that can't rely on WS pings.
+    Ping,
+    Unknown(String),
+}
+
+impl Capability {
+    /// Every capability the server can route to.
+    pub const KNOWN: &[Capability] =
+        &[Capability::Chat, Capability::FileTransfer, Capability::Ping];
+
+    /// Wire name of the capability, matching its serde representation.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Capability::Chat => "chat",
+            Capability::FileTransfer => "file_transfer",
+            Capability::Ping => "ping",
+            Capability::Unknown(name) => name,
+        }
+    }
+
+    /// Whether the server can route commands to this capability.
+    pub fn is_known(&self) -> bool {
+        !matches!(self, Capability::Unknown(_))
+    }
+}
+
+impl From<String> for Capability {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "chat" => Capability::Chat,
+            "file_transfer" => Capability::FileTransfer,
+            "ping" => Capability::Ping,
+            _ => Capability::Unknown(name),
+        }
+    }
+}
+
+impl From<Capability> for String {
+    fn from(capability: Capability) -> Self {
+        capability.as_str().to_string()
+    }
+}
+
+/// Strict parse that rejects capabilities the server does not know.
+impl std::str::FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Capability::from(s.to_string()) {
+            Capability::Unknown(other) => Err(format!("unknown capability: {other}")),
+            known => Ok(known),
+        }
+    }
+}
+
+/// Registers that restate a client's state within this long of the last one are ignored.
+const REGISTER_DEDUP_WINDOW: Duration = Duration::from_secs(2);
+
+/// Whisper AI chat replies to the player who spoke instead of posting them publicly.
+const ENV_BLUEKING_PRIVATE_REPLIES: &str = "BLUEKING_PRIVATE_REPLIES";
+/// Longest AI chat reply, in characters, forwarded to clients before truncation.
+const ENV_BLUEKING_MAX_REPLY_CHARS: &str = "BLUEKING_MAX_REPLY_CHARS";
+const DEFAULT_MAX_REPLY_CHARS: usize = 1024;
+/// Hard ceiling, in bytes, on a brain reply; larger replies are dropped, not truncated.
+const ENV_BLUEKING_MAX_BRAIN_REPLY_BYTES: &str = "BLUEKING_MAX_BRAIN_REPLY_BYTES";
+const DEFAULT_MAX_BRAIN_REPLY_BYTES: usize = 64 * 1024;
+/// Interim message sent while the brain is still thinking; empty disables it.
+const ENV_BLUEKING_THINKING_MESSAGE: &str = "BLUEKING_THINKING_MESSAGE";
+const DEFAULT_THINKING_MESSAGE: &str = "thinking…";
+/// Milliseconds without a brain reply before the interim message is sent.
+const ENV_BLUEKING_THINKING_DELAY_MS: &str = "BLUEKING_THINKING_DELAY_MS";
+const DEFAULT_THINKING_DELAY_MS: u64 = 3000;
+
+/// Interim chat message shown when the brain takes longer than `delay` to reply.
+#[derive(Debug, Clone)]
+pub struct ThinkingNotice {
+    pub message: String,
+    pub delay: Duration,
+}
+
+/// Routing of AI chat replies: the runtime-configurable capability that receives them and
+/// whether they go to public chat or privately to the speaker.
+#[derive(Clone)]
+pub struct ChatTarget {
+    capability: Arc<RwLock<Capability>>,
+    private_replies: bool,
+    max_reply_chars: usize,
+    /// Replies longer than this are treated as a brain fault and dropped.
+    max_brain_reply_bytes: usize,
+    thinking: Option<ThinkingNotice>,
+}
+
+impl ChatTarget {
+    pub fn new(
+        capability: Capability,
+        private_replies: bool,
+        max_reply_chars: usize,
+        max_brain_reply_bytes: usize,
+        thinking: Option<ThinkingNotice>,
+    ) -> Self {
+        Self {
+            capability: Arc::new(RwLock::new(capability)),
+            private_replies,
+            max_reply_chars,
+            max_brain_reply_bytes,
+            thinking,
+        }
+    }
+
+    /// Chat target for `capability` with reply privacy, length limit and thinking notice
+    /// taken from the environment.
+    pub fn from_env(capability: Capability) -> Self {
+        let message = crate::config::var(ENV_BLUEKING_THINKING_MESSAGE)
+            .unwrap_or_else(|| DEFAULT_THINKING_MESSAGE.to_string());
+        let thinking = (!message.is_empty()).then(|| ThinkingNotice {
+            message,
+            delay: Duration::from_millis(crate::env_or(
+                ENV_BLUEKING_THINKING_DELAY_MS,
+                DEFAULT_THINKING_DELAY_MS,
+            )),
+        });
+        Self::new(
+            capability,
+            crate::env_or(ENV_BLUEKING_PRIVATE_REPLIES, false),
+            crate::env_or(ENV_BLUEKING_MAX_REPLY_CHARS, DEFAULT_MAX_REPLY_CHARS),
+            crate::env_or(
+                ENV_BLUEKING_MAX_BRAIN_REPLY_BYTES,
+                DEFAULT_MAX_BRAIN_REPLY_BYTES,
+            ),
+            thinking,
+        )
+    }
+
+    /// Hard ceiling on brain reply size, beyond which the reply is dropped.
+    pub fn max_brain_reply_bytes(&self) -> usize {
+        self.max_brain_reply_bytes
+    }
+
+    /// Interim message for slow brain replies, if enabled.
+    pub fn thinking(&self) -> Option<&ThinkingNotice> {
+        self.thinking.as_ref()
+    }
+
+    pub async fn get(&self) -> Capability {
+        self.capability.read().await.clone()
+    }
+
+    pub async fn set(&self, capability: Capability) {
+        *self.capability.write().await = capability;
+    }
+
+    /// Build the command that delivers `reply` in response to a message from `username`.
+    ///
+    /// Replies longer than the configured limit are truncated with an ellipsis.
+    pub fn reply_command(&self, username: &str, reply: String) -> LuaCommand {
+        let reply = truncate_reply(reply, self.max_reply_chars);
+        if self.private_replies {
+            LuaCommand::whisper(username.to_string(), reply)
+        } else {
+            LuaCommand::chat_message(reply)
+        }
+    }
+}
+
+/// Default capability per kind of brain action, as `kind=capability,...`, e.g.
+/// `chat=chat,code=run_code`. Entries add to or override the built-in `chat=chat` and
+/// `write_file=file_transfer`.
+const ENV_BLUEKING_DEFAULT_TARGETS: &str = "BLUEKING_DEFAULT_TARGETS";
+
+/// Capability each kind of brain action is sent to when the brain names none.
+#[derive(Debug, Clone)]
+pub struct DefaultTargets {
+    targets: Arc<HashMap<String, Capability>>,
+}
+
+impl Default for DefaultTargets {
+    fn default() -> Self {
+        Self::new(HashMap::from([
+            ("chat".to_string(), Capability::Chat),
+            ("write_file".to_string(), Capability::FileTransfer),
+        ]))
+    }
+}
+
+impl DefaultTargets {
+    pub fn new(targets: HashMap<String, Capability>) -> Self {
+        Self {
+            targets: Arc::new(targets),
+        }
+    }
+
+    /// Built-in targets extended by `BLUEKING_DEFAULT_TARGETS`, skipping malformed entries.
+    pub fn from_env() -> Self {
+        Self::parse(crate::config::var(ENV_BLUEKING_DEFAULT_TARGETS))
+    }
+
+    fn parse(raw: Option<String>) -> Self {
+        let mut targets = Self::default().targets.as_ref().clone();
+        let raw = raw.unwrap_or_default();
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry
+                .split_once('=')
+                .map(|(kind, cap)| (kind.trim(), cap.trim()))
+                .filter(|(kind, cap)| !kind.is_empty() && !cap.is_empty())
+            {
+                Some((kind, cap)) => {
+                    targets.insert(kind.to_string(), Capability::from(cap.to_string()));
+                }
+                None => tracing::warn!(
+                    "Ignoring invalid {} entry: {}",
+                    ENV_BLUEKING_DEFAULT_TARGETS,
+                    entry
+                ),
+            }
+        }
+        Self::new(targets)
+    }
+
+    /// Capability for actions of `kind`, if one is configured.
+    pub fn get(&self, kind: &str) -> Option<&Capability> {
+        self.targets.get(kind)
+    }
+}
+
+/// Why a brain action was dead-lettered instead of sent.
+#[derive(Debug)]
+pub enum DeadLetterReason {
+    /// The brain named no capability and `kind` has no default target.
+    NoDefaultTarget(String),
+    /// The command is not a valid WebSocket command.
+    InvalidCommand(String),
+    /// Dispatch to the capability failed.
+    Dispatch(DispatchError),
+}
+
+impl std::fmt::Display for DeadLetterReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeadLetterReason::NoDefaultTarget(kind) => {
+                write!(
+                    f,
+                    "no capability given and no default target for {kind:?} actions"
+                )
+            }
+            DeadLetterReason::InvalidCommand(e) => write!(f, "invalid command: {e}"),
+            DeadLetterReason::Dispatch(e) => write!(f, "dispatch failed: {e}"),
+        }
+    }
+}
+
+/// Send a brain action to the capability it names, or the default target for its kind.
+///
+/// Returns the recipients, or why the action has to be dead-lettered.
+async fn route_brain_action(
+    dispatch: &ComputerDispatchService,
+    default_targets: &DefaultTargets,
+    action: &BrainAction,
+) -> Result<Vec<i32>, DeadLetterReason> {
+    let capability = action
+        .capability
+        .clone()
+        .or_else(|| default_targets.get(&action.kind).cloned())
+        .ok_or_else(|| DeadLetterReason::NoDefaultTarget(action.kind.clone()))?;
+    let command = serde_json::from_str::<LuaCommand>(&action.command)
+        .map_err(|e| DeadLetterReason::InvalidCommand(e.to_string()))?;
+    dispatch
+        .clone()
+        .oneshot(ComputerAction::SendToCapability {
+            capability,
+            command,
+        })
+        .await
+        .map_err(DeadLetterReason::Dispatch)
+}
+
+/// Cut `reply` to at most `max_chars` characters, ending in `…` when shortened.
+///
+/// Works on `char` boundaries so multibyte characters are never split.
+fn truncate_reply(reply: String, max_chars: usize) -> String {
+    let Some((cut, _)) = reply.char_indices().nth(max_chars) else {
+        return reply;
+    };
+    tracing::warn!(
+        "Brain reply of {} bytes exceeds {} characters, truncating",
+        reply.len(),
+        max_chars
+    );
+    // Leave room for the ellipsis within the limit.
+    let keep = reply[..cut]
+        .char_indices()
+        .nth(max_chars.saturating_sub(1))
+        .map_or(cut, |(i, _)| i);
+    let mut truncated = reply[..keep].to_string();
+    truncated.push('…');
+    truncated
 }
 
 fn default_capabilities() -> Vec<Capability> {
     vec![]
 }
 
+/// Protocol versions this server speaks; clients outside the range are turned away at register.
+pub const SUPPORTED_PROTOCOL_VERSIONS: std::ops::RangeInclusive<u32> = 1..=1;
+
+/// WebSocket frame type a client wants its commands in; the payload is JSON either way.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameFormat {
+    #[default]
+    Text,
+    Binary,
+}
+
+impl FrameFormat {
+    fn is_text(&self) -> bool {
+        *self == FrameFormat::Text
+    }
+}
+
+/// Version assumed for clients that predate protocol negotiation.
+pub fn default_protocol_version() -> u32 {
+    1
+}
+
 /// Event sent from a computer when a chat message occurs.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ComputerChatEvent {
     pub username: String,
     pub message: String,
+    /// Client the message arrived from; filled in by the server, never read from the client.
+    #[serde(skip)]
+    pub computer_id: Option<i32>,
 }
 
 impl From<pb::ChatEvent> for ComputerChatEvent {
@@ -36,11 +371,13 @@ impl From<pb::ChatEvent> for ComputerChatEvent {
         ComputerChatEvent {
             username: event.username,
             message: event.message,
+            computer_id: event.computer_id,
         }
     }
 }
 
 /// Event sent from a computer after executing a command.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandResultEvent {
     pub command_id: String,
@@ -49,27 +386,341 @@ pub struct CommandResultEvent {
 }
 
 /// All possible events that can be received from computers.
+///
+/// Wire names are never dropped: a renamed variant or field keeps its old name as a
+/// `#[serde(alias = "...")]`, and a field whose type changes accepts the old shape through a
+/// `deserialize_with` helper, so clients on older scripts keep decoding.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ComputerEvent {
     Register {
+        /// Must be positive; the handshake refuses 0 and negative ids.
         id: i32,
         #[serde(default = "default_capabilities")]
         capabilities: Vec<Capability>,
+        #[serde(default = "default_protocol_version")]
+        protocol_version: u32,
+        /// Human-friendly name, e.g. "MinerBob".
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        label: Option<String>,
+        /// Frame type for commands sent to this client; text unless it asks for binary.
+        #[serde(default, skip_serializing_if = "FrameFormat::is_text")]
+        frames: FrameFormat,
+        /// Secret required when `BLUEKING_CLIENT_TOKEN` is set; never serialized back out.
+        #[serde(default, skip_serializing)]
+        token: Option<String>,
     },
     Chat(ComputerChatEvent),
+    /// Optional acknowledgment that a command was received, sent before its result.
+    CommandAck {
+        command_id: String,
+    },
     CommandResult(CommandResultEvent),
-    /// Emitted when a client disconnects; `timed_out` distinguishes timeout vs negotiated close.
+    /// Reply to `query_capabilities`: what the client supports right now.
+    UpdateCapabilities {
+        capabilities: Vec<Capability>,
+    },
+    /// Free-form status snapshot (fuel, position, ...) persisted as the client's telemetry.
+    Telemetry {
+        data: Telemetry,
+    },
+    /// Emitted when a client disconnects; `reason` tells why the connection ended.
     Deregister {
         id: i32,
-        timed_out: bool,
+        /// Replaced `timed_out: bool`, which is still accepted.
+        #[serde(alias = "timed_out", deserialize_with = "deregister_reason")]
+        #[cfg_attr(feature = "schema", schemars(with = "DeregisterReason"))]
+        reason: DeregisterReason,
+    },
+    /// Ask what the server supports; answered with a `query_reply` command by the socket
+    /// handler, without reaching the control service.
+    Query {
+        what: QueryTopic,
     },
+    /// Highest command sequence number the client has seen, sent when it notices a gap;
+    /// `received` is the number that revealed it.
+    Sequence {
+        last_seen: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        received: Option<u64>,
+    },
+}
+
+/// Decode `Deregister.reason`, or the bool it replaced: `true` meant timed out.
+fn deregister_reason<'de, D>(deserializer: D) -> Result<DeregisterReason, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Wire {
+        Reason(DeregisterReason),
+        TimedOut(bool),
+    }
+    Ok(match Wire::deserialize(deserializer)? {
+        Wire::Reason(reason) => reason,
+        Wire::TimedOut(true) => DeregisterReason::TimedOut,
+        Wire::TimedOut(false) => DeregisterReason::Closed,
+    })
+}
+
+/// What a `ComputerEvent::Query` asks about.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryTopic {
+    /// Names of the `LuaCommand` types the server may send, with the negotiated protocol.
+    Commands,
+}
+
+impl ComputerEvent {
+    /// Variant tag used to label per-event metrics.
+    pub fn kind(&self) -> EventKind {
+        match self {
+            ComputerEvent::Register { .. } => EventKind::Register,
+            ComputerEvent::Chat(_) => EventKind::Chat,
+            ComputerEvent::CommandAck { .. } => EventKind::CommandAck,
+            ComputerEvent::CommandResult(_) => EventKind::CommandResult,
+            ComputerEvent::UpdateCapabilities { .. } => EventKind::UpdateCapabilities,
+            ComputerEvent::Telemetry { .. } => EventKind::Telemetry,
+            ComputerEvent::Deregister { .. } => EventKind::Deregister,
+            ComputerEvent::Query { .. } => EventKind::Query,
+            ComputerEvent::Sequence { .. } => EventKind::Sequence,
+        }
+    }
+
+    /// Whether the event may be dropped when the server is overloaded.
+    ///
+    /// Only chat and telemetry are: the other events drive the registry, pending commands,
+    /// history and the audit log, which would drift from the client if one were lost.
+    pub fn sheddable(&self) -> bool {
+        matches!(
+            self,
+            ComputerEvent::Chat(_) | ComputerEvent::Telemetry { .. }
+        )
+    }
+}
+
+/// Largest inbound event frame decoded, in bytes.
+pub const MAX_EVENT_BYTES: usize = 64 * 1024;
+/// Deepest array/object nesting accepted in an event, checked before parsing.
+const MAX_EVENT_DEPTH: usize = 32;
+
+/// Why an inbound frame could not be decoded into a `ComputerEvent`.
+#[derive(Debug)]
+pub enum EventDecodeError {
+    /// The frame exceeds `MAX_EVENT_BYTES`.
+    TooLarge(usize),
+    /// Arrays and objects nest deeper than `MAX_EVENT_DEPTH`.
+    TooDeep,
+    /// Not well-formed JSON, including non-finite numbers like `NaN`.
+    Malformed(serde_json::Error),
+    /// Well-formed JSON that doesn't match any event, e.g. an unknown type or duplicate key.
+    Invalid(serde_json::Error),
+}
+
+impl std::fmt::Display for EventDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventDecodeError::TooLarge(len) => {
+                write!(f, "event of {len} bytes exceeds {MAX_EVENT_BYTES}")
+            }
+            EventDecodeError::TooDeep => write!(f, "event nests deeper than {MAX_EVENT_DEPTH}"),
+            EventDecodeError::Malformed(e) => write!(f, "not valid JSON: {e}"),
+            EventDecodeError::Invalid(e) => write!(f, "invalid event: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for EventDecodeError {}
+
+/// Decode an untrusted frame from a computer.
+///
+/// Size and nesting are bounded before serde sees the input, so an adversarial frame costs at
+/// most one linear scan and a parse of `MAX_EVENT_BYTES`.
+///
+/// Also returns the optional `client_ts` field any event may carry: the client's clock, in
+/// milliseconds since the Unix epoch, when it sent the event.
+pub fn decode_event(bytes: &[u8]) -> Result<(ComputerEvent, Option<u64>), EventDecodeError> {
+    #[derive(Deserialize)]
+    struct Envelope {
+        #[serde(flatten)]
+        event: ComputerEvent,
+        #[serde(default)]
+        client_ts: Option<u64>,
+    }
+
+    if bytes.len() > MAX_EVENT_BYTES {
+        return Err(EventDecodeError::TooLarge(bytes.len()));
+    }
+    if nesting_depth(bytes) > MAX_EVENT_DEPTH {
+        return Err(EventDecodeError::TooDeep);
+    }
+    serde_json::from_slice(bytes)
+        .map(|envelope: Envelope| (envelope.event, envelope.client_ts))
+        .map_err(|e| match e.classify() {
+            serde_json::error::Category::Data => EventDecodeError::Invalid(e),
+            _ => EventDecodeError::Malformed(e),
+        })
+}
+
+/// Maximum bracket depth outside string literals; malformed input just yields some depth.
+fn nesting_depth(bytes: &[u8]) -> usize {
+    let (mut depth, mut max) = (0usize, 0usize);
+    let (mut in_string, mut escaped) = (false, false);
+    for &b in bytes {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max
+}
+
+/// Why a client was removed from the registry.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeregisterReason {
+    /// The client closed the connection or the stream ended.
+    Closed,
+    /// No activity within the client timeout, or no answer to an application-level ping.
+    TimedOut,
+    /// Evicted for persistently failing to keep up with outbound messages.
+    Slow,
+    /// A socket write blocked past the write deadline; the client stopped reading.
+    Stalled,
+    /// Still connected when a migration's grace period ended.
+    Migrated,
+    /// Closed by an operator through `DisconnectComputer`.
+    Disconnected,
+}
+
+/// A `ComputerEvent` tagged with the id of the client connection it arrived on.
+#[derive(Debug, Clone)]
+pub struct ClientEvent {
+    pub client_id: i32,
+    pub event: ComputerEvent,
+    /// Server clock when the event was received, in milliseconds since the Unix epoch.
+    pub received_at_ms: u64,
+    /// Client clock when the event was sent, if the client reported it.
+    pub client_ts: Option<u64>,
+}
+
+impl ClientEvent {
+    /// Stamp `event` with the current server time.
+    pub fn new(client_id: i32, event: ComputerEvent, client_ts: Option<u64>) -> Self {
+        Self {
+            client_id,
+            event,
+            received_at_ms: crate::audit::now_ms(),
+            client_ts,
+        }
+    }
+
+    /// How far the server clock is ahead of the client's, in milliseconds; includes transit.
+    pub fn clock_skew_ms(&self) -> Option<i64> {
+        self.client_ts
+            .map(|sent| self.received_at_ms as i64 - sent as i64)
+    }
+}
+
+/// Skew beyond which an event's client timestamp is logged as a warning.
+const CLOCK_SKEW_WARN: Duration = Duration::from_secs(5);
+
+/// An event retained in `EventHistory`, with the times it was sent and received.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedEvent {
+    #[serde(flatten)]
+    pub event: ComputerEvent,
+    pub received_at_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ts: Option<u64>,
+}
+
+/// Number of recent inbound events retained per client.
+const ENV_BLUEKING_EVENT_HISTORY: &str = "BLUEKING_EVENT_HISTORY";
+const DEFAULT_EVENT_HISTORY: usize = 64;
+
+/// Per-client ring buffer of recently received events, for debugging.
+///
+/// A client's buffer is dropped when it deregisters, so ids that come and go do not pile up.
+#[derive(Clone)]
+pub struct EventHistory {
+    events: Arc<std::sync::Mutex<HashMap<i32, VecDeque<RecordedEvent>>>>,
+    capacity: usize,
+}
+
+impl EventHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            capacity,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(crate::env_or(
+            ENV_BLUEKING_EVENT_HISTORY,
+            DEFAULT_EVENT_HISTORY,
+        ))
+    }
+
+    fn record(&self, event: &ClientEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut events = self.events.lock().expect("event history lock poisoned");
+        if let ComputerEvent::Deregister { .. } = event.event {
+            events.remove(&event.client_id);
+            return;
+        }
+        let buffer = events.entry(event.client_id).or_default();
+        if buffer.len() == self.capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(RecordedEvent {
+            event: event.event.clone(),
+            received_at_ms: event.received_at_ms,
+            client_ts: event.client_ts,
+        });
+    }
+
+    /// Up to `limit` most recent events of client `id`, oldest first; `0` returns all retained.
+    pub fn recent_events(&self, id: i32, limit: usize) -> Vec<RecordedEvent> {
+        let events = self.events.lock().expect("event history lock poisoned");
+        let Some(buffer) = events.get(&id) else {
+            return Vec::new();
+        };
+        let skip = match limit {
+            0 => 0,
+            limit => buffer.len().saturating_sub(limit),
+        };
+        buffer.iter().skip(skip).cloned().collect()
+    }
 }
 
 #[derive(Debug)]
 pub enum ControlError {
     Brain(crate::brain::BrainError),
     Dispatch(DispatchError),
+    /// The handler task panicked or was cancelled before finishing.
+    TaskPanicked(String),
 }
 
 impl std::fmt::Display for ControlError {
@@ -77,56 +728,454 @@ impl std::fmt::Display for ControlError {
         match self {
             ControlError::Brain(e) => write!(f, "brain error: {e}"),
             ControlError::Dispatch(e) => write!(f, "dispatch error: {e}"),
+            ControlError::TaskPanicked(e) => write!(f, "handler task failed: {e}"),
         }
     }
 }
 
 impl std::error::Error for ControlError {}
 
-/// Tower service that routes client events by invoking the brain and registry.
+/// Events buffered per internal subscriber before the slowest ones start lagging.
+const ENV_BLUEKING_EVENT_BUS_CAPACITY: &str = "BLUEKING_EVENT_BUS_CAPACITY";
+const DEFAULT_EVENT_BUS_CAPACITY: usize = 256;
+
+/// Chat events held while the brain is unreachable, replayed in order once it is back; 0 disables.
+const ENV_BLUEKING_BRAIN_CHAT_QUEUE: &str = "BLUEKING_BRAIN_CHAT_QUEUE";
+const DEFAULT_BRAIN_CHAT_QUEUE: usize = 0;
+
+/// Bounded FIFO of chat events waiting for the brain to come back.
+///
+/// Once anything is queued, later events queue behind it until the backlog has drained, so
+/// each client's messages still reach the brain in the order they were sent.
+#[derive(Clone)]
+struct ChatBacklog {
+    state: Arc<std::sync::Mutex<BacklogState>>,
+    queued: Arc<Notify>,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct BacklogState {
+    events: VecDeque<ComputerChatEvent>,
+    /// An event taken from the queue is being handled.
+    replaying: bool,
+}
+
+impl ChatBacklog {
+    fn new(capacity: usize) -> Self {
+        Self {
+            state: Arc::default(),
+            queued: Arc::new(Notify::new()),
+            capacity,
+        }
+    }
+
+    /// Queue `event` if the brain is down or older events are still pending; otherwise hand
+    /// it back to be handled right away. Events beyond the capacity are dropped.
+    fn offer(&self, event: ComputerChatEvent, brain_available: bool) -> Option<ComputerChatEvent> {
+        if self.capacity == 0 {
+            return Some(event);
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if brain_available && state.events.is_empty() && !state.replaying {
+            return Some(event);
+        }
+        if state.events.len() >= self.capacity {
+            tracing::warn!(
+                "Brain chat queue full ({} events), dropping message from {}",
+                self.capacity,
+                event.username
+            );
+            return None;
+        }
+        if state.events.is_empty() && !state.replaying {
+            tracing::info!("Brain unavailable, queueing chat events");
+        }
+        state.events.push_back(event);
+        drop(state);
+        self.queued.notify_one();
+        None
+    }
+
+    /// Take the oldest queued event, marking a replay in progress until `finish`.
+    fn take(&self) -> Option<ComputerChatEvent> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let event = state.events.pop_front();
+        state.replaying = event.is_some();
+        event
+    }
+
+    fn finish(&self) {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .replaying = false;
+    }
+
+    fn len(&self) -> usize {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .events
+            .len()
+    }
+}
+
+/// Subscribe-only handle on the processed-event bus of a `ComputerEventService`.
 #[derive(Clone)]
+pub struct EventSubscriber {
+    events: broadcast::Sender<ClientEvent>,
+}
+
+impl EventSubscriber {
+    /// Receive every event processed from now on, as `ComputerEventService::subscribe_events`.
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+}
+
+/// Tower service that routes client events by invoking the brain and registry.
 pub struct ComputerEventService<B: Brain> {
     brain: Arc<B>,
     registry: ClientRegistry,
     dispatch: ComputerDispatchService,
+    shutdown: ShutdownSignal,
+    chat_target: ChatTarget,
+    audit: AuditLog,
+    history: EventHistory,
+    metrics: Arc<Metrics>,
+    default_targets: DefaultTargets,
+    /// Processed events, for observers outside the routing path.
+    events: broadcast::Sender<ClientEvent>,
+    backlog: ChatBacklog,
 }
 
-pub type AppComputerControlService = ComputerEventService<BrainService>;
+// Manual impl: the brain sits behind an `Arc`, so `B` itself need not be `Clone`.
+impl<B: Brain> Clone for ComputerEventService<B> {
+    fn clone(&self) -> Self {
+        Self {
+            brain: Arc::clone(&self.brain),
+            registry: self.registry.clone(),
+            dispatch: self.dispatch.clone(),
+            shutdown: self.shutdown.clone(),
+            chat_target: self.chat_target.clone(),
+            audit: self.audit.clone(),
+            history: self.history.clone(),
+            metrics: Arc::clone(&self.metrics),
+            default_targets: self.default_targets.clone(),
+            events: self.events.clone(),
+            backlog: self.backlog.clone(),
+        }
+    }
+}
+
+pub type AppComputerControlService = ComputerEventService<BrainRouter>;
+
+/// Assembles a `ComputerEventService`; parts that are not set are read from the environment
+/// or left at their defaults.
+pub struct ComputerEventServiceBuilder<B: Brain> {
+    brain: Arc<B>,
+    registry: ClientRegistry,
+    dispatch: ComputerDispatchService,
+    shutdown: ShutdownSignal,
+    chat_target: Option<ChatTarget>,
+    audit: AuditLog,
+    history: Option<EventHistory>,
+    metrics: Option<Arc<Metrics>>,
+    default_targets: Option<DefaultTargets>,
+}
+
+impl<B: Brain> ComputerEventServiceBuilder<B> {
+    /// Where chat replies go; defaults to `ChatTarget::from_env(Capability::Chat)`.
+    pub fn chat_target(mut self, chat_target: ChatTarget) -> Self {
+        self.chat_target = Some(chat_target);
+        self
+    }
+
+    /// Audit log of processed events; defaults to none.
+    pub fn audit(mut self, audit: AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Per-client event history; defaults to `EventHistory::from_env()`.
+    pub fn history(mut self, history: EventHistory) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Capabilities for brain actions that name none; defaults to `DefaultTargets::from_env()`.
+    pub fn default_targets(mut self, default_targets: DefaultTargets) -> Self {
+        self.default_targets = Some(default_targets);
+        self
+    }
+
+    pub fn build(self) -> ComputerEventService<B> {
+        let capacity = crate::env_or(ENV_BLUEKING_EVENT_BUS_CAPACITY, DEFAULT_EVENT_BUS_CAPACITY);
+        let (events, _) = broadcast::channel(capacity.max(1));
+        let backlog = ChatBacklog::new(crate::env_or(
+            ENV_BLUEKING_BRAIN_CHAT_QUEUE,
+            DEFAULT_BRAIN_CHAT_QUEUE,
+        ));
+        ComputerEventService {
+            brain: self.brain,
+            registry: self.registry,
+            dispatch: self.dispatch,
+            shutdown: self.shutdown,
+            chat_target: self
+                .chat_target
+                .unwrap_or_else(|| ChatTarget::from_env(Capability::Chat)),
+            audit: self.audit,
+            history: self.history.unwrap_or_else(EventHistory::from_env),
+            metrics: self.metrics.unwrap_or_default(),
+            default_targets: self
+                .default_targets
+                .unwrap_or_else(DefaultTargets::from_env),
+            events,
+            backlog,
+        }
+    }
+}
 
 impl<B: Brain> ComputerEventService<B> {
-    pub fn new(brain: Arc<B>, registry: ClientRegistry, dispatch: ComputerDispatchService) -> Self {
-        Self {
+    pub fn builder(
+        brain: Arc<B>,
+        registry: ClientRegistry,
+        dispatch: ComputerDispatchService,
+        shutdown: ShutdownSignal,
+    ) -> ComputerEventServiceBuilder<B> {
+        ComputerEventServiceBuilder {
             brain,
             registry,
             dispatch,
+            shutdown,
+            chat_target: None,
+            audit: AuditLog::default(),
+            history: None,
+            metrics: None,
+            default_targets: None,
+        }
+    }
+
+    /// Dispatch service shared with the event handlers.
+    pub fn dispatch(&self) -> &ComputerDispatchService {
+        &self.dispatch
+    }
+
+    /// Whether the brain is currently believed reachable.
+    pub fn brain_available(&self) -> bool {
+        self.brain.is_available()
+    }
+
+    /// Replay queued chat events one at a time until shutdown.
+    ///
+    /// Each replay waits in the brain's reconnect loop, so the queue drains as soon as the
+    /// brain is reachable again. Whatever is still queued at shutdown is dropped.
+    pub fn chat_replayer(&self) -> impl Future<Output = ()> + Send + use<B> {
+        let this = self.clone();
+        async move {
+            let stop = this.shutdown.subscribe();
+            tokio::pin!(stop);
+            loop {
+                let Some(event) = this.backlog.take() else {
+                    tokio::select! {
+                        _ = &mut stop => break,
+                        _ = this.backlog.queued.notified() => continue,
+                    }
+                };
+                let handled = Self::handle_chat(
+                    Arc::clone(&this.brain),
+                    this.dispatch.clone(),
+                    this.shutdown.clone(),
+                    this.chat_target.clone(),
+                    this.default_targets.clone(),
+                    this.audit.clone(),
+                    event,
+                );
+                tokio::select! {
+                    _ = &mut stop => break,
+                    result = handled => {
+                        if let Err(err) = result {
+                            tracing::warn!("Failed to replay queued chat event: {}", err);
+                        }
+                    }
+                }
+                this.backlog.finish();
+            }
+            let dropped = this.backlog.len();
+            if dropped > 0 {
+                tracing::warn!("Dropping {} queued chat event(s) at shutdown", dropped);
+            }
+        }
+    }
+
+    /// Receive every event once it has been processed.
+    ///
+    /// A subscriber that falls more than the bus capacity behind gets `RecvError::Lagged`
+    /// and skips ahead; it never slows down event handling.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    /// Handle that subscribes to processed events later, e.g. once per gRPC stream, without
+    /// being able to publish on the bus.
+    pub fn event_subscriber(&self) -> EventSubscriber {
+        EventSubscriber {
+            events: self.events.clone(),
         }
     }
 
     async fn handle_chat(
         brain: Arc<B>,
         dispatch: ComputerDispatchService,
+        shutdown: ShutdownSignal,
+        chat_target: ChatTarget,
+        default_targets: DefaultTargets,
+        audit: AuditLog,
         chat_event: ComputerChatEvent,
     ) -> Result<(), ControlError> {
-        let reply = brain.chat(chat_event).await.map_err(ControlError::Brain)?;
+        let username = chat_event.username.clone();
+        let chat = brain.chat(chat_event);
+        tokio::pin!(chat);
+        let reply = match chat_target.thinking() {
+            Some(notice) => tokio::select! {
+                reply = &mut chat => reply,
+                _ = tokio::time::sleep(notice.delay) => {
+                    let interim = chat_target.reply_command(&username, notice.message.clone());
+                    if let Err(err) = dispatch
+                        .clone()
+                        .oneshot(ComputerAction::SendToCapability {
+                            capability: chat_target.get().await,
+                            command: interim,
+                        })
+                        .await
+                    {
+                        tracing::warn!("Failed to send thinking notice: {}", err);
+                    }
+                    chat.await
+                }
+            },
+            None => chat.await,
+        }
+        .map_err(ControlError::Brain)?;
+        let BrainReply {
+            text: reply,
+            target,
+            actions,
+        } = reply;
+        // The registry may already be draining; dispatching now only produces spurious errors.
+        if shutdown.is_triggered() {
+            tracing::info!("Shutdown in progress, dropping brain reply: {:?}", reply);
+            return Ok(());
+        }
+        for action in actions {
+            match route_brain_action(&dispatch, &default_targets, &action).await {
+                Ok(recipients) => {
+                    tracing::debug!("Brain {} action delivered to {:?}", action.kind, recipients)
+                }
+                Err(reason) => {
+                    tracing::warn!("Dead-lettering brain {} action: {}", action.kind, reason);
+                    audit.record_dead_letter(&action, &reason);
+                }
+            }
+        }
         if reply.is_empty() {
             return Ok(());
         }
+        if reply.len() > chat_target.max_brain_reply_bytes() {
+            tracing::error!(
+                "Dropping {}-byte brain reply to {}: exceeds the {}-byte ceiling",
+                reply.len(),
+                username,
+                chat_target.max_brain_reply_bytes()
+            );
+            return Ok(());
+        }
 
-        let cmd = LuaCommand::chat_message(reply);
-        dispatch
-            .oneshot(ComputerAction::SendToCapability {
-                capability: Capability::Chat,
+        // A brain-chosen computer wins; otherwise `chat` dispatches `most_recent` by default,
+        // so the reply lands on the computer that is carrying the conversation.
+        let cmd = chat_target.reply_command(&username, reply);
+        let capability = chat_target.get().await;
+        let action = match target {
+            Some(id) => ComputerAction::SendCommandToId {
+                id,
+                capability,
                 command: cmd,
-            })
+            },
+            None => ComputerAction::SendToCapability {
+                capability,
+                command: cmd,
+            },
+        };
+        dispatch
+            .oneshot(action)
             .await
+            .map(|recipients| tracing::debug!("Brain reply delivered to {:?}", recipients))
             .map_err(ControlError::Dispatch)
     }
 
-    async fn handle_command_result(result_event: CommandResultEvent) -> Result<(), ControlError> {
-        match result_event.error {
+    async fn handle_command_ack(
+        dispatch: ComputerDispatchService,
+        command_id: String,
+    ) -> Result<(), ControlError> {
+        if dispatch.pending().acknowledge(&command_id).await {
+            tracing::debug!("Command {} received by client", command_id);
+        } else {
+            tracing::warn!("Ack for unknown command {}", command_id);
+        }
+        Ok(())
+    }
+
+    async fn handle_command_result(
+        brain: Arc<B>,
+        dispatch: ComputerDispatchService,
+        metrics: Arc<Metrics>,
+        result_event: CommandResultEvent,
+    ) -> Result<(), ControlError> {
+        let id = &result_event.command_id;
+        dispatch.pending().deliver(&result_event);
+        match dispatch.pending().finish(id).await {
+            ResultMatch::OnTime(_) => {}
+            ResultMatch::Duplicate => tracing::debug!("Repeated result for command {}", id),
+            ResultMatch::Late(after) => {
+                tracing::warn!(
+                    "Late result for command {}: arrived {:?} after sending",
+                    id,
+                    after
+                );
+                metrics.unmatched_results.record(UnmatchedResult::Late);
+            }
+            ResultMatch::Expired => {
+                tracing::warn!("Result for expired command {}", id);
+                metrics.unmatched_results.record(UnmatchedResult::Expired);
+            }
+            ResultMatch::Unknown => {
+                tracing::warn!("Result for unknown command {}", id);
+                metrics.unmatched_results.record(UnmatchedResult::Unknown);
+            }
+        }
+        match &result_event.error {
             None => tracing::info!("Command {} succeeded", result_event.command_id),
             Some(err) => tracing::warn!("Command {} failed: {}", result_event.command_id, err),
         }
+        let command_id = result_event.command_id.clone();
+        match brain.command_result(result_event).await {
+            Ok(()) => {}
+            // Brains that predate the feedback RPC simply don't learn from results.
+            Err(BrainError::Rpc(status)) if status.code() == tonic::Code::Unimplemented => {
+                tracing::debug!("Brain does not accept command results");
+            }
+            Err(err) => tracing::warn!(
+                "Failed to report result of command {} to brain: {}",
+                command_id,
+                err
+            ),
+        }
         Ok(())
     }
 
@@ -134,7 +1183,27 @@ impl<B: Brain> ComputerEventService<B> {
         registry: ClientRegistry,
         id: i32,
         capabilities: Vec<Capability>,
+        label: Option<String>,
     ) -> Result<(), ControlError> {
+        if registry
+            .is_repeat_register(id, &capabilities, label.as_deref(), REGISTER_DEDUP_WINDOW)
+            .await
+        {
+            tracing::debug!("Client {} repeated an unchanged register; ignored", id);
+            return Ok(());
+        }
+        let unknown: Vec<&str> = capabilities
+            .iter()
+            .filter(|c| !c.is_known())
+            .map(Capability::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            tracing::warn!(
+                "Client {} advertised unrecognized capabilities {:?}",
+                id,
+                unknown
+            );
+        }
         match registry.update_capabilities(id, capabilities.clone()).await {
             Ok(()) => tracing::info!("Client {} refreshed capabilities {:?}", id, capabilities),
             Err(err) => tracing::warn!(
@@ -143,20 +1212,120 @@ impl<B: Brain> ComputerEventService<B> {
                 err
             ),
         }
+        // An omitted label keeps whatever was set before, e.g. by an operator.
+        if let Some(label) = label
+            && let Err(err) = registry.set_label(id, Some(label)).await
+        {
+            tracing::warn!("Client {} could not be labelled: {}", id, err);
+        }
         Ok(())
     }
 
-    async fn handle_deregister(id: i32, timed_out: bool) -> Result<(), ControlError> {
-        if timed_out {
-            tracing::warn!("Client {} timed out and was deregistered", id);
-        } else {
-            tracing::info!("Client {} deregistered", id);
+    /// Reconcile the registry with a client's re-reported capabilities, logging any drift.
+    async fn handle_update_capabilities(
+        registry: ClientRegistry,
+        id: i32,
+        capabilities: Vec<Capability>,
+    ) -> Result<(), ControlError> {
+        let Some(known) = registry.capabilities(id).await else {
+            tracing::warn!("Capabilities from client {} ignored: not registered", id);
+            return Ok(());
+        };
+        let added: Vec<&Capability> = capabilities.iter().filter(|c| !known.contains(c)).collect();
+        let removed: Vec<&Capability> =
+            known.iter().filter(|c| !capabilities.contains(c)).collect();
+        if added.is_empty() && removed.is_empty() {
+            tracing::debug!("Client {} capabilities unchanged", id);
+            return Ok(());
+        }
+        tracing::warn!(
+            "Client {} capabilities drifted: added {:?}, removed {:?}",
+            id,
+            added,
+            removed
+        );
+        if let Err(err) = registry.update_capabilities(id, capabilities).await {
+            tracing::warn!("Client {} capabilities not updated: {}", id, err);
         }
         Ok(())
     }
+
+    async fn handle_telemetry(
+        registry: ClientRegistry,
+        id: i32,
+        data: Telemetry,
+    ) -> Result<(), ControlError> {
+        if let Err(err) = registry.update_telemetry(id, data).await {
+            tracing::warn!("Telemetry from client {} not stored: {}", id, err);
+        }
+        Ok(())
+    }
+
+    async fn handle_sequence(
+        registry: ClientRegistry,
+        id: i32,
+        last_seen: u64,
+        received: Option<u64>,
+    ) -> Result<(), ControlError> {
+        let sent = registry.last_sequence(id).await.unwrap_or(0);
+        match (missed_commands(last_seen, received), received) {
+            (Some(missed), _) => tracing::warn!(
+                "Client {} missed commands {}..={} (sent up to {})",
+                id,
+                missed.start(),
+                missed.end(),
+                sent
+            ),
+            (None, Some(received)) => tracing::warn!(
+                "Client {} saw command {} out of order after {}",
+                id,
+                received,
+                last_seen
+            ),
+            (None, None) => tracing::debug!(
+                "Client {} has seen commands up to {} of {}",
+                id,
+                last_seen,
+                sent
+            ),
+        }
+        Ok(())
+    }
+
+    async fn handle_deregister(id: i32, reason: DeregisterReason) -> Result<(), ControlError> {
+        match reason {
+            DeregisterReason::Closed => tracing::info!("Client {} deregistered", id),
+            DeregisterReason::TimedOut => {
+                tracing::warn!("Client {} timed out and was deregistered", id)
+            }
+            DeregisterReason::Slow => tracing::warn!("Client {} was evicted as too slow", id),
+            DeregisterReason::Stalled => {
+                tracing::warn!("Client {} stopped reading and was disconnected", id)
+            }
+            DeregisterReason::Migrated => {
+                tracing::info!("Client {} was disconnected after migration", id)
+            }
+            DeregisterReason::Disconnected => {
+                tracing::warn!("Client {} was disconnected by an operator", id)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Commands a client never saw, by the sequence report that revealed the gap: those between
+/// the last one it saw and the one it just received.
+///
+/// Both numbers come from the client, so a `last_seen` at the top of the range reports no gap
+/// rather than overflowing.
+fn missed_commands(last_seen: u64, received: Option<u64>) -> Option<std::ops::RangeInclusive<u64>> {
+    let next = last_seen.checked_add(1)?;
+    received
+        .filter(|received| *received > next)
+        .map(|received| next..=received - 1)
 }
 
-impl<B: Brain> Service<ComputerEvent> for ComputerEventService<B> {
+impl<B: Brain> Service<ClientEvent> for ComputerEventService<B> {
     type Response = ();
     type Error = ControlError;
     type Future = EventFuture;
@@ -165,27 +1334,95 @@ impl<B: Brain> Service<ComputerEvent> for ComputerEventService<B> {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, event: ComputerEvent) -> Self::Future {
-        tracing::info!("Processing event: {:?}", event);
+    fn call(&mut self, event: ClientEvent) -> Self::Future {
+        tracing::info!(
+            "Processing event from client {}: {:?}",
+            event.client_id,
+            event.event
+        );
+        if let Some(skew) = event.clock_skew_ms() {
+            self.metrics
+                .client_clock_skew
+                .observe(Duration::from_millis(skew.unsigned_abs()));
+            if skew.unsigned_abs() > CLOCK_SKEW_WARN.as_millis() as u64 {
+                tracing::warn!(
+                    "Client {} clock is {}ms {} the server's",
+                    event.client_id,
+                    skew.unsigned_abs(),
+                    if skew > 0 { "behind" } else { "ahead of" }
+                );
+            } else {
+                tracing::debug!("Client {} clock skew {}ms", event.client_id, skew);
+            }
+        }
+        self.audit.record_event(&event);
+        self.history.record(&event);
         let brain = Arc::clone(&self.brain);
         let registry = self.registry.clone();
         let dispatch = self.dispatch.clone();
+        let shutdown = self.shutdown.clone();
+        let chat_target = self.chat_target.clone();
+        let default_targets = self.default_targets.clone();
+        let audit = self.audit.clone();
+        let metrics = Arc::clone(&self.metrics);
+        let backlog = self.backlog.clone();
 
+        let client_id = event.client_id;
+        let events = self.events.clone();
+        // Only pay for the clone when someone is listening.
+        let published = (events.receiver_count() > 0).then(|| event.clone());
         let handle = tokio::spawn(async move {
-            match event {
-                ComputerEvent::Chat(chat_event) => {
-                    Self::handle_chat(brain, dispatch, chat_event).await
+            let result = match event.event {
+                ComputerEvent::Chat(mut chat_event) => {
+                    chat_event.computer_id = Some(client_id);
+                    match backlog.offer(chat_event, brain.is_available()) {
+                        Some(chat_event) => {
+                            Self::handle_chat(
+                                brain,
+                                dispatch,
+                                shutdown,
+                                chat_target,
+                                default_targets,
+                                audit,
+                                chat_event,
+                            )
+                            .await
+                        }
+                        None => Ok(()),
+                    }
+                }
+                ComputerEvent::CommandAck { command_id } => {
+                    Self::handle_command_ack(dispatch, command_id).await
                 }
                 ComputerEvent::CommandResult(result_event) => {
-                    Self::handle_command_result(result_event).await
+                    Self::handle_command_result(brain, dispatch, metrics, result_event).await
+                }
+                ComputerEvent::Register {
+                    id,
+                    capabilities,
+                    label,
+                    ..
+                } => Self::handle_register(registry, id, capabilities, label).await,
+                ComputerEvent::UpdateCapabilities { capabilities } => {
+                    Self::handle_update_capabilities(registry, client_id, capabilities).await
                 }
-                ComputerEvent::Register { id, capabilities } => {
-                    Self::handle_register(registry, id, capabilities).await
+                ComputerEvent::Telemetry { data } => {
+                    Self::handle_telemetry(registry, client_id, data).await
                 }
-                ComputerEvent::Deregister { id, timed_out } => {
-                    Self::handle_deregister(id, timed_out).await
+                ComputerEvent::Deregister { id, reason } => {
+                    Self::handle_deregister(id, reason).await
                 }
+                ComputerEvent::Sequence {
+                    last_seen,
+                    received,
+                } => Self::handle_sequence(registry, client_id, last_seen, received).await,
+                // Answered by the socket handler; nothing to do if one arrives here.
+                ComputerEvent::Query { .. } => Ok(()),
+            };
+            if let Some(event) = published {
+                let _ = events.send(event);
             }
+            result
         });
 
         EventFuture::new(handle)
@@ -194,10 +1431,18 @@ impl<B: Brain> Service<ComputerEvent> for ComputerEventService<B> {
 
 pin_project! {
     /// Manual future for `ComputerEventService` so the outer service remains a concrete type.
+    ///
+    /// Dropping it (e.g. when a timeout layer gives up) aborts the spawned handler task.
     pub struct EventFuture {
         #[pin]
         handle: tokio::task::JoinHandle<Result<(), ControlError>>,
     }
+
+    impl PinnedDrop for EventFuture {
+        fn drop(this: Pin<&mut Self>) {
+            this.project().handle.abort();
+        }
+    }
 }
 
 impl EventFuture {
@@ -215,8 +1460,8 @@ impl Future for EventFuture {
         match join {
             Ok(res) => Poll::Ready(res),
             Err(err) => {
-                tracing::warn!("Control task join error: {}", err);
-                Poll::Ready(Ok(()))
+                tracing::error!("Control task join error: {}", err);
+                Poll::Ready(Err(ControlError::TaskPanicked(err.to_string())))
             }
         }
     }
@@ -256,3 +1501,333 @@ impl Future for EventFuture {
 
 //     history.push(result_msg).await;
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::{
+        DisabledCapabilities, DispatchLimits, DispatchStrategies, DispatchStrategy,
+        PendingCommands, RateLimits,
+    };
+    use crate::store::MemoryStore;
+    use crate::websocket::RegistryConfig;
+    use proptest::prelude::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    /// A telemetry event whose `data` holds `depth` nested arrays, `2 + depth` levels in all.
+    fn nested_telemetry(depth: usize) -> String {
+        format!(
+            r#"{{"type":"telemetry","data":{{"x":{}{}}}}}"#,
+            "[".repeat(depth),
+            "]".repeat(depth)
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn decode_event_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..4096)) {
+            let _ = decode_event(&bytes);
+        }
+
+        #[test]
+        fn decode_event_never_panics_on_json_like_text(text in r#"[\[\]{}":,a-z0-9\\ ]{0,512}"#) {
+            let _ = decode_event(text.as_bytes());
+        }
+
+        #[test]
+        fn nesting_depth_is_bounded_by_open_brackets(bytes in proptest::collection::vec(any::<u8>(), 0..4096)) {
+            let opened = bytes.iter().filter(|b| matches!(b, b'[' | b'{')).count();
+            prop_assert!(nesting_depth(&bytes) <= opened);
+        }
+
+        #[test]
+        fn nesting_depth_ignores_brackets_in_strings(inner in r#"[\[\]{}a-z]{0,64}"#) {
+            let text = format!(r#"{{"s":"{inner}"}}"#);
+            prop_assert_eq!(nesting_depth(text.as_bytes()), 1);
+        }
+
+        #[test]
+        fn events_deeper_than_the_limit_are_rejected(depth in 0usize..2 * MAX_EVENT_DEPTH) {
+            let frame = nested_telemetry(depth);
+            prop_assert_eq!(nesting_depth(frame.as_bytes()), depth + 2);
+            let too_deep = matches!(decode_event(frame.as_bytes()), Err(EventDecodeError::TooDeep));
+            prop_assert_eq!(too_deep, depth + 2 > MAX_EVENT_DEPTH);
+        }
+    }
+
+    fn action(kind: &str, capability: Option<Capability>, command: &str) -> BrainAction {
+        BrainAction {
+            kind: kind.to_string(),
+            capability,
+            command: command.to_string(),
+        }
+    }
+
+    const MESSAGE: &str = r#"{"name":"message","id":"a1","args":{"message":"hi"}}"#;
+
+    #[test]
+    fn default_targets_extend_the_built_in_chat_route() {
+        let targets = DefaultTargets::parse(Some("code=run_code, bogus,=chat".to_string()));
+        assert_eq!(targets.get("chat"), Some(&Capability::Chat));
+        assert_eq!(
+            targets.get("code"),
+            Some(&Capability::Unknown("run_code".to_string()))
+        );
+        assert_eq!(targets.get("bogus"), None);
+        assert_eq!(targets.targets.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn brain_actions_route_by_default_target_or_dead_letter() {
+        let registry =
+            ClientRegistry::new(RegistryConfig::from_env(), Arc::new(MemoryStore::default()));
+        let (tx, mut chat_client) = mpsc::channel(8);
+        let _sender = registry
+            .register(1, tx, vec![Capability::Chat], 1, None, FrameFormat::Text)
+            .await;
+        let dispatch = ComputerDispatchService::new(
+            registry.clone(),
+            AuditLog::default(),
+            PendingCommands::new(16, 4, Duration::from_secs(60)),
+            DispatchLimits::new(0, HashMap::new()),
+            RateLimits::new(0.0, 1.0, HashMap::new()),
+            DisabledCapabilities::default(),
+            DispatchStrategies::new(DispatchStrategy::First, HashMap::new()),
+            false,
+            None,
+        );
+        let targets = DefaultTargets::default();
+
+        let routed = route_brain_action(&dispatch, &targets, &action("chat", None, MESSAGE)).await;
+        assert_eq!(routed.unwrap(), [1]);
+        assert!(chat_client.try_recv().is_ok());
+
+        let unmapped =
+            route_brain_action(&dispatch, &targets, &action("code", None, MESSAGE)).await;
+        assert!(matches!(unmapped, Err(DeadLetterReason::NoDefaultTarget(kind)) if kind == "code"));
+
+        let explicit = action("code", Some(Capability::Chat), MESSAGE);
+        assert_eq!(
+            route_brain_action(&dispatch, &targets, &explicit)
+                .await
+                .unwrap(),
+            [1]
+        );
+
+        let garbled = route_brain_action(&dispatch, &targets, &action("chat", None, "{")).await;
+        assert!(matches!(garbled, Err(DeadLetterReason::InvalidCommand(_))));
+
+        let nobody = action("file", Some(Capability::FileTransfer), MESSAGE);
+        assert!(matches!(
+            route_brain_action(&dispatch, &targets, &nobody).await,
+            Err(DeadLetterReason::Dispatch(DispatchError::NoClient))
+        ));
+    }
+
+    /// Answers only once released, so a test can act while the reply is outstanding.
+    struct SlowBrain {
+        started: Notify,
+        release: Notify,
+    }
+
+    #[tonic::async_trait]
+    impl Brain for SlowBrain {
+        async fn chat(&self, chat_event: ComputerChatEvent) -> Result<BrainReply, BrainError> {
+            self.started.notify_one();
+            self.release.notified().await;
+            Ok(BrainReply {
+                text: format!("echo: {}", chat_event.message),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn reply_is_dropped_when_shutdown_begins_mid_chat() {
+        use crate::ShutdownReason;
+        use crate::actions::PendingCommands;
+        use crate::actions::tests::{connect, dispatch, registry};
+
+        let registry = registry();
+        let mut chat_client = connect(&registry, 1, vec![Capability::Chat]).await;
+        let dispatch = dispatch(
+            &registry,
+            PendingCommands::new(16, 4, Duration::from_secs(60)),
+        );
+        let brain = Arc::new(SlowBrain {
+            started: Notify::new(),
+            release: Notify::new(),
+        });
+        let shutdown = ShutdownSignal::new();
+        let chat = |shutdown: ShutdownSignal| {
+            ComputerEventService::handle_chat(
+                Arc::clone(&brain),
+                dispatch.clone(),
+                shutdown,
+                ChatTarget::new(Capability::Chat, false, 256, 4096, None),
+                DefaultTargets::default(),
+                AuditLog::default(),
+                ComputerChatEvent {
+                    username: "Steve".to_string(),
+                    message: "hello".to_string(),
+                    computer_id: Some(1),
+                },
+            )
+        };
+
+        let answered = tokio::spawn(chat(ShutdownSignal::new()));
+        brain.started.notified().await;
+        brain.release.notify_one();
+        answered.await.unwrap().unwrap();
+        assert!(chat_client.try_recv().is_ok());
+
+        let in_flight = tokio::spawn(chat(shutdown.clone()));
+        brain.started.notified().await;
+        shutdown.trigger(ShutdownReason::Terminate);
+        brain.release.notify_one();
+        in_flight.await.unwrap().unwrap();
+        assert!(chat_client.try_recv().is_err());
+    }
+
+    #[test]
+    fn sequence_reports_name_the_missed_commands() {
+        assert_eq!(missed_commands(3, Some(7)), Some(4..=6));
+        assert_eq!(missed_commands(3, Some(5)), Some(4..=4));
+        // The next number in order, a repeat, or a plain progress report show no gap.
+        assert_eq!(missed_commands(3, Some(4)), None);
+        assert_eq!(missed_commands(3, Some(2)), None);
+        assert_eq!(missed_commands(3, None), None);
+        assert_eq!(missed_commands(u64::MAX, Some(u64::MAX)), None);
+        assert_eq!(missed_commands(u64::MAX, Some(1)), None);
+        assert_eq!(
+            missed_commands(u64::MAX - 2, Some(u64::MAX)),
+            Some(u64::MAX - 1..=u64::MAX - 1)
+        );
+    }
+
+    #[test]
+    fn pathologically_nested_frames_are_refused_without_recursing() {
+        // As deep as fits in the size limit, balanced and unbalanced.
+        let depth = (MAX_EVENT_BYTES - 64) / 2;
+        let balanced = nested_telemetry(depth);
+        assert!(balanced.len() <= MAX_EVENT_BYTES);
+        assert!(matches!(
+            decode_event(balanced.as_bytes()),
+            Err(EventDecodeError::TooDeep)
+        ));
+        let unbalanced = "[".repeat(MAX_EVENT_BYTES);
+        assert!(matches!(
+            decode_event(unbalanced.as_bytes()),
+            Err(EventDecodeError::TooDeep)
+        ));
+        let oversized = nested_telemetry(MAX_EVENT_BYTES);
+        assert!(matches!(
+            decode_event(oversized.as_bytes()),
+            Err(EventDecodeError::TooLarge(_))
+        ));
+
+        // Past the depth check, serde's own recursion limit still refuses rather than
+        // overflowing the stack.
+        assert!(serde_json::from_str::<serde_json::Value>(&balanced).is_err());
+
+        // The same brackets inside a string are just text.
+        let message = "[{".repeat(depth / 2);
+        let chat = serde_json::json!({
+            "type": "chat",
+            "username": "steve",
+            "message": message,
+            "computer_id": 1,
+        })
+        .to_string();
+        assert!(chat.len() <= MAX_EVENT_BYTES);
+        let (event, _) = decode_event(chat.as_bytes()).unwrap();
+        assert!(matches!(event, ComputerEvent::Chat(chat) if chat.message == message));
+    }
+
+    #[test]
+    fn event_history_forgets_clients_that_deregister() {
+        let history = EventHistory::new(4);
+        let telemetry = || ComputerEvent::Telemetry {
+            data: Default::default(),
+        };
+        for id in [1, 2] {
+            for _ in 0..6 {
+                history.record(&ClientEvent::new(id, telemetry(), None));
+            }
+        }
+        assert_eq!(history.recent_events(1, 0).len(), 4);
+
+        history.record(&ClientEvent::new(
+            1,
+            ComputerEvent::Deregister {
+                id: 1,
+                reason: DeregisterReason::Closed,
+            },
+            None,
+        ));
+        assert!(history.recent_events(1, 0).is_empty());
+        assert!(!history.events.lock().unwrap().contains_key(&1));
+        assert_eq!(history.recent_events(2, 0).len(), 4);
+    }
+
+    #[test]
+    fn private_replies_whisper_to_the_player_who_spoke() {
+        let target = |private| ChatTarget::new(Capability::Chat, private, 5, 1024, None);
+
+        let LuaCommand::Whisper { args, .. } =
+            target(true).reply_command("Steve", "hello there".to_string())
+        else {
+            panic!("private replies are whispers");
+        };
+        assert_eq!(args.target, "Steve");
+        assert_eq!(args.message.chars().count(), 5, "still truncated");
+
+        assert!(matches!(
+            target(false).reply_command("Steve", "hi".to_string()),
+            LuaCommand::Message { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn processed_events_reach_subscribers() {
+        use crate::actions::PendingCommands;
+        use crate::actions::tests::{connect, dispatch, registry};
+        use crate::brain::BrainService;
+
+        let registry = registry();
+        let _client = connect(&registry, 1, vec![Capability::Chat]).await;
+        let shutdown = ShutdownSignal::new();
+        let brain = BrainService::new(
+            "localhost:50051".to_string(),
+            shutdown.clone(),
+            Arc::new(Metrics::default()),
+        )
+        .unwrap();
+        let mut service = ComputerEventService::builder(
+            Arc::new(brain),
+            registry.clone(),
+            dispatch(
+                &registry,
+                PendingCommands::new(16, 4, Duration::from_secs(60)),
+            ),
+            shutdown,
+        )
+        .build();
+        let mut direct = service.subscribe_events();
+        let mut later = service.event_subscriber().subscribe();
+
+        let telemetry: ComputerEvent =
+            serde_json::from_value(serde_json::json!({"type": "telemetry", "data": {"fuel": 10}}))
+                .unwrap();
+        service
+            .call(ClientEvent::new(1, telemetry, None))
+            .await
+            .unwrap();
+        for rx in [&mut direct, &mut later] {
+            let event = rx.try_recv().unwrap();
+            assert_eq!(event.client_id, 1);
+            assert!(matches!(event.event, ComputerEvent::Telemetry { .. }));
+        }
+    }
+}
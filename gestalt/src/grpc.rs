@@ -1,50 +1,384 @@
 //! gRPC server for the Gestalt API. Exposes a dispatch service to the Python "Brain" so that it can ask to send commands to computers over WebSocket.
 
-use crate::ShutdownSignal;
-use crate::actions::{ComputerAction, ComputerDispatchService};
-use crate::events::Capability;
-use crate::websocket::LuaCommand;
-use blueking::DispatchError;
-use blueking::gestalt_server::{Gestalt as GestaltApi, GestaltServer};
-use blueking::send_chat_message_response::Status as SendStatus;
-use blueking::{SendChatMessageRequest, SendChatMessageResponse};
-use std::net::SocketAddr;
+use crate::DispatchError;
+use crate::actions::{ComputerAction, ComputerDispatchService, DispatchStrategy};
+use crate::events::{Capability, ChatTarget, ClientEvent, EventHistory, EventSubscriber};
+use crate::gestalt_server::{Gestalt as GestaltApi, GestaltServer};
+use crate::metrics::EventKind;
+use crate::send_chat_message_response::Status as SendStatus;
+use crate::templates::{CommandTemplates, TemplateError, assign_ids};
+use crate::websocket::{
+    BroadcastOutcome, ClientQuery, ClientRegistry, LuaCommand, serialize_lua_command,
+};
+use crate::{
+    API_VERSION, ApiVersionResponse, ChatTargetResponse, ComputerInfo, DisconnectComputerRequest,
+    DisconnectComputerResponse, DispatchStrategyResponse, GetApiVersionRequest,
+    GetChatTargetRequest, GetRecentEventsRequest, GetRecentEventsResponse, InvokeTemplateRequest,
+    InvokeTemplateResponse, ListComputersRequest, ListComputersResponse,
+    ListPendingCommandsRequest, ListPendingCommandsResponse, LiveEvent, MaintenanceModeResponse,
+    MigrateClientsRequest, MigrateClientsResponse, PendingCommandInfo, RefreshCapabilitiesRequest,
+    RefreshCapabilitiesResponse, SendAndWaitRequest, SendAndWaitResponse, SendChatMessageRequest,
+    SendChatMessageResponse, ServerInfoRequest, ServerInfoResponse, SetCapabilityEnabledRequest,
+    SetCapabilityEnabledResponse, SetChatTargetRequest, SetClientPausedRequest,
+    SetClientPausedResponse, SetDispatchStrategyRequest, SetMaintenanceModeRequest,
+    SubscribeEventsRequest,
+};
+use crate::{Listen, ShutdownSignal};
+use futures::Stream;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tonic::transport::server::TcpIncoming;
 use tonic::{Request, Response, Status};
 use tower::ServiceExt;
 
 const GRPC_BIND: ([u8; 4], u16) = ([127, 0, 0, 1], 50052);
+/// Address the gRPC server binds, overriding `GRPC_BIND`; port 0 picks a free port.
+const ENV_BLUEKING_GRPC_ADDR: &str = "BLUEKING_GRPC_ADDR";
+/// Bearer token required by administrative RPCs. When unset, they are open to any caller.
+const ENV_BLUEKING_GRPC_TOKEN: &str = "BLUEKING_GRPC_TOKEN";
+/// Longest `SendChatMessage` payload, in characters, accepted for delivery.
+const ENV_BLUEKING_MAX_CHAT_PAYLOAD_CHARS: &str = "BLUEKING_MAX_CHAT_PAYLOAD_CHARS";
+const DEFAULT_MAX_CHAT_PAYLOAD_CHARS: usize = 1024;
+/// Comma-separated CIDR blocks allowed to call the gRPC API, e.g. `127.0.0.1/32,10.0.0.0/8`.
+/// Unset allows any peer.
+const ENV_BLUEKING_GRPC_ALLOW: &str = "BLUEKING_GRPC_ALLOW";
+/// Serve gRPC reflection so tools like `grpcurl` can list RPCs. Defaults to on in debug builds.
+const ENV_BLUEKING_GRPC_REFLECTION: &str = "BLUEKING_GRPC_REFLECTION";
+/// Seconds `MigrateClients` waits before closing clients that did not move on their own.
+const ENV_BLUEKING_MIGRATION_GRACE_SECS: &str = "BLUEKING_MIGRATION_GRACE_SECS";
+const DEFAULT_MIGRATION_GRACE_SECS: u64 = 30;
 
-/// Run the Gestalt gRPC server, wiring it to the computer dispatch service.
+/// Address the gRPC server binds: `BLUEKING_GRPC_ADDR`, else `GRPC_BIND`.
+pub fn bind_addr() -> SocketAddr {
+    crate::env_or(ENV_BLUEKING_GRPC_ADDR, SocketAddr::from(GRPC_BIND))
+}
+
+/// Run the Gestalt gRPC server on `listen`, wiring it to the computer dispatch service.
+#[allow(clippy::too_many_arguments)]
 pub async fn run_grpc(
+    registry: ClientRegistry,
     dispatch: ComputerDispatchService,
+    chat_target: ChatTarget,
+    history: EventHistory,
+    events: EventSubscriber,
+    templates: CommandTemplates,
+    started_at: Instant,
+    allowlist: Option<Arc<Vec<CidrBlock>>>,
     shutdown: ShutdownSignal,
-) -> Result<(), tonic::transport::Error> {
-    let addr = SocketAddr::from(GRPC_BIND);
+    listen: Listen,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let addr = listen.addr;
     tracing::info!("Binding gRPC server: {}", addr);
-    tonic::transport::server::Server::builder()
-        .add_service(GestaltServer::new(GestaltService::new(dispatch)))
-        .serve_with_shutdown(addr, shutdown.subscribe())
+    let listener = tokio::net::TcpListener::bind(addr)
         .await
+        .inspect_err(|err| {
+            tracing::error!("Failed to bind gRPC listener: {}", err);
+        })?;
+    let local_addr = listener.local_addr()?;
+    if local_addr != addr {
+        tracing::info!("gRPC server bound to {}", local_addr);
+    }
+    if let Some(bound) = listen.bound {
+        let _ = bound.send(local_addr);
+    }
+    let admin_token = admin_token_from_env();
+    let reflection = if crate::env_or(ENV_BLUEKING_GRPC_REFLECTION, cfg!(debug_assertions)) {
+        tracing::info!("gRPC reflection enabled");
+        Some(
+            tonic_reflection::server::Builder::configure()
+                .register_encoded_file_descriptor_set(crate::FILE_DESCRIPTOR_SET)
+                .build_v1()
+                .expect("failed to build gRPC reflection service"),
+        )
+    } else {
+        None
+    };
+    if let Some(allowlist) = &allowlist {
+        tracing::info!("gRPC restricted to peers in {:?}", allowlist);
+    }
+    #[allow(clippy::result_large_err)]
+    let peer_filter = move |request: Request<()>| match &allowlist {
+        Some(allowlist) => check_peer(allowlist, request),
+        None => Ok(request),
+    };
+    tonic::transport::server::Server::builder()
+        .layer(tonic::service::interceptor(peer_filter))
+        .add_optional_service(reflection)
+        .add_service(GestaltServer::new(GestaltService::new(
+            registry,
+            dispatch,
+            chat_target,
+            history,
+            events,
+            templates,
+            admin_token,
+            started_at,
+            shutdown.clone(),
+            crate::env_or(
+                ENV_BLUEKING_MAX_CHAT_PAYLOAD_CHARS,
+                DEFAULT_MAX_CHAT_PAYLOAD_CHARS,
+            ),
+        )))
+        // Same socket options as `serve_with_shutdown`.
+        .serve_with_incoming_shutdown(
+            TcpIncoming::from_listener(listener, true, None)?,
+            shutdown.subscribe(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Encode a processed event for `SubscribeEvents`.
+#[allow(clippy::result_large_err)]
+fn live_event(event: &ClientEvent) -> Result<LiveEvent, Status> {
+    Ok(LiveEvent {
+        client_id: event.client_id,
+        r#type: event.event.kind().label().to_string(),
+        event_json: serde_json::to_string(&event.event)
+            .map_err(|e| Status::internal(e.to_string()))?,
+        received_at_ms: event.received_at_ms,
+        client_ts: event.client_ts,
+    })
+}
+
+/// Map a dispatch failure to a gRPC status, for RPCs that report errors as statuses.
+fn dispatch_status(err: DispatchError) -> Status {
+    match err {
+        DispatchError::NoClient | DispatchError::SendFailed(_) | DispatchError::ClientGone(_) => {
+            Status::unavailable(err.to_string())
+        }
+        DispatchError::InvalidCommand(_) => Status::invalid_argument(err.to_string()),
+        DispatchError::Overloaded(_)
+        | DispatchError::RateLimited(_)
+        | DispatchError::TooManyPending(_) => Status::resource_exhausted(err.to_string()),
+        DispatchError::Disabled(_) | DispatchError::ClientPaused(_) => {
+            Status::failed_precondition(err.to_string())
+        }
+        DispatchError::TaskPanicked(_) => Status::internal(err.to_string()),
+    }
+}
+
+/// Map a dispatch outcome to the status reported by the send RPCs.
+#[allow(clippy::result_large_err)]
+fn send_status(
+    result: Result<Vec<i32>, DispatchError>,
+) -> Result<(SendStatus, String, Vec<i32>), Status> {
+    let err = match result {
+        Ok(recipients) => return Ok((SendStatus::Ok, String::new(), recipients)),
+        Err(err) => err,
+    };
+    let (status, message) = match err {
+        // No catch-all: a new `DispatchError` variant must be given a status here.
+        DispatchError::NoClient => (
+            SendStatus::NoChatClient,
+            "no chat clients connected".to_string(),
+        ),
+        DispatchError::SendFailed(err) => (SendStatus::SendFailed, err),
+        err @ DispatchError::ClientGone(_) => (SendStatus::SendFailed, err.to_string()),
+        err @ DispatchError::InvalidCommand(_) => (SendStatus::InvalidCommand, err.to_string()),
+        err @ DispatchError::Overloaded(_) => (SendStatus::Overloaded, err.to_string()),
+        err @ DispatchError::RateLimited(_) => (SendStatus::RateLimited, err.to_string()),
+        err @ DispatchError::Disabled(_) => (SendStatus::Disabled, err.to_string()),
+        err @ DispatchError::TooManyPending(_) => (SendStatus::TooManyPending, err.to_string()),
+        err @ DispatchError::ClientPaused(_) => (SendStatus::ClientPaused, err.to_string()),
+        err @ DispatchError::TaskPanicked(_) => {
+            return Err(Status::internal(err.to_string()));
+        }
+    };
+    Ok((status, message, Vec::new()))
+}
+
+/// Admin bearer token, shared by the gRPC admin RPCs and the `/monitor` WebSocket feed.
+pub fn admin_token_from_env() -> Option<String> {
+    crate::config::var(ENV_BLUEKING_GRPC_TOKEN).filter(|t| !t.is_empty())
+}
+
+/// Parse `BLUEKING_GRPC_ALLOW`; `None` when unset or empty, an error on any malformed entry.
+pub fn allowlist_from_env() -> Result<Option<Arc<Vec<CidrBlock>>>, String> {
+    let Some(raw) = crate::config::var(ENV_BLUEKING_GRPC_ALLOW) else {
+        return Ok(None);
+    };
+    let blocks = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<CidrBlock>, _>>()
+        .map_err(|e| format!("{ENV_BLUEKING_GRPC_ALLOW}: {e}"))?;
+    Ok((!blocks.is_empty()).then(|| Arc::new(blocks)))
+}
+
+/// Reject requests whose peer address is unknown or outside every allowed block.
+#[allow(clippy::result_large_err)]
+fn check_peer(allowlist: &[CidrBlock], request: Request<()>) -> Result<Request<()>, Status> {
+    match request.remote_addr() {
+        Some(peer) if allowlist.iter().any(|b| b.contains(peer.ip())) => Ok(request),
+        peer => {
+            tracing::warn!("Rejected gRPC request from {:?}: not in allowlist", peer);
+            Err(Status::permission_denied("peer not allowed"))
+        }
+    }
+}
+
+/// An IP network such as `10.0.0.0/8`; a bare address is a single-host block.
+#[derive(Debug, Clone, Copy)]
+pub struct CidrBlock {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl CidrBlock {
+    /// Whether `ip` falls in this block. IPv4-mapped IPv6 peers match IPv4 blocks.
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for CidrBlock {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in {s:?}"))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in {s:?}"))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
 }
 
 /// Tonic service implementation for the generated `Gestalt` gRPC API.
 pub struct GestaltService {
+    registry: ClientRegistry,
     dispatch: ComputerDispatchService,
+    chat_target: ChatTarget,
+    history: EventHistory,
+    /// Processed client events, streamed to `SubscribeEvents` callers.
+    events: EventSubscriber,
+    templates: CommandTemplates,
+    admin_token: Option<String>,
+    /// When the process started, for uptime reporting.
+    started_at: Instant,
+    /// Ends event streams so they don't hold up graceful shutdown.
+    shutdown: ShutdownSignal,
+    max_payload_chars: usize,
 }
 
 impl GestaltService {
-    pub fn new(dispatch: ComputerDispatchService) -> Self {
-        Self { dispatch }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        registry: ClientRegistry,
+        dispatch: ComputerDispatchService,
+        chat_target: ChatTarget,
+        history: EventHistory,
+        events: EventSubscriber,
+        templates: CommandTemplates,
+        admin_token: Option<String>,
+        started_at: Instant,
+        shutdown: ShutdownSignal,
+        max_payload_chars: usize,
+    ) -> Self {
+        Self {
+            registry,
+            dispatch,
+            chat_target,
+            history,
+            events,
+            templates,
+            admin_token,
+            started_at,
+            shutdown,
+            max_payload_chars,
+        }
+    }
+
+    /// Reject chat payloads that would show up blank in-game or exceed the configured length.
+    fn validate_payload(&self, payload: &str) -> Result<(), String> {
+        if payload.trim().is_empty() {
+            return Err("payload is empty".to_string());
+        }
+        let chars = payload.chars().count();
+        if chars > self.max_payload_chars {
+            return Err(format!(
+                "payload is {chars} characters, limit is {}",
+                self.max_payload_chars
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the `authorization: Bearer <token>` metadata against the configured admin token.
+    #[allow(clippy::result_large_err)]
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(expected) = self.admin_token.as_deref() else {
+            return Ok(());
+        };
+        let provided = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match provided {
+            Some(token) if token == expected => Ok(()),
+            Some(_) => Err(Status::permission_denied("invalid token")),
+            None => Err(Status::unauthenticated("missing bearer token")),
+        }
     }
 }
 
 #[tonic::async_trait]
 impl GestaltApi for GestaltService {
+    async fn get_api_version(
+        &self,
+        _request: Request<GetApiVersionRequest>,
+    ) -> Result<Response<ApiVersionResponse>, Status> {
+        Ok(Response::new(ApiVersionResponse {
+            api_version: API_VERSION,
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+        }))
+    }
+
     async fn send_chat_message(
         &self,
         request: Request<SendChatMessageRequest>,
     ) -> Result<Response<SendChatMessageResponse>, Status> {
         let payload = request.into_inner().payload;
+        if let Err(error_message) = self.validate_payload(&payload) {
+            return Ok(Response::new(SendChatMessageResponse {
+                status: SendStatus::InvalidPayload as i32,
+                error_message,
+                ..Default::default()
+            }));
+        }
         let cmd = LuaCommand::chat_message(payload);
         // Delegate to the internal Tower service that knows how to talk to
         // WebSocket clients via the registry.
@@ -52,23 +386,448 @@ impl GestaltApi for GestaltService {
             .dispatch
             .clone()
             .oneshot(ComputerAction::SendToCapability {
-                capability: Capability::Chat,
+                capability: self.chat_target.get().await,
                 command: cmd,
             })
             .await;
 
-        let (status, error_message) = match send_res {
-            Ok(()) => (SendStatus::Ok, String::new()),
-            Err(DispatchError::NoClient) => (
-                SendStatus::NoChatClient,
-                "no chat clients connected".to_string(),
-            ),
-            Err(DispatchError::SendFailed(err)) => (SendStatus::SendFailed, err),
-        };
+        let (status, error_message, delivered_to) = send_status(send_res)?;
 
         Ok(Response::new(SendChatMessageResponse {
             status: status as i32,
             error_message,
+            delivered_to,
+        }))
+    }
+
+    async fn invoke_template(
+        &self,
+        request: Request<InvokeTemplateRequest>,
+    ) -> Result<Response<InvokeTemplateResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        let (capability, command) = self
+            .templates
+            .instantiate(&request.name, &request.params)
+            .map_err(|err| match err {
+                TemplateError::NotFound(_) => Status::not_found(err.to_string()),
+                TemplateError::Invalid(_) => Status::invalid_argument(err.to_string()),
+            })?;
+        let command_id = command.id().to_string();
+        let send_res = self
+            .dispatch
+            .clone()
+            .oneshot(ComputerAction::SendToCapability {
+                capability,
+                command,
+            })
+            .await;
+        let (status, error_message, delivered_to) = send_status(send_res)?;
+
+        Ok(Response::new(InvokeTemplateResponse {
+            status: status as i32,
+            error_message,
+            command_id,
+            delivered_to,
+        }))
+    }
+
+    async fn server_info(
+        &self,
+        _request: Request<ServerInfoRequest>,
+    ) -> Result<Response<ServerInfoResponse>, Status> {
+        let uptime = self.started_at.elapsed();
+        let started_at_unix_ms = SystemTime::now()
+            .checked_sub(uptime)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_millis() as u64);
+        Ok(Response::new(ServerInfoResponse {
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            api_version: API_VERSION,
+            uptime_secs: uptime.as_secs(),
+            started_at_unix_ms,
+            build_profile: if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            }
+            .to_string(),
+            capabilities: Capability::KNOWN
+                .iter()
+                .map(|c| c.as_str().to_string())
+                .collect(),
+            command_types: LuaCommand::NAMES.iter().map(|n| n.to_string()).collect(),
+        }))
+    }
+
+    async fn get_chat_target(
+        &self,
+        request: Request<GetChatTargetRequest>,
+    ) -> Result<Response<ChatTargetResponse>, Status> {
+        self.authorize(&request)?;
+        Ok(Response::new(ChatTargetResponse {
+            capability: self.chat_target.get().await.as_str().to_string(),
+        }))
+    }
+
+    async fn set_chat_target(
+        &self,
+        request: Request<SetChatTargetRequest>,
+    ) -> Result<Response<ChatTargetResponse>, Status> {
+        self.authorize(&request)?;
+        let capability: Capability = request
+            .into_inner()
+            .capability
+            .parse()
+            .map_err(Status::invalid_argument)?;
+        tracing::info!("Chat replies now routed to capability {:?}", capability);
+        self.chat_target.set(capability.clone()).await;
+        Ok(Response::new(ChatTargetResponse {
+            capability: capability.as_str().to_string(),
+        }))
+    }
+
+    async fn get_recent_events(
+        &self,
+        request: Request<GetRecentEventsRequest>,
+    ) -> Result<Response<GetRecentEventsResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        let events = self
+            .history
+            .recent_events(request.client_id, request.limit as usize)
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(GetRecentEventsResponse { events }))
+    }
+
+    type SubscribeEventsStream = Pin<Box<dyn Stream<Item = Result<LiveEvent, Status>> + Send>>;
+
+    async fn subscribe_events(
+        &self,
+        request: Request<SubscribeEventsRequest>,
+    ) -> Result<Response<Self::SubscribeEventsStream>, Status> {
+        self.authorize(&request)?;
+        let mut kinds = HashSet::new();
+        for name in request.into_inner().types {
+            let Some(kind) = EventKind::ALL.into_iter().find(|kind| kind.label() == name) else {
+                return Err(Status::invalid_argument(format!(
+                    "unknown event type {name:?}"
+                )));
+            };
+            kinds.insert(kind);
+        }
+        let state = (
+            self.events.subscribe(),
+            Box::pin(self.shutdown.subscribe()),
+            kinds,
+        );
+        let stream = futures::stream::unfold(state, |(mut rx, mut shutdown, kinds)| async move {
+            loop {
+                let received = tokio::select! {
+                    received = rx.recv() => received,
+                    _ = &mut shutdown => return None,
+                };
+                match received {
+                    Ok(event) if kinds.is_empty() || kinds.contains(&event.event.kind()) => {
+                        let item = live_event(&event);
+                        return Some((item, (rx, shutdown, kinds)));
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Event subscriber fell behind, skipped {} events", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn list_computers(
+        &self,
+        request: Request<ListComputersRequest>,
+    ) -> Result<Response<ListComputersResponse>, Status> {
+        self.authorize(&request)?;
+        let pending = self.dispatch.pending().per_client().await;
+        let computers = self
+            .registry
+            .list()
+            .await
+            .into_iter()
+            .map(|client| ComputerInfo {
+                id: client.id,
+                label: client.label,
+                capabilities: client.capabilities.into_iter().map(String::from).collect(),
+                protocol_version: client.protocol_version,
+                pending_commands: pending.get(&client.id).copied().unwrap_or(0) as u32,
+                paused: client.paused,
+            })
+            .collect();
+        Ok(Response::new(ListComputersResponse { computers }))
+    }
+
+    async fn list_pending_commands(
+        &self,
+        request: Request<ListPendingCommandsRequest>,
+    ) -> Result<Response<ListPendingCommandsResponse>, Status> {
+        self.authorize(&request)?;
+        let client_id = request.into_inner().client_id;
+        let commands = self
+            .dispatch
+            .pending()
+            .snapshot()
+            .await
+            .into_iter()
+            .filter(|command| client_id.is_none() || command.client == client_id)
+            .map(|command| PendingCommandInfo {
+                command_id: command.command_id,
+                client_id: command.client,
+                age_ms: command.age.as_millis() as u64,
+                state: command.state.as_str().to_string(),
+                awaited: command.awaited,
+            })
+            .collect();
+        Ok(Response::new(ListPendingCommandsResponse { commands }))
+    }
+
+    async fn set_capability_enabled(
+        &self,
+        request: Request<SetCapabilityEnabledRequest>,
+    ) -> Result<Response<SetCapabilityEnabledResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        let capability: Capability = request
+            .capability
+            .parse()
+            .map_err(Status::invalid_argument)?;
+        let disabled = self.dispatch.disabled();
+        if disabled.set_enabled(capability.clone(), request.enabled) {
+            let state = if request.enabled {
+                "enabled"
+            } else {
+                "disabled"
+            };
+            tracing::warn!("Capability {:?} {} by operator", capability, state);
+        }
+        Ok(Response::new(SetCapabilityEnabledResponse {
+            disabled_capabilities: disabled.list().into_iter().map(String::from).collect(),
+        }))
+    }
+
+    async fn set_maintenance_mode(
+        &self,
+        request: Request<SetMaintenanceModeRequest>,
+    ) -> Result<Response<MaintenanceModeResponse>, Status> {
+        self.authorize(&request)?;
+        let enabled = request.into_inner().enabled;
+        if self.registry.set_maintenance(enabled) != enabled {
+            let state = if enabled { "on" } else { "off" };
+            tracing::warn!(
+                "Maintenance mode {}: new registrations refused while on",
+                state
+            );
+        }
+        Ok(Response::new(MaintenanceModeResponse { enabled }))
+    }
+
+    async fn set_client_paused(
+        &self,
+        request: Request<SetClientPausedRequest>,
+    ) -> Result<Response<SetClientPausedResponse>, Status> {
+        self.authorize(&request)?;
+        let SetClientPausedRequest { client_id, paused } = request.into_inner();
+        let previous = self
+            .registry
+            .set_paused(client_id, paused)
+            .await
+            .map_err(Status::not_found)?;
+        if previous != paused {
+            let state = if paused { "paused" } else { "resumed" };
+            tracing::warn!("Dispatch to client {} {} by operator", client_id, state);
+        }
+        Ok(Response::new(SetClientPausedResponse { paused }))
+    }
+
+    async fn migrate_clients(
+        &self,
+        request: Request<MigrateClientsRequest>,
+    ) -> Result<Response<MigrateClientsResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        if !(request.url.starts_with("ws://") || request.url.starts_with("wss://")) {
+            return Err(Status::invalid_argument(
+                "url must be a ws:// or wss:// address",
+            ));
+        }
+        let grace = match request.grace_secs {
+            0 => crate::env_or(
+                ENV_BLUEKING_MIGRATION_GRACE_SECS,
+                DEFAULT_MIGRATION_GRACE_SECS,
+            ),
+            secs => u64::from(secs),
+        };
+        tracing::warn!(
+            "Migrating clients to {}; draining, remaining clients close in {}s",
+            request.url,
+            grace
+        );
+        let outcomes = self
+            .registry
+            .migrate(
+                request.url,
+                std::time::Duration::from_secs(grace),
+                &self.shutdown,
+            )
+            .await;
+        let (notified, failed): (Vec<_>, Vec<_>) = outcomes
+            .iter()
+            .partition(|(_, outcome)| matches!(outcome, BroadcastOutcome::Delivered));
+        Ok(Response::new(MigrateClientsResponse {
+            notified: notified.into_iter().map(|(id, _)| *id).collect(),
+            failed: failed.into_iter().map(|(id, _)| *id).collect(),
+        }))
+    }
+
+    async fn disconnect_computer(
+        &self,
+        request: Request<DisconnectComputerRequest>,
+    ) -> Result<Response<DisconnectComputerResponse>, Status> {
+        self.authorize(&request)?;
+        let DisconnectComputerRequest { client_id, reason } = request.into_inner();
+        // Close frame reasons are limited to 123 bytes.
+        const MAX_REASON_BYTES: usize = 123;
+        let mut reason = match reason.trim() {
+            "" => "disconnected by operator".to_string(),
+            reason => reason.to_string(),
+        };
+        while reason.len() > MAX_REASON_BYTES {
+            reason.pop();
+        }
+        let disconnected = self.registry.disconnect(client_id, reason).await;
+        if disconnected {
+            tracing::warn!("Client {} disconnected by operator", client_id);
+        }
+        Ok(Response::new(DisconnectComputerResponse { disconnected }))
+    }
+
+    async fn send_and_wait(
+        &self,
+        request: Request<SendAndWaitRequest>,
+    ) -> Result<Response<SendAndWaitResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        let capability = match request.capability.as_str() {
+            "" if !request.query_json.is_empty() || !request.label.is_empty() => None,
+            name => Some(
+                name.parse::<Capability>()
+                    .map_err(Status::invalid_argument)?,
+            ),
+        };
+        let mut command: serde_json::Value = serde_json::from_str(&request.command_json)
+            .map_err(|e| Status::invalid_argument(format!("invalid command JSON: {e}")))?;
+        assign_ids(&mut command);
+        let command: LuaCommand = serde_json::from_value(command)
+            .map_err(|e| Status::invalid_argument(format!("invalid command: {e}")))?;
+        let command_id = command.id().to_string();
+        let action = match (capability, request.query_json.as_str()) {
+            (None, "") => ComputerAction::SendToLabel {
+                label: request.label,
+                command,
+            },
+            _ if !request.label.is_empty() => {
+                return Err(Status::invalid_argument(
+                    "label cannot be combined with capability or query_json",
+                ));
+            }
+            (Some(capability), "") => ComputerAction::SendToCapability {
+                capability,
+                command,
+            },
+            (capability, query) => {
+                let mut predicate: ClientQuery = serde_json::from_str(query)
+                    .map_err(|e| Status::invalid_argument(format!("invalid query JSON: {e}")))?;
+                if capability.is_some() {
+                    predicate.capability = capability;
+                }
+                ComputerAction::SendToQuery { predicate, command }
+            }
+        };
+        let pending = self.dispatch.pending();
+        let timeout = match request.timeout_ms {
+            0 => pending.timeout(),
+            ms => std::time::Duration::from_millis(ms.into()).min(pending.timeout()),
+        };
+        let waiter = pending.wait_for(&command_id);
+        let delivered_to = self
+            .dispatch
+            .clone()
+            .oneshot(action)
+            .await
+            .map_err(dispatch_status)?;
+        if delivered_to.is_empty() {
+            return Err(Status::unavailable(
+                "command was not delivered to any client",
+            ));
+        }
+        let Ok(Some(result)) = tokio::time::timeout(timeout, waiter.result()).await else {
+            return Err(Status::deadline_exceeded(format!(
+                "no result for command {command_id} within {timeout:?}"
+            )));
+        };
+        Ok(Response::new(SendAndWaitResponse {
+            command_id,
+            delivered_to,
+            success: result.error.is_none(),
+            error: result.error.unwrap_or_default(),
+        }))
+    }
+
+    async fn refresh_capabilities(
+        &self,
+        request: Request<RefreshCapabilitiesRequest>,
+    ) -> Result<Response<RefreshCapabilitiesResponse>, Status> {
+        self.authorize(&request)?;
+        let id = request.into_inner().client_id;
+        if !self.registry.is_connected(id).await {
+            return Err(Status::not_found(format!("client {id} is not registered")));
+        }
+        let text = serialize_lua_command(&LuaCommand::query_capabilities())
+            .map_err(|e| Status::internal(e.to_string()))?;
+        self.dispatch
+            .clone()
+            .oneshot(ComputerAction::SendToId {
+                id,
+                message: axum::extract::ws::Message::Text(text),
+            })
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        Ok(Response::new(RefreshCapabilitiesResponse {}))
+    }
+
+    async fn set_dispatch_strategy(
+        &self,
+        request: Request<SetDispatchStrategyRequest>,
+    ) -> Result<Response<DispatchStrategyResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        let capability: Capability = request
+            .capability
+            .parse()
+            .map_err(Status::invalid_argument)?;
+        let strategy: DispatchStrategy =
+            request.strategy.parse().map_err(Status::invalid_argument)?;
+        tracing::info!(
+            "Capability {:?} now dispatched with strategy {}",
+            capability,
+            strategy.as_str()
+        );
+        self.dispatch.strategies().set(capability.clone(), strategy);
+        Ok(Response::new(DispatchStrategyResponse {
+            capability: capability.as_str().to_string(),
+            strategy: strategy.as_str().to_string(),
         }))
     }
 }
@@ -0,0 +1,116 @@
+//! `instrument` module holds a Tower layer that times and logs every event through a service.
+
+use crate::events::ClientEvent;
+use crate::metrics::{EventKind, Metrics};
+use pin_project_lite::pin_project;
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tower::{Layer, Service};
+
+/// Wraps an event service so each call records its latency and outcome by `EventKind`.
+#[derive(Clone)]
+pub struct EventMetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl EventMetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for EventMetricsLayer {
+    type Service = EventMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        EventMetrics {
+            inner,
+            metrics: Arc::clone(&self.metrics),
+        }
+    }
+}
+
+/// Service produced by `EventMetricsLayer`.
+#[derive(Clone)]
+pub struct EventMetrics<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Service<ClientEvent> for EventMetrics<S>
+where
+    S: Service<ClientEvent, Response = ()>,
+    S::Error: Display,
+{
+    type Response = ();
+    type Error = S::Error;
+    type Future = InstrumentedFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, event: ClientEvent) -> Self::Future {
+        let kind = event.event.kind();
+        let client_id = event.client_id;
+        InstrumentedFuture {
+            inner: self.inner.call(event),
+            metrics: Arc::clone(&self.metrics),
+            kind,
+            client_id,
+            started: Instant::now(),
+        }
+    }
+}
+
+pin_project! {
+    /// Records latency and outcome once the wrapped call resolves.
+    pub struct InstrumentedFuture<F> {
+        #[pin]
+        inner: F,
+        metrics: Arc<Metrics>,
+        kind: EventKind,
+        client_id: i32,
+        started: Instant,
+    }
+}
+
+impl<F, E> Future for InstrumentedFuture<F>
+where
+    F: Future<Output = Result<(), E>>,
+    E: Display,
+{
+    type Output = Result<(), E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = std::task::ready!(this.inner.poll(cx));
+        let elapsed = this.started.elapsed();
+        this.metrics.events.record(*this.kind, elapsed);
+        match &result {
+            Ok(()) => tracing::debug!(
+                event = this.kind.label(),
+                client_id = *this.client_id,
+                elapsed_ms = elapsed.as_millis() as u64,
+                outcome = "ok",
+                "Event processed"
+            ),
+            Err(err) => {
+                this.metrics.events.record_failure(*this.kind);
+                tracing::debug!(
+                    event = this.kind.label(),
+                    client_id = *this.client_id,
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    outcome = "error",
+                    error = %err,
+                    "Event processed"
+                );
+            }
+        }
+        Poll::Ready(result)
+    }
+}
@@ -1,10 +1,72 @@
+pub mod actions;
+pub mod audit;
+pub mod brain;
+pub mod config;
+pub mod events;
+pub mod grpc;
+pub mod instrument;
+pub mod metrics;
+pub mod reload;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod store;
+pub mod supervisor;
+pub mod templates;
+pub mod websocket;
+
+use crate::actions::ComputerDispatchService;
+use crate::audit::AuditLog;
+use crate::brain::{Brain, BrainRouter};
+use crate::events::{Capability, ChatTarget, ComputerEventService, EventHistory};
+use crate::metrics::Metrics;
+use crate::supervisor::{Restart, Supervisor};
+use crate::websocket::{ClientRegistry, RegistryConfig};
+use futures::{FutureExt, TryFutureExt};
 use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, oneshot};
+
+/// Debug logging level environment variable.
+/// For debug builds, this is enabled by default.
+/// Setting to `0` or `false` disables debug logging. `trace` enables trace-level logging.
+const ENV_BLUEKING_DEBUG: &str = "BLUEKING_DEBUG";
+/// tracing crate configuration.
+const ENV_BLUEKING_LOG: &str = "BLUEKIND_LOG";
+/// Seconds shutdown may take: clients are told this in the shutdown notice and closed once it
+/// passes, with inbound events still handled in between.
+const ENV_BLUEKING_SHUTDOWN_TIMEOUT_SECS: &str = "BLUEKING_SHUTDOWN_TIMEOUT_SECS";
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+/// How long past the shutdown timeout the watchdog lets draining run before giving up.
+const SHUTDOWN_WATCHDOG_SLACK: Duration = Duration::from_secs(5);
+/// Version of the gRPC API shape, reported by `GetApiVersion`.
+///
+/// Protobuf decoding already skips unknown fields, so adding fields or RPCs keeps older
+/// clients working; bump this only for changes they cannot tolerate.
+pub const API_VERSION: u32 = 1;
 
 /// Shared error type for dispatching actions to clients.
 #[derive(Debug)]
 pub enum DispatchError {
     SendFailed(String),
     NoClient,
+    InvalidCommand(String),
+    /// Shed because the capability already has its maximum number of commands in flight.
+    Overloaded(String),
+    /// The target client exceeded its outbound command rate.
+    RateLimited(String),
+    /// The capability was switched off by an operator.
+    Disabled(String),
+    /// The target client already has its maximum number of commands awaiting a result.
+    TooManyPending(i32),
+    /// Dispatch to the target client was paused by an operator.
+    ClientPaused(i32),
+    /// The dispatch task panicked or was cancelled before finishing.
+    TaskPanicked(String),
+    /// The target client disconnected or was replaced while the send was in flight.
+    ClientGone(i32),
 }
 
 impl fmt::Display for DispatchError {
@@ -12,10 +74,400 @@ impl fmt::Display for DispatchError {
         match self {
             DispatchError::SendFailed(e) => write!(f, "send failed: {e}"),
             DispatchError::NoClient => write!(f, "no client available"),
+            DispatchError::InvalidCommand(e) => write!(f, "invalid command: {e}"),
+            DispatchError::Overloaded(cap) => write!(f, "too many commands in flight to {cap}"),
+            DispatchError::RateLimited(target) => write!(f, "rate limit exceeded for {target}"),
+            DispatchError::Disabled(cap) => write!(f, "capability {cap} is disabled"),
+            DispatchError::TooManyPending(id) => {
+                write!(f, "client {id} has too many commands awaiting a result")
+            }
+            DispatchError::ClientPaused(id) => write!(f, "dispatch to client {id} is paused"),
+            DispatchError::TaskPanicked(e) => write!(f, "dispatch task failed: {e}"),
+            DispatchError::ClientGone(id) => write!(f, "client {id} disconnected mid-send"),
         }
     }
 }
 
 impl std::error::Error for DispatchError {}
 
-tonic::include_proto!("blueking");
+/// Run the server with the brain, listeners and reloading configured from the environment.
+pub async fn start(
+    config: Option<(PathBuf, config::Config)>,
+    log: LogHandle,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let shutdown = ShutdownSignal::new();
+    let mut supervisor = Supervisor::new(shutdown.clone());
+    let watcher = shutdown.clone();
+    supervisor.spawn("shutdown-watcher", Restart::OnPanic, move || {
+        let watcher = watcher.clone();
+        async move { watcher.trigger(shutdown_signal_once().await) }
+    });
+
+    let metrics = Arc::new(Metrics::default());
+    let brain = Arc::new(BrainRouter::from_env(shutdown.clone(), metrics.clone())?);
+    let idle_brain = Arc::clone(&brain);
+    supervisor.spawn("brain-idle-sweeper", Restart::OnPanic, move || {
+        idle_brain.idle_sweeper()
+    });
+    let options = ServeOptions {
+        websocket: Listen::new(websocket::bind_addr()),
+        grpc: Listen::new(grpc::bind_addr()),
+        reload: Some((config, log)),
+        shutdown_timeout: Duration::from_secs(env_or(
+            ENV_BLUEKING_SHUTDOWN_TIMEOUT_SECS,
+            DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+        )),
+    };
+    serve(brain, metrics, shutdown, supervisor, options).await
+}
+
+/// Where a server listens, and who to tell the address it actually bound.
+pub struct Listen {
+    /// Address to bind; port 0 picks a free port.
+    pub addr: SocketAddr,
+    /// Receives the address actually bound, e.g. the port picked for port 0.
+    pub bound: Option<oneshot::Sender<SocketAddr>>,
+}
+
+impl Listen {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr, bound: None }
+    }
+
+    /// Listen on a free loopback port, returning where to learn which one was picked.
+    pub fn ephemeral() -> (Self, oneshot::Receiver<SocketAddr>) {
+        let (tx, rx) = oneshot::channel();
+        let listen = Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            bound: Some(tx),
+        };
+        (listen, rx)
+    }
+}
+
+/// What `serve` listens on and whether it reloads settings.
+pub struct ServeOptions {
+    pub websocket: Listen,
+    pub grpc: Listen,
+    /// Config file and log handle to reload on SIGHUP; `None` disables reloading.
+    pub reload: Option<(Option<(PathBuf, config::Config)>, LogHandle)>,
+    /// Grace window announced to clients on shutdown; the watchdog allows slightly more.
+    pub shutdown_timeout: Duration,
+}
+
+/// Run the WebSocket and gRPC servers in front of `brain` until `shutdown` fires.
+///
+/// Background tasks are added to `supervisor`, which is run alongside the servers.
+pub async fn serve<B: Brain>(
+    brain: Arc<B>,
+    metrics: Arc<Metrics>,
+    shutdown: ShutdownSignal,
+    mut supervisor: Supervisor,
+    options: ServeOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let started_at = std::time::Instant::now();
+    let grpc_allowlist = grpc::allowlist_from_env()?;
+    let templates = templates::CommandTemplates::from_env()?;
+    let registry = ClientRegistry::new(RegistryConfig::from_env(), store::from_env().await?);
+    let sweeper = (registry.clone(), shutdown.clone());
+    supervisor.spawn("tombstone-sweeper", Restart::OnPanic, move || {
+        sweeper.0.tombstone_sweeper(sweeper.1.clone())
+    });
+    #[cfg(unix)]
+    {
+        let dumper = (registry.clone(), shutdown.clone());
+        supervisor.spawn("snapshot-dumper", Restart::OnPanic, move || {
+            dumper.0.snapshot_dumper(dumper.1.clone())
+        });
+    }
+    let (audit, audit_writer) = AuditLog::from_env().await?;
+    let dispatch = ComputerDispatchService::from_env(registry.clone(), audit.clone());
+    #[cfg(unix)]
+    if let Some((config, log)) = options.reload {
+        let reloader = Arc::new(reload::Reloader::new(
+            config,
+            log,
+            dispatch.rate_limits().clone(),
+            dispatch.disabled().clone(),
+        ));
+        let shutdown = shutdown.clone();
+        supervisor.spawn("config-reloader", Restart::OnPanic, move || {
+            reloader.clone().run(shutdown.clone())
+        });
+    }
+    #[cfg(not(unix))]
+    let _ = options.reload;
+    let chat_target = ChatTarget::from_env(Capability::Chat);
+    let history = EventHistory::from_env();
+    let control =
+        ComputerEventService::builder(brain, registry.clone(), dispatch.clone(), shutdown.clone())
+            .chat_target(chat_target.clone())
+            .audit(audit)
+            .history(history.clone())
+            .metrics(metrics.clone())
+            .build();
+
+    let replayer = control.clone();
+    let events = control.event_subscriber();
+    supervisor.spawn("chat-replayer", Restart::OnPanic, move || {
+        replayer.chat_replayer()
+    });
+
+    let ws = websocket::run_websocket(
+        registry.clone(),
+        control,
+        metrics,
+        shutdown.clone(),
+        options.shutdown_timeout,
+        options.websocket,
+    )
+    .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { Box::new(e) });
+    let grpc = grpc::run_grpc(
+        registry,
+        dispatch,
+        chat_target,
+        history,
+        events,
+        templates,
+        started_at,
+        grpc_allowlist,
+        shutdown.clone(),
+        options.grpc,
+    );
+
+    let supervised = supervisor.run().map(Ok);
+
+    // Fail rather than hang when draining outlives the window clients were promised.
+    let watchdog = {
+        let requested = shutdown.subscribe();
+        let limit = options.shutdown_timeout + SHUTDOWN_WATCHDOG_SLACK;
+        async move {
+            requested.await;
+            tokio::time::sleep(limit).await;
+            limit
+        }
+    };
+    let served = tokio::select! {
+        served = async { futures::try_join!(ws, grpc, supervised).map(|_| ()) } => served,
+        limit = watchdog => {
+            tracing::error!("Shutdown did not finish within {:?}", limit);
+            Err(format!("shutdown did not finish within {limit:?}").into())
+        }
+    };
+    // The writer ends once the last audit handle is dropped with the servers above.
+    if let Some(writer) = audit_writer
+        && tokio::time::timeout(SHUTDOWN_WATCHDOG_SLACK, writer)
+            .await
+            .is_err()
+    {
+        tracing::warn!("Audit log still held open after shutdown; exiting without it");
+    }
+    served
+}
+
+/// Parse the variable `name`, or its config file setting when unset, falling back to `default`
+/// when neither is set or the value is invalid.
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    parse_or(name, config::var(name), default)
+}
+
+/// Parse `value` of the setting `name`, falling back to `default` when it is missing or invalid.
+pub(crate) fn parse_or<T: std::str::FromStr>(name: &str, value: Option<String>, default: T) -> T {
+    match value {
+        Some(value) => value.trim().parse().unwrap_or_else(|_| {
+            tracing::warn!("Invalid value for {}: {}", name, value);
+            default
+        }),
+        None => default,
+    }
+}
+
+/// Swaps the active log filter when settings are reloaded.
+pub type LogHandle =
+    tracing_subscriber::reload::Handle<tracing_subscriber::EnvFilter, tracing_subscriber::Registry>;
+
+/// Install the global tracing subscriber, returning a handle to swap its filter.
+#[inline(always)]
+pub fn init_tracing() -> LogHandle {
+    use tracing_subscriber::prelude::*;
+
+    let debug = cfg!(debug_assertions);
+    let filter = log_filter(
+        crate::config::var(ENV_BLUEKING_DEBUG),
+        crate::config::var(ENV_BLUEKING_LOG),
+    );
+    let (filter, handle) = tracing_subscriber::reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_line_number(debug)
+                .with_file(debug)
+                .with_thread_names(debug)
+                .compact(),
+        )
+        .init();
+    handle
+}
+
+/// Build the log filter from the `BLUEKING_DEBUG` level and `BLUEKIND_LOG` directives.
+fn log_filter(debug: Option<String>, directives: Option<String>) -> tracing_subscriber::EnvFilter {
+    use tracing::Level;
+    #[cfg(debug_assertions)]
+    let default_level = Level::DEBUG;
+    #[cfg(not(debug_assertions))]
+    let default_level = Level::INFO;
+    let trace_level = if let Some(mut value) = debug {
+        value = value.trim().chars().take(5).collect();
+        if value.is_empty() {
+            default_level
+        } else if value == "0" || value.eq_ignore_ascii_case("false") {
+            Level::INFO
+        } else if value.eq_ignore_ascii_case("true")
+            || value == "1"
+            || value.eq_ignore_ascii_case("debug")
+        {
+            Level::DEBUG
+        } else if value.eq_ignore_ascii_case("trace") || value.parse::<u8>().unwrap_or(0) > 1 {
+            Level::TRACE
+        } else {
+            eprintln!("Invalid value for {}: {}", ENV_BLUEKING_DEBUG, value);
+            default_level
+        }
+    } else {
+        default_level
+    };
+
+    const ENVFILTER_ERROR_MSG: &str = "EnvFilter configuration failed";
+    tracing_subscriber::EnvFilter::builder()
+        .with_default_directive(tracing::level_filters::LevelFilter::from_level(trace_level).into())
+        .parse_lossy(directives.unwrap_or_default())
+        .add_directive("hyper=info".parse().expect(ENVFILTER_ERROR_MSG))
+        .add_directive("tower=info".parse().expect(ENVFILTER_ERROR_MSG))
+        .add_directive("h2=info".parse().expect(ENVFILTER_ERROR_MSG))
+        .add_directive("reqwest=info".parse().expect(ENVFILTER_ERROR_MSG))
+}
+
+async fn shutdown_signal_once() -> ShutdownReason {
+    #[cfg(windows)]
+    {
+        tracing::info!("Listening for shutdown signal (Ctrl+C)");
+        tokio::signal::ctrl_c()
+            .inspect(|_| tracing::warn!("Ctrl+C received, shutting down"))
+            .await
+            .expect("Failed to listen for Ctrl+C");
+        ShutdownReason::Interrupt
+    }
+
+    #[cfg(unix)]
+    {
+        use futures::future::try_join3;
+        use tokio::signal::unix::{SignalKind, signal};
+
+        tracing::info!("Listening for shutdown signals (SIGINT, SIGTERM, SIGQUIT)");
+
+        let mut sigint = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+        let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        let mut sigquit = signal(SignalKind::quit()).expect("Failed to listen for SIGQUIT");
+
+        let sigint_fut = async {
+            sigint.recv().await;
+            Err::<(), _>(("SIGINT", ShutdownReason::Interrupt))
+        };
+        let sigterm_fut = async {
+            sigterm.recv().await;
+            Err::<(), _>(("SIGTERM", ShutdownReason::Terminate))
+        };
+        let sigquit_fut = async {
+            sigquit.recv().await;
+            Err::<(), _>(("SIGQUIT", ShutdownReason::Quit))
+        };
+
+        match try_join3(sigint_fut, sigterm_fut, sigquit_fut).await {
+            Err((signal, reason)) => {
+                eprintln!();
+                tracing::warn!("{signal} received, shutting down");
+                reason
+            }
+            Ok(_) => unreachable!("signal futures only complete with an error"),
+        }
+    }
+}
+
+/// Why the process is shutting down, derived from the signal that fired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// SIGINT / Ctrl+C: manual stop.
+    Interrupt,
+    /// SIGTERM: the server is going away.
+    Terminate,
+    /// SIGQUIT.
+    Quit,
+}
+
+impl ShutdownReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ShutdownReason::Interrupt => "interrupt",
+            ShutdownReason::Terminate => "terminate",
+            ShutdownReason::Quit => "quit",
+        }
+    }
+}
+
+/// One-shot shutdown broadcaster backed by a `Notify`.
+#[derive(Clone, Default)]
+pub struct ShutdownSignal {
+    notify: Arc<Notify>,
+    flag: Arc<std::sync::atomic::AtomicBool>,
+    reason: Arc<std::sync::OnceLock<ShutdownReason>>,
+}
+
+impl ShutdownSignal {
+    pub fn new() -> Self {
+        Self {
+            notify: Arc::new(Notify::new()),
+            flag: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            reason: Arc::new(std::sync::OnceLock::new()),
+        }
+    }
+
+    /// Request shutdown and wake every subscriber; later calls keep the first reason.
+    pub fn trigger(&self, reason: ShutdownReason) {
+        let _ = self.reason.set(reason);
+        self.flag.store(true, std::sync::atomic::Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    /// Future that resolves when shutdown is requested.
+    pub fn subscribe(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let notify = self.notify.clone();
+        let flag = self.flag.clone();
+        async move {
+            if flag.load(std::sync::atomic::Ordering::SeqCst) {
+                return;
+            }
+            notify.notified().await;
+        }
+    }
+
+    /// Whether shutdown has already been requested.
+    pub fn is_triggered(&self) -> bool {
+        self.flag.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Why shutdown was requested; `None` until it has been.
+    pub fn reason(&self) -> Option<ShutdownReason> {
+        self.reason.get().copied()
+    }
+}
+
+mod proto {
+    // Generated code for the empty `Storage` service trips this lint.
+    #![allow(clippy::match_single_binding)]
+    tonic::include_proto!("blueking");
+}
+pub use proto::*;
+
+/// Encoded descriptors of `blueking.proto`, for gRPC server reflection.
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("blueking_descriptor");
@@ -0,0 +1,360 @@
+//! `metrics` module holds process-wide counters and renders them in the Prometheus text format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters shared across the WebSocket and gRPC servers.
+#[derive(Default)]
+pub struct Metrics {
+    pub handshake: HandshakeMetrics,
+    pub events: EventLatencyMetrics,
+    /// Round-trip time of the brain `Chat` RPC.
+    pub brain_chat: LatencyHistogram,
+    pub unmatched_results: UnmatchedResultMetrics,
+    /// Absolute difference between an event's `client_ts` and its receive time.
+    pub client_clock_skew: LatencyHistogram,
+}
+
+impl Metrics {
+    /// Render all counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.handshake.render(&mut out);
+        self.events.render(&mut out);
+        out.push_str(
+            "# HELP blueking_brain_chat_seconds Latency of brain Chat RPCs.\n\
+             # TYPE blueking_brain_chat_seconds histogram\n",
+        );
+        self.brain_chat
+            .render(&mut out, "blueking_brain_chat_seconds", "");
+        self.unmatched_results.render(&mut out);
+        out.push_str(
+            "# HELP blueking_client_clock_skew_seconds Absolute skew between client event timestamps and their receive time.\n\
+             # TYPE blueking_client_clock_skew_seconds histogram\n",
+        );
+        self.client_clock_skew
+            .render(&mut out, "blueking_client_clock_skew_seconds", "");
+        out
+    }
+}
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Fixed-bucket latency histogram; each observation is a few relaxed atomic adds.
+#[derive(Default)]
+pub struct LatencyHistogram {
+    /// Per-bucket (non-cumulative) counts; observations above the last bound only hit `count`.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Write the `_bucket`, `_sum` and `_count` series; `labels` is prepended to `le`.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {count}");
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        if labels.is_empty() {
+            let _ = writeln!(out, "{name}_sum {sum}");
+            let _ = writeln!(out, "{name}_count {count}");
+        } else {
+            let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+            let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+        }
+    }
+}
+
+/// Kinds of client event, used to tag processing latency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Chat,
+    CommandAck,
+    CommandResult,
+    Register,
+    UpdateCapabilities,
+    Telemetry,
+    Deregister,
+    Query,
+    Sequence,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 9] = [
+        EventKind::Chat,
+        EventKind::CommandAck,
+        EventKind::CommandResult,
+        EventKind::Register,
+        EventKind::UpdateCapabilities,
+        EventKind::Telemetry,
+        EventKind::Deregister,
+        EventKind::Query,
+        EventKind::Sequence,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            EventKind::Chat => "chat",
+            EventKind::CommandAck => "command_ack",
+            EventKind::CommandResult => "command_result",
+            EventKind::Register => "register",
+            EventKind::UpdateCapabilities => "update_capabilities",
+            EventKind::Telemetry => "telemetry",
+            EventKind::Deregister => "deregister",
+            EventKind::Query => "query",
+            EventKind::Sequence => "sequence",
+        }
+    }
+}
+
+/// Time from an event entering the control service to its future resolving, one histogram per
+/// `EventKind`, plus a count of the events that failed.
+#[derive(Default)]
+pub struct EventLatencyMetrics {
+    /// Failed (or timed out) events, indexed by `EventKind` discriminant.
+    failures: [AtomicU64; EventKind::ALL.len()],
+    chat: LatencyHistogram,
+    command_ack: LatencyHistogram,
+    command_result: LatencyHistogram,
+    register: LatencyHistogram,
+    update_capabilities: LatencyHistogram,
+    telemetry: LatencyHistogram,
+    deregister: LatencyHistogram,
+    query: LatencyHistogram,
+    sequence: LatencyHistogram,
+}
+
+impl EventLatencyMetrics {
+    pub fn record(&self, kind: EventKind, elapsed: Duration) {
+        self.histogram(kind).observe(elapsed);
+    }
+
+    pub fn record_failure(&self, kind: EventKind) {
+        self.failures[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn histogram(&self, kind: EventKind) -> &LatencyHistogram {
+        match kind {
+            EventKind::Chat => &self.chat,
+            EventKind::CommandAck => &self.command_ack,
+            EventKind::CommandResult => &self.command_result,
+            EventKind::Register => &self.register,
+            EventKind::UpdateCapabilities => &self.update_capabilities,
+            EventKind::Telemetry => &self.telemetry,
+            EventKind::Deregister => &self.deregister,
+            EventKind::Query => &self.query,
+            EventKind::Sequence => &self.sequence,
+        }
+    }
+
+    fn render(&self, out: &mut String) {
+        out.push_str(
+            "# HELP blueking_event_processing_seconds Time to process a client event by type.\n",
+        );
+        out.push_str("# TYPE blueking_event_processing_seconds histogram\n");
+        for kind in EventKind::ALL {
+            self.histogram(kind).render(
+                out,
+                "blueking_event_processing_seconds",
+                &format!("event=\"{}\"", kind.label()),
+            );
+        }
+        out.push_str(
+            "# HELP blueking_event_failures_total Client events whose processing failed or timed out.\n",
+        );
+        out.push_str("# TYPE blueking_event_failures_total counter\n");
+        for kind in EventKind::ALL {
+            let _ = writeln!(
+                out,
+                "blueking_event_failures_total{{event=\"{}\"}} {}",
+                kind.label(),
+                self.failures[kind as usize].load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+/// Command results that don't match a command awaiting one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmatchedResult {
+    /// The command was still tracked but past the pending timeout.
+    Late,
+    /// The command had already been evicted as lost.
+    Expired,
+    /// The command id was never issued, or too long ago to remember.
+    Unknown,
+}
+
+impl UnmatchedResult {
+    const ALL: [UnmatchedResult; 3] = [
+        UnmatchedResult::Late,
+        UnmatchedResult::Expired,
+        UnmatchedResult::Unknown,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            UnmatchedResult::Late => "late",
+            UnmatchedResult::Expired => "expired",
+            UnmatchedResult::Unknown => "unknown",
+        }
+    }
+}
+
+/// Unmatched command result counters, one per `UnmatchedResult` kind.
+#[derive(Default)]
+pub struct UnmatchedResultMetrics {
+    late: AtomicU64,
+    expired: AtomicU64,
+    unknown: AtomicU64,
+}
+
+impl UnmatchedResultMetrics {
+    pub fn record(&self, kind: UnmatchedResult) {
+        self.counter(kind).fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counter(&self, kind: UnmatchedResult) -> &AtomicU64 {
+        match kind {
+            UnmatchedResult::Late => &self.late,
+            UnmatchedResult::Expired => &self.expired,
+            UnmatchedResult::Unknown => &self.unknown,
+        }
+    }
+
+    fn render(&self, out: &mut String) {
+        out.push_str(
+            "# HELP blueking_unmatched_command_results_total Command results that matched no pending command.\n",
+        );
+        out.push_str("# TYPE blueking_unmatched_command_results_total counter\n");
+        for kind in UnmatchedResult::ALL {
+            let _ = writeln!(
+                out,
+                "blueking_unmatched_command_results_total{{reason=\"{}\"}} {}",
+                kind.label(),
+                self.counter(kind).load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+/// Ways a client can fail the register handshake before it is added to the registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    /// No frame arrived before the register deadline.
+    TimedOut,
+    /// The connection closed, errored or sent a non-text frame first.
+    NotText,
+    /// The first frame was not valid JSON.
+    InvalidJson,
+    /// The first frame was JSON but not a valid `ComputerEvent`.
+    InvalidEvent,
+    /// The first frame was a valid event other than `register`.
+    NotRegister,
+    /// The client registered with a protocol version outside the supported range.
+    UnsupportedVersion,
+    /// The client registered with an id of 0 or below.
+    InvalidId,
+    /// The client presented no client token or the wrong one.
+    Unauthorized,
+    /// The client's id was held by another live connection.
+    DuplicateId,
+}
+
+impl HandshakeFailure {
+    fn label(self) -> &'static str {
+        match self {
+            HandshakeFailure::TimedOut => "timed_out",
+            HandshakeFailure::NotText => "not_text",
+            HandshakeFailure::InvalidJson => "invalid_json",
+            HandshakeFailure::InvalidEvent => "invalid_event",
+            HandshakeFailure::NotRegister => "not_register",
+            HandshakeFailure::UnsupportedVersion => "unsupported_version",
+            HandshakeFailure::InvalidId => "invalid_id",
+            HandshakeFailure::Unauthorized => "unauthorized",
+            HandshakeFailure::DuplicateId => "duplicate_id",
+        }
+    }
+}
+
+/// Handshake failure counters, one per `HandshakeFailure` mode.
+#[derive(Default)]
+pub struct HandshakeMetrics {
+    timed_out: AtomicU64,
+    not_text: AtomicU64,
+    invalid_json: AtomicU64,
+    invalid_event: AtomicU64,
+    not_register: AtomicU64,
+    unsupported_version: AtomicU64,
+    invalid_id: AtomicU64,
+    unauthorized: AtomicU64,
+    duplicate_id: AtomicU64,
+}
+
+impl HandshakeMetrics {
+    pub fn record(&self, failure: HandshakeFailure) {
+        self.counter(failure).fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counter(&self, failure: HandshakeFailure) -> &AtomicU64 {
+        match failure {
+            HandshakeFailure::TimedOut => &self.timed_out,
+            HandshakeFailure::NotText => &self.not_text,
+            HandshakeFailure::InvalidJson => &self.invalid_json,
+            HandshakeFailure::InvalidEvent => &self.invalid_event,
+            HandshakeFailure::NotRegister => &self.not_register,
+            HandshakeFailure::UnsupportedVersion => &self.unsupported_version,
+            HandshakeFailure::InvalidId => &self.invalid_id,
+            HandshakeFailure::Unauthorized => &self.unauthorized,
+            HandshakeFailure::DuplicateId => &self.duplicate_id,
+        }
+    }
+
+    fn render(&self, out: &mut String) {
+        out.push_str(
+            "# HELP blueking_handshake_failures_total Failed register handshakes by reason.\n",
+        );
+        out.push_str("# TYPE blueking_handshake_failures_total counter\n");
+        for failure in [
+            HandshakeFailure::TimedOut,
+            HandshakeFailure::NotText,
+            HandshakeFailure::InvalidJson,
+            HandshakeFailure::InvalidEvent,
+            HandshakeFailure::NotRegister,
+            HandshakeFailure::UnsupportedVersion,
+            HandshakeFailure::InvalidId,
+            HandshakeFailure::Unauthorized,
+            HandshakeFailure::DuplicateId,
+        ] {
+            let _ = writeln!(
+                out,
+                "blueking_handshake_failures_total{{reason=\"{}\"}} {}",
+                failure.label(),
+                self.counter(failure).load(Ordering::Relaxed)
+            );
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! `reload` module re-reads hot-reloadable settings from the config file on SIGHUP.
+//!
+//! The log filter, client rate limits and disabled capabilities are swapped in place without
+//! dropping connections; any other changed setting only takes effect after a restart.
+
+use crate::actions::{DisabledCapabilities, RateLimits};
+use crate::config::Config;
+use crate::{ENV_BLUEKING_DEBUG, ENV_BLUEKING_LOG, LogHandle, ShutdownSignal};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Applies a re-read config file to the running services.
+pub struct Reloader {
+    /// Config file in use; `None` when the server was started without one.
+    path: Option<PathBuf>,
+    /// Settings as of the last successful (re)load, to report what changed.
+    current: Mutex<Config>,
+    log: LogHandle,
+    rate_limits: RateLimits,
+    disabled: DisabledCapabilities,
+}
+
+impl Reloader {
+    pub fn new(
+        config: Option<(PathBuf, Config)>,
+        log: LogHandle,
+        rate_limits: RateLimits,
+        disabled: DisabledCapabilities,
+    ) -> Self {
+        let (path, current) = match config {
+            Some((path, config)) => (Some(path), config),
+            None => (None, Config::default()),
+        };
+        Self {
+            path,
+            current: Mutex::new(current),
+            log,
+            rate_limits,
+            disabled,
+        }
+    }
+
+    /// Reload on every SIGHUP until shutdown.
+    #[cfg(unix)]
+    pub async fn run(self: Arc<Self>, shutdown: ShutdownSignal) {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(sig) => sig,
+            Err(err) => {
+                tracing::warn!("Failed to listen for SIGHUP, reload disabled: {}", err);
+                return;
+            }
+        };
+        let shutdown = shutdown.subscribe();
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = sighup.recv() => {}
+            }
+            tracing::info!("SIGHUP received, reloading settings");
+            self.reload();
+        }
+    }
+
+    /// Re-read the config file and apply its hot-reloadable settings.
+    ///
+    /// A file that fails to load leaves every setting as it was.
+    fn reload(&self) {
+        let Some(path) = &self.path else {
+            tracing::warn!("No config file in use; nothing to reload");
+            return;
+        };
+        let config = match Config::from_path(path) {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::error!("Reload failed, keeping current settings: {}", err);
+                return;
+            }
+        };
+        let var = |name: &str| config.var(name);
+
+        let filter = crate::log_filter(var(ENV_BLUEKING_DEBUG), var(ENV_BLUEKING_LOG));
+        if let Err(err) = self.log.reload(filter) {
+            tracing::warn!("Failed to reload log filter: {}", err);
+        }
+        self.rate_limits.reload(var);
+        self.disabled.reload(var);
+
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        for name in current.changed(&config) {
+            if !is_reloadable(&name) {
+                tracing::warn!(
+                    "{} changed in {}; restart required to apply",
+                    name,
+                    path.display()
+                );
+            }
+        }
+        *current = config;
+        tracing::info!("Reloaded settings from {}", path.display());
+    }
+}
+
+fn is_reloadable(name: &str) -> bool {
+    name == ENV_BLUEKING_DEBUG
+        || RateLimits::VARS.contains(&name)
+        || DisabledCapabilities::VARS.contains(&name)
+}
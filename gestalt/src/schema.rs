@@ -0,0 +1,41 @@
+//! `schema` module exports JSON Schema for the WebSocket wire types, for Lua client authors.
+
+use crate::events::{Capability, ComputerEvent};
+use crate::websocket::LuaCommand;
+use schemars::JsonSchema;
+use schemars::r#gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+
+/// Capabilities travel as plain strings; names the server doesn't know are still accepted.
+impl JsonSchema for Capability {
+    fn schema_name() -> String {
+        "Capability".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        let mut schema = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            ..Default::default()
+        };
+        schema.metadata().description = Some(format!(
+            "Peripheral capability name. Recognized: {}.",
+            Capability::KNOWN
+                .iter()
+                .map(Capability::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+        schema.into()
+    }
+}
+
+/// Print the schemas of inbound events, outbound commands and capabilities as one JSON object.
+pub fn print() -> Result<(), serde_json::Error> {
+    let schemas = serde_json::json!({
+        "computer_event": schemars::schema_for!(ComputerEvent),
+        "lua_command": schemars::schema_for!(LuaCommand),
+        "capability": schemars::schema_for!(Capability),
+    });
+    println!("{}", serde_json::to_string_pretty(&schemas)?);
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! `store` module persists per-client state (capabilities, last telemetry) behind the `StateStore` trait.
+
+use crate::events::Capability;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Path of a JSON file used to persist client state. When unset, state is kept in memory only.
+const ENV_BLUEKING_STATE_FILE: &str = "BLUEKING_STATE_FILE";
+
+/// Free-form key/value snapshot reported by a client.
+pub type Telemetry = serde_json::Map<String, serde_json::Value>;
+
+/// Durable view of a client, keyed by its computer id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoredClient {
+    pub id: i32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub telemetry: Option<Telemetry>,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Serialize(serde_json::Error),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "io error: {e}"),
+            StoreError::Serialize(e) => write!(f, "serialize error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        StoreError::Serialize(err)
+    }
+}
+
+/// Trait to allow swapping where client state is kept.
+#[tonic::async_trait]
+pub trait StateStore: Send + Sync + 'static {
+    /// Persist the client's capabilities, keeping any stored telemetry.
+    async fn save_client(&self, id: i32, capabilities: &[Capability]) -> Result<(), StoreError>;
+
+    /// Load what is known about a client, if anything.
+    async fn load_client(&self, id: i32) -> Result<Option<StoredClient>, StoreError>;
+
+    /// Persist the client's latest telemetry snapshot.
+    async fn save_telemetry(&self, id: i32, telemetry: &Telemetry) -> Result<(), StoreError>;
+}
+
+/// Pick the store configured via `BLUEKING_STATE_FILE`, defaulting to memory.
+pub async fn from_env() -> Result<Arc<dyn StateStore>, StoreError> {
+    match crate::config::var(ENV_BLUEKING_STATE_FILE).filter(|p| !p.is_empty()) {
+        Some(path) => {
+            let store = JsonFileStore::open(PathBuf::from(path)).await?;
+            Ok(Arc::new(store))
+        }
+        None => Ok(Arc::new(MemoryStore::default())),
+    }
+}
+
+/// Default store: state lives only as long as the process.
+#[derive(Default)]
+pub struct MemoryStore {
+    clients: Mutex<HashMap<i32, StoredClient>>,
+}
+
+#[tonic::async_trait]
+impl StateStore for MemoryStore {
+    async fn save_client(&self, id: i32, capabilities: &[Capability]) -> Result<(), StoreError> {
+        let mut clients = self.clients.lock().await;
+        let client = clients.entry(id).or_insert_with(|| StoredClient {
+            id,
+            ..Default::default()
+        });
+        client.capabilities = capabilities.to_vec();
+        Ok(())
+    }
+
+    async fn load_client(&self, id: i32) -> Result<Option<StoredClient>, StoreError> {
+        Ok(self.clients.lock().await.get(&id).cloned())
+    }
+
+    async fn save_telemetry(&self, id: i32, telemetry: &Telemetry) -> Result<(), StoreError> {
+        let mut clients = self.clients.lock().await;
+        let client = clients.entry(id).or_insert_with(|| StoredClient {
+            id,
+            ..Default::default()
+        });
+        client.telemetry = Some(telemetry.clone());
+        Ok(())
+    }
+}
+
+/// Store that mirrors all clients into a single JSON file, rewritten on every change.
+pub struct JsonFileStore {
+    path: PathBuf,
+    memory: MemoryStore,
+    /// Serializes rewrites so concurrent saves don't race on the temporary file.
+    write_lock: Mutex<()>,
+}
+
+impl JsonFileStore {
+    /// Open `path`, loading existing state if the file is present.
+    pub async fn open(path: PathBuf) -> Result<Self, StoreError> {
+        let clients: Vec<StoredClient> = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        tracing::info!(
+            "Loaded {} client(s) from state file {}",
+            clients.len(),
+            path.display()
+        );
+        let memory = MemoryStore {
+            clients: Mutex::new(clients.into_iter().map(|c| (c.id, c)).collect()),
+        };
+        Ok(Self {
+            path,
+            memory,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Write the full state to a temporary file and atomically swap it in.
+    async fn flush(&self) -> Result<(), StoreError> {
+        let _guard = self.write_lock.lock().await;
+        let snapshot = {
+            let clients = self.memory.clients.lock().await;
+            let mut clients: Vec<&StoredClient> = clients.values().collect();
+            clients.sort_by_key(|c| c.id);
+            serde_json::to_vec_pretty(&clients)?
+        };
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, snapshot).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl StateStore for JsonFileStore {
+    async fn save_client(&self, id: i32, capabilities: &[Capability]) -> Result<(), StoreError> {
+        self.memory.save_client(id, capabilities).await?;
+        self.flush().await
+    }
+
+    async fn load_client(&self, id: i32) -> Result<Option<StoredClient>, StoreError> {
+        self.memory.load_client(id).await
+    }
+
+    async fn save_telemetry(&self, id: i32, telemetry: &Telemetry) -> Result<(), StoreError> {
+        self.memory.save_telemetry(id, telemetry).await?;
+        self.flush().await
+    }
+}
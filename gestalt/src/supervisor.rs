@@ -0,0 +1,171 @@
+//! `supervisor` module tracks the long-running background tasks spawned by `start()`.
+
+use crate::ShutdownSignal;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::{Id, JoinError, JoinSet};
+
+/// Pause before restarting a panicked task so one that panics on startup doesn't spin.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// How long to wait for tasks to finish after shutdown before aborting them.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do when a supervised task panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    /// Log the panic and leave the task dead.
+    Never,
+    /// Log the panic and spawn a fresh instance of the task.
+    OnPanic,
+}
+
+type TaskFactory = Arc<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
+struct TaskSpec {
+    name: &'static str,
+    restart: Restart,
+    factory: TaskFactory,
+}
+
+/// Owns background tasks in a `JoinSet`, logging (and optionally restarting) ones that panic.
+pub struct Supervisor {
+    tasks: JoinSet<()>,
+    specs: HashMap<Id, TaskSpec>,
+    shutdown: ShutdownSignal,
+}
+
+impl Supervisor {
+    pub fn new(shutdown: ShutdownSignal) -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            specs: HashMap::new(),
+            shutdown,
+        }
+    }
+
+    /// Spawn a task built by `factory`; the factory is called again for each restart.
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, restart: Restart, factory: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let factory: TaskFactory = Arc::new(move || Box::pin(factory()));
+        let handle = self.tasks.spawn(factory());
+        self.specs.insert(
+            handle.id(),
+            TaskSpec {
+                name,
+                restart,
+                factory,
+            },
+        );
+    }
+
+    fn respawn(&mut self, spec: TaskSpec) {
+        let task = (spec.factory)();
+        let shutdown = self.shutdown.subscribe();
+        let handle = self.tasks.spawn(async move {
+            tokio::select! {
+                _ = shutdown => return,
+                _ = tokio::time::sleep(RESTART_DELAY) => {}
+            }
+            task.await;
+        });
+        self.specs.insert(handle.id(), spec);
+    }
+
+    fn reap(&mut self, joined: Result<(Id, ()), JoinError>) {
+        let (id, err) = match joined {
+            Ok((id, ())) => (id, None),
+            Err(err) => (err.id(), Some(err)),
+        };
+        let Some(spec) = self.specs.remove(&id) else {
+            return;
+        };
+        match err {
+            None => tracing::debug!("Background task {} finished", spec.name),
+            Some(err) if err.is_panic() => {
+                tracing::error!("Background task {} panicked: {}", spec.name, err);
+                if spec.restart == Restart::OnPanic && !self.shutdown.is_triggered() {
+                    tracing::info!("Restarting background task {}", spec.name);
+                    self.respawn(spec);
+                }
+            }
+            Some(err) => tracing::debug!("Background task {} cancelled: {}", spec.name, err),
+        }
+    }
+
+    /// Watch tasks until shutdown, then wait for them to finish, aborting stragglers.
+    pub async fn run(mut self) {
+        let shutdown = self.shutdown.subscribe();
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                Some(joined) = self.tasks.join_next_with_id() => self.reap(joined),
+            }
+        }
+
+        if self.tasks.is_empty() {
+            return;
+        }
+        tracing::debug!(
+            "Waiting for {} background task(s) to finish",
+            self.tasks.len()
+        );
+        let drain = async {
+            while let Some(joined) = self.tasks.join_next_with_id().await {
+                self.reap(joined);
+            }
+        };
+        if tokio::time::timeout(DRAIN_TIMEOUT, drain).await.is_err() {
+            let names: Vec<_> = self.specs.values().map(|spec| spec.name).collect();
+            tracing::warn!(
+                "Background task(s) {:?} did not stop within {:?}, aborting",
+                names,
+                DRAIN_TIMEOUT
+            );
+            self.tasks.shutdown().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShutdownReason;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Spawn a task that panics on its first run only, returning how often it has run.
+    fn panics_once(supervisor: &mut Supervisor, restart: Restart) -> Arc<AtomicUsize> {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&runs);
+        supervisor.spawn("panics-once", restart, move || {
+            let first = counter.fetch_add(1, Ordering::SeqCst) == 0;
+            async move {
+                if first {
+                    panic!("first run fails");
+                }
+            }
+        });
+        runs
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_tasks_marked_on_panic_are_restarted() {
+        let shutdown = ShutdownSignal::new();
+        let mut supervisor = Supervisor::new(shutdown.clone());
+        let restarted = panics_once(&mut supervisor, Restart::OnPanic);
+        let left_dead = panics_once(&mut supervisor, Restart::Never);
+        let running = tokio::spawn(supervisor.run());
+
+        tokio::time::sleep(RESTART_DELAY * 2).await;
+        assert_eq!(restarted.load(Ordering::SeqCst), 2);
+        assert_eq!(left_dead.load(Ordering::SeqCst), 1);
+
+        shutdown.trigger(ShutdownReason::Interrupt);
+        running.await.unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+//! `templates` module holds named, parameterized Lua commands loaded from a file.
+//!
+//! ```toml
+//! [greet]
+//! capability = "chat"
+//! command = { name = "whisper", args = { target = "{player}", message = "Hello {player}!" } }
+//! ```
+//!
+//! `{param}` placeholders in string values are replaced on invocation. Ids are minted per
+//! invocation, for the command and for every step of a batch.
+
+use crate::config::{ConfigError, read_file};
+use crate::events::Capability;
+use crate::websocket::{LuaCommand, next_command_id};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// TOML or JSON file of command templates; unset means no templates.
+const ENV_BLUEKING_TEMPLATES_FILE: &str = "BLUEKING_TEMPLATES_FILE";
+
+/// A command with `{param}` placeholders and the capability it is sent to.
+#[derive(Debug, Deserialize)]
+struct CommandTemplate {
+    capability: Capability,
+    /// `LuaCommand` JSON without ids.
+    command: Value,
+}
+
+impl CommandTemplate {
+    /// Placeholder names used anywhere in the command.
+    fn params(&self) -> BTreeSet<String> {
+        let mut params = BTreeSet::new();
+        visit_strings(&self.command, &mut |s| params.extend(placeholders(s)));
+        params
+    }
+
+    /// Substitute `params` and build the command with fresh ids.
+    fn instantiate(&self, params: &HashMap<String, String>) -> Result<LuaCommand, String> {
+        let expected = self.params();
+        if let Some(missing) = expected.iter().find(|p| !params.contains_key(*p)) {
+            return Err(format!("missing parameter {missing:?}"));
+        }
+        if let Some(unknown) = params.keys().find(|p| !expected.contains(*p)) {
+            return Err(format!("unknown parameter {unknown:?}"));
+        }
+        let mut command = self.command.clone();
+        substitute(&mut command, params);
+        assign_ids(&mut command);
+        let command: LuaCommand =
+            serde_json::from_value(command).map_err(|e| format!("invalid command: {e}"))?;
+        command.validate()?;
+        Ok(command)
+    }
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    NotFound(String),
+    Invalid(String),
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::NotFound(name) => write!(f, "no template named {name:?}"),
+            TemplateError::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Named command templates, shared read-only across the gRPC handlers.
+#[derive(Clone, Default)]
+pub struct CommandTemplates {
+    templates: Arc<HashMap<String, CommandTemplate>>,
+}
+
+impl CommandTemplates {
+    /// Load `BLUEKING_TEMPLATES_FILE`, checking that every template builds a valid command.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let Some(path) = crate::config::var(ENV_BLUEKING_TEMPLATES_FILE)
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+        else {
+            return Ok(Self::default());
+        };
+        let templates: HashMap<String, CommandTemplate> = read_file(&path)?;
+        for (name, template) in &templates {
+            let sample = template
+                .params()
+                .into_iter()
+                .map(|p| (p, String::new()))
+                .collect();
+            template
+                .instantiate(&sample)
+                .map_err(|e| ConfigError::Parse(path.clone(), format!("template {name}: {e}")))?;
+        }
+        tracing::info!(
+            "Loaded {} command template(s) from {}",
+            templates.len(),
+            path.display()
+        );
+        Ok(Self {
+            templates: Arc::new(templates),
+        })
+    }
+
+    /// Build the command of template `name` with `params` substituted.
+    pub fn instantiate(
+        &self,
+        name: &str,
+        params: &HashMap<String, String>,
+    ) -> Result<(Capability, LuaCommand), TemplateError> {
+        let template = self
+            .templates
+            .get(name)
+            .ok_or_else(|| TemplateError::NotFound(name.to_string()))?;
+        let command = template
+            .instantiate(params)
+            .map_err(|e| TemplateError::Invalid(format!("template {name}: {e}")))?;
+        Ok((template.capability.clone(), command))
+    }
+}
+
+fn visit_strings(value: &Value, f: &mut impl FnMut(&str)) {
+    match value {
+        Value::String(s) => f(s),
+        Value::Array(items) => items.iter().for_each(|v| visit_strings(v, f)),
+        Value::Object(map) => map.values().for_each(|v| visit_strings(v, f)),
+        _ => {}
+    }
+}
+
+/// Names inside `{...}` that look like identifiers; other braces are left alone.
+fn placeholders(s: &str) -> impl Iterator<Item = String> + '_ {
+    s.split('{').skip(1).filter_map(|rest| {
+        let (name, _) = rest.split_once('}')?;
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        valid.then(|| name.to_string())
+    })
+}
+
+/// Replace placeholders in one pass, so parameter values are never substituted into.
+fn fill(s: &str, params: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after
+            .split_once('}')
+            .and_then(|(name, tail)| Some((params.get(name)?, tail)))
+        {
+            Some((param, tail)) => {
+                out.push_str(param);
+                rest = tail;
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn substitute(value: &mut Value, params: &HashMap<String, String>) {
+    match value {
+        Value::String(s) => *s = fill(s, params),
+        Value::Array(items) => items.iter_mut().for_each(|v| substitute(v, params)),
+        Value::Object(map) => map.values_mut().for_each(|v| substitute(v, params)),
+        _ => {}
+    }
+}
+
+/// Give the command, and each step of a batch, a fresh id.
+pub(crate) fn assign_ids(command: &mut Value) {
+    let Value::Object(map) = command else {
+        return;
+    };
+    map.insert("id".to_string(), Value::String(next_command_id()));
+    if let Some(Value::Array(steps)) = map
+        .get_mut("args")
+        .and_then(|args| args.get_mut("commands"))
+    {
+        steps.iter_mut().for_each(assign_ids);
+    }
+}
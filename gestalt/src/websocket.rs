@@ -1,50 +1,259 @@
 //! `websocket` module is responsible for terminating the `/cc` WebSocket endpoint (via Axum), tracking connected in‑game computers in `ClientRegistry`, turning raw websocket frames into `ComputerEvent`s and forwarding them into the Tower `ComputerEventService`.
 
 use crate::{
-    ShutdownSignal,
-    events::{AppComputerControlService, ComputerEvent},
+    DispatchError, Listen, ShutdownReason, ShutdownSignal,
+    brain::Brain,
+    events::{
+        Capability, ClientEvent, ComputerEvent, ComputerEventService, DeregisterReason,
+        EventDecodeError, FrameFormat, MAX_EVENT_BYTES, QueryTopic, SUPPORTED_PROTOCOL_VERSIONS,
+        decode_event, default_protocol_version,
+    },
+    instrument::{EventMetrics, EventMetricsLayer},
+    metrics::{EventKind, HandshakeFailure, Metrics},
+    store::{StateStore, Telemetry},
 };
 use axum::{
-    extract::ws::{Message, WebSocket},
-    extract::{State, WebSocketUpgrade},
-    response::IntoResponse,
+    extract::ws::{CloseFrame, Message, WebSocket, close_code},
+    extract::{Query, State, WebSocketUpgrade},
+    http::{
+        HeaderMap, StatusCode,
+        header::{AUTHORIZATION, ORIGIN},
+    },
+    response::{IntoResponse, Response},
+};
+use futures::{
+    sink::{Sink, SinkExt},
+    stream::{SplitSink, SplitStream, StreamExt},
 };
-use futures::{sink::SinkExt, stream::StreamExt};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, net::SocketAddr};
 use tokio::sync::Mutex as AsyncMutex;
-use tokio::sync::{Mutex, mpsc};
-use tower::ServiceExt;
+use tokio::sync::{Mutex, Notify, mpsc};
+use tower::limit::{ConcurrencyLimit, GlobalConcurrencyLimitLayer};
+use tower::load_shed::LoadShed;
+use tower::load_shed::error::Overloaded;
+use tower::timeout::Timeout;
+use tower::{ServiceBuilder, ServiceExt};
 
 const WS_BIND: ([u8; 4], u16) = ([0, 0, 0, 0], 3000);
+/// Address the WebSocket server binds, overriding `WS_BIND`; port 0 picks a free port.
+const ENV_BLUEKING_WS_ADDR: &str = "BLUEKING_WS_ADDR";
+/// Comma-separated list of `Origin` values allowed to open `/cc`. Unset accepts any origin.
+const ENV_BLUEKING_WS_ALLOWED_ORIGINS: &str = "BLUEKING_WS_ALLOWED_ORIGINS";
+/// Milliseconds a send may wait on a client's full queue before counting as a stall.
+const ENV_BLUEKING_SLOW_CLIENT_DEADLINE_MS: &str = "BLUEKING_SLOW_CLIENT_DEADLINE_MS";
+/// Consecutive stalls after which a client is considered slow.
+const ENV_BLUEKING_SLOW_CLIENT_STRIKES: &str = "BLUEKING_SLOW_CLIENT_STRIKES";
+/// `true` to disconnect slow clients instead of only logging them.
+const ENV_BLUEKING_EVICT_SLOW_CLIENTS: &str = "BLUEKING_EVICT_SLOW_CLIENTS";
+/// Seconds an inbound event may take to be fully processed before it is abandoned.
+const ENV_BLUEKING_EVENT_TIMEOUT_SECS: &str = "BLUEKING_EVENT_TIMEOUT_SECS";
+const DEFAULT_EVENT_TIMEOUT_SECS: u64 = 180;
+/// Chat and telemetry events processed at once across all clients; further ones are shed. 0 means
+/// no limit. Other events are never shed.
+const ENV_BLUEKING_MAX_EVENTS_IN_FLIGHT: &str = "BLUEKING_MAX_EVENTS_IN_FLIGHT";
+const DEFAULT_MAX_EVENTS_IN_FLIGHT: usize = 0;
+/// Milliseconds a single socket write may block before the client is dropped as stalled.
+const ENV_BLUEKING_SOCKET_WRITE_TIMEOUT_MS: &str = "BLUEKING_SOCKET_WRITE_TIMEOUT_MS";
+const DEFAULT_SOCKET_WRITE_TIMEOUT_MS: u64 = 10_000;
+/// Seconds without an inbound frame before a client with the `ping` capability is sent a
+/// `ping` command; 0 disables application-level pings.
+const ENV_BLUEKING_APP_PING_IDLE_SECS: &str = "BLUEKING_APP_PING_IDLE_SECS";
+const DEFAULT_APP_PING_IDLE_SECS: u64 = 30;
+/// Seconds a client has to report the `ping` result before it is disconnected.
+const ENV_BLUEKING_APP_PING_TIMEOUT_SECS: &str = "BLUEKING_APP_PING_TIMEOUT_SECS";
+const DEFAULT_APP_PING_TIMEOUT_SECS: u64 = 10;
+/// Milliseconds a disconnected client's metadata is kept for re-adoption on reconnect.
+const ENV_BLUEKING_RECONNECT_GRACE_MS: &str = "BLUEKING_RECONNECT_GRACE_MS";
+/// `false` to stop restoring a reconnecting client's capabilities when its register omits them.
+const ENV_BLUEKING_RESTORE_CAPABILITIES: &str = "BLUEKING_RESTORE_CAPABILITIES";
+/// Maximum number of disconnected clients remembered during the reconnect grace period.
+const ENV_BLUEKING_MAX_TOMBSTONES: &str = "BLUEKING_MAX_TOMBSTONES";
+/// Most clients connected at once; further registrations are refused. 0 means unlimited.
+const ENV_BLUEKING_MAX_CLIENTS: &str = "BLUEKING_MAX_CLIENTS";
+/// Seconds a connected client must have been silent before a new connection using its id
+/// takes over; until then the newcomer is refused as a duplicate. 0 always takes over.
+const ENV_BLUEKING_TAKEOVER_AFTER_SECS: &str = "BLUEKING_TAKEOVER_AFTER_SECS";
+const DEFAULT_TAKEOVER_AFTER_SECS: u64 = 90;
+/// Shared secret computers must present as `token` in their register frame or as `?token=`;
+/// unset or empty lets any computer register.
+const ENV_BLUEKING_CLIENT_TOKEN: &str = "BLUEKING_CLIENT_TOKEN";
+/// Sends a single broadcast keeps in flight at once; each waits up to the slow-client deadline
+/// for room in a full queue.
+const ENV_BLUEKING_BROADCAST_CONCURRENCY: &str = "BLUEKING_BROADCAST_CONCURRENCY";
+const DEFAULT_BROADCAST_CONCURRENCY: usize = 64;
+/// `false` to hold events sent before register instead of closing the connection.
+const ENV_BLUEKING_STRICT_REGISTER: &str = "BLUEKING_STRICT_REGISTER";
+/// Events held before register when not strict; one more closes the connection.
+const ENV_BLUEKING_PRE_REGISTER_EVENTS: &str = "BLUEKING_PRE_REGISTER_EVENTS";
+const DEFAULT_PRE_REGISTER_EVENTS: usize = 8;
+/// File the registry snapshot is written to on SIGUSR1.
+#[cfg(unix)]
+const ENV_BLUEKING_SNAPSHOT_FILE: &str = "BLUEKING_SNAPSHOT_FILE";
+#[cfg(unix)]
+const DEFAULT_SNAPSHOT_FILE: &str = "blueking-snapshot.json";
+
+/// Tunables for `ClientRegistry`.
+#[derive(Debug, Clone)]
+pub struct RegistryConfig {
+    pub slow_client: SlowClientPolicy,
+    pub reconnect_grace: Duration,
+    pub restore_capabilities: bool,
+    pub max_tombstones: usize,
+    pub broadcast_concurrency: usize,
+    pub max_clients: usize,
+    pub takeover_after: Duration,
+    /// Command names whose queued duplicates are collapsed, keeping only the newest per name
+    /// and target.
+    pub coalesce_commands: Arc<[String]>,
+}
+
+impl RegistryConfig {
+    pub fn from_env() -> Self {
+        Self {
+            slow_client: SlowClientPolicy::from_env(),
+            reconnect_grace: Duration::from_millis(crate::env_or(
+                ENV_BLUEKING_RECONNECT_GRACE_MS,
+                5000,
+            )),
+            restore_capabilities: crate::env_or(ENV_BLUEKING_RESTORE_CAPABILITIES, true),
+            max_tombstones: crate::env_or(ENV_BLUEKING_MAX_TOMBSTONES, 1024),
+            broadcast_concurrency: crate::env_or(
+                ENV_BLUEKING_BROADCAST_CONCURRENCY,
+                DEFAULT_BROADCAST_CONCURRENCY,
+            )
+            .max(1),
+            max_clients: crate::env_or(ENV_BLUEKING_MAX_CLIENTS, 0),
+            takeover_after: Duration::from_secs(crate::env_or(
+                ENV_BLUEKING_TAKEOVER_AFTER_SECS,
+                DEFAULT_TAKEOVER_AFTER_SECS,
+            )),
+            coalesce_commands: crate::env_or(ENV_BLUEKING_COALESCE_COMMANDS, String::new())
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect(),
+        }
+    }
+}
+
+/// Thresholds for detecting clients that cannot keep up with outbound messages.
+#[derive(Debug, Clone, Copy)]
+pub struct SlowClientPolicy {
+    pub deadline: Duration,
+    pub max_strikes: u32,
+    pub evict: bool,
+}
+
+impl SlowClientPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            deadline: Duration::from_millis(crate::env_or(
+                ENV_BLUEKING_SLOW_CLIENT_DEADLINE_MS,
+                2000,
+            )),
+            max_strikes: crate::env_or(ENV_BLUEKING_SLOW_CLIENT_STRIKES, 3),
+            evict: crate::env_or(ENV_BLUEKING_EVICT_SLOW_CLIENTS, false),
+        }
+    }
+}
+
+/// Address the WebSocket server binds: `BLUEKING_WS_ADDR`, else `WS_BIND`.
+pub fn bind_addr() -> SocketAddr {
+    crate::env_or(ENV_BLUEKING_WS_ADDR, SocketAddr::from(WS_BIND))
+}
 
 /// Start Axum WebSocket server listening on `/cc`.
 ///
 /// The server is parameterised by:
 /// - `registry`: shared registry of connected computers.
-/// - `control`: Tower service that handles `ComputerEvent`s.
+/// - `control`: Tower service that handles `ComputerEvent`s, generic over the `Brain` backend.
+/// - `metrics`: shared counters, also served as Prometheus text on `/metrics`.
+/// - `/readyz` answers 503 while the brain is unreachable or the server is shutting down.
 /// - `shutdown`: cooperative shutdown signal.
-pub async fn run_websocket(
+/// - `listen`: address to bind, and who to tell the address actually bound.
+pub async fn run_websocket<B: Brain>(
     registry: ClientRegistry,
-    control: AppComputerControlService,
+    control: ComputerEventService<B>,
+    metrics: Arc<Metrics>,
     shutdown: ShutdownSignal,
+    shutdown_grace: Duration,
+    listen: Listen,
 ) -> Result<(), std::io::Error> {
-    let addr = SocketAddr::from(WS_BIND);
+    let addr = listen.addr;
     tracing::info!("Binding Command&Control WebSocket HTTP server: {}", addr);
-    let shutdown = shutdown.subscribe();
-    if let Err(error) = axum::serve(
-        match tokio::net::TcpListener::bind(addr).await {
-            Ok(listener) => listener,
-            Err(err) => {
-                tracing::error!("Failed to bind WebSocket listener: {}", err);
-                return Err(err);
-            }
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("Failed to bind WebSocket listener: {}", err);
+            return Err(err);
+        }
+    };
+    let local_addr = listener.local_addr()?;
+    if local_addr != addr {
+        tracing::info!("WebSocket server bound to {}", local_addr);
+    }
+    if let Some(bound) = listen.bound {
+        let _ = bound.send(local_addr);
+    }
+    let allowed_origins = allowed_origins_from_env();
+    let client_token = crate::config::var(ENV_BLUEKING_CLIENT_TOKEN)
+        .filter(|t| !t.is_empty())
+        .map(Arc::<str>::from);
+    let event_timeout = Duration::from_secs(crate::env_or(
+        ENV_BLUEKING_EVENT_TIMEOUT_SECS,
+        DEFAULT_EVENT_TIMEOUT_SECS,
+    ));
+    let event_slots = Arc::new(tokio::sync::Semaphore::new(
+        match crate::env_or(
+            ENV_BLUEKING_MAX_EVENTS_IN_FLIGHT,
+            DEFAULT_MAX_EVENTS_IN_FLIGHT,
+        ) {
+            0 => tokio::sync::Semaphore::MAX_PERMITS,
+            n => n,
         },
+    ));
+    let write_timeout = Duration::from_millis(crate::env_or(
+        ENV_BLUEKING_SOCKET_WRITE_TIMEOUT_MS,
+        DEFAULT_SOCKET_WRITE_TIMEOUT_MS,
+    ));
+    let app_ping = AppPingPolicy::from_env();
+    if let Some(origins) = &allowed_origins {
+        tracing::info!("WebSocket upgrades restricted to origins {:?}", origins);
+    }
+    // Upgraded sockets outlive the HTTP server, so hold it open for the grace window.
+    let drained = {
+        let requested = shutdown.subscribe();
+        async move {
+            requested.await;
+            tokio::time::sleep(shutdown_grace).await;
+        }
+    };
+    if let Err(error) = axum::serve(
+        listener,
         axum::Router::new()
-            .route("/cc", axum::routing::get(ws_handler))
-            .with_state(WebsocketState::new(registry, control)),
+            .route("/cc", axum::routing::get(ws_handler::<B>))
+            .route("/metrics", axum::routing::get(metrics_handler::<B>))
+            .route("/readyz", axum::routing::get(readyz_handler::<B>))
+            .route("/monitor", axum::routing::get(monitor_handler::<B>))
+            .with_state(WebsocketState::new(
+                registry,
+                control,
+                metrics,
+                allowed_origins,
+                event_timeout,
+                event_slots,
+                write_timeout,
+                app_ping,
+                shutdown_grace,
+                shutdown,
+                client_token,
+                RegisterPolicy::from_env(),
+            )),
     )
-    .with_graceful_shutdown(shutdown)
+    .with_graceful_shutdown(drained)
     .await
     {
         tracing::error!("Fatal error in WebSocket server: {}", error);
@@ -54,27 +263,351 @@ pub async fn run_websocket(
     }
 }
 
+/// Parse the origin allowlist from the environment; `None` when unset or empty.
+fn allowed_origins_from_env() -> Option<Arc<Vec<String>>> {
+    let raw = crate::config::var(ENV_BLUEKING_WS_ALLOWED_ORIGINS)?;
+    let origins: Vec<String> = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .map(str::to_string)
+        .collect();
+    (!origins.is_empty()).then(|| Arc::new(origins))
+}
+
 /// Registry of connected WebSocket clients.
+///
+/// Locking: `clients` is never held across an await that waits on a client, such as a send
+/// that may block on a full queue; clone the `ClientSender` out and release the lock first.
+/// Per-entry bookkeeping that must agree with a send (see `send_tracked`) is written under
+/// the lock after re-checking that the same connection is still registered. When both locks
+/// are needed, take `clients` before `tombstones`.
 #[derive(Clone)]
 pub struct ClientRegistry {
     clients: Arc<Mutex<HashMap<i32, ClientEntry>>>,
+    /// Metadata of recently disconnected clients, kept for the reconnect grace period.
+    tombstones: Arc<Mutex<HashMap<i32, Tombstone>>>,
+    config: RegistryConfig,
+    store: Arc<dyn StateStore>,
+    /// While set, new registrations are refused; connected clients are unaffected.
+    maintenance: Arc<AtomicBool>,
+}
+
+/// Per-client bookkeeping that outlives a single connection.
+#[derive(Debug, Clone, Copy)]
+struct ClientMeta {
+    first_seen: Instant,
+    reconnects: u32,
+}
+
+struct Tombstone {
+    meta: ClientMeta,
+    /// Last-known capabilities, restored if the reconnecting client doesn't re-advertise any.
+    capabilities: Vec<crate::events::Capability>,
+    expires_at: Instant,
 }
 
 #[derive(Clone)]
 struct ClientEntry {
+    meta: ClientMeta,
     sender: ClientSender,
     capabilities: Vec<crate::events::Capability>,
+    /// Protocol version negotiated at register.
+    protocol_version: u32,
+    /// Latest telemetry reported by the client, used for metadata queries.
+    telemetry: Telemetry,
+    /// Human-friendly name shown alongside the id.
+    label: Option<String>,
+    /// Set by an operator to hold back commands; inbound events are still handled.
+    paused: bool,
+    /// When the capabilities were last set, by the handshake or a refresh.
+    refreshed_at: Instant,
+    /// Messages delivered through `send_tracked` or `send_command_tracked` on this connection,
+    /// and when the last one was.
+    sent: u64,
+    last_sent: Option<Instant>,
+}
+
+/// Point-in-time description of a connected client.
+#[derive(Debug, Clone)]
+pub struct ClientSummary {
+    pub id: i32,
+    pub label: Option<String>,
+    pub capabilities: Vec<crate::events::Capability>,
+    pub protocol_version: u32,
+    pub paused: bool,
+}
+
+/// Full dump of registry state for bug reports; durations are relative to `ClientRegistry::snapshot`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RegistrySnapshot {
+    pub clients: Vec<ClientSnapshot>,
+    pub tombstones: Vec<TombstoneSnapshot>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ClientSnapshot {
+    pub id: i32,
+    pub label: Option<String>,
+    pub capabilities: Vec<crate::events::Capability>,
+    pub protocol_version: u32,
+    pub reconnects: u32,
+    /// Seconds since the id was first seen, across reconnects.
+    pub first_seen_secs: f64,
+    /// Seconds since the last inbound frame.
+    pub last_seen_secs: f64,
+    /// Outbound messages waiting in the client's queue.
+    pub queue_depth: usize,
+    /// Consecutive sends that hit the slow-client deadline.
+    pub stalls: u32,
+    pub paused: bool,
+    /// Messages sent to this connection through `send_tracked` or `send_command_tracked`.
+    pub sent: u64,
+    /// Seconds since the last of them, if any.
+    pub last_sent_secs: Option<f64>,
+    pub telemetry: Telemetry,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TombstoneSnapshot {
+    pub id: i32,
+    pub capabilities: Vec<crate::events::Capability>,
+    pub reconnects: u32,
+    /// Seconds left in the reconnect grace period.
+    pub expires_in_secs: f64,
+}
+
+/// Serializable client filter: every given condition must hold.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ClientQuery {
+    /// Required capability, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capability: Option<crate::events::Capability>,
+    /// Telemetry keys that must be present with exactly these values.
+    #[serde(default, skip_serializing_if = "Telemetry::is_empty")]
+    pub metadata: Telemetry,
+}
+
+impl ClientQuery {
+    fn matches(&self, entry: &ClientEntry) -> bool {
+        self.capability
+            .as_ref()
+            .is_none_or(|cap| entry.capabilities.contains(cap))
+            && self
+                .metadata
+                .iter()
+                .all(|(key, value)| entry.telemetry.get(key) == Some(value))
+    }
+}
+
+/// What a queued frame carries.
+enum Payload {
+    /// An encoded command; the forwarder numbers it when it is written.
+    Command(Arc<serde_json::Map<String, serde_json::Value>>),
+    /// A frame written as-is, without a sequence number.
+    Raw(Message),
+}
+
+/// Wire form of a command: its fields behind the connection's sequence number.
+#[derive(serde::Serialize)]
+struct Sequenced<'a> {
+    seq: u64,
+    #[serde(flatten)]
+    command: &'a serde_json::Map<String, serde_json::Value>,
+}
+
+/// Encode a command into the object the forwarder sequences and writes.
+fn encode_command(
+    cmd: &LuaCommand,
+) -> Result<Arc<serde_json::Map<String, serde_json::Value>>, serde_json::Error> {
+    match serde_json::to_value(cmd)? {
+        serde_json::Value::Object(fields) => Ok(Arc::new(fields)),
+        other => Err(serde::ser::Error::custom(format!(
+            "command encoded as {other}, not an object"
+        ))),
+    }
+}
+
+/// Turns queued payloads into frames for one connection, numbering commands as it goes.
+///
+/// Owned by the connection's forwarding task, so numbers reach the wire in order.
+struct Framer {
+    sequence: Arc<AtomicU64>,
+    frames: FrameFormat,
+}
+
+impl Framer {
+    fn frame(&self, payload: Payload) -> Result<Message, serde_json::Error> {
+        let command = match payload {
+            Payload::Raw(message) => return Ok(message),
+            Payload::Command(command) => command,
+        };
+        let seq = self.sequence.load(Ordering::Relaxed) + 1;
+        let encoded = serde_json::to_string(&Sequenced {
+            seq,
+            command: &command,
+        })?;
+        self.sequence.store(seq, Ordering::Relaxed);
+        Ok(match self.frames {
+            FrameFormat::Text => Message::Text(encoded),
+            FrameFormat::Binary => Message::Binary(encoded.into_bytes()),
+        })
+    }
+}
+
+/// Tracks the newest queued command per coalescing key for one connection.
+///
+/// Senders record each coalescing command as they queue it; the forwarding task drops any
+/// frame that a newer one with the same key has since joined the queue behind.
+#[derive(Debug, Default)]
+struct Coalescer {
+    names: Arc<[String]>,
+    next: AtomicU64,
+    newest: std::sync::Mutex<HashMap<String, u64>>,
+}
+
+impl Coalescer {
+    fn new(names: Arc<[String]>) -> Self {
+        Self {
+            names,
+            ..Self::default()
+        }
+    }
+
+    /// Key under which queued copies of a command collapse: its `name`, plus `args.target`
+    /// when it has one, with a fresh generation. `None` unless the command is configured to
+    /// coalesce.
+    fn key(&self, command: &serde_json::Map<String, serde_json::Value>) -> Option<(String, u64)> {
+        let name = command.get("name")?.as_str()?;
+        if !self.names.iter().any(|n| n == name) {
+            return None;
+        }
+        let key = match command
+            .get("args")
+            .and_then(|args| args.get("target"))
+            .and_then(|t| t.as_str())
+        {
+            Some(target) => format!("{name}:{target}"),
+            None => name.to_string(),
+        };
+        Some((key, self.next.fetch_add(1, Ordering::Relaxed)))
+    }
+
+    /// Record that the frame of this generation is in the queue.
+    fn queued(&self, key: &str, generation: u64) {
+        let mut newest = self.newest.lock().unwrap_or_else(|e| e.into_inner());
+        let latest = newest.entry(key.to_string()).or_insert(generation);
+        *latest = (*latest).max(generation);
+    }
+
+    /// Whether a newer frame with the same key was queued after this one. The key is forgotten
+    /// once its newest frame leaves the queue.
+    fn superseded(&self, key: &str, generation: u64) -> bool {
+        let mut newest = self.newest.lock().unwrap_or_else(|e| e.into_inner());
+        match newest.get(key) {
+            Some(latest) if *latest > generation => true,
+            Some(latest) if *latest == generation => {
+                newest.remove(key);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the forwarder should drop `outbound` for a newer queued frame with its key.
+    ///
+    /// The client never sees a dropped command, so it is finished in `pending` rather than
+    /// left to wait for a result that cannot come.
+    async fn drop_superseded(
+        &self,
+        outbound: &Outbound,
+        pending: &crate::actions::PendingCommands,
+        client_id: i32,
+    ) -> bool {
+        let Some((key, generation)) = &outbound.coalesce else {
+            return false;
+        };
+        if !self.superseded(key, *generation) {
+            return false;
+        }
+        tracing::debug!(
+            "Coalesced queued {} frame for client {} into a later one",
+            key,
+            client_id
+        );
+        if let Some(command_id) = outbound.command_id() {
+            pending.finish(command_id).await;
+        }
+        true
+    }
+}
+
+/// A frame waiting in a client's outbound queue.
+pub struct Outbound {
+    payload: Payload,
+    queued_at: Instant,
+    /// Drop the frame instead of writing it once it has waited this long; `None` never expires.
+    ttl: Option<Duration>,
+    /// Key and generation for commands configured in `BLUEKING_COALESCE_COMMANDS`; a queued
+    /// frame is dropped in favour of a later one with the same key.
+    coalesce: Option<(String, u64)>,
+}
+
+impl Outbound {
+    fn new(payload: Payload, ttl: Option<Duration>, coalesce: Option<(String, u64)>) -> Self {
+        Self {
+            coalesce,
+            payload,
+            queued_at: Instant::now(),
+            ttl,
+        }
+    }
+
+    /// Id of the queued command, if the frame carries one.
+    fn command_id(&self) -> Option<&str> {
+        match &self.payload {
+            Payload::Command(command) => command.get("id")?.as_str(),
+            Payload::Raw(_) => None,
+        }
+    }
+
+    /// How long the frame waited, if that exceeds its TTL.
+    fn expired(&self) -> Option<Duration> {
+        let waited = self.queued_at.elapsed();
+        self.ttl.filter(|ttl| waited > *ttl).map(|_| waited)
+    }
 }
 
 #[derive(Clone)]
 pub struct ClientSender {
-    sender: mpsc::Sender<Message>,
+    id: i32,
+    sender: mpsc::Sender<Outbound>,
+    policy: SlowClientPolicy,
+    /// Consecutive sends that could not make progress within the policy deadline.
+    stalls: Arc<AtomicU32>,
+    evict: Arc<Notify>,
+    /// When the last inbound frame arrived on this connection.
+    last_seen: Arc<std::sync::Mutex<Instant>>,
+    /// When the client last relayed a chat message; `None` until it has.
+    last_chat: Arc<std::sync::Mutex<Option<Instant>>>,
+    /// Signalled when a migration's grace period ends with the client still connected.
+    migrated: Arc<Notify>,
+    /// Close reason set by an operator disconnect, signalled through `disconnect`.
+    disconnect_reason: Arc<std::sync::Mutex<Option<String>>>,
+    disconnect: Arc<Notify>,
+    /// Sequence number of the last command written to this connection; starts at 0 on
+    /// every registration.
+    sequence: Arc<AtomicU64>,
+    /// Frame type the client negotiated for commands.
+    frames: FrameFormat,
+    coalescer: Arc<Coalescer>,
 }
 
 #[derive(Debug)]
 pub enum ClientSendError {
     SerializeFailed(serde_json::Error),
     SendFailed(String),
+    TimedOut,
 }
 
 impl std::fmt::Display for ClientSendError {
@@ -82,298 +615,2545 @@ impl std::fmt::Display for ClientSendError {
         match self {
             ClientSendError::SerializeFailed(e) => write!(f, "serialize failed: {e}"),
             ClientSendError::SendFailed(e) => write!(f, "send failed: {e}"),
+            ClientSendError::TimedOut => write!(f, "client queue stayed full past the deadline"),
+        }
+    }
+}
+
+/// Failure of `ClientRegistry::send_tracked` and `send_command_tracked`.
+#[derive(Debug)]
+pub enum TrackedSendError {
+    /// Not registered when the send started, or removed or replaced while it was in flight.
+    ClientGone(i32),
+    Send(i32, ClientSendError),
+}
+
+impl std::fmt::Display for TrackedSendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackedSendError::ClientGone(id) => write!(f, "Client {id} is not registered"),
+            TrackedSendError::Send(id, e) => write!(f, "Failed to send to client {id}: {e}"),
+        }
+    }
+}
+
+impl From<TrackedSendError> for DispatchError {
+    fn from(err: TrackedSendError) -> Self {
+        match err {
+            TrackedSendError::ClientGone(id) => DispatchError::ClientGone(id),
+            err @ TrackedSendError::Send(..) => DispatchError::SendFailed(err.to_string()),
+        }
+    }
+}
+
+/// Why `ClientRegistry::register` refused a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// Another connection heard from within the takeover window already holds the id.
+    DuplicateId(i32),
+    /// `max` clients are already connected.
+    Full { id: i32, max: usize },
+}
+
+impl std::fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegisterError::DuplicateId(id) => write!(f, "client id {id} is already connected"),
+            RegisterError::Full { id, max } => {
+                write!(f, "client {id} refused: {max} clients already connected")
+            }
         }
     }
 }
 
+impl std::error::Error for RegisterError {}
+
+/// Why a broadcast recipient was passed over without an attempt to deliver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The client's outbound queue was full.
+    Backpressure,
+    /// Dispatch to the client was paused by an operator.
+    Paused,
+    /// The client already has as many commands awaiting a result as it may.
+    TooManyPending,
+}
+
+/// Per-client result of a broadcast.
+#[derive(Debug)]
+pub enum BroadcastOutcome {
+    Delivered,
+    Skipped(SkipReason),
+    Failed(ClientSendError),
+}
+
 impl ClientSender {
-    fn new(sender: mpsc::Sender<Message>) -> Self {
-        Self { sender }
+    fn new(
+        id: i32,
+        sender: mpsc::Sender<Outbound>,
+        policy: SlowClientPolicy,
+        frames: FrameFormat,
+        coalesce_commands: Arc<[String]>,
+    ) -> Self {
+        Self {
+            id,
+            sender,
+            policy,
+            stalls: Arc::new(AtomicU32::new(0)),
+            evict: Arc::new(Notify::new()),
+            last_seen: Arc::new(std::sync::Mutex::new(Instant::now())),
+            last_chat: Arc::new(std::sync::Mutex::new(None)),
+            migrated: Arc::new(Notify::new()),
+            disconnect_reason: Arc::new(std::sync::Mutex::new(None)),
+            disconnect: Arc::new(Notify::new()),
+            sequence: Arc::new(AtomicU64::new(0)),
+            frames,
+            coalescer: Arc::new(Coalescer::new(coalesce_commands)),
+        }
+    }
+
+    /// Framer for the connection's forwarding task, writing commands in the frame type the
+    /// client negotiated.
+    fn framer(&self) -> Framer {
+        Framer {
+            sequence: Arc::clone(&self.sequence),
+            frames: self.frames,
+        }
+    }
+
+    /// Record inbound activity on this connection.
+    fn mark_seen(&self) {
+        *self.last_seen.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    fn last_seen(&self) -> Instant {
+        *self.last_seen.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Note an event from the client; only chat counts towards being the most recent.
+    fn mark_event(&self, event: &ComputerEvent) {
+        if matches!(event, ComputerEvent::Chat(_)) {
+            *self.last_chat.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+        }
+    }
+
+    fn last_chat(&self) -> Option<Instant> {
+        *self.last_chat.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Messages queued for the client but not yet written to the socket.
+    fn queue_depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Id of the client this sender delivers to.
+    pub fn id(&self) -> i32 {
+        self.id
     }
 
     /// Send a raw WebSocket message into the client's mpsc channel.
-    pub async fn send_message(&self, message: Message) -> Result<(), ClientSendError> {
-        self.sender
-            .send(message)
-            .await
-            .map_err(|e| ClientSendError::SendFailed(e.to_string()))
+    ///
+    /// Gives up once the policy deadline passes so a stuck client cannot hold the caller.
+    /// The frame is dropped instead of written if still queued once `ttl` has passed.
+    pub async fn send_message_with_ttl(
+        &self,
+        message: Message,
+        ttl: Option<Duration>,
+    ) -> Result<(), ClientSendError> {
+        self.enqueue(Payload::Raw(message), ttl).await
+    }
+
+    async fn enqueue(
+        &self,
+        payload: Payload,
+        ttl: Option<Duration>,
+    ) -> Result<(), ClientSendError> {
+        let coalesce = match &payload {
+            Payload::Command(command) => self.coalescer.key(command),
+            Payload::Raw(_) => None,
+        };
+        let outbound = Outbound::new(payload, ttl, coalesce.clone());
+        match tokio::time::timeout(self.policy.deadline, self.sender.send(outbound)).await {
+            Ok(Ok(())) => {
+                // Recorded only once queued, so a send that fails supersedes nothing.
+                if let Some((key, generation)) = coalesce {
+                    self.coalescer.queued(&key, generation);
+                }
+                self.record_progress(true);
+                Ok(())
+            }
+            Ok(Err(e)) => Err(ClientSendError::SendFailed(e.to_string())),
+            Err(_) => {
+                self.record_progress(false);
+                Err(ClientSendError::TimedOut)
+            }
+        }
+    }
+
+    /// Track consecutive stalls and flag the client once it crosses the policy threshold.
+    fn record_progress(&self, on_time: bool) {
+        if on_time {
+            self.stalls.store(0, Ordering::Relaxed);
+            return;
+        }
+        let strikes = self.stalls.fetch_add(1, Ordering::Relaxed) + 1;
+        if strikes == self.policy.max_strikes {
+            tracing::warn!(
+                "Client {} is slow ({} consecutive stalled sends)",
+                self.id,
+                strikes
+            );
+            if self.policy.evict {
+                self.evict.notify_one();
+            }
+        }
+    }
+
+    /// Resolves once the client has been flagged for eviction.
+    pub async fn evicted(&self) {
+        self.evict.notified().await
+    }
+
+    /// Resolves once the client should be closed because it was migrated away.
+    pub async fn migrated(&self) {
+        self.migrated.notified().await
+    }
+
+    /// Resolves with the reason once an operator asked for the client to be closed.
+    pub async fn disconnected(&self) -> String {
+        self.disconnect.notified().await;
+        self.disconnect_reason
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .unwrap_or_default()
+    }
+
+    /// Enqueue an encoded command, skipping the client if its queue stays full.
+    async fn broadcast_command(
+        &self,
+        command: Arc<serde_json::Map<String, serde_json::Value>>,
+        ttl: Option<Duration>,
+    ) -> BroadcastOutcome {
+        match self.enqueue(Payload::Command(command), ttl).await {
+            Ok(()) => BroadcastOutcome::Delivered,
+            Err(ClientSendError::TimedOut) => BroadcastOutcome::Skipped(SkipReason::Backpressure),
+            Err(err) => BroadcastOutcome::Failed(err),
+        }
     }
 
-    pub async fn send_text(&self, text: String) -> Result<(), ClientSendError> {
-        self.send_message(Message::Text(text)).await
+    /// Send raw bytes to the client as a binary frame, without a sequence number.
+    pub async fn send_binary(&self, bytes: Vec<u8>) -> Result<(), ClientSendError> {
+        self.enqueue(Payload::Raw(Message::Binary(bytes)), None)
+            .await
     }
 
-    /// Serialize and send a typed Lua command to the client.
+    /// Serialize and send a typed Lua command to the client, as a text frame unless it
+    /// negotiated binary ones.
     pub async fn send_lua_command(&self, cmd: &LuaCommand) -> Result<(), ClientSendError> {
-        let serialized = serialize_lua_command(cmd).map_err(ClientSendError::SerializeFailed)?;
-        self.send_text(serialized).await
+        self.send_lua_command_with_ttl(cmd, None).await
+    }
+
+    /// Like `send_lua_command`, but the command is dropped if still queued once `ttl` has passed.
+    pub async fn send_lua_command_with_ttl(
+        &self,
+        cmd: &LuaCommand,
+        ttl: Option<Duration>,
+    ) -> Result<(), ClientSendError> {
+        let encoded = encode_command(cmd).map_err(ClientSendError::SerializeFailed)?;
+        self.enqueue(Payload::Command(encoded), ttl).await
     }
 }
 
 impl ClientRegistry {
-    pub fn new() -> Self {
+    pub fn new(config: RegistryConfig, store: Arc<dyn StateStore>) -> Self {
         Self {
             clients: Arc::new(Mutex::new(HashMap::new())),
+            tombstones: Arc::new(Mutex::new(HashMap::new())),
+            config,
+            store,
+            maintenance: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// Turn maintenance mode on or off; returns the previous state.
+    pub fn set_maintenance(&self, enabled: bool) -> bool {
+        self.maintenance.swap(enabled, Ordering::SeqCst)
+    }
+
+    /// Whether new registrations are currently refused.
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::SeqCst)
+    }
+
+    /// Tell every client to reconnect to `url` and start draining.
+    ///
+    /// Turns maintenance mode on so no new clients register, then closes whoever is still
+    /// connected once `grace` has passed and puts maintenance mode back how it was. Shutdown
+    /// cuts the wait short without closing anyone. Returns the delivery outcome per client.
+    pub async fn migrate(
+        &self,
+        url: String,
+        grace: Duration,
+        shutdown: &ShutdownSignal,
+    ) -> Vec<(i32, BroadcastOutcome)> {
+        let previous = self.set_maintenance(true);
+        let outcomes = self
+            .fan_out(|_| true, &LuaCommand::reconnect(url), None)
+            .await;
+        let registry = self.clone();
+        let shutdown = shutdown.subscribe();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(grace) => {
+                    for entry in registry.clients.lock().await.values() {
+                        entry.sender.migrated.notify_one();
+                    }
+                }
+                _ = shutdown => {}
+            }
+            registry.set_maintenance(previous);
+        });
+        outcomes
+    }
+
     /// Register a fresh client id with its outbound sender and advertised capabilities.
+    ///
+    /// Metadata left behind by a disconnect within the grace period is re-adopted, along with
+    /// its capabilities if the client advertises none. On first contact, such a client is
+    /// hydrated from the state store instead.
+    /// Returns the client's sender handle, which also signals slow-client eviction, or why the
+    /// client was refused: its id is held by a connection heard from within `takeover_after`,
+    /// or `max_clients` are already connected.
     pub async fn register(
         &self,
         id: i32,
-        sender: mpsc::Sender<Message>,
-        capabilities: Vec<crate::events::Capability>,
-    ) {
+        sender: mpsc::Sender<Outbound>,
+        mut capabilities: Vec<crate::events::Capability>,
+        protocol_version: u32,
+        label: Option<String>,
+        frames: FrameFormat,
+    ) -> Result<ClientSender, RegisterError> {
+        let stored = match self.store.load_client(id).await {
+            Ok(stored) => stored,
+            Err(err) => {
+                tracing::warn!("Failed to load stored state for client {}: {}", id, err);
+                None
+            }
+        };
+        let telemetry = stored
+            .as_ref()
+            .and_then(|stored| stored.telemetry.clone())
+            .unwrap_or_default();
+        let sender = ClientSender::new(
+            id,
+            sender,
+            self.config.slow_client,
+            frames,
+            Arc::clone(&self.config.coalesce_commands),
+        );
         let mut clients = self.clients.lock().await;
+        match clients.get(&id) {
+            Some(entry)
+                if !self.config.takeover_after.is_zero()
+                    && !entry.sender.sender.is_closed()
+                    && entry.sender.last_seen().elapsed() < self.config.takeover_after =>
+            {
+                return Err(RegisterError::DuplicateId(id));
+            }
+            None if self.config.max_clients > 0 && clients.len() >= self.config.max_clients => {
+                return Err(RegisterError::Full {
+                    id,
+                    max: self.config.max_clients,
+                });
+            }
+            _ => {}
+        }
+        let previous = match clients.get(&id) {
+            // Reconnected before the old connection was torn down.
+            Some(entry) => Some((entry.meta, entry.capabilities.clone())),
+            None => self
+                .tombstones
+                .lock()
+                .await
+                .remove(&id)
+                .filter(|t| t.expires_at > Instant::now())
+                .map(|t| (t.meta, t.capabilities)),
+        };
+        let meta = match previous {
+            Some((meta, last_capabilities)) => {
+                if capabilities.is_empty()
+                    && self.config.restore_capabilities
+                    && !last_capabilities.is_empty()
+                {
+                    tracing::info!(
+                        "Client {} restored capabilities {:?} from before reconnect",
+                        id,
+                        last_capabilities
+                    );
+                    capabilities = last_capabilities;
+                }
+                let meta = ClientMeta {
+                    reconnects: meta.reconnects + 1,
+                    ..meta
+                };
+                tracing::info!(
+                    "Client {} reconnected (#{}), first seen {:?} ago",
+                    id,
+                    meta.reconnects,
+                    meta.first_seen.elapsed()
+                );
+                meta
+            }
+            None => {
+                if let Some(stored) = stored.filter(|_| capabilities.is_empty()) {
+                    tracing::info!(
+                        "Client {} hydrated from store with capabilities {:?}",
+                        id,
+                        stored.capabilities
+                    );
+                    capabilities = stored.capabilities;
+                }
+                ClientMeta {
+                    first_seen: Instant::now(),
+                    reconnects: 0,
+                }
+            }
+        };
         clients.insert(
             id,
             ClientEntry {
-                sender: ClientSender::new(sender),
-                capabilities,
+                meta,
+                sender: sender.clone(),
+                capabilities: capabilities.clone(),
+                protocol_version,
+                telemetry,
+                label: label.clone(),
+                paused: false,
+                refreshed_at: Instant::now(),
+                sent: 0,
+                last_sent: None,
             },
         );
-        tracing::info!("Client {} registered. Total clients: {}", id, clients.len());
-    }
-
-    /// Remove a client from the registry (usually on disconnect).
-    pub async fn remove(&self, id: i32) {
-        let mut clients = self.clients.lock().await;
-        clients.remove(&id);
         tracing::info!(
-            "Client {} disconnected. Total clients: {}",
-            id,
+            "Client {} registered with protocol v{}. Total clients: {}",
+            client_name(id, label.as_deref()),
+            protocol_version,
             clients.len()
         );
+        drop(clients);
+        self.persist_capabilities(id, &capabilities).await;
+        Ok(sender)
     }
 
-    // pub async fn broadcast(&self, command: Command) {
-    //     let clients = self.clients.lock().await;
-
-    //     if clients.is_empty() {
-    //         tracing::warn!("No clients connected to broadcast command to");
-    //         return;
-    //     }
-
-    //     let command_json = match serde_json::to_string(&command) {
-    //         Ok(json) => json,
-    //         Err(e) => {
-    //             tracing::error!("Failed to serialize command: {}", e);
-    //             return;
-    //         }
-    //     };
-
-    //     tracing::info!(
-    //         "Broadcasting command '{}' (id: {}) to {} client(s)",
-    //         command.name,
-    //         command.id,
-    //         clients.len()
-    //     );
-
-    //     for client in clients.values() {
-    //         if let Err(e) = client.sender.send_text(command_json.clone()).await {
-    //             tracing::error!("Failed to send command to client: {:?}", e);
-    //         }
-    //     }
-    // }
-
-    /// Send a WebSocket message to a single client, if still registered.
-    pub async fn send_to(&self, id: i32, message: Message) -> Result<(), String> {
-        // Clone the sender without holding the lock while awaiting.
-        let sender = {
-            let clients = self.clients.lock().await;
-            clients.get(&id).map(|c| c.sender.clone())
-        };
+    /// Label of a registered client, if it has one.
+    pub async fn label_of(&self, id: i32) -> Option<String> {
+        self.clients
+            .lock()
+            .await
+            .get(&id)
+            .and_then(|entry| entry.label.clone())
+    }
 
-        match sender {
-            Some(tx) => tx
-                .send_message(message)
-                .await
-                .map_err(|e| format!("Failed to send to client {id}: {e:?}")),
-            None => Err(format!("Client {id} is not registered")),
-        }
+    /// `id (label)` for logs, or just the id when the client is unlabelled or unknown.
+    pub async fn display_name(&self, id: i32) -> String {
+        client_name(id, self.label_of(id).await.as_deref())
     }
 
-    /// Refresh capabilities for an already-registered client.
-    pub async fn update_capabilities(
-        &self,
-        id: i32,
-        capabilities: Vec<crate::events::Capability>,
-    ) -> Result<(), String> {
+    /// Set or clear the human-friendly label of a registered client.
+    pub async fn set_label(&self, id: i32, label: Option<String>) -> Result<(), String> {
         let mut clients = self.clients.lock().await;
-        match clients.get_mut(&id) {
-            Some(entry) => {
-                entry.capabilities = capabilities;
-                Ok(())
-            }
-            None => Err(format!("Client {id} is not registered")),
+        let entry = clients
+            .get_mut(&id)
+            .ok_or_else(|| format!("Client {id} is not registered"))?;
+        if entry.label != label {
+            tracing::info!("Client {} labelled {:?}", id, label);
+            entry.label = label;
         }
+        Ok(())
     }
 
-    /// Find any client that advertises the requested capability.
-    pub async fn find_by_capability(
-        &self,
-        capability: crate::events::Capability,
-    ) -> Option<ClientSender> {
-        let clients = self.clients.lock().await;
-        clients
-            .values()
-            .find(|entry| entry.capabilities.contains(&capability))
-            .map(|entry| entry.sender.clone())
+    /// Hold back or resume commands to a client; returns the previous state.
+    ///
+    /// The flag lives on the connection, so a client that reconnects starts unpaused.
+    pub async fn set_paused(&self, id: i32, paused: bool) -> Result<bool, String> {
+        let mut clients = self.clients.lock().await;
+        let entry = clients
+            .get_mut(&id)
+            .ok_or_else(|| format!("Client {id} is not registered"))?;
+        Ok(std::mem::replace(&mut entry.paused, paused))
     }
-}
 
-/// Axum state for the WebSocket endpoint.
-#[derive(Clone)]
-pub struct WebsocketState {
-    registry: ClientRegistry,
-    control: AppComputerControlService,
-}
+    /// Ask the connection of client `id` to close with `reason`; `false` if it is not connected.
+    ///
+    /// The connection deregisters the client and sends the close frame itself.
+    pub async fn disconnect(&self, id: i32, reason: String) -> bool {
+        let clients = self.clients.lock().await;
+        let Some(entry) = clients.get(&id) else {
+            return false;
+        };
+        *entry
+            .sender
+            .disconnect_reason
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(reason);
+        entry.sender.disconnect.notify_one();
+        true
+    }
 
-impl WebsocketState {
-    pub fn new(registry: ClientRegistry, control: AppComputerControlService) -> Self {
-        Self { registry, control }
+    /// Whether commands to the client are currently held back.
+    pub async fn is_paused(&self, id: i32) -> bool {
+        self.clients
+            .lock()
+            .await
+            .get(&id)
+            .is_some_and(|entry| entry.paused)
     }
-}
 
-pub async fn ws_handler(
-    ws: WebSocketUpgrade,
-    State(state): State<WebsocketState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state.clone()))
-}
+    /// Sender of the client with the given label, if one is connected.
+    ///
+    /// Labels need not be unique; when several clients share one, the lowest id wins.
+    pub async fn find_by_label(&self, label: &str) -> Option<ClientSender> {
+        let clients = self.clients.lock().await;
+        clients
+            .iter()
+            .filter(|(_, entry)| entry.label.as_deref() == Some(label))
+            .min_by_key(|(id, _)| **id)
+            .map(|(_, entry)| entry.sender.clone())
+    }
+
+    /// Describe every connected client, ordered by id.
+    pub async fn list(&self) -> Vec<ClientSummary> {
+        let clients = self.clients.lock().await;
+        let mut summaries: Vec<ClientSummary> = clients
+            .iter()
+            .map(|(id, entry)| ClientSummary {
+                id: *id,
+                label: entry.label.clone(),
+                capabilities: entry.capabilities.clone(),
+                protocol_version: entry.protocol_version,
+                paused: entry.paused,
+            })
+            .collect();
+        summaries.sort_by_key(|s| s.id);
+        summaries
+    }
+
+    async fn persist_capabilities(&self, id: i32, capabilities: &[crate::events::Capability]) {
+        if let Err(err) = self.store.save_client(id, capabilities).await {
+            tracing::warn!("Failed to persist client {}: {}", id, err);
+        }
+    }
+
+    /// Remove a client from the registry (usually on disconnect).
+    ///
+    /// Only removes the entry if it still belongs to `connection`, so a stale connection
+    /// closing late cannot evict the client's newer one. The client's metadata is kept as
+    /// a tombstone for the reconnect grace period.
+    /// Returns whether the entry was removed.
+    pub async fn remove(&self, id: i32, connection: &ClientSender) -> bool {
+        let mut clients = self.clients.lock().await;
+        match clients.get(&id) {
+            Some(entry) if entry.sender.sender.same_channel(&connection.sender) => {}
+            _ => {
+                tracing::debug!("Client {} already replaced by a newer connection", id);
+                return false;
+            }
+        }
+        let mut name = id.to_string();
+        if let Some(entry) = clients.remove(&id) {
+            name = client_name(id, entry.label.as_deref());
+            let mut tombstones = self.tombstones.lock().await;
+            tombstones.insert(
+                id,
+                Tombstone {
+                    meta: entry.meta,
+                    capabilities: entry.capabilities,
+                    expires_at: Instant::now() + self.config.reconnect_grace,
+                },
+            );
+            // Stay bounded under churn by forgetting whoever would expire first.
+            while tombstones.len() > self.config.max_tombstones {
+                let Some(oldest) = tombstones
+                    .iter()
+                    .min_by_key(|(_, t)| t.expires_at)
+                    .map(|(id, _)| *id)
+                else {
+                    break;
+                };
+                tombstones.remove(&oldest);
+            }
+        }
+        tracing::info!(
+            "Client {} disconnected. Total clients: {}",
+            name,
+            clients.len()
+        );
+        true
+    }
+
+    /// Copy the full registry state under its locks; serialize the result after they are released.
+    pub async fn snapshot(&self) -> RegistrySnapshot {
+        let now = Instant::now();
+        let mut clients: Vec<ClientSnapshot> = self
+            .clients
+            .lock()
+            .await
+            .iter()
+            .map(|(id, entry)| ClientSnapshot {
+                id: *id,
+                label: entry.label.clone(),
+                capabilities: entry.capabilities.clone(),
+                protocol_version: entry.protocol_version,
+                reconnects: entry.meta.reconnects,
+                first_seen_secs: now.duration_since(entry.meta.first_seen).as_secs_f64(),
+                last_seen_secs: now.duration_since(entry.sender.last_seen()).as_secs_f64(),
+                queue_depth: entry.sender.queue_depth(),
+                stalls: entry.sender.stalls.load(Ordering::Relaxed),
+                paused: entry.paused,
+                sent: entry.sent,
+                last_sent_secs: entry
+                    .last_sent
+                    .map(|at| now.duration_since(at).as_secs_f64()),
+                telemetry: entry.telemetry.clone(),
+            })
+            .collect();
+        let mut tombstones: Vec<TombstoneSnapshot> = self
+            .tombstones
+            .lock()
+            .await
+            .iter()
+            .map(|(id, t)| TombstoneSnapshot {
+                id: *id,
+                capabilities: t.capabilities.clone(),
+                reconnects: t.meta.reconnects,
+                expires_in_secs: t.expires_at.saturating_duration_since(now).as_secs_f64(),
+            })
+            .collect();
+        clients.sort_by_key(|c| c.id);
+        tombstones.sort_by_key(|t| t.id);
+        RegistrySnapshot {
+            clients,
+            tombstones,
+        }
+    }
+
+    /// Write a registry snapshot to `BLUEKING_SNAPSHOT_FILE` whenever SIGUSR1 arrives.
+    #[cfg(unix)]
+    pub fn snapshot_dumper(
+        &self,
+        shutdown: ShutdownSignal,
+    ) -> impl Future<Output = ()> + Send + use<> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let path = crate::config::var(ENV_BLUEKING_SNAPSHOT_FILE)
+            .filter(|p| !p.is_empty())
+            .map(std::path::PathBuf::from)
+            .unwrap_or_else(|| DEFAULT_SNAPSHOT_FILE.into());
+        let registry = self.clone();
+        async move {
+            let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+                Ok(sig) => sig,
+                Err(err) => {
+                    tracing::warn!("Failed to listen for SIGUSR1, snapshots disabled: {}", err);
+                    return;
+                }
+            };
+            let shutdown = shutdown.subscribe();
+            tokio::pin!(shutdown);
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    _ = sigusr1.recv() => {}
+                }
+                let snapshot = registry.snapshot().await;
+                let written = match serde_json::to_vec_pretty(&snapshot) {
+                    Ok(bytes) => tokio::fs::write(&path, bytes)
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(err) => Err(err.to_string()),
+                };
+                match written {
+                    Ok(()) => tracing::info!(
+                        "Wrote snapshot of {} client(s) to {}",
+                        snapshot.clients.len(),
+                        path.display()
+                    ),
+                    Err(err) => tracing::warn!("Failed to write snapshot: {}", err),
+                }
+            }
+        }
+    }
+
+    /// Periodically finalize removal of clients whose reconnect grace period has lapsed.
+    pub fn tombstone_sweeper(
+        &self,
+        shutdown: ShutdownSignal,
+    ) -> impl Future<Output = ()> + Send + use<> {
+        let tombstones = Arc::clone(&self.tombstones);
+        let period = self.config.reconnect_grace.max(Duration::from_secs(1));
+        async move {
+            let mut interval = tokio::time::interval(period);
+            let shutdown = shutdown.subscribe();
+            tokio::pin!(shutdown);
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    _ = interval.tick() => {}
+                }
+                let now = Instant::now();
+                tombstones.lock().await.retain(|id, t| {
+                    let keep = t.expires_at > now;
+                    if !keep {
+                        tracing::debug!("Client {} reconnect grace expired", id);
+                    }
+                    keep
+                });
+            }
+        }
+    }
+
+    /// Send a Lua command to each connected client in `ids`; ids that are no longer connected
+    /// are left out of the result.
+    ///
+    /// Up to `broadcast_concurrency` sends run at once, so a client with a full queue holds
+    /// one slot for at most the slow-client deadline and is then skipped instead of stalling
+    /// delivery to the rest. The command is encoded lazily for recipients and an encoding
+    /// failure is recorded against the recipient rather than aborting the fan-out.
+    /// Returns the outcome for each recipient, ordered by client id. Queued copies are dropped
+    /// once `ttl` passes.
+    pub async fn fan_out_lua_command(
+        &self,
+        ids: &[i32],
+        cmd: &LuaCommand,
+        ttl: Option<Duration>,
+    ) -> Vec<(i32, BroadcastOutcome)> {
+        self.fan_out(|id| ids.contains(&id), cmd, ttl).await
+    }
+
+    /// Ids of the clients matching `query`, ordered by id.
+    pub async fn recipients(&self, query: &ClientQuery) -> Vec<i32> {
+        let clients = self.clients.lock().await;
+        let mut ids: Vec<i32> = clients
+            .iter()
+            .filter(|(_, entry)| query.matches(entry))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Capabilities a connected client is routed by.
+    pub async fn capabilities(&self, id: i32) -> Option<Vec<crate::events::Capability>> {
+        self.clients
+            .lock()
+            .await
+            .get(&id)
+            .map(|entry| entry.capabilities.clone())
+    }
+
+    /// Whether a register from `id` restates exactly what was recorded less than `window` ago.
+    pub async fn is_repeat_register(
+        &self,
+        id: i32,
+        capabilities: &[crate::events::Capability],
+        label: Option<&str>,
+        window: Duration,
+    ) -> bool {
+        self.clients.lock().await.get(&id).is_some_and(|entry| {
+            entry.refreshed_at.elapsed() < window
+                && entry.capabilities.len() == capabilities.len()
+                && capabilities.iter().all(|c| entry.capabilities.contains(c))
+                && label.is_none_or(|label| entry.label.as_deref() == Some(label))
+        })
+    }
+
+    /// Sequence number of the last command written to a connected client.
+    pub async fn last_sequence(&self, id: i32) -> Option<u64> {
+        self.clients
+            .lock()
+            .await
+            .get(&id)
+            .map(|entry| entry.sender.sequence.load(Ordering::Relaxed))
+    }
+
+    /// Whether a client with this id is currently connected.
+    pub async fn is_connected(&self, id: i32) -> bool {
+        self.clients.lock().await.contains_key(&id)
+    }
+
+    async fn fan_out(
+        &self,
+        filter: impl Fn(i32) -> bool,
+        cmd: &LuaCommand,
+        ttl: Option<Duration>,
+    ) -> Vec<(i32, BroadcastOutcome)> {
+        let recipients: Vec<(i32, ClientSender, bool)> = {
+            let clients = self.clients.lock().await;
+            clients
+                .iter()
+                .filter(|(id, _)| filter(**id))
+                .map(|(id, entry)| (*id, entry.sender.clone(), entry.paused))
+                .collect()
+        };
+        let mut encoded: Option<Arc<serde_json::Map<String, serde_json::Value>>> = None;
+        let mut outcomes = Vec::with_capacity(recipients.len());
+        let mut sends = Vec::with_capacity(recipients.len());
+        for (id, sender, paused) in recipients {
+            if paused {
+                tracing::debug!("Broadcast skipped client {}: paused", id);
+                outcomes.push((id, BroadcastOutcome::Skipped(SkipReason::Paused)));
+                continue;
+            }
+            let command = match &encoded {
+                Some(command) => Arc::clone(command),
+                None => match encode_command(cmd) {
+                    Ok(command) => Arc::clone(encoded.insert(command)),
+                    Err(err) => {
+                        tracing::error!("Failed to encode broadcast for client {}: {}", id, err);
+                        outcomes.push((
+                            id,
+                            BroadcastOutcome::Failed(ClientSendError::SerializeFailed(err)),
+                        ));
+                        continue;
+                    }
+                },
+            };
+            sends.push(async move {
+                let outcome = sender.broadcast_command(command, ttl).await;
+                if let BroadcastOutcome::Skipped(reason) = &outcome {
+                    tracing::warn!("Broadcast skipped client {}: {:?}", id, reason);
+                }
+                (id, outcome)
+            });
+        }
+        outcomes.extend(
+            futures::stream::iter(sends)
+                .buffer_unordered(self.config.broadcast_concurrency)
+                .collect::<Vec<_>>()
+                .await,
+        );
+        // Sends finish in any order; sorting keeps the outcomes deterministic.
+        outcomes.sort_unstable_by_key(|(id, _)| *id);
+        outcomes
+    }
+
+    /// Send a WebSocket message to a single client and count it against that connection.
+    ///
+    /// The message is dropped if still queued once `ttl` has passed. The send runs without the
+    /// lock; the count is only recorded if the same connection is still registered afterwards,
+    /// otherwise the client went away mid-send and `ClientGone` is returned.
+    pub async fn send_tracked(
+        &self,
+        id: i32,
+        message: Message,
+        ttl: Option<Duration>,
+    ) -> Result<(), TrackedSendError> {
+        let sender = {
+            let clients = self.clients.lock().await;
+            clients.get(&id).map(|c| c.sender.clone())
+        }
+        .ok_or(TrackedSendError::ClientGone(id))?;
+        sender
+            .send_message_with_ttl(message, ttl)
+            .await
+            .map_err(|e| TrackedSendError::Send(id, e))?;
+        self.track_sent(&sender).await
+    }
+
+    /// Send a command to a client the caller already picked, counting it like `send_tracked`.
+    pub async fn send_command_tracked(
+        &self,
+        sender: &ClientSender,
+        command: &LuaCommand,
+        ttl: Option<Duration>,
+    ) -> Result<(), TrackedSendError> {
+        sender
+            .send_lua_command_with_ttl(command, ttl)
+            .await
+            .map_err(|e| TrackedSendError::Send(sender.id, e))?;
+        self.track_sent(sender).await
+    }
+
+    /// Count a completed send against `sender`'s connection, if it is still the registered one.
+    async fn track_sent(&self, sender: &ClientSender) -> Result<(), TrackedSendError> {
+        let mut clients = self.clients.lock().await;
+        match clients.get_mut(&sender.id) {
+            Some(entry) if entry.sender.sender.same_channel(&sender.sender) => {
+                entry.sent += 1;
+                entry.last_sent = Some(Instant::now());
+                Ok(())
+            }
+            _ => Err(TrackedSendError::ClientGone(sender.id)),
+        }
+    }
+
+    /// Refresh capabilities for an already-registered client.
+    pub async fn update_capabilities(
+        &self,
+        id: i32,
+        capabilities: Vec<crate::events::Capability>,
+    ) -> Result<(), String> {
+        {
+            let mut clients = self.clients.lock().await;
+            match clients.get_mut(&id) {
+                Some(entry) => {
+                    entry.capabilities = capabilities.clone();
+                    entry.refreshed_at = Instant::now();
+                }
+                None => return Err(format!("Client {id} is not registered")),
+            }
+        }
+        self.persist_capabilities(id, &capabilities).await;
+        Ok(())
+    }
+
+    /// Record the latest telemetry snapshot of a registered client.
+    pub async fn update_telemetry(&self, id: i32, telemetry: Telemetry) -> Result<(), String> {
+        match self.clients.lock().await.get_mut(&id) {
+            Some(entry) => entry.telemetry = telemetry.clone(),
+            None => return Err(format!("Client {id} is not registered")),
+        }
+        self.store
+            .save_telemetry(id, &telemetry)
+            .await
+            .map_err(|e| format!("Failed to persist telemetry for client {id}: {e}"))
+    }
+
+    /// Find any client that advertises the requested capability.
+    pub async fn find_by_capability(
+        &self,
+        capability: crate::events::Capability,
+    ) -> Option<ClientSender> {
+        let clients = self.clients.lock().await;
+        clients
+            .values()
+            .find(|entry| entry.capabilities.contains(&capability))
+            .map(|entry| entry.sender.clone())
+    }
+
+    /// Sender of client `id`, if it is connected and advertises `capability`.
+    pub async fn find_with_capability(
+        &self,
+        id: i32,
+        capability: &crate::events::Capability,
+    ) -> Option<ClientSender> {
+        let clients = self.clients.lock().await;
+        clients
+            .get(&id)
+            .filter(|entry| entry.capabilities.contains(capability))
+            .map(|entry| entry.sender.clone())
+    }
+
+    /// The `turn`-th client (modulo their count) advertising `capability`, ordered by id.
+    pub async fn pick_by_capability(
+        &self,
+        capability: &crate::events::Capability,
+        turn: usize,
+    ) -> Option<ClientSender> {
+        let clients = self.clients.lock().await;
+        let mut matching: Vec<(&i32, &ClientEntry)> = clients
+            .iter()
+            .filter(|(_, entry)| entry.capabilities.contains(capability))
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        matching.sort_unstable_by_key(|(id, _)| **id);
+        Some(matching[turn % matching.len()].1.sender.clone())
+    }
+
+    /// The `nth` most recently chatting client advertising `capability`, counting from 0.
+    ///
+    /// Only clients that have relayed a chat message since registering are ranked; telemetry,
+    /// acks and other traffic do not count.
+    pub async fn find_most_recent_by_capability(
+        &self,
+        capability: &crate::events::Capability,
+        nth: usize,
+    ) -> Option<ClientSender> {
+        let clients = self.clients.lock().await;
+        let mut active: Vec<(Instant, &ClientSender)> = clients
+            .values()
+            .filter(|entry| entry.capabilities.contains(capability))
+            .filter_map(|entry| Some((entry.sender.last_chat()?, &entry.sender)))
+            .collect();
+        active.sort_unstable_by_key(|(at, _)| std::cmp::Reverse(*at));
+        active.get(nth).map(|(_, sender)| (*sender).clone())
+    }
+}
+
+/// How strictly the handshake expects `register` to be the first event.
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterPolicy {
+    /// Close the connection on any other event before register.
+    pub strict: bool,
+    /// Events held for dispatch after registration when not strict; one more closes the
+    /// connection.
+    pub max_held: usize,
+}
+
+impl RegisterPolicy {
+    /// Read `BLUEKING_STRICT_REGISTER` and `BLUEKING_PRE_REGISTER_EVENTS`.
+    pub fn from_env() -> Self {
+        Self {
+            strict: crate::env_or(ENV_BLUEKING_STRICT_REGISTER, true),
+            max_held: crate::env_or(
+                ENV_BLUEKING_PRE_REGISTER_EVENTS,
+                DEFAULT_PRE_REGISTER_EVENTS,
+            ),
+        }
+    }
+}
+
+/// Application-level liveness checks for clients with the `ping` capability.
+#[derive(Debug, Clone, Copy)]
+pub struct AppPingPolicy {
+    /// Send a `ping` command after this long without an inbound frame.
+    pub idle: Duration,
+    /// Disconnect the client if the ping's result has not arrived by then.
+    pub timeout: Duration,
+}
+
+impl AppPingPolicy {
+    /// Read `BLUEKING_APP_PING_IDLE_SECS` and `BLUEKING_APP_PING_TIMEOUT_SECS`; `None` when
+    /// pings are disabled.
+    pub fn from_env() -> Option<Self> {
+        match crate::env_or(ENV_BLUEKING_APP_PING_IDLE_SECS, DEFAULT_APP_PING_IDLE_SECS) {
+            0 => None,
+            idle => Some(Self {
+                idle: Duration::from_secs(idle),
+                timeout: Duration::from_secs(crate::env_or(
+                    ENV_BLUEKING_APP_PING_TIMEOUT_SECS,
+                    DEFAULT_APP_PING_TIMEOUT_SECS,
+                )),
+            }),
+        }
+    }
+
+    /// How often a connection checks whether a ping is due or overdue.
+    fn check_interval(&self) -> Duration {
+        (self.idle.min(self.timeout) / 2).max(Duration::from_secs(1))
+    }
+}
+
+/// What a connection's periodic ping check calls for.
+#[derive(Debug)]
+enum PingCheck {
+    Wait,
+    /// Send this ping, now outstanding.
+    Send(LuaCommand),
+    /// The ping with this id went unanswered past the timeout.
+    Overdue(String),
+}
+
+/// The application-level ping awaiting its result on one connection, if any.
+#[derive(Debug, Default)]
+struct PingTracker {
+    outstanding: Option<(String, Instant)>,
+}
+
+impl PingTracker {
+    /// Check at `now` whether the outstanding ping is overdue, or a new one is due because a
+    /// `pingable` client has been quiet since `last_seen` for the idle period.
+    fn check(
+        &mut self,
+        policy: &AppPingPolicy,
+        last_seen: Instant,
+        pingable: bool,
+        now: Instant,
+    ) -> PingCheck {
+        match &self.outstanding {
+            Some((id, sent)) if now.duration_since(*sent) > policy.timeout => {
+                PingCheck::Overdue(id.clone())
+            }
+            Some(_) => PingCheck::Wait,
+            None if pingable && now.duration_since(last_seen) >= policy.idle => {
+                let ping = LuaCommand::ping();
+                self.outstanding = Some((ping.id().to_string(), now));
+                PingCheck::Send(ping)
+            }
+            None => PingCheck::Wait,
+        }
+    }
+
+    /// Whether `command_id` is the outstanding ping.
+    fn is_outstanding(&self, command_id: &str) -> bool {
+        self.outstanding
+            .as_ref()
+            .is_some_and(|(id, _)| id == command_id)
+    }
+
+    /// The ping's result arrived.
+    fn answered(&mut self) {
+        self.outstanding = None;
+    }
+}
+
+/// Axum state for the WebSocket endpoint.
+pub struct WebsocketState<B: Brain> {
+    registry: ClientRegistry,
+    control: ComputerEventService<B>,
+    metrics: Arc<Metrics>,
+    allowed_origins: Option<Arc<Vec<String>>>,
+    event_timeout: Duration,
+    /// Permits for events in flight, shared by every connection.
+    event_slots: Arc<tokio::sync::Semaphore>,
+    write_timeout: Duration,
+    app_ping: Option<AppPingPolicy>,
+    /// Time between the shutdown notice and closing each connection.
+    shutdown_grace: Duration,
+    shutdown: ShutdownSignal,
+    /// Secret clients must present to register, when one is configured.
+    client_token: Option<Arc<str>>,
+    register: RegisterPolicy,
+}
+
+impl<B: Brain> Clone for WebsocketState<B> {
+    fn clone(&self) -> Self {
+        Self {
+            registry: self.registry.clone(),
+            control: self.control.clone(),
+            metrics: Arc::clone(&self.metrics),
+            allowed_origins: self.allowed_origins.clone(),
+            event_timeout: self.event_timeout,
+            event_slots: Arc::clone(&self.event_slots),
+            write_timeout: self.write_timeout,
+            app_ping: self.app_ping,
+            shutdown_grace: self.shutdown_grace,
+            shutdown: self.shutdown.clone(),
+            client_token: self.client_token.clone(),
+            register: self.register,
+        }
+    }
+}
+
+impl<B: Brain> WebsocketState<B> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        registry: ClientRegistry,
+        control: ComputerEventService<B>,
+        metrics: Arc<Metrics>,
+        allowed_origins: Option<Arc<Vec<String>>>,
+        event_timeout: Duration,
+        event_slots: Arc<tokio::sync::Semaphore>,
+        write_timeout: Duration,
+        app_ping: Option<AppPingPolicy>,
+        shutdown_grace: Duration,
+        shutdown: ShutdownSignal,
+        client_token: Option<Arc<str>>,
+        register: RegisterPolicy,
+    ) -> Self {
+        Self {
+            registry,
+            control,
+            metrics,
+            allowed_origins,
+            event_timeout,
+            event_slots,
+            write_timeout,
+            app_ping,
+            shutdown_grace,
+            shutdown,
+            client_token,
+            register,
+        }
+    }
+
+    /// Requests without an `Origin` header (in-game computers) are always allowed;
+    /// browser origins must be on the allowlist when one is configured.
+    fn origin_allowed(&self, headers: &HeaderMap) -> bool {
+        let (Some(allowed), Some(origin)) = (&self.allowed_origins, headers.get(ORIGIN)) else {
+            return true;
+        };
+        origin
+            .to_str()
+            .is_ok_and(|origin| allowed.iter().any(|a| a == origin))
+    }
+}
+
+/// Optional `/cc?id=<i32>&caps=chat,run_code&token=...` parameters for clients that can't build
+/// a register frame themselves.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct ConnectParams {
+    id: Option<i32>,
+    caps: Option<String>,
+    /// Client token, for clients whose register frame does not carry one.
+    token: Option<String>,
+}
+
+impl ConnectParams {
+    /// The registration implied by the query string, if it names a client id.
+    fn registration(&self) -> Option<Registration> {
+        let id = self.id?;
+        let capabilities = self
+            .caps
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(|c| Capability::from(c.to_string()))
+            .collect();
+        Some(Registration {
+            id,
+            capabilities,
+            protocol_version: default_protocol_version(),
+            label: None,
+            frames: FrameFormat::Text,
+            token: self.token.clone(),
+        })
+    }
+}
+
+pub async fn ws_handler<B: Brain>(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(params): Query<ConnectParams>,
+    State(state): State<WebsocketState<B>>,
+) -> Response {
+    if !state.origin_allowed(&headers) {
+        tracing::warn!(
+            "Rejected WebSocket upgrade from origin {:?}",
+            headers.get(ORIGIN)
+        );
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    // Refuse oversized messages while reading them, before they are buffered in full;
+    // `decode_event` then bounds their nesting.
+    ws.max_message_size(MAX_EVENT_BYTES)
+        .on_upgrade(move |socket| handle_socket(socket, state.clone(), params))
+}
+
+pub async fn metrics_handler<B: Brain>(State(state): State<WebsocketState<B>>) -> String {
+    state.metrics.render()
+}
+
+/// Readiness probe: unready while the brain circuit is open, so load balancers route chat
+/// elsewhere during a brain outage, and once shutdown has begun.
+pub async fn readyz_handler<B: Brain>(State(state): State<WebsocketState<B>>) -> Response {
+    if state.shutdown.is_triggered() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down").into_response();
+    }
+    if !state.control.brain_available() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "brain unavailable").into_response();
+    }
+    (StatusCode::OK, "ready").into_response()
+}
+
+/// Query parameters accepted by `/monitor`, for browsers that cannot set headers.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct MonitorParams {
+    token: Option<String>,
+}
+
+/// Read-only feed of dispatched actions for admin dashboards.
+///
+/// Requires the admin token as `Authorization: Bearer` or `?token=`; refused outright when no
+/// token is configured.
+pub async fn monitor_handler<B: Brain>(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(params): Query<MonitorParams>,
+    State(state): State<WebsocketState<B>>,
+) -> Response {
+    let Some(expected) = crate::grpc::admin_token_from_env() else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or(params.token.as_deref());
+    if provided != Some(expected.as_str()) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let feed = state.control.dispatch().subscribe_monitor();
+    ws.on_upgrade(move |socket| stream_monitor(socket, feed, state.shutdown))
+}
+
+/// Forward monitor records until the viewer leaves, falls behind, or the server shuts down.
+async fn stream_monitor(
+    socket: WebSocket,
+    mut feed: tokio::sync::broadcast::Receiver<Arc<str>>,
+    shutdown: ShutdownSignal,
+) {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let (mut sink, mut stream) = socket.split();
+    let shutdown = shutdown.subscribe();
+    tokio::pin!(shutdown);
+    tracing::info!("Monitor attached");
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            inbound = stream.next() => match inbound {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {} // read-only feed
+            },
+            record = feed.recv() => match record {
+                Ok(record) => {
+                    if sink.send(Message::Text(record.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Dropping monitor that fell {} records behind", missed);
+                    break;
+                }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+    let _ = sink.send(Message::Close(None)).await;
+    tracing::info!("Monitor detached");
+}
 
 /// Drive a single WebSocket connection: register, then forward frames as `ComputerEvent`s.
-async fn handle_socket(socket: WebSocket, state: WebsocketState) {
+async fn handle_socket<B: Brain>(
+    socket: WebSocket,
+    state: WebsocketState<B>,
+    params: ConnectParams,
+) {
     let (sender, mut receiver) = socket.split();
     let sender = Arc::new(AsyncMutex::new(sender));
     let registry = state.registry.clone();
-    let control = state.control.clone();
+    let control = EventStack::new(
+        state.control.clone(),
+        Arc::clone(&state.metrics),
+        Arc::clone(&state.event_slots),
+        state.event_timeout,
+    );
+
+    let client_token = state.client_token.as_deref();
+    let (registration, early_events) = match handshake(
+        &mut receiver,
+        &params,
+        &registry,
+        client_token,
+        state.register,
+    )
+    .await
+    {
+        Ok(accepted) => accepted,
+        Err(err) => return err.refuse(&state.metrics, &sender).await,
+    };
+    let Registration {
+        id: client_id,
+        capabilities,
+        protocol_version,
+        label,
+        frames,
+        token: _,
+    } = registration;
+    let register_event = ComputerEvent::Register {
+        id: client_id,
+        capabilities: capabilities.clone(),
+        protocol_version,
+        label: label.clone(),
+        frames,
+        token: None,
+    };
+
+    // Register client
+    let (tx, rx) = mpsc::channel::<Outbound>(8);
+    let client = match registry
+        .register(client_id, tx, capabilities, protocol_version, label, frames)
+        .await
+    {
+        Ok(client) => client,
+        Err(err) => {
+            return HandshakeError::from(err)
+                .refuse(&state.metrics, &sender)
+                .await;
+        }
+    };
+    let negotiated = registry.capabilities(client_id).await.unwrap_or_default();
+    if let Err(err) = client
+        .send_lua_command(&LuaCommand::registered(
+            client_id,
+            negotiated,
+            protocol_version,
+        ))
+        .await
+    {
+        tracing::warn!(
+            "Failed to acknowledge registration of client {}: {}",
+            client_id,
+            err
+        );
+    }
+    // Inform the control service about registration for bookkeeping.
+    dispatch_event(&control, register_event, client_id).await;
+    for event in early_events {
+        dispatch_event(&control, event, client_id).await;
+    }
+
+    // Forward messages from other tasks to this websocket
+    let stalled = Arc::new(Notify::new());
+    tokio::spawn(forward_outbound(
+        client_id,
+        rx,
+        Arc::clone(&sender),
+        client.framer(),
+        Arc::clone(&client.coalescer),
+        state.control.dispatch().pending().clone(),
+        state.write_timeout,
+        Arc::clone(&stalled),
+    ));
+
+    // Handle incoming messages
+    use tokio::time::timeout;
+    const CLIENT_TIMEOUT_SECS: u64 = 120;
+    const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+    let draining = state.shutdown.subscribe();
+    tokio::pin!(draining);
+    let app_ping = state.app_ping;
+    let mut ping_check = tokio::time::interval(
+        app_ping.map_or(Duration::from_secs(60), |policy| policy.check_interval()),
+    );
+    ping_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut pings = PingTracker::default();
+    // When the connection closes, once shutdown has been announced to the client.
+    let mut drain_deadline: Option<tokio::time::Instant> = None;
+
+    loop {
+        let msg = tokio::select! {
+            _ = &mut draining, if drain_deadline.is_none() => {
+                let grace = state.shutdown_grace;
+                let notice = LuaCommand::server_notice(
+                    format!(
+                        "Server shutting down ({})",
+                        state.shutdown.reason().unwrap_or(ShutdownReason::Terminate).as_str()
+                    ),
+                    grace.as_secs(),
+                );
+                if let Err(err) = client.send_lua_command(&notice).await {
+                    tracing::warn!("Failed to send shutdown notice to client {}: {}", client_id, err);
+                }
+                drain_deadline = Some(tokio::time::Instant::now() + grace);
+                continue;
+            }
+            _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(tokio::time::Instant::now)),
+                if drain_deadline.is_some() =>
+            {
+                let frame = shutdown_close_frame(state.shutdown.reason());
+                tracing::info!(
+                    "Closing client {} for shutdown: {}",
+                    registry.display_name(client_id).await,
+                    frame.reason
+                );
+                if registry.remove(client_id, &client).await {
+                    dispatch_event(
+                        &control,
+                        ComputerEvent::Deregister {
+                            id: client_id,
+                            reason: DeregisterReason::Closed,
+                        },
+                        client_id,
+                    )
+                    .await;
+                }
+                let _ = timeout(CLOSE_TIMEOUT, async {
+                    sender.lock().await.send(Message::Close(Some(frame))).await
+                })
+                .await;
+                break;
+            }
+            msg = timeout(Duration::from_secs(CLIENT_TIMEOUT_SECS), receiver.next()) => msg,
+            _ = ping_check.tick(), if app_ping.is_some() => {
+                let Some(policy) = app_ping else { continue };
+                let pingable = registry
+                    .capabilities(client_id)
+                    .await
+                    .unwrap_or_default()
+                    .contains(&Capability::Ping);
+                match pings.check(&policy, client.last_seen(), pingable, Instant::now()) {
+                    PingCheck::Overdue(id) => {
+                        tracing::warn!(
+                            "Client {} did not answer ping {} within {:?}",
+                            registry.display_name(client_id).await,
+                            id,
+                            policy.timeout
+                        );
+                        if registry.remove(client_id, &client).await {
+                            dispatch_event(
+                                &control,
+                                ComputerEvent::Deregister {
+                                    id: client_id,
+                                    reason: DeregisterReason::TimedOut,
+                                },
+                                client_id,
+                            )
+                            .await;
+                        }
+                        let _ = timeout(CLOSE_TIMEOUT, async {
+                            sender.lock().await.send(Message::Close(None)).await
+                        })
+                        .await;
+                        break;
+                    }
+                    PingCheck::Send(ping) => {
+                        if let Err(err) = client.send_lua_command(&ping).await {
+                            tracing::warn!("Failed to ping client {}: {}", client_id, err);
+                        }
+                    }
+                    PingCheck::Wait => {}
+                }
+                continue;
+            }
+            _ = client.migrated() => {
+                tracing::info!(
+                    "Closing client {}: migration grace period over",
+                    registry.display_name(client_id).await
+                );
+                if registry.remove(client_id, &client).await {
+                    dispatch_event(
+                        &control,
+                        ComputerEvent::Deregister {
+                            id: client_id,
+                            reason: DeregisterReason::Migrated,
+                        },
+                        client_id,
+                    )
+                    .await;
+                }
+                let frame = CloseFrame {
+                    code: close_code::RESTART,
+                    reason: "migrated".into(),
+                };
+                let _ = timeout(CLOSE_TIMEOUT, async {
+                    sender.lock().await.send(Message::Close(Some(frame))).await
+                })
+                .await;
+                break;
+            }
+            reason = client.disconnected() => {
+                tracing::warn!(
+                    "Closing client {} on operator request: {}",
+                    registry.display_name(client_id).await,
+                    reason
+                );
+                if registry.remove(client_id, &client).await {
+                    dispatch_event(
+                        &control,
+                        ComputerEvent::Deregister {
+                            id: client_id,
+                            reason: DeregisterReason::Disconnected,
+                        },
+                        client_id,
+                    )
+                    .await;
+                }
+                let frame = CloseFrame {
+                    code: close_code::POLICY,
+                    reason: reason.into(),
+                };
+                let _ = timeout(CLOSE_TIMEOUT, async {
+                    sender.lock().await.send(Message::Close(Some(frame))).await
+                })
+                .await;
+                break;
+            }
+            _ = client.evicted() => {
+                tracing::warn!("Evicting slow client {}", registry.display_name(client_id).await);
+                if registry.remove(client_id, &client).await {
+                    dispatch_event(
+                        &control,
+                        ComputerEvent::Deregister {
+                            id: client_id,
+                            reason: DeregisterReason::Slow,
+                        },
+                        client_id,
+                    )
+                    .await;
+                }
+                // The forwarder may be stuck on this very socket; don't wait on it for long.
+                let _ = timeout(CLOSE_TIMEOUT, async {
+                    sender.lock().await.send(Message::Close(None)).await
+                })
+                .await;
+                break;
+            }
+            _ = stalled.notified() => {
+                tracing::warn!(
+                    "Client {} stalled: socket write blocked for {:?}",
+                    registry.display_name(client_id).await,
+                    state.write_timeout
+                );
+                if registry.remove(client_id, &client).await {
+                    dispatch_event(
+                        &control,
+                        ComputerEvent::Deregister {
+                            id: client_id,
+                            reason: DeregisterReason::Stalled,
+                        },
+                        client_id,
+                    )
+                    .await;
+                }
+                // The socket is not draining, so a close frame would block too; just drop it.
+                break;
+            }
+        };
+        if let Ok(Some(Ok(frame))) = &msg {
+            client.mark_seen();
+            trace_frame("inbound", &client_id, frame);
+        }
+        match msg {
+            Ok(Some(Ok(Message::Text(text)))) => match decode_event(text.as_bytes()) {
+                Ok((ComputerEvent::Query { what }, _)) => {
+                    let started = Instant::now();
+                    let reply = LuaCommand::query_reply(what, protocol_version);
+                    if let Err(err) = client.send_lua_command(&reply).await {
+                        tracing::warn!("Failed to answer query from client {}: {}", client_id, err);
+                    }
+                    state
+                        .metrics
+                        .events
+                        .record(EventKind::Query, started.elapsed());
+                }
+                Ok((ComputerEvent::CommandResult(result), _))
+                    if pings.is_outstanding(&result.command_id) =>
+                {
+                    tracing::trace!("Client {} answered ping {}", client_id, result.command_id);
+                    pings.answered();
+                }
+                Ok((ComputerEvent::CommandAck { command_id }, _))
+                    if pings.is_outstanding(&command_id) => {}
+                Ok((event, client_ts)) => {
+                    client.mark_event(&event);
+                    dispatch_client_event(&control, ClientEvent::new(client_id, event, client_ts))
+                        .await
+                }
+                Err(e) => tracing::error!("Invalid event: {}", e),
+            },
+            Ok(Some(Ok(Message::Close(_)))) => {
+                tracing::info!(
+                    "Client {} disconnected",
+                    registry.display_name(client_id).await
+                );
+                if registry.remove(client_id, &client).await {
+                    dispatch_event(
+                        &control,
+                        ComputerEvent::Deregister {
+                            id: client_id,
+                            reason: DeregisterReason::Closed,
+                        },
+                        client_id,
+                    )
+                    .await;
+                }
+                break;
+            }
+            Ok(Some(Ok(_))) => {} // ignore non-text frames
+            Ok(Some(Err(e))) => {
+                tracing::warn!("WebSocket error for client {}: {}", client_id, e);
+            }
+            Ok(None) => {
+                tracing::info!(
+                    "Client {} stream ended",
+                    registry.display_name(client_id).await
+                );
+                if registry.remove(client_id, &client).await {
+                    dispatch_event(
+                        &control,
+                        ComputerEvent::Deregister {
+                            id: client_id,
+                            reason: DeregisterReason::Closed,
+                        },
+                        client_id,
+                    )
+                    .await;
+                }
+                break;
+            }
+            Err(_) => {
+                tracing::warn!(
+                    "Client {} timed out (no activity)",
+                    registry.display_name(client_id).await
+                );
+                if registry.remove(client_id, &client).await {
+                    dispatch_event(
+                        &control,
+                        ComputerEvent::Deregister {
+                            id: client_id,
+                            reason: DeregisterReason::TimedOut,
+                        },
+                        client_id,
+                    )
+                    .await;
+                }
+                // Attempt to close the socket gracefully.
+                let _ = sender.lock().await.send(Message::Close(None)).await;
+                break;
+            }
+        }
+    }
+}
+
+/// Send `{"type":"error","detail":...}` describing a malformed frame, then close the socket.
+async fn send_error_frame(sender: &AsyncMutex<SplitSink<WebSocket, Message>>, detail: &str) {
+    let frame = serde_json::json!({ "type": "error", "detail": detail }).to_string();
+    let mut sink = sender.lock().await;
+    let _ = sink.send(Message::Text(frame)).await;
+    let _ = sink
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::PROTOCOL,
+            reason: "invalid register frame".into(),
+        })))
+        .await;
+}
+
+/// Tell a client its register was refused, then close the socket with `code`.
+async fn reject_registration(
+    sender: &AsyncMutex<SplitSink<WebSocket, Message>>,
+    reason: String,
+    code: u16,
+) {
+    let mut sink = sender.lock().await;
+    match serialize_lua_command(&LuaCommand::register_rejected(reason.clone())) {
+        Ok(text) => {
+            let _ = sink.send(Message::Text(text)).await;
+        }
+        Err(err) => tracing::error!("Failed to encode register rejection: {}", err),
+    }
+    let _ = sink
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })))
+        .await;
+}
+
+/// Write a client's queued frames to its socket until the queue closes or a write fails.
+///
+/// A write blocked past `write_timeout` means the client stopped reading; `stalled` is
+/// signalled and forwarding stops.
+#[allow(clippy::too_many_arguments)]
+async fn forward_outbound<S: Sink<Message> + Unpin>(
+    client_id: i32,
+    mut rx: mpsc::Receiver<Outbound>,
+    sender: Arc<AsyncMutex<S>>,
+    framer: Framer,
+    coalescer: Arc<Coalescer>,
+    pending: crate::actions::PendingCommands,
+    write_timeout: Duration,
+    stalled: Arc<Notify>,
+) {
+    while let Some(outbound) = rx.recv().await {
+        if coalescer
+            .drop_superseded(&outbound, &pending, client_id)
+            .await
+        {
+            continue;
+        }
+        if let Some(waited) = outbound.expired() {
+            tracing::warn!(
+                "Dropping stale frame for client {}: queued {:?} ago, ttl {:?}",
+                client_id,
+                waited,
+                outbound.ttl.unwrap_or_default()
+            );
+            continue;
+        }
+        let msg = match framer.frame(outbound.payload) {
+            Ok(msg) => msg,
+            Err(err) => {
+                tracing::error!("Failed to encode frame for client {}: {}", client_id, err);
+                continue;
+            }
+        };
+        trace_frame("outbound", &client_id, &msg);
+        let mut sink = sender.lock().await;
+        match tokio::time::timeout(write_timeout, sink.send(msg)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => break,
+            Err(_) => {
+                stalled.notify_one();
+                break;
+            }
+        }
+    }
+}
+
+/// Format a client for logs as `id (label)`, falling back to the bare id.
+fn client_name(id: i32, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{id} ({label})"),
+        None => id.to_string(),
+    }
+}
+
+/// Close frame telling a client why the server is going away, so it can decide when to reconnect.
+fn shutdown_close_frame(reason: Option<ShutdownReason>) -> CloseFrame<'static> {
+    let reason = reason.unwrap_or(ShutdownReason::Terminate);
+    CloseFrame {
+        code: close_code::AWAY,
+        reason: format!("shutdown: {}", reason.as_str()).into(),
+    }
+}
+
+/// Log a raw frame at trace level, cut to `BLUEKING_TRACE_FRAME_CHARS` characters.
+///
+/// Costs a single level check unless trace logging is enabled.
+fn trace_frame(direction: &str, client: &dyn std::fmt::Display, frame: &Message) {
+    if !tracing::enabled!(tracing::Level::TRACE) {
+        return;
+    }
+    static MAX_CHARS: std::sync::OnceLock<usize> = std::sync::OnceLock::new();
+    let max = *MAX_CHARS
+        .get_or_init(|| crate::env_or(ENV_BLUEKING_TRACE_FRAME_CHARS, DEFAULT_TRACE_FRAME_CHARS));
+    let hex = |bytes: &[u8]| -> String {
+        bytes
+            .iter()
+            .take(max / 2)
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    };
+    let (kind, len, body) = match frame {
+        Message::Text(text) => ("text", text.len(), text.chars().take(max).collect()),
+        Message::Binary(bytes) => ("binary", bytes.len(), hex(bytes)),
+        Message::Ping(bytes) => ("ping", bytes.len(), hex(bytes)),
+        Message::Pong(bytes) => ("pong", bytes.len(), hex(bytes)),
+        Message::Close(close) => ("close", 0, format!("{close:?}")),
+    };
+    tracing::trace!(
+        "{} {} frame, client {} ({} bytes): {}",
+        direction,
+        kind,
+        client,
+        len,
+        body
+    );
+}
+
+/// Identity a client presented during the register handshake.
+struct Registration {
+    /// Accepted range is `1..=i32::MAX`.
+    id: i32,
+    capabilities: Vec<Capability>,
+    protocol_version: u32,
+    label: Option<String>,
+    frames: FrameFormat,
+    /// Client token presented in the register frame or query string.
+    token: Option<String>,
+}
+
+/// Why a connection was turned away before registering.
+#[derive(Debug)]
+enum HandshakeError {
+    /// The connection closed, errored or sent a non-text frame first.
+    NotText,
+    /// The register frame was not valid JSON.
+    InvalidJson(String),
+    /// The register frame was JSON but not a valid `ComputerEvent`.
+    InvalidEvent(String),
+    /// Strict mode and the first event was something other than `register`.
+    NotRegister,
+    /// No register frame arrived before the deadline.
+    Timeout,
+    /// The client speaks a protocol version outside `SUPPORTED_PROTOCOL_VERSIONS`.
+    UnsupportedVersion { id: i32, version: u32 },
+    /// The client picked an id of 0 or below, most likely a bug in its firmware.
+    InvalidId(i32),
+    /// A client token is configured and the client presented none or the wrong one.
+    Unauthorized(i32),
+    /// Another live connection already holds the id.
+    DuplicateId(i32),
+    /// The registration was well-formed but the server is not taking clients right now.
+    Rejected { id: i32, reason: RejectReason },
+}
+
+/// Why a well-formed registration was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RejectReason {
+    /// Maintenance mode is on, e.g. while migrating clients to another server.
+    Maintenance,
+    /// `BLUEKING_MAX_CLIENTS` clients are already connected.
+    Full(usize),
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::Maintenance => write!(f, "maintenance"),
+            RejectReason::Full(max) => write!(f, "server full ({max} clients)"),
+        }
+    }
+}
+
+impl From<RegisterError> for HandshakeError {
+    fn from(err: RegisterError) -> Self {
+        match err {
+            RegisterError::DuplicateId(id) => HandshakeError::DuplicateId(id),
+            RegisterError::Full { id, max } => HandshakeError::Rejected {
+                id,
+                reason: RejectReason::Full(max),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::NotText => write!(f, "expected register message"),
+            HandshakeError::InvalidJson(detail) | HandshakeError::InvalidEvent(detail) => {
+                write!(f, "{detail}")
+            }
+            HandshakeError::NotRegister => write!(f, "expected register event"),
+            HandshakeError::Timeout => write!(f, "timed out waiting for register message"),
+            HandshakeError::UnsupportedVersion { version, .. } => write!(
+                f,
+                "unsupported protocol version {}; supported {}..={}",
+                version,
+                SUPPORTED_PROTOCOL_VERSIONS.start(),
+                SUPPORTED_PROTOCOL_VERSIONS.end()
+            ),
+            HandshakeError::InvalidId(id) => {
+                write!(f, "invalid client id {id}; ids must be positive")
+            }
+            HandshakeError::Unauthorized(_) => write!(f, "invalid or missing client token"),
+            HandshakeError::DuplicateId(id) => write!(f, "client id {id} is already connected"),
+            HandshakeError::Rejected { reason, .. } => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl HandshakeError {
+    /// Metric bucket for the failure; `None` for refusals that are not the client's fault.
+    fn failure(&self) -> Option<HandshakeFailure> {
+        match self {
+            HandshakeError::NotText => Some(HandshakeFailure::NotText),
+            HandshakeError::InvalidJson(_) => Some(HandshakeFailure::InvalidJson),
+            HandshakeError::InvalidEvent(_) => Some(HandshakeFailure::InvalidEvent),
+            HandshakeError::NotRegister => Some(HandshakeFailure::NotRegister),
+            HandshakeError::Timeout => Some(HandshakeFailure::TimedOut),
+            HandshakeError::UnsupportedVersion { .. } => Some(HandshakeFailure::UnsupportedVersion),
+            HandshakeError::InvalidId(_) => Some(HandshakeFailure::InvalidId),
+            HandshakeError::Unauthorized(_) => Some(HandshakeFailure::Unauthorized),
+            HandshakeError::DuplicateId(_) => Some(HandshakeFailure::DuplicateId),
+            HandshakeError::Rejected { .. } => None,
+        }
+    }
+
+    /// Close code sent with the refusal; `None` when the socket is simply dropped.
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            HandshakeError::NotText | HandshakeError::Timeout => None,
+            HandshakeError::InvalidJson(_)
+            | HandshakeError::InvalidEvent(_)
+            | HandshakeError::NotRegister
+            | HandshakeError::UnsupportedVersion { .. }
+            | HandshakeError::InvalidId(_) => Some(close_code::PROTOCOL),
+            HandshakeError::Unauthorized(_) | HandshakeError::DuplicateId(_) => {
+                Some(close_code::POLICY)
+            }
+            HandshakeError::Rejected { .. } => Some(close_code::AGAIN),
+        }
+    }
+
+    fn log(&self) {
+        match self {
+            HandshakeError::InvalidJson(_) | HandshakeError::InvalidEvent(_) => {
+                tracing::error!("Invalid register message: {}", self)
+            }
+            HandshakeError::NotRegister => tracing::error!("First message must be register event"),
+            HandshakeError::UnsupportedVersion { id, version } => tracing::warn!(
+                "Rejecting client {} with unsupported protocol v{}",
+                id,
+                version
+            ),
+            HandshakeError::InvalidId(_) => tracing::warn!("Rejecting client: {}", self),
+            HandshakeError::Unauthorized(id) | HandshakeError::DuplicateId(id) => {
+                tracing::warn!("Rejecting client {}: {}", id, self)
+            }
+            HandshakeError::Rejected { id, reason } => {
+                tracing::info!("Refusing client {} registration: {}", id, reason)
+            }
+            _ => tracing::error!("Handshake failed: {}", self),
+        }
+    }
+
+    /// Log and count the failure, then tell the client why it was turned away.
+    async fn refuse(self, metrics: &Metrics, sender: &AsyncMutex<SplitSink<WebSocket, Message>>) {
+        self.log();
+        if let Some(failure) = self.failure() {
+            metrics.handshake.record(failure);
+        }
+        self.respond(sender).await;
+    }
+
+    /// Tell the client why it was turned away and close the socket.
+    async fn respond(&self, sender: &AsyncMutex<SplitSink<WebSocket, Message>>) {
+        let Some(code) = self.close_code() else {
+            return;
+        };
+        match self {
+            HandshakeError::InvalidJson(detail) | HandshakeError::InvalidEvent(detail) => {
+                send_error_frame(sender, detail).await
+            }
+            _ => reject_registration(sender, self.to_string(), code).await,
+        }
+    }
+}
+
+/// Run the register handshake and vet the registration it yields.
+async fn handshake(
+    receiver: &mut SplitStream<WebSocket>,
+    params: &ConnectParams,
+    registry: &ClientRegistry,
+    client_token: Option<&str>,
+    policy: RegisterPolicy,
+) -> Result<(Registration, Vec<ComputerEvent>), HandshakeError> {
+    let (registration, early_events) =
+        await_register(receiver, params.registration(), policy).await?;
+    let presented = registration.token.as_deref().or(params.token.as_deref());
+    vet(
+        &registration,
+        presented,
+        client_token,
+        registry.in_maintenance(),
+    )?;
+    Ok((registration, early_events))
+}
+
+/// Check a registration against the server's rules before it reaches the registry.
+fn vet(
+    registration: &Registration,
+    presented_token: Option<&str>,
+    client_token: Option<&str>,
+    maintenance: bool,
+) -> Result<(), HandshakeError> {
+    let id = registration.id;
+    if id <= 0 {
+        return Err(HandshakeError::InvalidId(id));
+    }
+    if !SUPPORTED_PROTOCOL_VERSIONS.contains(&registration.protocol_version) {
+        return Err(HandshakeError::UnsupportedVersion {
+            id,
+            version: registration.protocol_version,
+        });
+    }
+    if let Some(expected) = client_token
+        && presented_token != Some(expected)
+    {
+        return Err(HandshakeError::Unauthorized(id));
+    }
+    if maintenance {
+        return Err(HandshakeError::Rejected {
+            id,
+            reason: RejectReason::Maintenance,
+        });
+    }
+    Ok(())
+}
+
+/// Wait for the register frame.
+///
+/// A register frame always wins. When the query string implies a register event, it is used if
+/// no frame arrives promptly or the first frame is some other event, which is then returned so
+/// it can be dispatched after registration. Without one, any other event before register is a
+/// protocol error that closes the connection, unless the policy is not strict. Then up to
+/// `policy.max_held` such events are held and returned for dispatch after registration;
+/// register must still arrive before the deadline.
+async fn await_register<S>(
+    receiver: &mut S,
+    mut fallback: Option<Registration>,
+    policy: RegisterPolicy,
+) -> Result<(Registration, Vec<ComputerEvent>), HandshakeError>
+where
+    S: futures::Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    const REGISTER_TIMEOUT: Duration = Duration::from_secs(30);
+    // How long a query-string client gets to send a register frame of its own.
+    const QUERY_REGISTER_GRACE: Duration = Duration::from_secs(2);
+    let RegisterPolicy { strict, max_held } = policy;
+    let mut held = Vec::new();
+
+    let wait = if fallback.is_some() {
+        QUERY_REGISTER_GRACE
+    } else {
+        REGISTER_TIMEOUT
+    };
+    let deadline = tokio::time::Instant::now() + wait;
+
+    // Expect first message to be register
+    loop {
+        let first = tokio::time::timeout_at(deadline, receiver.next()).await;
+        if let Ok(Some(Ok(frame))) = &first {
+            trace_frame("inbound", &"unregistered", frame);
+        }
+        let register_msg = match first {
+            Ok(Some(Ok(Message::Text(msg)))) => msg,
+            Ok(_) => return Err(HandshakeError::NotText),
+            Err(_) => {
+                let Some(registration) = fallback else {
+                    return Err(HandshakeError::Timeout);
+                };
+                tracing::debug!(
+                    "No register frame from client {}, using query parameters",
+                    registration.id
+                );
+                return Ok((registration, held));
+            }
+        };
+
+        // Keep malformed JSON apart from a schema mismatch so the client can tell them apart.
+        let event = match decode_event(register_msg.as_bytes()) {
+            Ok((event, _)) => event,
+            Err(err) => {
+                if let Some(registration) = fallback {
+                    tracing::error!("Invalid event: {}", err);
+                    return Ok((registration, held));
+                }
+                return Err(match err {
+                    EventDecodeError::Invalid(_) => HandshakeError::InvalidEvent(err.to_string()),
+                    _ => HandshakeError::InvalidJson(err.to_string()),
+                });
+            }
+        };
+
+        // A query-string registration already identifies the client, so its early events stand.
+        match (event, fallback.take()) {
+            (
+                ComputerEvent::Register {
+                    id,
+                    capabilities,
+                    protocol_version,
+                    label,
+                    frames,
+                    token,
+                },
+                _,
+            ) => {
+                return Ok((
+                    Registration {
+                        id,
+                        capabilities,
+                        protocol_version,
+                        label,
+                        frames,
+                        token,
+                    },
+                    held,
+                ));
+            }
+            (event, Some(registration)) => {
+                held.push(event);
+                return Ok((registration, held));
+            }
+            (event, None) if !strict && held.len() < max_held => {
+                tracing::warn!("Holding event received before register: {:?}", event);
+                held.push(event);
+            }
+            (_, None) => return Err(HandshakeError::NotRegister),
+        }
+    }
+}
+
+/// Characters of each raw frame logged at trace level (`BLUEKING_DEBUG=trace`).
+const ENV_BLUEKING_TRACE_FRAME_CHARS: &str = "BLUEKING_TRACE_FRAME_CHARS";
+const DEFAULT_TRACE_FRAME_CHARS: usize = 1024;
+
+/// Comma-separated command names whose queued duplicates the forwarder collapses, keeping
+/// only the newest per name and target. Empty (the default) sends everything.
+const ENV_BLUEKING_COALESCE_COMMANDS: &str = "BLUEKING_COALESCE_COMMANDS";
+
+/// How fresh command ids are minted: `uuid` (default) or `counter`.
+const ENV_BLUEKING_COMMAND_IDS: &str = "BLUEKING_COMMAND_IDS";
+
+/// Scheme used by the `LuaCommand` constructors to mint correlation ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandIdScheme {
+    /// Random UUID v4, unique across restarts.
+    Uuid,
+    /// Short monotonic `u64`, restarting at 1 with the process.
+    Counter,
+}
+
+impl std::str::FromStr for CommandIdScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "uuid" => Ok(CommandIdScheme::Uuid),
+            "counter" => Ok(CommandIdScheme::Counter),
+            other => Err(format!("unknown command id scheme: {other}")),
+        }
+    }
+}
+
+/// Mint a command id using the scheme configured via `BLUEKING_COMMAND_IDS`.
+pub(crate) fn next_command_id() -> String {
+    static SCHEME: std::sync::OnceLock<CommandIdScheme> = std::sync::OnceLock::new();
+    static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    match SCHEME.get_or_init(|| crate::env_or(ENV_BLUEKING_COMMAND_IDS, CommandIdScheme::Uuid)) {
+        CommandIdScheme::Uuid => uuid::Uuid::new_v4().to_string(),
+        CommandIdScheme::Counter => COUNTER.fetch_add(1, Ordering::Relaxed).to_string(),
+    }
+}
+
+/// JSON payload for a chat message Lua command.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MessageArgs {
+    pub message: String,
+}
+
+/// JSON payload for a private message to a single player.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct WhisperArgs {
+    pub target: String,
+    pub message: String,
+}
+
+/// Largest `write_file` contents, in bytes, the server will dispatch.
+pub const MAX_WRITE_FILE_BYTES: usize = 32 * 1024;
+
+/// How `write_file` treats an existing file.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteMode {
+    /// Replace the file's contents.
+    #[default]
+    Overwrite,
+    /// Add to the end of the file.
+    Append,
+}
+
+/// JSON payload for writing a file on the computer.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct WriteFileArgs {
+    /// Path on the computer's filesystem; must not contain `..` components.
+    pub path: String,
+    pub contents: String,
+    #[serde(default)]
+    pub mode: WriteMode,
+}
+
+impl WriteFileArgs {
+    /// Reject empty or escaping paths and contents over `MAX_WRITE_FILE_BYTES`.
+    fn validate(&self) -> Result<(), String> {
+        if self.path.trim().is_empty() {
+            return Err("write_file path is empty".to_string());
+        }
+        if self.path.split(['/', '\\']).any(|part| part == "..") {
+            return Err(format!(
+                "write_file path {:?} escapes its directory",
+                self.path
+            ));
+        }
+        if self.contents.len() > MAX_WRITE_FILE_BYTES {
+            return Err(format!(
+                "write_file contents of {} bytes exceed {MAX_WRITE_FILE_BYTES}",
+                self.contents.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// JSON payload for a batch Lua command: sub-commands executed in order.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BatchArgs {
+    pub commands: Vec<LuaCommand>,
+}
+
+/// JSON payload acknowledging a successful register handshake.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RegisteredArgs {
+    pub client_id: i32,
+    /// Capabilities the server will route by, which may differ from those advertised.
+    pub negotiated_capabilities: Vec<Capability>,
+    pub protocol_version: u32,
+    pub server_version: String,
+}
+
+/// JSON payload explaining why a register handshake was refused.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RegisterRejectedArgs {
+    pub reason: String,
+}
+
+/// JSON payload announcing a server shutdown.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ServerNoticeArgs {
+    pub message: String,
+    /// Seconds until the server closes the connection.
+    pub shutdown_in_secs: u64,
+}
+
+/// JSON payload naming the server a client should move to.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ReconnectArgs {
+    /// WebSocket URL of the new server, e.g. `ws://host:3000/api/ws`.
+    pub url: String,
+}
+
+/// JSON payload answering a client's `query` event.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct QueryReplyArgs {
+    pub what: QueryTopic,
+    /// Wire names of the commands this server may send.
+    pub commands: Vec<String>,
+    /// Protocol version negotiated at register.
+    pub protocol_version: u32,
+}
+
+/// Commands sent to Lua clients, tagged by `name` in the JSON envelope.
+///
+/// Follows the same wire-name policy as `ComputerEvent`: renames keep the old name as a serde
+/// alias, since commands are also accepted from gRPC callers and templates.
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub enum LuaCommand {
+    Message {
+        id: String,
+        args: MessageArgs,
+    },
+    /// Chat message visible only to `args.target`.
+    Whisper {
+        id: String,
+        args: WhisperArgs,
+    },
+    /// Runs its sub-commands in order and reports a single result under the batch id.
+    Batch {
+        id: String,
+        args: BatchArgs,
+    },
+    /// Handshake acknowledgement; informational, clients don't ack or report a result.
+    Registered {
+        id: String,
+        args: RegisteredArgs,
+    },
+    /// Negative handshake acknowledgement, sent just before the server closes the connection.
+    RegisterRejected {
+        id: String,
+        args: RegisterRejectedArgs,
+    },
+    /// Ask the client to re-report its capabilities with an `update_capabilities` event;
+    /// informational like the handshake replies, so no ack or result.
+    QueryCapabilities {
+        id: String,
+    },
+    /// Ask the client to reconnect to another server; informational, no ack or result.
+    Reconnect {
+        id: String,
+        args: ReconnectArgs,
+    },
+    /// Answer to a client's `query` event; informational, no ack or result.
+    QueryReply {
+        id: String,
+        args: QueryReplyArgs,
+    },
+    /// Write a small file on the computer; the outcome comes back as a `command_result`.
+    WriteFile {
+        id: String,
+        args: WriteFileArgs,
+    },
+    /// No-op liveness probe for idle clients; answered with a `command_result`.
+    Ping {
+        id: String,
+    },
+    /// Announces that the server is going away; informational, no ack or result.
+    ServerNotice {
+        id: String,
+        args: ServerNoticeArgs,
+    },
+}
 
-    // Expect first message to be register
-    let register_msg = receiver.next().await;
-    let register_msg = match register_msg {
-        Some(Ok(Message::Text(msg))) => msg,
-        _ => {
-            tracing::error!("Expected register message");
-            return;
+impl LuaCommand {
+    /// Wire names of every command type, as carried in the `name` tag.
+    pub const NAMES: &[&str] = &[
+        "message",
+        "whisper",
+        "batch",
+        "registered",
+        "register_rejected",
+        "query_capabilities",
+        "reconnect",
+        "query_reply",
+        "write_file",
+        "ping",
+        "server_notice",
+    ];
+
+    /// Correlation id echoed back by the client in acks and results.
+    pub fn id(&self) -> &str {
+        match self {
+            LuaCommand::Message { id, .. }
+            | LuaCommand::Whisper { id, .. }
+            | LuaCommand::Batch { id, .. }
+            | LuaCommand::Registered { id, .. }
+            | LuaCommand::RegisterRejected { id, .. }
+            | LuaCommand::QueryCapabilities { id }
+            | LuaCommand::Reconnect { id, .. }
+            | LuaCommand::QueryReply { id, .. }
+            | LuaCommand::WriteFile { id, .. }
+            | LuaCommand::Ping { id }
+            | LuaCommand::ServerNotice { id, .. } => id,
         }
-    };
+    }
 
-    let register_event: ComputerEvent = match serde_json::from_str(&register_msg) {
-        Ok(event) => event,
-        Err(e) => {
-            tracing::error!("Invalid register message: {}", e);
-            return;
+    /// Construct a private message to `target` with a fresh id.
+    pub fn whisper(target: String, message: String) -> Self {
+        LuaCommand::Whisper {
+            id: next_command_id(),
+            args: WhisperArgs { target, message },
         }
-    };
+    }
 
-    let (client_id, capabilities) = match register_event.clone() {
-        ComputerEvent::Register { id, capabilities } => (id, capabilities),
-        _ => {
-            tracing::error!("First message must be register event");
-            return;
+    /// Construct a batch of sub-commands with a fresh id.
+    pub fn batch(commands: Vec<LuaCommand>) -> Self {
+        LuaCommand::Batch {
+            id: next_command_id(),
+            args: BatchArgs { commands },
         }
-    };
+    }
 
-    // Register client
-    let (tx, mut rx) = mpsc::channel::<Message>(8);
-    registry.register(client_id, tx, capabilities).await;
-    // Inform the control service about registration for bookkeeping.
-    dispatch_event(&control, register_event, client_id).await;
+    /// Construct the acknowledgement of a successful register handshake.
+    pub fn registered(
+        client_id: i32,
+        negotiated_capabilities: Vec<Capability>,
+        protocol_version: u32,
+    ) -> Self {
+        LuaCommand::Registered {
+            id: next_command_id(),
+            args: RegisteredArgs {
+                client_id,
+                negotiated_capabilities,
+                protocol_version,
+                server_version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+        }
+    }
 
-    // Forward messages from other tasks to this websocket
-    let sender_forward = Arc::clone(&sender);
-    tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if sender_forward.lock().await.send(msg).await.is_err() {
-                break;
-            }
+    /// Construct the refusal of a register handshake.
+    pub fn register_rejected(reason: String) -> Self {
+        LuaCommand::RegisterRejected {
+            id: next_command_id(),
+            args: RegisterRejectedArgs { reason },
         }
-    });
+    }
 
-    // Handle incoming messages
-    use tokio::time::{Duration, timeout};
-    const CLIENT_TIMEOUT_SECS: u64 = 120;
+    /// Construct a request for the client to re-report its capabilities.
+    pub fn query_capabilities() -> Self {
+        LuaCommand::QueryCapabilities {
+            id: next_command_id(),
+        }
+    }
 
-    loop {
-        let msg = timeout(Duration::from_secs(CLIENT_TIMEOUT_SECS), receiver.next()).await;
-        match msg {
-            Ok(Some(Ok(Message::Text(text)))) => {
-                match serde_json::from_str::<ComputerEvent>(&text) {
-                    Ok(event) => dispatch_event(&control, event, client_id).await,
-                    Err(e) => tracing::error!("Invalid event: {}", e),
+    /// Construct a request for the client to move to the server at `url`.
+    pub fn reconnect(url: String) -> Self {
+        LuaCommand::Reconnect {
+            id: next_command_id(),
+            args: ReconnectArgs { url },
+        }
+    }
+
+    /// Construct a file write with a fresh id.
+    pub fn write_file(path: String, contents: String, mode: WriteMode) -> Self {
+        LuaCommand::WriteFile {
+            id: next_command_id(),
+            args: WriteFileArgs {
+                path,
+                contents,
+                mode,
+            },
+        }
+    }
+
+    /// Construct a shutdown notice giving clients `shutdown_in_secs` before they are closed.
+    pub fn server_notice(message: String, shutdown_in_secs: u64) -> Self {
+        LuaCommand::ServerNotice {
+            id: next_command_id(),
+            args: ServerNoticeArgs {
+                message,
+                shutdown_in_secs,
+            },
+        }
+    }
+
+    /// Construct a liveness probe with a fresh id.
+    pub fn ping() -> Self {
+        LuaCommand::Ping {
+            id: next_command_id(),
+        }
+    }
+
+    /// Construct the answer to a client's `query` about `what`.
+    pub fn query_reply(what: QueryTopic, protocol_version: u32) -> Self {
+        let commands = match what {
+            QueryTopic::Commands => LuaCommand::NAMES.iter().map(|n| n.to_string()).collect(),
+        };
+        LuaCommand::QueryReply {
+            id: next_command_id(),
+            args: QueryReplyArgs {
+                what,
+                commands,
+                protocol_version,
+            },
+        }
+    }
+
+    /// Check structural rules the clients rely on; batches must not nest, and file writes
+    /// must stay within their size limit and below their directory.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            LuaCommand::Message { .. }
+            | LuaCommand::Whisper { .. }
+            | LuaCommand::Registered { .. }
+            | LuaCommand::RegisterRejected { .. }
+            | LuaCommand::QueryCapabilities { .. }
+            | LuaCommand::Reconnect { .. }
+            | LuaCommand::QueryReply { .. }
+            | LuaCommand::Ping { .. }
+            | LuaCommand::ServerNotice { .. } => Ok(()),
+            LuaCommand::WriteFile { args, .. } => args.validate(),
+            LuaCommand::Batch { id, args } => {
+                if args
+                    .commands
+                    .iter()
+                    .any(|c| matches!(c, LuaCommand::Batch { .. }))
+                {
+                    return Err(format!("batch {id} contains a nested batch"));
                 }
-            }
-            Ok(Some(Ok(Message::Close(_)))) => {
-                tracing::info!("Client {} disconnected", client_id);
-                registry.remove(client_id).await;
-                dispatch_event(
-                    &control,
-                    ComputerEvent::Deregister {
-                        id: client_id,
-                        timed_out: false,
-                    },
-                    client_id,
-                )
-                .await;
-                break;
-            }
-            Ok(Some(Ok(_))) => {} // ignore non-text frames
-            Ok(Some(Err(e))) => {
-                tracing::warn!("WebSocket error for client {}: {}", client_id, e);
-            }
-            Ok(None) => {
-                tracing::info!("Client {} stream ended", client_id);
-                registry.remove(client_id).await;
-                dispatch_event(
-                    &control,
-                    ComputerEvent::Deregister {
-                        id: client_id,
-                        timed_out: false,
-                    },
-                    client_id,
-                )
-                .await;
-                break;
-            }
-            Err(_) => {
-                tracing::warn!("Client {} timed out (no activity)", client_id);
-                registry.remove(client_id).await;
-                dispatch_event(
-                    &control,
-                    ComputerEvent::Deregister {
-                        id: client_id,
-                        timed_out: true,
-                    },
-                    client_id,
-                )
-                .await;
-                // Attempt to close the socket gracefully.
-                let _ = sender.lock().await.send(Message::Close(None)).await;
-                break;
+                args.commands.iter().try_for_each(LuaCommand::validate)
             }
         }
     }
-}
 
-/// JSON payload for a chat message Lua command.
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct MessageArgs {
-    pub message: String,
-}
-
-/// Commands sent to Lua clients, tagged by `name` in the JSON envelope.
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-#[serde(tag = "name", rename_all = "snake_case")]
-pub enum LuaCommand {
-    Message { id: String, args: MessageArgs },
-}
+    /// Capability a recipient must advertise to be sent this command, e.g. `file_transfer`
+    /// for `write_file`; `None` when any client may receive it.
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            LuaCommand::WriteFile { .. } => Some(Capability::FileTransfer),
+            LuaCommand::Batch { args, .. } => args
+                .commands
+                .iter()
+                .find_map(LuaCommand::required_capability),
+            _ => None,
+        }
+    }
 
-impl LuaCommand {
     /// Construct a chat message command with a fresh id.
     pub fn chat_message(message: String) -> Self {
         LuaCommand::Message {
-            id: uuid::Uuid::new_v4().to_string(),
+            id: next_command_id(),
             args: MessageArgs { message },
         }
     }
@@ -384,9 +3164,894 @@ pub fn serialize_lua_command(cmd: &LuaCommand) -> Result<String, serde_json::Err
     serde_json::to_string(cmd)
 }
 
+/// Per-connection event pipeline built in `handle_socket`.
+///
+/// Every event gets an overall deadline; abandoning the call also aborts its handler task.
+/// Sheddable events beyond the shared in-flight limit fail fast instead of queueing behind
+/// it, while the rest bypass the limit so they are never lost to load.
+struct EventStack<S> {
+    sheddable: EventMetrics<LoadShed<ConcurrencyLimit<Timeout<S>>>>,
+    reliable: EventMetrics<Timeout<S>>,
+}
+
+impl<S: Clone> Clone for EventStack<S> {
+    fn clone(&self) -> Self {
+        Self {
+            sheddable: self.sheddable.clone(),
+            reliable: self.reliable.clone(),
+        }
+    }
+}
+
+impl<S> EventStack<S>
+where
+    S: tower::Service<ClientEvent, Response = ()> + Clone,
+    S::Error: Into<tower::BoxError>,
+{
+    fn new(
+        service: S,
+        metrics: Arc<Metrics>,
+        slots: Arc<tokio::sync::Semaphore>,
+        timeout: Duration,
+    ) -> Self {
+        let sheddable = ServiceBuilder::new()
+            .layer(EventMetricsLayer::new(Arc::clone(&metrics)))
+            .load_shed()
+            .layer(GlobalConcurrencyLimitLayer::with_semaphore(slots))
+            .timeout(timeout)
+            .service(service.clone());
+        let reliable = ServiceBuilder::new()
+            .layer(EventMetricsLayer::new(metrics))
+            .timeout(timeout)
+            .service(service);
+        Self {
+            sheddable,
+            reliable,
+        }
+    }
+
+    /// Process one event through the pipeline its kind calls for.
+    async fn call(&self, event: ClientEvent) -> Result<(), tower::BoxError> {
+        if event.event.sheddable() {
+            self.sheddable.clone().oneshot(event).await
+        } else {
+            self.reliable.clone().oneshot(event).await
+        }
+    }
+}
+
 /// Helper to send one `ComputerEvent` into the Tower service.
-async fn dispatch_event(service: &AppComputerControlService, event: ComputerEvent, client_id: i32) {
-    if let Err(err) = service.clone().oneshot(event).await {
-        tracing::error!("Failed to process event for client {}: {}", client_id, err);
+async fn dispatch_event<B: Brain>(
+    service: &EventStack<ComputerEventService<B>>,
+    event: ComputerEvent,
+    client_id: i32,
+) {
+    dispatch_client_event(service, ClientEvent::new(client_id, event, None)).await
+}
+
+/// Like `dispatch_event`, for an event already stamped with its receive time.
+async fn dispatch_client_event<B: Brain>(
+    service: &EventStack<ComputerEventService<B>>,
+    event: ClientEvent,
+) {
+    let client_id = event.client_id;
+    if let Err(err) = service.call(event).await {
+        if err.is::<tower::timeout::error::Elapsed>() {
+            tracing::error!("Event processing for client {} timed out", client_id);
+        } else if err.is::<Overloaded>() {
+            tracing::warn!(
+                "Shedding event from client {}: server overloaded",
+                client_id
+            );
+        } else {
+            tracing::error!("Failed to process event for client {}: {}", client_id, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::convert::Infallible;
+    use std::sync::atomic::AtomicUsize;
+
+    fn client_event(event: serde_json::Value) -> ClientEvent {
+        ClientEvent::new(1, serde_json::from_value(event).unwrap(), None)
+    }
+
+    #[tokio::test]
+    async fn saturated_pipeline_sheds_only_chat_and_telemetry() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&handled);
+        let service = tower::service_fn(move |_: ClientEvent| {
+            let counter = Arc::clone(&counter);
+            async move {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Ok::<(), Infallible>(())
+            }
+        });
+        // No free slots: the shared in-flight limit is already reached.
+        let stack = EventStack::new(
+            service,
+            Arc::new(Metrics::default()),
+            Arc::new(tokio::sync::Semaphore::new(0)),
+            Duration::from_secs(1),
+        );
+
+        for event in [
+            json!({"type": "chat", "username": "alice", "message": "hi"}),
+            json!({"type": "telemetry", "data": {"fuel": 10}}),
+        ] {
+            let err = stack.call(client_event(event)).await.unwrap_err();
+            assert!(err.is::<Overloaded>(), "{err}");
+        }
+        for event in [
+            json!({"type": "register", "id": 1}),
+            json!({"type": "command_result", "command_id": "c1"}),
+            json!({"type": "deregister", "id": 1, "reason": "disconnected"}),
+        ] {
+            stack.call(client_event(event)).await.unwrap();
+        }
+        assert_eq!(handled.load(Ordering::Relaxed), 3);
+    }
+
+    fn write(path: &str, contents: String) -> LuaCommand {
+        LuaCommand::write_file(path.to_string(), contents, WriteMode::Append)
+    }
+
+    #[test]
+    fn commands_round_trip_through_json() {
+        let cases = [
+            (
+                write("logs/out.txt", "line\n".to_string()),
+                "write_file",
+                json!({"path": "logs/out.txt", "contents": "line\n", "mode": "append"}),
+                Some(Capability::FileTransfer),
+            ),
+            (
+                LuaCommand::whisper("Steve".to_string(), "psst".to_string()),
+                "whisper",
+                json!({"target": "Steve", "message": "psst"}),
+                None,
+            ),
+            (
+                LuaCommand::server_notice("Server shutting down (terminate)".to_string(), 10),
+                "server_notice",
+                json!({"message": "Server shutting down (terminate)", "shutdown_in_secs": 10}),
+                None,
+            ),
+            (
+                LuaCommand::reconnect("ws://next:3000/api/ws".to_string()),
+                "reconnect",
+                json!({"url": "ws://next:3000/api/ws"}),
+                None,
+            ),
+        ];
+        for (command, name, args, capability) in cases {
+            let encoded = serde_json::to_value(&command).unwrap();
+            assert_eq!(encoded["name"], name);
+            assert_eq!(encoded["args"], args, "{name}");
+
+            let decoded: LuaCommand = serde_json::from_value(encoded.clone()).unwrap();
+            assert_eq!(decoded.id(), command.id(), "{name}");
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), encoded);
+            assert_eq!(decoded.required_capability(), capability, "{name}");
+            assert!(decoded.validate().is_ok(), "{name}");
+        }
+    }
+
+    #[test]
+    fn write_file_rejects_oversized_contents_and_escaping_paths() {
+        assert!(
+            write("a.txt", "x".repeat(MAX_WRITE_FILE_BYTES))
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            write("a.txt", "x".repeat(MAX_WRITE_FILE_BYTES + 1))
+                .validate()
+                .is_err()
+        );
+        for path in [
+            "../startup",
+            "disk/../../rom",
+            "disk\\..\\startup",
+            "..",
+            " ",
+        ] {
+            assert!(write(path, String::new()).validate().is_err(), "{path}");
+        }
+        assert!(write("disk/..hidden", String::new()).validate().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcast_skips_a_full_queue_without_stalling_the_rest() {
+        let registry = ClientRegistry::new(
+            RegistryConfig {
+                slow_client: SlowClientPolicy {
+                    deadline: Duration::from_millis(100),
+                    max_strikes: 3,
+                    evict: false,
+                },
+                broadcast_concurrency: 2,
+                ..RegistryConfig::from_env()
+            },
+            Arc::new(crate::store::MemoryStore::default()),
+        );
+        let mut queues = Vec::new();
+        for id in [3, 1, 2] {
+            let (tx, rx) = mpsc::channel(1);
+            registry
+                .register(id, tx, vec![Capability::Chat], 1, None, FrameFormat::Text)
+                .await
+                .unwrap();
+            queues.push((id, rx));
+        }
+        // Client 2 already has a queued message nobody reads.
+        registry
+            .send_tracked(2, Message::Text("backlog".to_string()), None)
+            .await
+            .unwrap();
+
+        let started = tokio::time::Instant::now();
+        let outcomes = registry
+            .fan_out_lua_command(
+                &[1, 2, 3],
+                &LuaCommand::chat_message("hi".to_string()),
+                None,
+            )
+            .await;
+        assert_eq!(started.elapsed(), Duration::from_millis(100));
+        let ids: Vec<i32> = outcomes.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        assert!(matches!(outcomes[0].1, BroadcastOutcome::Delivered));
+        assert!(matches!(
+            outcomes[1].1,
+            BroadcastOutcome::Skipped(SkipReason::Backpressure)
+        ));
+        assert!(matches!(outcomes[2].1, BroadcastOutcome::Delivered));
+        for (id, mut rx) in queues {
+            assert!(rx.try_recv().is_ok(), "client {id}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn migrate_restores_maintenance_mode_after_the_grace_period() {
+        let registry = ClientRegistry::new(
+            RegistryConfig::from_env(),
+            Arc::new(crate::store::MemoryStore::default()),
+        );
+        let (tx, mut rx) = mpsc::channel(8);
+        let client = registry
+            .register(1, tx, Vec::new(), 1, None, FrameFormat::Text)
+            .await
+            .unwrap();
+        let shutdown = ShutdownSignal::new();
+
+        let outcomes = registry
+            .migrate("ws://next".to_string(), Duration::from_secs(5), &shutdown)
+            .await;
+        assert!(matches!(outcomes[..], [(1, BroadcastOutcome::Delivered)]));
+        assert!(rx.try_recv().is_ok());
+        assert!(registry.in_maintenance());
+
+        tokio::time::timeout(Duration::from_secs(6), client.migrated())
+            .await
+            .expect("remaining clients are closed once the grace period ends");
+        tokio::task::yield_now().await;
+        assert!(!registry.in_maintenance());
+
+        // An operator's maintenance mode outlives the migration; shutdown ends the wait early
+        // without closing anyone.
+        registry.set_maintenance(true);
+        registry
+            .migrate("ws://next".to_string(), Duration::from_secs(60), &shutdown)
+            .await;
+        shutdown.trigger(ShutdownReason::Terminate);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(120), client.migrated())
+                .await
+                .is_err()
+        );
+        assert!(registry.in_maintenance());
+    }
+
+    fn registration(id: i32, protocol_version: u32) -> Registration {
+        Registration {
+            id,
+            capabilities: vec![Capability::Chat],
+            protocol_version,
+            label: None,
+            frames: FrameFormat::Text,
+            token: None,
+        }
+    }
+
+    #[test]
+    fn vet_refuses_bad_ids_versions_tokens_and_maintenance() {
+        let ok = registration(7, default_protocol_version());
+        assert!(vet(&ok, None, None, false).is_ok());
+        assert!(vet(&ok, Some("s3cret"), Some("s3cret"), false).is_ok());
+
+        assert!(matches!(
+            vet(&registration(0, 1), None, None, false),
+            Err(HandshakeError::InvalidId(0))
+        ));
+        assert!(matches!(
+            vet(&registration(7, u32::MAX), None, None, false),
+            Err(HandshakeError::UnsupportedVersion { id: 7, .. })
+        ));
+        for presented in [None, Some("guess")] {
+            assert!(matches!(
+                vet(&ok, presented, Some("s3cret"), false),
+                Err(HandshakeError::Unauthorized(7))
+            ));
+        }
+        assert!(matches!(
+            vet(&ok, None, None, true),
+            Err(HandshakeError::Rejected {
+                id: 7,
+                reason: RejectReason::Maintenance
+            })
+        ));
+    }
+
+    #[test]
+    fn each_handshake_error_has_a_metric_and_close_code() {
+        let cases = [
+            (
+                HandshakeError::NotText,
+                Some(HandshakeFailure::NotText),
+                None,
+            ),
+            (
+                HandshakeError::InvalidJson("eof".to_string()),
+                Some(HandshakeFailure::InvalidJson),
+                Some(close_code::PROTOCOL),
+            ),
+            (
+                HandshakeError::InvalidEvent("unknown variant".to_string()),
+                Some(HandshakeFailure::InvalidEvent),
+                Some(close_code::PROTOCOL),
+            ),
+            (
+                HandshakeError::NotRegister,
+                Some(HandshakeFailure::NotRegister),
+                Some(close_code::PROTOCOL),
+            ),
+            (
+                HandshakeError::Timeout,
+                Some(HandshakeFailure::TimedOut),
+                None,
+            ),
+            (
+                HandshakeError::UnsupportedVersion { id: 1, version: 9 },
+                Some(HandshakeFailure::UnsupportedVersion),
+                Some(close_code::PROTOCOL),
+            ),
+            (
+                HandshakeError::InvalidId(-1),
+                Some(HandshakeFailure::InvalidId),
+                Some(close_code::PROTOCOL),
+            ),
+            (
+                HandshakeError::Unauthorized(1),
+                Some(HandshakeFailure::Unauthorized),
+                Some(close_code::POLICY),
+            ),
+            (
+                HandshakeError::DuplicateId(1),
+                Some(HandshakeFailure::DuplicateId),
+                Some(close_code::POLICY),
+            ),
+            (
+                HandshakeError::Rejected {
+                    id: 1,
+                    reason: RejectReason::Full(2),
+                },
+                None,
+                Some(close_code::AGAIN),
+            ),
+        ];
+        for (error, failure, code) in cases {
+            assert_eq!(error.failure(), failure, "{error:?}");
+            assert_eq!(error.close_code(), code, "{error:?}");
+            assert!(!error.to_string().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn registry_refuses_live_duplicates_and_clients_past_the_limit() {
+        let config = RegistryConfig {
+            max_clients: 1,
+            takeover_after: Duration::from_secs(60),
+            ..RegistryConfig::from_env()
+        };
+        let registry = ClientRegistry::new(config, Arc::new(crate::store::MemoryStore::default()));
+        let register = |id| {
+            let (tx, rx) = mpsc::channel(1);
+            let registry = registry.clone();
+            async move {
+                let registered = registry
+                    .register(id, tx, Vec::new(), 1, None, FrameFormat::Text)
+                    .await;
+                (registered, rx)
+            }
+        };
+
+        let (first, first_rx) = register(1).await;
+        assert!(first.is_ok());
+        let (duplicate, _) = register(1).await;
+        assert_eq!(duplicate.err(), Some(RegisterError::DuplicateId(1)));
+        let (overflow, _) = register(2).await;
+        assert_eq!(overflow.err(), Some(RegisterError::Full { id: 2, max: 1 }));
+        assert!(matches!(
+            HandshakeError::from(RegisterError::Full { id: 2, max: 1 }),
+            HandshakeError::Rejected {
+                id: 2,
+                reason: RejectReason::Full(1)
+            }
+        ));
+
+        // Once the first connection's queue is gone, the id can be taken over.
+        drop(first_rx);
+        let (takeover, _rx) = register(1).await;
+        assert!(takeover.is_ok());
+    }
+
+    #[test]
+    fn framer_numbers_commands_and_passes_raw_frames_through() {
+        let sequence = Arc::new(AtomicU64::new(0));
+        let framer = Framer {
+            sequence: Arc::clone(&sequence),
+            frames: FrameFormat::Text,
+        };
+        let command = LuaCommand::chat_message("hi".to_string());
+        let encoded = encode_command(&command).unwrap();
+
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            let Message::Text(text) = framer
+                .frame(Payload::Command(Arc::clone(&encoded)))
+                .unwrap()
+            else {
+                panic!("commands go out as text frames");
+            };
+            let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+            seen.push(value["seq"].as_u64().unwrap());
+            // The envelope adds the number without disturbing the command itself.
+            let decoded: LuaCommand = serde_json::from_value(value).unwrap();
+            assert_eq!(decoded.id(), command.id());
+        }
+        assert_eq!(seen, vec![1, 2, 3]);
+
+        let raw = framer
+            .frame(Payload::Raw(Message::Text("{\"raw\":true}".to_string())))
+            .unwrap();
+        assert!(matches!(raw, Message::Text(text) if text == "{\"raw\":true}"));
+        assert_eq!(sequence.load(Ordering::Relaxed), 3);
+
+        let binary = Framer {
+            sequence: Arc::new(AtomicU64::new(0)),
+            frames: FrameFormat::Binary,
+        };
+        let Message::Binary(bytes) = binary.frame(Payload::Command(encoded)).unwrap() else {
+            panic!("binary clients get binary frames");
+        };
+        let value: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(value["seq"], 1);
+    }
+
+    #[tokio::test]
+    async fn coalescing_keeps_the_newest_command_and_finishes_the_rest() {
+        let registry = ClientRegistry::new(
+            RegistryConfig {
+                coalesce_commands: Arc::from(["message".to_string()]),
+                ..RegistryConfig::from_env()
+            },
+            Arc::new(crate::store::MemoryStore::default()),
+        );
+        let (tx, mut rx) = mpsc::channel(8);
+        let client = registry
+            .register(1, tx, vec![Capability::Chat], 1, None, FrameFormat::Text)
+            .await
+            .unwrap();
+        let pending = crate::actions::PendingCommands::new(16, 16, Duration::from_secs(60));
+        let mut ids = Vec::new();
+        for text in ["one", "two", "three"] {
+            let command = LuaCommand::chat_message(text.to_string());
+            ids.push(command.id().to_string());
+            pending
+                .track(command.id().to_string(), Some(1), None)
+                .await
+                .unwrap();
+            client.send_lua_command(&command).await.unwrap();
+        }
+        // Not configured to coalesce, so it is always written.
+        client
+            .send_lua_command(&LuaCommand::query_capabilities())
+            .await
+            .unwrap();
+
+        let mut written = Vec::new();
+        while let Ok(outbound) = rx.try_recv() {
+            if !client
+                .coalescer
+                .drop_superseded(&outbound, &pending, 1)
+                .await
+            {
+                written.push(outbound.command_id().unwrap().to_string());
+            }
+        }
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[0], ids[2]);
+        // The dropped commands no longer hold pending slots; the survivor still waits.
+        let tracked: Vec<String> = pending
+            .snapshot()
+            .await
+            .into_iter()
+            .map(|command| command.command_id)
+            .collect();
+        assert_eq!(tracked, vec![ids[2].clone()]);
+    }
+
+    fn frames(
+        events: &[serde_json::Value],
+    ) -> impl futures::Stream<Item = Result<Message, axum::Error>> + Unpin + use<> {
+        let frames: Vec<_> = events
+            .iter()
+            .map(|event| Ok(Message::Text(event.to_string())))
+            .collect();
+        futures::stream::iter(frames)
+    }
+
+    #[tokio::test]
+    async fn lenient_registration_holds_at_most_max_held_early_events() {
+        let chat = json!({"type": "chat", "username": "steve", "message": "hi", "computer_id": 1});
+        let register = json!({"type": "register", "id": 1, "capabilities": ["chat"]});
+        let lenient = RegisterPolicy {
+            strict: false,
+            max_held: 2,
+        };
+
+        let mut receiver = frames(&[chat.clone(), chat.clone(), register.clone()]);
+        let (registration, held) = await_register(&mut receiver, None, lenient).await.unwrap();
+        assert_eq!(registration.id, 1);
+        assert_eq!(held.len(), 2);
+
+        let mut receiver = frames(&[chat.clone(), chat.clone(), chat.clone(), register.clone()]);
+        assert!(matches!(
+            await_register(&mut receiver, None, lenient).await,
+            Err(HandshakeError::NotRegister)
+        ));
+    }
+
+    #[tokio::test]
+    async fn strict_registration_refuses_a_chat_first_frame() {
+        let chat = json!({"type": "chat", "username": "steve", "message": "hi", "computer_id": 1});
+        let register = json!({"type": "register", "id": 1, "capabilities": ["chat"]});
+        let strict = RegisterPolicy {
+            strict: true,
+            max_held: 8,
+        };
+
+        let mut receiver = frames(&[chat, register.clone()]);
+        let Err(refused) = await_register(&mut receiver, None, strict).await else {
+            panic!("a chat before register was accepted");
+        };
+        assert!(matches!(refused, HandshakeError::NotRegister));
+        assert_eq!(refused.close_code(), Some(close_code::PROTOCOL));
+
+        let mut receiver = frames(&[register]);
+        let (registration, held) = await_register(&mut receiver, None, strict).await.unwrap();
+        assert_eq!(registration.id, 1);
+        assert!(held.is_empty());
+    }
+
+    #[tokio::test]
+    async fn non_positive_ids_are_refused() {
+        for id in [0, -1, i32::MIN] {
+            let mut receiver = frames(&[json!({"type": "register", "id": id, "capabilities": []})]);
+            let (registration, _) = await_register(&mut receiver, None, RegisterPolicy::from_env())
+                .await
+                .unwrap();
+            assert!(
+                matches!(
+                    vet(&registration, None, None, false),
+                    Err(HandshakeError::InvalidId(refused)) if refused == id
+                ),
+                "id {id} was not refused"
+            );
+        }
+        assert!(vet(&registration(1, 1), None, None, false).is_ok());
+    }
+
+    const PING_POLICY: AppPingPolicy = AppPingPolicy {
+        idle: Duration::from_secs(30),
+        timeout: Duration::from_secs(10),
+    };
+
+    #[test]
+    fn an_unanswered_ping_becomes_overdue() {
+        let mut pings = PingTracker::default();
+        let start = Instant::now();
+        let quiet = start + PING_POLICY.idle;
+
+        let PingCheck::Send(ping) = pings.check(&PING_POLICY, start, true, quiet) else {
+            panic!("a quiet pingable client is pinged");
+        };
+        assert!(pings.is_outstanding(ping.id()));
+        // Waiting out the timeout, without sending a second ping.
+        let check = pings.check(&PING_POLICY, start, true, quiet + PING_POLICY.timeout);
+        assert!(matches!(check, PingCheck::Wait));
+        let check = pings.check(
+            &PING_POLICY,
+            start,
+            true,
+            quiet + PING_POLICY.timeout + Duration::from_secs(1),
+        );
+        assert!(matches!(check, PingCheck::Overdue(id) if id == ping.id()));
+    }
+
+    #[test]
+    fn an_answered_ping_clears_and_only_quiet_pingable_clients_are_pinged() {
+        let mut pings = PingTracker::default();
+        let start = Instant::now();
+        let quiet = start + PING_POLICY.idle;
+
+        assert!(matches!(
+            pings.check(&PING_POLICY, start, false, quiet),
+            PingCheck::Wait
+        ));
+        assert!(matches!(
+            pings.check(&PING_POLICY, start, true, quiet - Duration::from_secs(1)),
+            PingCheck::Wait
+        ));
+
+        let PingCheck::Send(ping) = pings.check(&PING_POLICY, start, true, quiet) else {
+            panic!("a quiet pingable client is pinged");
+        };
+        assert!(!pings.is_outstanding("some other command"));
+        assert!(pings.is_outstanding(ping.id()));
+        pings.answered();
+
+        // Long after the timeout, the answered ping is never reported overdue; the client
+        // was heard from, so no new ping is due either.
+        let later = quiet + PING_POLICY.timeout * 2;
+        assert!(matches!(
+            pings.check(&PING_POLICY, later, true, later),
+            PingCheck::Wait
+        ));
+    }
+
+    #[tokio::test]
+    async fn only_chat_makes_a_client_the_most_recent() {
+        let registry = ClientRegistry::new(
+            RegistryConfig::from_env(),
+            Arc::new(crate::store::MemoryStore::default()),
+        );
+        let mut clients = Vec::new();
+        for id in [1, 2] {
+            let (tx, rx) = mpsc::channel(8);
+            let client = registry
+                .register(id, tx, vec![Capability::Chat], 1, None, FrameFormat::Text)
+                .await
+                .unwrap();
+            clients.push((client, rx));
+        }
+        let most_recent = || async {
+            registry
+                .find_most_recent_by_capability(&Capability::Chat, 0)
+                .await
+                .map(|sender| sender.id())
+        };
+        let chat: ComputerEvent =
+            serde_json::from_value(json!({"type": "chat", "username": "alice", "message": "hi"}))
+                .unwrap();
+        let telemetry: ComputerEvent =
+            serde_json::from_value(json!({"type": "telemetry", "data": {"fuel": 10}})).unwrap();
+
+        clients[1].0.mark_event(&telemetry);
+        assert_eq!(most_recent().await, None);
+        clients[0].0.mark_event(&chat);
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        clients[1].0.mark_event(&telemetry);
+        assert_eq!(most_recent().await, Some(1));
+        clients[1].0.mark_event(&chat);
+        assert_eq!(most_recent().await, Some(2));
+    }
+
+    #[test]
+    fn origins_are_checked_only_when_an_allowlist_is_configured() {
+        use crate::actions::PendingCommands;
+        use crate::actions::tests::{dispatch, registry};
+        use crate::brain::BrainService;
+        use axum::http::HeaderValue;
+
+        let state = |allowed_origins: Option<Vec<&str>>| {
+            let registry = registry();
+            let shutdown = ShutdownSignal::new();
+            let metrics = Arc::new(Metrics::default());
+            let brain = BrainService::new(
+                "localhost:50051".to_string(),
+                shutdown.clone(),
+                Arc::clone(&metrics),
+            )
+            .unwrap();
+            let control = ComputerEventService::builder(
+                Arc::new(brain),
+                registry.clone(),
+                dispatch(
+                    &registry,
+                    PendingCommands::new(16, 4, Duration::from_secs(60)),
+                ),
+                shutdown.clone(),
+            )
+            .build();
+            WebsocketState::new(
+                registry,
+                control,
+                metrics,
+                allowed_origins
+                    .map(|origins| Arc::new(origins.into_iter().map(String::from).collect())),
+                Duration::from_secs(1),
+                Arc::new(tokio::sync::Semaphore::new(1)),
+                Duration::from_secs(1),
+                None,
+                Duration::from_secs(1),
+                shutdown,
+                None,
+                RegisterPolicy {
+                    strict: true,
+                    max_held: 0,
+                },
+            )
+        };
+        let from = |origin: Option<&[u8]>| {
+            let mut headers = HeaderMap::new();
+            if let Some(origin) = origin {
+                headers.insert(ORIGIN, HeaderValue::from_bytes(origin).unwrap());
+            }
+            headers
+        };
+
+        let open = state(None);
+        assert!(open.origin_allowed(&from(None)));
+        assert!(open.origin_allowed(&from(Some(b"https://anywhere.example"))));
+
+        let restricted = state(Some(vec!["https://dash.example"]));
+        assert!(
+            restricted.origin_allowed(&from(None)),
+            "in-game computers send no origin"
+        );
+        assert!(restricted.origin_allowed(&from(Some(b"https://dash.example"))));
+        assert!(!restricted.origin_allowed(&from(Some(b"https://evil.example"))));
+        assert!(!restricted.origin_allowed(&from(Some(b"https://dash.example.evil"))));
+        assert!(!restricted.origin_allowed(&from(Some(b"https://dash.\xff"))));
+    }
+
+    #[tokio::test]
+    async fn a_fan_out_frames_each_recipient_in_its_format_and_isolates_failures() {
+        let registry = ClientRegistry::new(
+            RegistryConfig::from_env(),
+            Arc::new(crate::store::MemoryStore::default()),
+        );
+        let mut queues = Vec::new();
+        for (id, frames) in [
+            (1, FrameFormat::Text),
+            (2, FrameFormat::Binary),
+            (3, FrameFormat::Text),
+        ] {
+            let (tx, rx) = mpsc::channel(4);
+            let client = registry
+                .register(id, tx, vec![Capability::Chat], 1, None, frames)
+                .await
+                .unwrap();
+            queues.push((client, rx));
+        }
+        // Client 3's forwarder is gone, so its queue refuses the send.
+        let (_, closed) = queues.pop().unwrap();
+        drop(closed);
+
+        let command = LuaCommand::chat_message("hi".to_string());
+        let outcomes = registry
+            .fan_out_lua_command(&[1, 2, 3], &command, None)
+            .await;
+        assert!(matches!(outcomes[0], (1, BroadcastOutcome::Delivered)));
+        assert!(matches!(outcomes[1], (2, BroadcastOutcome::Delivered)));
+        assert!(matches!(
+            outcomes[2],
+            (3, BroadcastOutcome::Failed(ClientSendError::SendFailed(_)))
+        ));
+
+        let mut written = Vec::new();
+        for (client, rx) in &mut queues {
+            let outbound = rx.try_recv().unwrap();
+            written.push(client.framer().frame(outbound.payload).unwrap());
+        }
+        let bodies: Vec<serde_json::Value> = match written.as_slice() {
+            [Message::Text(text), Message::Binary(bytes)] => vec![
+                serde_json::from_str(text).unwrap(),
+                serde_json::from_slice(bytes).unwrap(),
+            ],
+            other => panic!("expected a text then a binary frame, got {other:?}"),
+        };
+        assert_eq!(bodies[0], bodies[1]);
+        assert_eq!(bodies[0]["id"], command.id());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_client_that_never_reads_is_flagged_as_stalled() {
+        let registry = ClientRegistry::new(
+            RegistryConfig::from_env(),
+            Arc::new(crate::store::MemoryStore::default()),
+        );
+        let (tx, rx) = mpsc::channel(4);
+        let client = registry
+            .register(1, tx, vec![Capability::Chat], 1, None, FrameFormat::Text)
+            .await
+            .unwrap();
+        // Accepts the frame but never finishes writing it, like a socket whose peer stopped
+        // reading once the TCP buffers filled.
+        let socket = Box::pin(futures::sink::unfold((), |(), _: Message| {
+            std::future::pending::<Result<(), Infallible>>()
+        }));
+        let stalled = Arc::new(Notify::new());
+        let write_timeout = Duration::from_secs(10);
+        let forwarder = tokio::spawn(forward_outbound(
+            1,
+            rx,
+            Arc::new(AsyncMutex::new(socket)),
+            client.framer(),
+            Arc::clone(&client.coalescer),
+            crate::actions::PendingCommands::new(16, 16, Duration::from_secs(60)),
+            write_timeout,
+            Arc::clone(&stalled),
+        ));
+
+        let started = tokio::time::Instant::now();
+        client
+            .send_lua_command(&LuaCommand::chat_message("hi".to_string()))
+            .await
+            .unwrap();
+        stalled.notified().await;
+        assert_eq!(started.elapsed(), write_timeout);
+        // The forwarder gives up on the socket rather than writing anything else.
+        forwarder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_binary_writes_a_binary_frame_to_the_socket() {
+        let registry = ClientRegistry::new(
+            RegistryConfig::from_env(),
+            Arc::new(crate::store::MemoryStore::default()),
+        );
+        let (tx, rx) = mpsc::channel(4);
+        let client = registry
+            .register(1, tx, Vec::new(), 1, None, FrameFormat::Text)
+            .await
+            .unwrap();
+        let (socket, mut written) = futures::channel::mpsc::unbounded::<Message>();
+        let forwarder = tokio::spawn(forward_outbound(
+            1,
+            rx,
+            Arc::new(AsyncMutex::new(socket)),
+            client.framer(),
+            Arc::clone(&client.coalescer),
+            crate::actions::PendingCommands::new(16, 16, Duration::from_secs(60)),
+            Duration::from_secs(1),
+            Arc::new(Notify::new()),
+        ));
+
+        client.send_binary(vec![0, 159, 255]).await.unwrap();
+        let frame = written.next().await.unwrap();
+        assert!(matches!(frame, Message::Binary(bytes) if bytes == [0, 159, 255]));
+        // Raw frames are not numbered, so the next command is still the first.
+        assert_eq!(client.sequence.load(Ordering::Relaxed), 0);
+
+        registry.remove(1, &client).await;
+        drop(client);
+        forwarder.await.unwrap();
     }
 }
@@ -0,0 +1,295 @@
+//! Starts the WebSocket and gRPC servers in-process in front of a stub brain and drives them
+//! like a real computer and a real operator would.
+
+use blueking::brain::{Brain, BrainError, BrainReply};
+use blueking::events::ComputerChatEvent;
+use blueking::gestalt_client::GestaltClient;
+use blueking::metrics::Metrics;
+use blueking::supervisor::Supervisor;
+use blueking::{
+    ListComputersRequest, Listen, SendAndWaitRequest, ServeOptions, ShutdownReason, ShutdownSignal,
+};
+use futures::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Grace window the server announces on shutdown; short to keep the tests quick.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Replies to every chat message by echoing it back.
+struct EchoBrain;
+
+#[tonic::async_trait]
+impl Brain for EchoBrain {
+    async fn chat(&self, chat_event: ComputerChatEvent) -> Result<BrainReply, BrainError> {
+        Ok(BrainReply {
+            text: format!("echo: {}", chat_event.message),
+            ..Default::default()
+        })
+    }
+}
+
+/// A running server and the addresses it bound.
+struct Harness {
+    ws: SocketAddr,
+    grpc: SocketAddr,
+    shutdown: ShutdownSignal,
+    server: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>,
+}
+
+impl Harness {
+    async fn start<B: Brain>(brain: B) -> Self {
+        let shutdown = ShutdownSignal::new();
+        let (websocket, ws_bound) = Listen::ephemeral();
+        let (grpc, grpc_bound) = Listen::ephemeral();
+        let options = ServeOptions {
+            websocket,
+            grpc,
+            reload: None,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+        };
+        let server = tokio::spawn(blueking::serve(
+            Arc::new(brain),
+            Arc::new(Metrics::default()),
+            shutdown.clone(),
+            Supervisor::new(shutdown.clone()),
+            options,
+        ));
+        let ws = within(ws_bound)
+            .await
+            .expect("WebSocket server did not bind");
+        let grpc = within(grpc_bound).await.expect("gRPC server did not bind");
+        Self {
+            ws,
+            grpc,
+            shutdown,
+            server,
+        }
+    }
+
+    /// Open a WebSocket and complete the handshake as computer `id`.
+    async fn register(&self, id: i32, capabilities: &[&str]) -> Socket {
+        let (mut socket, _) = within(tokio_tungstenite::connect_async(format!(
+            "ws://{}/cc",
+            self.ws
+        )))
+        .await
+        .expect("WebSocket connect failed");
+        send(
+            &mut socket,
+            json!({"type": "register", "id": id, "capabilities": capabilities}),
+        )
+        .await;
+        let ack = next_command(&mut socket, "registered").await;
+        assert_eq!(ack["args"]["client_id"], id);
+        socket
+    }
+
+    async fn stop(self) {
+        self.shutdown.trigger(ShutdownReason::Interrupt);
+        within(self.server)
+            .await
+            .expect("server task panicked")
+            .expect("server failed");
+    }
+}
+
+async fn within<F: Future>(future: F) -> F::Output {
+    tokio::time::timeout(STEP_TIMEOUT, future)
+        .await
+        .expect("timed out")
+}
+
+async fn send(socket: &mut Socket, event: Value) {
+    within(socket.send(Message::text(event.to_string())))
+        .await
+        .expect("WebSocket send failed");
+}
+
+/// Read frames until a command called `name` arrives, skipping anything else.
+async fn next_command(socket: &mut Socket, name: &str) -> Value {
+    loop {
+        let frame = within(socket.next())
+            .await
+            .expect("socket closed")
+            .expect("WebSocket read failed");
+        let Message::Text(text) = frame else {
+            continue;
+        };
+        let command: Value = serde_json::from_str(&text).expect("command is not JSON");
+        if command["name"] == name {
+            return command;
+        }
+    }
+}
+
+#[tokio::test]
+async fn chat_round_trips_through_the_brain() {
+    let harness = Harness::start(EchoBrain).await;
+    let mut socket = harness.register(7, &["chat"]).await;
+
+    let mut grpc = GestaltClient::connect(format!("http://{}", harness.grpc))
+        .await
+        .expect("gRPC connect failed");
+    let computers = grpc
+        .list_computers(ListComputersRequest {})
+        .await
+        .expect("ListComputers failed")
+        .into_inner()
+        .computers;
+    assert_eq!(computers.iter().map(|c| c.id).collect::<Vec<_>>(), [7]);
+
+    send(
+        &mut socket,
+        json!({"type": "chat", "username": "alice", "message": "hello"}),
+    )
+    .await;
+    let reply = next_command(&mut socket, "message").await;
+    assert_eq!(reply["args"]["message"], "echo: hello");
+
+    drop(socket);
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn shutdown_notice_precedes_the_close_frame() {
+    let harness = Harness::start(EchoBrain).await;
+    let mut socket = harness.register(8, &["chat"]).await;
+
+    harness.shutdown.trigger(ShutdownReason::Terminate);
+    let notice = next_command(&mut socket, "server_notice").await;
+    assert_eq!(
+        notice["args"]["shutdown_in_secs"],
+        SHUTDOWN_TIMEOUT.as_secs()
+    );
+    let frame = within(socket.next())
+        .await
+        .expect("socket closed without a close frame")
+        .expect("WebSocket read failed");
+    let Message::Close(Some(close)) = frame else {
+        panic!("expected the close frame after the notice, got {frame:?}");
+    };
+    assert_eq!(u16::from(close.code), 1001);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn binary_clients_get_commands_in_binary_frames() {
+    let harness = Harness::start(EchoBrain).await;
+    let (mut socket, _) = within(tokio_tungstenite::connect_async(format!(
+        "ws://{}/cc",
+        harness.ws
+    )))
+    .await
+    .expect("WebSocket connect failed");
+    send(
+        &mut socket,
+        json!({"type": "register", "id": 4, "capabilities": [], "frames": "binary"}),
+    )
+    .await;
+
+    let frame = within(socket.next())
+        .await
+        .expect("socket closed")
+        .expect("WebSocket read failed");
+    let Message::Binary(bytes) = frame else {
+        panic!("expected a binary frame, got {frame:?}");
+    };
+    let ack: Value = serde_json::from_slice(&bytes).expect("command is not JSON");
+    assert_eq!(ack["name"], "registered");
+    assert_eq!(ack["args"]["client_id"], 4);
+    assert_eq!(ack["seq"], 1);
+
+    drop(socket);
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn send_and_wait_reaches_only_clients_matching_the_query() {
+    let harness = Harness::start(EchoBrain).await;
+    let mut nether = harness.register(5, &["chat"]).await;
+    let _overworld = harness.register(6, &["chat"]).await;
+    send(
+        &mut nether,
+        json!({"type": "telemetry", "data": {"dimension": "nether"}}),
+    )
+    .await;
+    // Events from one socket are handled in order, so the reply means the telemetry landed.
+    send(&mut nether, json!({"type": "query", "what": "commands"})).await;
+    next_command(&mut nether, "query_reply").await;
+
+    let mut grpc = GestaltClient::connect(format!("http://{}", harness.grpc))
+        .await
+        .expect("gRPC connect failed");
+    let call = tokio::spawn(async move {
+        grpc.send_and_wait(SendAndWaitRequest {
+            capability: String::new(),
+            command_json: json!({"name": "message", "args": {"message": "hi"}}).to_string(),
+            timeout_ms: 0,
+            query_json: json!({"metadata": {"dimension": "nether"}}).to_string(),
+            label: String::new(),
+        })
+        .await
+    });
+    let command = next_command(&mut nether, "message").await;
+    send(
+        &mut nether,
+        json!({"type": "command_result", "command_id": command["id"]}),
+    )
+    .await;
+    let response = within(call)
+        .await
+        .expect("SendAndWait task panicked")
+        .expect("SendAndWait failed")
+        .into_inner();
+    assert_eq!(response.delivered_to, [5]);
+    assert!(response.success);
+
+    drop(nether);
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn a_duplicate_live_id_is_rejected_with_a_policy_close() {
+    let harness = Harness::start(EchoBrain).await;
+    let _live = harness.register(9, &["chat"]).await;
+    let (mut duplicate, _) = within(tokio_tungstenite::connect_async(format!(
+        "ws://{}/cc",
+        harness.ws
+    )))
+    .await
+    .expect("WebSocket connect failed");
+    send(
+        &mut duplicate,
+        json!({"type": "register", "id": 9, "capabilities": ["chat"]}),
+    )
+    .await;
+
+    let rejection = next_command(&mut duplicate, "register_rejected").await;
+    assert!(
+        rejection["args"]["reason"]
+            .as_str()
+            .is_some_and(|reason| reason.contains("already connected")),
+        "unexpected rejection {rejection}"
+    );
+    let frame = within(duplicate.next())
+        .await
+        .expect("socket closed without a close frame")
+        .expect("WebSocket read failed");
+    let Message::Close(Some(close)) = frame else {
+        panic!("expected a close frame after the rejection, got {frame:?}");
+    };
+    assert_eq!(u16::from(close.code), 1008);
+
+    harness.stop().await;
+}
@@ -0,0 +1,103 @@
+//! Payloads older clients and callers still send must keep decoding as the protocol evolves.
+//!
+//! Each file under `tests/fixtures/wire` is one payload captured in an earlier wire shape; add a
+//! fixture before renaming or retyping anything it covers.
+
+use blueking::SendAndWaitRequest;
+use blueking::events::{ComputerEvent, DeregisterReason, decode_event};
+use blueking::websocket::LuaCommand;
+use prost::Message;
+use std::path::{Path, PathBuf};
+
+fn fixtures(kind: &str) -> Vec<(String, Vec<u8>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/wire")
+        .join(kind);
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("reading {}: {e}", dir.display()))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no fixtures in {}", dir.display());
+    paths
+        .into_iter()
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, std::fs::read(&path).unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn historical_events_still_decode() {
+    for (name, bytes) in fixtures("events") {
+        let (event, _) =
+            decode_event(&bytes).unwrap_or_else(|e| panic!("{name} no longer decodes: {e}"));
+        let expected = match name.as_str() {
+            "deregister_v1_timed_out" => Some(DeregisterReason::TimedOut),
+            "deregister_v1_closed" => Some(DeregisterReason::Closed),
+            _ => None,
+        };
+        if let Some(expected) = expected {
+            match event {
+                ComputerEvent::Deregister { id: 1, reason } => assert_eq!(reason, expected),
+                other => panic!("{name} decoded as {other:?}"),
+            }
+        }
+    }
+}
+
+#[test]
+fn historical_commands_still_decode() {
+    for (name, bytes) in fixtures("commands") {
+        serde_json::from_slice::<LuaCommand>(&bytes)
+            .unwrap_or_else(|e| panic!("{name} no longer decodes: {e}"));
+    }
+}
+
+/// `SendAndWaitRequest` as first released, before the query and label fields.
+#[derive(Clone, PartialEq, prost::Message)]
+struct SendAndWaitRequestV1 {
+    #[prost(string, tag = "1")]
+    capability: String,
+    #[prost(string, tag = "2")]
+    command_json: String,
+    #[prost(uint32, tag = "3")]
+    timeout_ms: u32,
+}
+
+/// A caller built against a newer proto, with a field this server does not know yet.
+#[derive(Clone, PartialEq, prost::Message)]
+struct SendAndWaitRequestNext {
+    #[prost(string, tag = "1")]
+    capability: String,
+    #[prost(string, tag = "2")]
+    command_json: String,
+    #[prost(string, tag = "99")]
+    added_later: String,
+}
+
+#[test]
+fn older_and_newer_grpc_requests_still_decode() {
+    let older = SendAndWaitRequestV1 {
+        capability: "turtle".to_string(),
+        command_json: r#"{"name":"message","args":{"message":"hi"}}"#.to_string(),
+        timeout_ms: 500,
+    };
+    let decoded = SendAndWaitRequest::decode(older.encode_to_vec().as_slice()).unwrap();
+    assert_eq!(decoded.capability, older.capability);
+    assert_eq!(decoded.command_json, older.command_json);
+    assert_eq!(decoded.timeout_ms, 500);
+    // Fields added since default to empty, which keeps the old capability routing.
+    assert!(decoded.query_json.is_empty() && decoded.label.is_empty());
+
+    let newer = SendAndWaitRequestNext {
+        capability: "turtle".to_string(),
+        command_json: "{}".to_string(),
+        added_later: "ignored".to_string(),
+    };
+    let decoded = SendAndWaitRequest::decode(newer.encode_to_vec().as_slice()).unwrap();
+    assert_eq!(decoded.capability, "turtle");
+    assert_eq!(decoded.command_json, "{}");
+}
@@ -6,18 +6,44 @@ local function sendRegistration(ws)
     local regEvent = {
         type = "register",
         id = os.getComputerID(),
-        capabilities = peripherals.currentCapabilities()
+        capabilities = peripherals.currentCapabilities(),
+        protocol_version = config.protocol_version,
+        label = config.label
     }
     print("[GESTALT] Sending registration: " .. textutils.serialiseJSON(regEvent))
+    -- Added after logging so the secret never reaches the console
+    regEvent.token = config.token
     ws.send(textutils.serialiseJSON(regEvent))
 end
 
+-- Sequence number of the last command received on this connection.
+local lastSeq = 0
+
+-- Tell the server when commands were skipped, so it can log the loss.
+local function checkSequence(ws, seq)
+    if seq ~= lastSeq + 1 then
+        print("[WARN] Command sequence jumped from " .. lastSeq .. " to " .. seq)
+        ws.send(textutils.serialiseJSON({
+            type = "sequence",
+            last_seen = lastSeq,
+            received = seq
+        }))
+    end
+    lastSeq = seq
+end
+
+-- Returns the URL to move to when the server migrates this client, otherwise nil.
 local function handleWebsocketMessage(ws, message)
     print("[GESTALT] Received message: " .. message)
 
     local ok, data = pcall(textutils.unserialiseJSON, message)
-    if ok and data then
-        commands.execute(ws, data)
+    if ok and data and type(data.seq) == "number" then
+        checkSequence(ws, data.seq)
+    end
+    if ok and data and data.type == "error" then
+        print("[ERROR] Server rejected frame: " .. tostring(data.detail))
+    elseif ok and data then
+        return commands.execute(ws, data)
     else
         print("[ERROR] Failed to parse message: " .. tostring(data))
     end
@@ -35,6 +61,7 @@ local function run()
         local ws = nil
         local connected = false
         local keepaliveTimer = nil
+        local reconnectDelay = config.reconnect_delay
 
         while true do
             local event, p1, p2, p3, p4 = os.pullEvent()
@@ -43,6 +70,7 @@ local function run()
                 if p1 == config.server_url then
                     ws = p2
                     connected = true
+                    lastSeq = 0
                     print("[GESTALT] Connected to brain!")
 
                     sendRegistration(ws)
@@ -57,7 +85,7 @@ local function run()
 
             elseif event == "websocket_closed" then
                 if p1 == config.server_url then
-                    print("[GESTALT] Connection closed")
+                    print("[GESTALT] Connection closed: " .. tostring(p2))
                     connected = false
                     keepaliveTimer = nil
                     break
@@ -65,7 +93,15 @@ local function run()
 
             elseif event == "websocket_message" then
                 if p1 == config.server_url then
-                    handleWebsocketMessage(ws, p2)
+                    local newUrl = handleWebsocketMessage(ws, p2)
+                    if newUrl then
+                        ws.close()
+                        config.server_url = newUrl
+                        reconnectDelay = config.restart_reconnect_delay
+                        connected = false
+                        keepaliveTimer = nil
+                        break
+                    end
                 end
 
             elseif event == "chat" then
@@ -76,7 +112,8 @@ local function run()
                     local chatEvent = textutils.serialiseJSON({
                         type = "chat",
                         username = username,
-                        message = message
+                        message = message,
+                        client_ts = os.epoch("utc")
                     })
 
                     print("[GESTALT] Sending chat event: " .. chatEvent)
@@ -91,8 +128,8 @@ local function run()
             end
         end
 
-        print("[GESTALT] Reconnecting in " .. config.reconnect_delay .. " seconds...")
-        os.sleep(config.reconnect_delay)
+        print("[GESTALT] Reconnecting in " .. reconnectDelay .. " seconds...")
+        os.sleep(reconnectDelay)
     end
 end
 
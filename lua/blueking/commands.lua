@@ -1,30 +1,101 @@
+local config = require("blueking.config")
 local peripherals = require("blueking.peripherals")
 
-local function execute(ws, command)
-    print("[GESTALT] Executing command: " .. command.name .. " (id: " .. command.id .. ")")
-
-    local errorMsg
-
+-- Run a single command and return an error message, or nil on success.
+local function run(command)
     if command.name == "message" then
         local ok, result = pcall(function()
             return peripherals.sendMessage(command.args.message)
         end)
 
         if ok and result then
-            errorMsg = nil
+            return nil
+        elseif ok then
+            return "Failed to send message (no chatBox)"
+        else
+            return tostring(result)
+        end
+    elseif command.name == "whisper" then
+        local ok, result = pcall(function()
+            return peripherals.sendMessageToPlayer(command.args.message, command.args.target)
+        end)
+
+        if ok and result then
+            return nil
         elseif ok then
-            errorMsg = "Failed to send message (no chatBox)"
+            return "Failed to send whisper (no chatBox)"
         else
-            errorMsg = tostring(result)
+            return tostring(result)
         end
+    elseif command.name == "batch" then
+        for i, sub in ipairs(command.args.commands) do
+            local errorMsg = run(sub)
+            if errorMsg then
+                return "Batch step " .. i .. " (" .. sub.name .. ") failed: " .. errorMsg
+            end
+        end
+        return nil
+    elseif command.name == "ping" then
+        return nil
+    elseif command.name == "write_file" then
+        if not config.file_transfer then
+            return "file_transfer is not enabled on this computer"
+        end
+        local file, err = fs.open(command.args.path, command.args.mode == "append" and "a" or "w")
+        if not file then
+            return err or ("Cannot open " .. command.args.path)
+        end
+        file.write(command.args.contents)
+        file.close()
+        return nil
     else
-        errorMsg = "Unknown command: " .. command.name
+        return "Unknown command: " .. command.name
     end
+end
+
+-- Returns the URL to reconnect to when the server migrates this client, otherwise nil.
+local function execute(ws, command)
+    -- Handshake replies are informational: no ack, no result
+    if command.name == "registered" then
+        print("[GESTALT] Registered as " .. command.args.client_id
+            .. " (server v" .. command.args.server_version .. ")")
+        return
+    elseif command.name == "register_rejected" then
+        print("[ERROR] Registration rejected: " .. command.args.reason)
+        return
+    elseif command.name == "query_capabilities" then
+        ws.send(textutils.serialiseJSON({
+            type = "update_capabilities",
+            capabilities = peripherals.currentCapabilities()
+        }))
+        return
+    elseif command.name == "query_reply" then
+        print("[GESTALT] Server speaks protocol v" .. command.args.protocol_version
+            .. ": " .. table.concat(command.args.commands, ", "))
+        return
+    elseif command.name == "server_notice" then
+        print("[GESTALT] " .. command.args.message .. "; closing in "
+            .. command.args.shutdown_in_secs .. "s")
+        return
+    elseif command.name == "reconnect" then
+        print("[GESTALT] Server asked to reconnect to " .. command.args.url)
+        return command.args.url
+    end
+
+    ws.send(textutils.serialiseJSON({
+        type = "command_ack",
+        command_id = command.id
+    }))
+
+    print("[GESTALT] Executing command: " .. command.name .. " (id: " .. command.id .. ")")
+
+    local errorMsg = run(command)
 
     local resultEvent = {
         type = "command_result",
         command_id = command.id,
-        error = errorMsg
+        error = errorMsg,
+        client_ts = os.epoch("utc")
     }
 
     local resultJson = textutils.serialiseJSON(resultEvent)
@@ -1,9 +1,17 @@
 local config = {
     version = "0.2.0",
+    protocol_version = 1,
     server_url = "ws://192.168.50.176:3000/api/ws",
     bot_name = "Gestalt",
+    -- Optional human-friendly name for this computer, e.g. "MinerBob"
+    label = nil,
     reconnect_delay = 5,
-    keepalive_interval = 60
+    restart_reconnect_delay = 1,
+    keepalive_interval = 60,
+    -- Secret the server requires when BLUEKING_CLIENT_TOKEN is set
+    token = nil,
+    -- Let the server write files on this computer (advertises the file_transfer capability)
+    file_transfer = false
 }
 
 return config
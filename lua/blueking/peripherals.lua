@@ -16,7 +16,12 @@ end
 
 local function currentCapabilities()
     refreshChatBox()
-    return chatBox and { "chat" } or {}
+    local capabilities = chatBox and { "chat" } or {}
+    if config.file_transfer then
+        table.insert(capabilities, "file_transfer")
+    end
+    table.insert(capabilities, "ping")
+    return capabilities
 end
 
 local function sendMessage(message)
@@ -30,8 +35,20 @@ local function sendMessage(message)
     end
 end
 
+local function sendMessageToPlayer(message, username)
+    if chatBox then
+        print("[GESTALT] Whispering to " .. username .. ": " .. message)
+        chatBox.sendMessageToPlayer(message, username, config.bot_name)
+        return true
+    else
+        print("[ERROR] No chatBox found")
+        return false
+    end
+end
+
 return {
     refreshChatBox = refreshChatBox,
     currentCapabilities = currentCapabilities,
-    sendMessage = sendMessage
+    sendMessage = sendMessage,
+    sendMessageToPlayer = sendMessageToPlayer
 }